@@ -1,5 +1,5 @@
-use std::io::Write;
-use byteorder::{LittleEndian, WriteBytesExt};
+use std::io::{ErrorKind, Read, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use protobuf::Message;
 use crate::pb::backup::KVPair;
 
@@ -9,3 +9,42 @@ pub fn write_to<W>(entry: &KVPair, wt: &mut W) -> crate::Result<()> where W: Wri
     wt.write_all(&buf)?;
     Ok(())
 }
+
+/// Reads the next pair written by `write_to`, None at the end of `rd`.
+pub fn read_from<R>(rd: &mut R) -> crate::Result<Option<KVPair>>
+where
+    R: Read,
+{
+    let len = match rd.read_u64::<LittleEndian>() {
+        Ok(len) => len,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    // The length isn't trusted, the buffer grows with the bytes actually read.
+    let mut buf = vec![];
+    rd.by_ref().take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(format!("Truncated backup entry, {} of {} bytes", buf.len(), len).into());
+    }
+    let entry = KVPair::parse_from_bytes(&buf)
+        .map_err(|err| crate::Error::from(format!("Invalid backup entry, {}", err)))?;
+    Ok(Some(entry))
+}
+
+#[test]
+fn read_truncated_entry() {
+    let mut pair = KVPair::new();
+    pair.key = b"key".to_vec();
+    pair.value = b"value".to_vec();
+    let mut buf = vec![];
+    write_to(&pair, &mut buf).unwrap();
+    let got = read_from(&mut buf.as_slice()).unwrap().unwrap();
+    assert_eq!(got.value, b"value".to_vec());
+    // A corrupt length fails without allocating it.
+    buf[..8].copy_from_slice(&(i64::MAX as u64).to_le_bytes());
+    let err = read_from(&mut buf.as_slice()).unwrap_err();
+    assert!(err.to_string().contains("Truncated"), "{}", err);
+    buf.truncate(12);
+    buf[..8].copy_from_slice(&10u64.to_le_bytes());
+    assert!(read_from(&mut buf.as_slice()).is_err());
+}
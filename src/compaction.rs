@@ -5,20 +5,25 @@ use crate::table::table::Table;
 use log::{error, info, warn};
 use parking_lot::lock_api::{RwLockReadGuard, RwLockWriteGuard};
 use parking_lot::{RawRwLock, RwLock};
-use std::fmt::{Display, Formatter};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display, Formatter};
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::SystemTime;
 
 #[derive(Debug)]
 pub(crate) struct CompactStatus {
     // every level has a *CompactionStatus* that includes multipart *KeyRange*
     pub(crate) levels: RwLock<Vec<LevelCompactStatus>>,
+    // Ids of the tables read by running compactions, of all levels.
+    tables: RwLock<HashSet<u64>>,
 }
 
 impl Default for CompactStatus {
     fn default() -> Self {
         CompactStatus {
             levels: RwLock::new(vec![]),
+            tables: RwLock::new(HashSet::new()),
         }
     }
 }
@@ -28,13 +33,14 @@ impl CompactStatus {
     // other running Compaction. If it can be run, it would store this run in the compactStatus state.
     pub(crate) fn compare_and_add(&self, cd: &CompactDef) -> bool {
         let level = cd.this_level.level();
+        // Checked and added under the write lock, so two workers can't both pass the checks.
+        let lc = self.wl();
         assert!(
-            level + 1 < self.rl().len(),
+            level + 1 < lc.len(),
             "Got level {}, max level {}",
             level,
-            self.rl().len()
+            lc.len()
         );
-        let lc = self.rl();
         let this_level = lc.get(level).unwrap();
         let next_level = lc.get(level + 1).unwrap();
         if this_level.overlaps_with(&cd.this_range) {
@@ -43,13 +49,23 @@ impl CompactStatus {
         if next_level.overlaps_with(&cd.next_range) {
             return false;
         }
+        let mut tables = self.tables.write();
+        if cd
+            .top
+            .iter()
+            .chain(cd.bot.iter())
+            .any(|tb| tables.contains(&tb.id()))
+        {
+            return false;
+        }
 
         // Check whether this level really needs compaction or not. Otherwise, we'll end up
         // running parallel compactions for the same level.
         // *NOTE*: We can directly call this_level.total_size, because we already have acquired a read lock
         // over this and the next level.
-        if cd.this_level.get_total_size() - this_level.get_del_size()
-            < cd.this_level.get_max_total_size()
+        if !cd.seek
+            && cd.this_level.get_total_size() - this_level.get_del_size()
+                < cd.this_level.get_max_total_size()
         {
             log::info!(
                 "skip the compaction, top_size:{}, bot_size:{}, max_size:{}",
@@ -62,6 +78,7 @@ impl CompactStatus {
         this_level.add(cd.this_range.clone());
         next_level.add(cd.next_range.clone());
         this_level.incr_del_size(cd.this_size.load(Ordering::Relaxed));
+        tables.extend(cd.top.iter().chain(cd.bot.iter()).map(|tb| tb.id()));
         true
     }
 
@@ -95,6 +112,35 @@ impl CompactStatus {
             warn!("Looking for seek k range");
             warn!("{}, {}", cd.this_range, cd.next_range);
         }
+        let mut tables = self.tables.write();
+        for tb in cd.top.iter().chain(cd.bot.iter()) {
+            if !tables.remove(&tb.id()) {
+                warn!("Table {} is not being compacted", tb.id());
+            }
+        }
+    }
+
+    // Returns true if the table is read by a running compaction.
+    pub(crate) fn is_compacting(&self, table_id: u64) -> bool {
+        self.tables.read().contains(&table_id)
+    }
+
+    // Marks the whole key range of `level` as being compacted if it has no running compaction.
+    // Returns false if it has one.
+    pub(crate) fn try_hold_level(&self, level: usize) -> bool {
+        let levels = self.wl();
+        let status = &levels[level];
+        if !status.rl().is_empty() {
+            return false;
+        }
+        status.add(INFO_RANGE);
+        true
+    }
+
+    // Releases a level held by `try_hold_level`.
+    pub(crate) fn release_level(&self, level: usize) {
+        let found = self.rl()[level].remove(&INFO_RANGE);
+        assert!(found, "level {} is not held", level);
     }
 
     // Return trur if the level overlap with this, otherwise false
@@ -136,6 +182,190 @@ impl CompactStatus {
     }
 }
 
+/// A compaction in progress, see `KV::compaction_status`.
+#[derive(Debug, Clone)]
+pub struct CompactionJob {
+    /// The level compacted and the level it's compacted into, the same level when tables
+    /// are rewritten in place.
+    pub this_level: usize,
+    pub next_level: usize,
+    /// Ids of the tables compacted, of both levels.
+    pub top_tables: Vec<u64>,
+    pub bot_tables: Vec<u64>,
+    /// Smallest and biggest keys of the tables compacted.
+    pub smallest: Vec<u8>,
+    pub biggest: Vec<u8>,
+    /// Bytes of keys and values read from the tables so far.
+    pub bytes_read: u64,
+    pub started_at: SystemTime,
+}
+
+/// How far a level is over its limit, a level is compacted once its score reaches 1.
+#[derive(Debug, Clone)]
+pub struct LevelCompactionStatus {
+    pub level: usize,
+    pub num_tables: usize,
+    pub total_size: u64,
+    /// Size limit of the level, level 0 is limited by its number of tables instead.
+    pub max_size: u64,
+    pub score: f64,
+}
+
+/// The compactions running and the scores of the levels.
+#[derive(Debug, Clone, Default)]
+pub struct CompactionStatus {
+    /// Oldest first.
+    pub jobs: Vec<CompactionJob>,
+    pub levels: Vec<LevelCompactionStatus>,
+}
+
+/// What a `CompactionFilter` does with an entry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompactionDecision {
+    Keep,
+    /// Deletes the key. A tombstone is written in place of the entry unless nothing older
+    /// can be below the output level, so older values of the key don't show up again.
+    Remove,
+    /// Keeps the entry with a new value, stored in the LSM tree.
+    ChangeValue(Vec<u8>),
+}
+
+/// Decides the fate of the entries rewritten by compactions, e.g. to expire keys by an
+/// application policy or scrub data, without scanning the whole store. It's called from the
+/// compaction threads, for every entry but tombstones and the internal keys, so it must be
+/// cheap and give the same answer for the same entry.
+pub trait CompactionFilter: Send + Sync {
+    /// Names the filter in logs.
+    fn name(&self) -> &str;
+
+    /// `level` is the level the entry is written to. `value` is None when it's kept in the
+    /// value log, it isn't read by compactions.
+    fn filter(
+        &self,
+        level: usize,
+        key: &[u8],
+        value: Option<&[u8]>,
+        user_meta: u8,
+    ) -> CompactionDecision;
+}
+
+impl Debug for dyn CompactionFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CompactionFilter({})", self.name())
+    }
+}
+
+/// The steps of a compaction, passed to the `CompactionListener` of the options. `id` is the
+/// same for the events of one compaction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompactionEvent {
+    /// A level over its limit, or read too often, is to be compacted.
+    Picked {
+        level: usize,
+        score: f64,
+        top_tables: Vec<u64>,
+        bot_tables: Vec<u64>,
+    },
+    Started {
+        id: u64,
+        this_level: usize,
+        next_level: usize,
+        top_tables: Vec<u64>,
+        bot_tables: Vec<u64>,
+    },
+    /// The new tables are written, not yet in the manifest.
+    Produced {
+        id: u64,
+        tables: Vec<u64>,
+    },
+    /// The tables of the next level that replace the ones compacted, the same tables when
+    /// they're moved as is.
+    Committed {
+        id: u64,
+        tables: Vec<u64>,
+    },
+    Failed {
+        id: u64,
+        error: String,
+    },
+}
+
+/// Receives the compaction events. It's called from the compaction threads and must not
+/// block, a channel sender is one.
+pub trait CompactionListener: Send + Sync {
+    fn on_event(&self, event: CompactionEvent);
+}
+
+impl Debug for dyn CompactionListener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CompactionListener")
+    }
+}
+
+impl CompactionListener for tokio::sync::mpsc::UnboundedSender<CompactionEvent> {
+    fn on_event(&self, event: CompactionEvent) {
+        // Nobody listens anymore.
+        let _ = self.send(event);
+    }
+}
+
+// The compactions in progress, by id.
+#[derive(Clone, Default)]
+pub(crate) struct RunningCompactions {
+    next_id: Arc<AtomicU64>,
+    jobs: Arc<RwLock<HashMap<u64, (CompactionJob, Arc<AtomicU64>)>>>,
+}
+
+impl RunningCompactions {
+    // Registers the compaction of `cd`, returns the id to finish it with.
+    pub(crate) fn start(&self, cd: &CompactDef) -> u64 {
+        let tables = cd
+            .top
+            .iter()
+            .chain(cd.bot.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        let (smallest, biggest) = if tables.is_empty() {
+            (vec![], vec![])
+        } else {
+            let kr = KeyRange::get_range(&tables);
+            (kr.left, kr.right)
+        };
+        let job = CompactionJob {
+            this_level: cd.this_level.level(),
+            next_level: cd.next_level.level(),
+            top_tables: cd.top.iter().map(|tb| tb.id()).collect(),
+            bot_tables: cd.bot.iter().map(|tb| tb.id()).collect(),
+            smallest,
+            biggest,
+            bytes_read: 0,
+            started_at: SystemTime::now(),
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.jobs.write().insert(id, (job, cd.bytes_read.clone()));
+        id
+    }
+
+    pub(crate) fn finish(&self, id: u64) {
+        self.jobs.write().remove(&id);
+    }
+
+    pub(crate) fn jobs(&self) -> Vec<CompactionJob> {
+        let mut jobs = self
+            .jobs
+            .read()
+            .values()
+            .map(|(job, bytes_read)| {
+                let mut job = job.clone();
+                job.bytes_read = bytes_read.load(Ordering::Relaxed);
+                job
+            })
+            .collect::<Vec<_>>();
+        jobs.sort_by_key(|job| job.started_at);
+        jobs
+    }
+}
+
 // Every level compacted status(ranges).
 // del_size: all KeyRange size at the level (NOTE: equal LevelCompactStatus.ranges delete size, so after compacting,
 // KeyRange and del_size all be decr)
@@ -267,7 +497,7 @@ impl KeyRange {
 
     // Left, right, inf all same, indicate equal
     pub(crate) fn equals(&self, other: &KeyRange) -> bool {
-        self.left == other.left && self.right == self.right && self.inf == self.inf
+        self.left == other.left && self.right == other.right && self.inf == other.inf
     }
 
     // Check for overlap, *Notice*, if a and b are all inf, indicate has overlap.
@@ -289,7 +519,7 @@ impl KeyRange {
 }
 
 mod tests {
-    use crate::compaction::{KeyRange, INFO_RANGE};
+    use crate::compaction::{KeyRange, LevelCompactStatus, INFO_RANGE};
 
     #[test]
     fn key_range() {
@@ -324,4 +554,24 @@ mod tests {
             println!("{}, {:?}, {:?}", i, left, right);
         }
     }
+
+    #[test]
+    fn level_compact_status_ranges() {
+        let range = |left: &[u8], right: &[u8]| KeyRange {
+            left: left.to_vec(),
+            right: right.to_vec(),
+            inf: false,
+        };
+        let status = LevelCompactStatus::default();
+        status.add(range(b"a", b"c"));
+        status.add(range(b"a", b"f"));
+        assert!(status.overlaps_with(&range(b"b", b"b")));
+        assert!(!status.overlaps_with(&range(b"g", b"h")));
+        // Only the exact range is released.
+        assert!(!status.remove(&range(b"a", b"d")));
+        assert!(status.remove(&range(b"a", b"f")));
+        assert!(!status.overlaps_with(&range(b"d", b"e")));
+        assert!(status.overlaps_with(&range(b"c", b"d")));
+        assert!(!status.remove(&INFO_RANGE));
+    }
 }
@@ -0,0 +1,313 @@
+use crate::value_log::{MetaBit, ValuePointer};
+use crate::y::{Decode, Result, SyncedFileWriter, ValueStruct};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use log::warn;
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const DISCARD_FILE_NAME: &str = "DISCARD";
+// The least time between two persists of the stats on compactions, the stats changed in
+// between are persisted by the next one or on close.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The value log entries compactions left out, by file: (offset, len) of every entry.
+pub(crate) type Discarded = HashMap<u32, Vec<(u32, u32)>>;
+
+#[derive(Default)]
+struct FileStats {
+    discarded: u64,
+    // Ranges [start, end) of stale entries, adjacent ones merged.
+    stale: BTreeMap<u32, u32>,
+    // Stale ranges whose blocks are freed, see `ValueLogCore::punch_holes`.
+    holes: BTreeMap<u32, u32>,
+}
+
+/// Stale bytes of every value log file, i.e. the bytes of the values whose pointers
+/// compactions dropped or found superseded, and where they are. Kept in the `DISCARD` file
+/// of the value dir, the value log GC rewrites the file with the most of them.
+pub(crate) struct DiscardStats {
+    file_name: String,
+    stats: Mutex<HashMap<u32, FileStats>>,
+    // When the stats were last persisted, and whether they changed since.
+    persisted: Mutex<(Instant, bool)>,
+}
+
+impl DiscardStats {
+    pub(crate) fn open(value_dir: &str) -> Result<DiscardStats> {
+        let file_name = Path::new(value_dir)
+            .join(DISCARD_FILE_NAME)
+            .to_string_lossy()
+            .to_string();
+        let mut stats = HashMap::new();
+        if Path::new(&file_name).exists() {
+            let buf = std::fs::read(&file_name)?;
+            let mut rd = Cursor::new(buf.as_slice());
+            while (rd.position() as usize) < buf.len() {
+                let fid = rd.read_u32::<BigEndian>()?;
+                let discarded = rd.read_u64::<BigEndian>()?;
+                let stale = read_ranges(&mut rd)?;
+                let holes = read_ranges(&mut rd)?;
+                stats.insert(
+                    fid,
+                    FileStats {
+                        discarded,
+                        stale,
+                        holes,
+                    },
+                );
+            }
+        }
+        Ok(DiscardStats {
+            file_name,
+            stats: Mutex::new(stats),
+            persisted: Mutex::new((Instant::now(), false)),
+        })
+    }
+
+    /// Adds the stale entries `discarded` of every file, the stats are persisted at most once
+    /// every `PERSIST_INTERVAL`.
+    pub(crate) fn update(&self, discarded: &Discarded) -> Result<()> {
+        if discarded.is_empty() {
+            return Ok(());
+        }
+        let mut stats = self.stats.lock();
+        for (fid, entries) in discarded {
+            let file = stats.entry(*fid).or_default();
+            for (offset, len) in entries {
+                let Some(end) = offset.checked_add(*len) else {
+                    warn!(
+                        "Invalid discarded entry in file {}, offset: {}, len: {}",
+                        fid, offset, len
+                    );
+                    continue;
+                };
+                file.discarded += *len as u64;
+                insert_range(&mut file.stale, *offset, end);
+            }
+        }
+        let mut persisted = self.persisted.lock();
+        if persisted.0.elapsed() < PERSIST_INTERVAL {
+            persisted.1 = true;
+            return Ok(());
+        }
+        drop(persisted);
+        self.persist(&stats)
+    }
+
+    /// Persists the stats changed since they were last persisted, if any.
+    pub(crate) fn sync(&self) -> Result<()> {
+        let stats = self.stats.lock();
+        if !self.persisted.lock().1 {
+            return Ok(());
+        }
+        self.persist(&stats)
+    }
+
+    /// Forgets the file `fid`, once it's rewritten.
+    pub(crate) fn remove(&self, fid: u32) -> Result<()> {
+        let mut stats = self.stats.lock();
+        if stats.remove(&fid).is_some() {
+            self.persist(&stats)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets every file, once the value log is dropped: the new files reuse their ids.
+    pub(crate) fn clear(&self) -> Result<()> {
+        let mut stats = self.stats.lock();
+        stats.clear();
+        self.persist(&stats)
+    }
+
+    pub(crate) fn get(&self, fid: u32) -> u64 {
+        self.stats
+            .lock()
+            .get(&fid)
+            .map(|file| file.discarded)
+            .unwrap_or_default()
+    }
+
+    /// Returns the file among `fids` with the most stale bytes, if any has some.
+    pub(crate) fn max_discard(&self, fids: &[u32]) -> Option<(u32, u64)> {
+        let stats = self.stats.lock();
+        fids.iter()
+            .filter_map(|fid| stats.get(fid).map(|file| (*fid, file.discarded)))
+            .filter(|(_, n)| *n > 0)
+            .max_by_key(|(fid, n)| (*n, std::cmp::Reverse(*fid)))
+    }
+
+    /// The stale ranges of the file `fid` not punched yet.
+    pub(crate) fn stale_ranges(&self, fid: u32) -> Vec<(u32, u32)> {
+        self.stats
+            .lock()
+            .get(&fid)
+            .map(|file| file.stale.iter().map(|(s, e)| (*s, *e)).collect())
+            .unwrap_or_default()
+    }
+
+    /// The punched ranges of the file `fid`, they're skipped when the file is read through.
+    pub(crate) fn holes(&self, fid: u32) -> BTreeMap<u32, u32> {
+        self.stats
+            .lock()
+            .get(&fid)
+            .map(|file| file.holes.clone())
+            .unwrap_or_default()
+    }
+
+    /// Bytes of the punched ranges of the file `fid`.
+    pub(crate) fn punched(&self, fid: u32) -> u64 {
+        self.stats.lock().get(&fid).map_or(0, |file| {
+            file.holes
+                .iter()
+                .map(|(start, end)| (end - start) as u64)
+                .sum()
+        })
+    }
+
+    /// Moves the stale `ranges` of the file `fid` to its holes and persists the stats.
+    pub(crate) fn set_punched(&self, fid: u32, ranges: &[(u32, u32)]) -> Result<()> {
+        if ranges.is_empty() {
+            return Ok(());
+        }
+        let mut stats = self.stats.lock();
+        let file = stats.entry(fid).or_default();
+        for (start, end) in ranges {
+            file.stale.remove(start);
+            file.holes.insert(*start, *end);
+        }
+        self.persist(&stats)
+    }
+
+    // Layout: (fid(u32) | discarded bytes(u64) | stale ranges | holes)*, with ranges as
+    // count(u32) | (start(u32) | end(u32))*
+    fn persist(&self, stats: &HashMap<u32, FileStats>) -> Result<()> {
+        let mut buf = vec![];
+        for (fid, file) in stats {
+            buf.write_u32::<BigEndian>(*fid)?;
+            buf.write_u64::<BigEndian>(file.discarded)?;
+            for ranges in [&file.stale, &file.holes] {
+                buf.write_u32::<BigEndian>(ranges.len() as u32)?;
+                for (start, end) in ranges {
+                    buf.write_u32::<BigEndian>(*start)?;
+                    buf.write_u32::<BigEndian>(*end)?;
+                }
+            }
+        }
+        let mut wt = SyncedFileWriter::create(&self.file_name)?;
+        wt.write_all(&buf)?;
+        wt.finish()?;
+        *self.persisted.lock() = (Instant::now(), false);
+        Ok(())
+    }
+}
+
+fn read_ranges(rd: &mut impl Read) -> Result<BTreeMap<u32, u32>> {
+    let n = rd.read_u32::<BigEndian>()?;
+    let mut ranges = BTreeMap::new();
+    for _ in 0..n {
+        let start = rd.read_u32::<BigEndian>()?;
+        ranges.insert(start, rd.read_u32::<BigEndian>()?);
+    }
+    Ok(ranges)
+}
+
+// Adds [start, end) to `ranges`, merged with the ranges it touches.
+fn insert_range(ranges: &mut BTreeMap<u32, u32>, mut start: u32, mut end: u32) {
+    if let Some((prev_start, prev_end)) = ranges.range(..=start).next_back() {
+        if *prev_end >= start {
+            start = *prev_start;
+            end = end.max(*prev_end);
+        }
+    }
+    while let Some((next_start, next_end)) = ranges.range(start..).next() {
+        if *next_start > end {
+            break;
+        }
+        let (next_start, next_end) = (*next_start, *next_end);
+        end = end.max(next_end);
+        ranges.remove(&next_start);
+    }
+    ranges.insert(start, end);
+}
+
+/// Adds the value of `vs` to `discarded` if it's in the value log.
+pub(crate) fn add_discarded(discarded: &Mutex<Discarded>, vs: &ValueStruct) {
+    if vs.meta & MetaBit::BIT_VALUE_POINTER.bits() == 0 || vs.value.is_empty() {
+        return;
+    }
+    let mut vp = ValuePointer::default();
+    if let Err(err) = vp.dec(&mut Cursor::new(&vs.value)) {
+        warn!("Invalid value pointer in compaction, err: {}", err);
+        return;
+    }
+    discarded
+        .lock()
+        .entry(vp.fid)
+        .or_default()
+        .push((vp.offset, vp.len));
+}
+
+#[test]
+fn discard_stats_persist() {
+    let dir = crate::test_util::create_random_tmp_dir();
+    let stats = DiscardStats::open(&dir).unwrap();
+    assert_eq!(stats.max_discard(&[0, 1, 2]), None);
+    stats
+        .update(&HashMap::from([
+            (0, vec![(0, 10)]),
+            (1, vec![(0, 10), (10, 20)]),
+            (2, vec![(100, 20)]),
+        ]))
+        .unwrap();
+    stats.update(&HashMap::from([(0, vec![(20, 25)])])).unwrap();
+    // Past the end of the file offsets, left out.
+    stats
+        .update(&HashMap::from([(2, vec![(u32::MAX, 1)])]))
+        .unwrap();
+    assert_eq!(stats.max_discard(&[0, 1, 2]), Some((0, 35)));
+    // Files being written to or gone are left out.
+    assert_eq!(stats.max_discard(&[1, 2]), Some((1, 30)));
+    assert_eq!(stats.stale_ranges(0), vec![(0, 10), (20, 45)]);
+    assert_eq!(stats.stale_ranges(1), vec![(0, 30)]);
+    stats.set_punched(0, &[(20, 45)]).unwrap();
+    stats.remove(1).unwrap();
+
+    let stats = DiscardStats::open(&dir).unwrap();
+    assert_eq!((stats.get(0), stats.get(1), stats.get(2)), (35, 0, 20));
+    assert_eq!(stats.max_discard(&[1, 2]), Some((2, 20)));
+    assert_eq!(stats.stale_ranges(0), vec![(0, 10)]);
+    assert_eq!(stats.holes(0), BTreeMap::from([(20, 45)]));
+    stats.clear().unwrap();
+
+    let stats = DiscardStats::open(&dir).unwrap();
+    assert_eq!(stats.max_discard(&[0, 1, 2]), None);
+    assert!(stats.holes(0).is_empty());
+}
+
+#[test]
+fn discard_stats_sync() {
+    let dir = crate::test_util::create_random_tmp_dir();
+    let stats = DiscardStats::open(&dir).unwrap();
+    stats.update(&HashMap::from([(0, vec![(0, 10)])])).unwrap();
+    // Within the persist interval of the open, only in memory.
+    assert_eq!(DiscardStats::open(&dir).unwrap().get(0), 0);
+    stats.sync().unwrap();
+    assert_eq!(DiscardStats::open(&dir).unwrap().get(0), 10);
+}
+
+#[test]
+fn insert_stale_ranges() {
+    let mut ranges = BTreeMap::new();
+    insert_range(&mut ranges, 10, 20);
+    insert_range(&mut ranges, 30, 40);
+    insert_range(&mut ranges, 0, 5);
+    assert_eq!(ranges, BTreeMap::from([(0, 5), (10, 20), (30, 40)]));
+    // Adjacent on both sides.
+    insert_range(&mut ranges, 20, 30);
+    assert_eq!(ranges, BTreeMap::from([(0, 5), (10, 40)]));
+    insert_range(&mut ranges, 5, 10);
+    assert_eq!(ranges, BTreeMap::from([(0, 40)]));
+}
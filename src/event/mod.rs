@@ -1,6 +1,11 @@
 use crate::table::table::Table;
+use crate::value_log::ValueLogInfo;
 use lazy_static::lazy_static;
-use prometheus::{Gauge, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry};
+use prometheus::core::Collector;
+use prometheus::{
+    Gauge, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry,
+};
 use std::fmt;
 use std::fmt::Formatter;
 use std::time::{Duration, Instant};
@@ -13,6 +18,24 @@ lazy_static! {
         )
         .unwrap(),
         vlog_size: IntGauge::new("vlog_size", "vlog size bytes").unwrap(),
+        vlog_file_bytes: IntGaugeVec::new(
+            Opts::new("vlog_file_bytes", "bytes written to a value log file"),
+            &["fid"]
+        )
+        .unwrap(),
+        vlog_file_stale_bytes: IntGaugeVec::new(
+            Opts::new(
+                "vlog_file_stale_bytes",
+                "bytes of the deleted or superseded values of a value log file"
+            ),
+            &["fid"]
+        )
+        .unwrap(),
+        vlog_reclaimable_bytes: IntGauge::new(
+            "vlog_reclaimable_bytes",
+            "bytes a value log GC of every file would give back"
+        )
+        .unwrap(),
         pending_writes: IntGauge::new("pending_writes_total", "pending writes total").unwrap(),
         num_reads: IntCounter::new("num_reads", "number of reads").unwrap(),
         num_writes: IntCounter::new("num_writes", "number of writes").unwrap(),
@@ -21,6 +44,11 @@ lazy_static! {
         num_lsm_gets: IntCounter::new("num_lsm_gets", "number of lsm gets").unwrap(),
         num_lsm_bloom_hits: IntCounter::new("num_bloom_hits", "number of bloom hits").unwrap(),
         num_blocked_puts: IntCounter::new("num_blocked_hits", "number of blocked hits").unwrap(),
+        num_delayed_writes: IntCounter::new(
+            "num_delayed_writes",
+            "number of write batches slowed down by the LSM tree pressure"
+        )
+        .unwrap(),
         num_mem_tables_gets: IntCounter::new("num_mem_tables", "number of the memtable gets")
             .unwrap(),
         num_gets: IntCounter::new("num_gets", "number of gets").unwrap(),
@@ -30,6 +58,76 @@ lazy_static! {
             "block hash calc cost for bloom"
         )
         .unwrap(),
+        num_index_cache_hits: IntCounter::new(
+            "num_index_cache_hits",
+            "number of table index cache hits"
+        )
+        .unwrap(),
+        num_index_cache_evictions: IntCounter::new(
+            "num_index_cache_evictions",
+            "number of table indexes evicted from the index cache"
+        )
+        .unwrap(),
+        num_index_cache_misses: IntCounter::new(
+            "num_index_cache_misses",
+            "number of table indexes read again from their table"
+        )
+        .unwrap(),
+        num_block_cache_hits: IntCounter::new(
+            "num_block_cache_hits",
+            "number of table blocks found in the block cache"
+        )
+        .unwrap(),
+        num_block_cache_misses: IntCounter::new(
+            "num_block_cache_misses",
+            "number of table blocks read from their table with the block cache on"
+        )
+        .unwrap(),
+        num_vlog_gc_runs: IntCounter::new("num_vlog_gc_runs", "number of value log GC runs")
+            .unwrap(),
+        num_vlog_gc_rewrites: IntCounter::new(
+            "num_vlog_gc_rewrites",
+            "number of value log files rewritten by the GC"
+        )
+        .unwrap(),
+        get_latency_seconds: Histogram::with_opts(HistogramOpts::new(
+            "get_latency_seconds",
+            "latency of the reads of a key in the memtables and the LSM tree"
+        ))
+        .unwrap(),
+        num_memtable_flushes: IntCounter::new(
+            "num_memtable_flushes",
+            "number of memtables flushed to level 0"
+        )
+        .unwrap(),
+        num_compactions: compaction_counter("num_compactions", "number of compactions"),
+        num_compaction_tables: compaction_counter(
+            "num_compaction_tables",
+            "number of tables compacted, of both levels"
+        ),
+        compaction_bytes_read: compaction_counter(
+            "compaction_bytes_read",
+            "bytes of keys and values read by compactions"
+        ),
+        compaction_bytes_written: compaction_counter(
+            "compaction_bytes_written",
+            "bytes of tables written by compactions"
+        ),
+        compaction_entries_dropped: compaction_counter(
+            "compaction_entries_dropped",
+            "number of entries left out by compactions"
+        ),
+        compaction_time_ms: compaction_counter(
+            "compaction_time_ms",
+            "milliseconds spent in compactions"
+        ),
+    };
+    static ref REGISTRY: Registry = {
+        let registry = Registry::new();
+        for collector in EV.collectors() {
+            registry.register(collector).unwrap();
+        }
+        registry
     };
 }
 
@@ -37,6 +135,10 @@ lazy_static! {
 pub struct EvMetrics {
     pub lsm_size: IntGaugeVec,
     pub vlog_size: IntGauge,
+    /// Value log space usage by file, labeled `fid`, see `KV::value_log_info`.
+    pub vlog_file_bytes: IntGaugeVec,
+    pub vlog_file_stale_bytes: IntGaugeVec,
+    pub vlog_reclaimable_bytes: IntGauge,
     pub pending_writes: IntGauge,
 
     /// These are cumulative
@@ -49,9 +151,144 @@ pub struct EvMetrics {
     pub num_gets: IntCounter,
     pub num_puts: IntCounter,
     pub num_blocked_puts: IntCounter,
+    pub num_delayed_writes: IntCounter,
     /// number of the memtable gets
     pub num_mem_tables_gets: IntCounter,
     pub block_hash_calc_cost: IntCounter,
+    pub num_index_cache_hits: IntCounter,
+    pub num_index_cache_evictions: IntCounter,
+    pub num_index_cache_misses: IntCounter,
+    pub num_block_cache_hits: IntCounter,
+    pub num_block_cache_misses: IntCounter,
+    /// Value log GC runs, and the ones that rewrote a file.
+    pub num_vlog_gc_runs: IntCounter,
+    pub num_vlog_gc_rewrites: IntCounter,
+    pub get_latency_seconds: Histogram,
+    pub num_memtable_flushes: IntCounter,
+    /// Compaction stats by the level compacted, labeled `level`. Tables moved down
+    /// as they are count as compactions that read and write nothing.
+    pub num_compactions: IntCounterVec,
+    pub num_compaction_tables: IntCounterVec,
+    pub compaction_bytes_read: IntCounterVec,
+    pub compaction_bytes_written: IntCounterVec,
+    pub compaction_entries_dropped: IntCounterVec,
+    pub compaction_time_ms: IntCounterVec,
+}
+
+fn compaction_counter(name: &str, help: &str) -> IntCounterVec {
+    IntCounterVec::new(Opts::new(name, help), &["level"]).unwrap()
+}
+
+impl EvMetrics {
+    // Adds up a compaction of `level`.
+    pub(crate) fn record_compaction(
+        &self,
+        level: usize,
+        tables: usize,
+        bytes_read: u64,
+        bytes_written: u64,
+        entries_dropped: u64,
+        took: Duration,
+    ) {
+        let level = level.to_string();
+        let labels = &[level.as_str()];
+        self.num_compactions.with_label_values(labels).inc();
+        self.num_compaction_tables
+            .with_label_values(labels)
+            .inc_by(tables as u64);
+        self.compaction_bytes_read
+            .with_label_values(labels)
+            .inc_by(bytes_read);
+        self.compaction_bytes_written
+            .with_label_values(labels)
+            .inc_by(bytes_written);
+        self.compaction_entries_dropped
+            .with_label_values(labels)
+            .inc_by(entries_dropped);
+        self.compaction_time_ms
+            .with_label_values(labels)
+            .inc_by(took.as_millis() as u64);
+    }
+
+    // Every metric, to register them.
+    fn collectors(&self) -> Vec<Box<dyn Collector>> {
+        vec![
+            Box::new(self.lsm_size.clone()),
+            Box::new(self.vlog_size.clone()),
+            Box::new(self.vlog_file_bytes.clone()),
+            Box::new(self.vlog_file_stale_bytes.clone()),
+            Box::new(self.vlog_reclaimable_bytes.clone()),
+            Box::new(self.pending_writes.clone()),
+            Box::new(self.num_reads.clone()),
+            Box::new(self.num_writes.clone()),
+            Box::new(self.num_bytes_read.clone()),
+            Box::new(self.num_bytes_written.clone()),
+            Box::new(self.num_lsm_gets.clone()),
+            Box::new(self.num_lsm_bloom_hits.clone()),
+            Box::new(self.num_gets.clone()),
+            Box::new(self.num_puts.clone()),
+            Box::new(self.num_blocked_puts.clone()),
+            Box::new(self.num_delayed_writes.clone()),
+            Box::new(self.num_mem_tables_gets.clone()),
+            Box::new(self.block_hash_calc_cost.clone()),
+            Box::new(self.num_index_cache_hits.clone()),
+            Box::new(self.num_index_cache_evictions.clone()),
+            Box::new(self.num_index_cache_misses.clone()),
+            Box::new(self.num_block_cache_hits.clone()),
+            Box::new(self.num_block_cache_misses.clone()),
+            Box::new(self.num_vlog_gc_runs.clone()),
+            Box::new(self.num_vlog_gc_rewrites.clone()),
+            Box::new(self.get_latency_seconds.clone()),
+            Box::new(self.num_memtable_flushes.clone()),
+            Box::new(self.num_compactions.clone()),
+            Box::new(self.num_compaction_tables.clone()),
+            Box::new(self.compaction_bytes_read.clone()),
+            Box::new(self.compaction_bytes_written.clone()),
+            Box::new(self.compaction_entries_dropped.clone()),
+            Box::new(self.compaction_time_ms.clone()),
+        ]
+    }
+
+    /// Returns the registry of all the metrics, to gather them or serve them with others.
+    pub fn registry(&self) -> &'static Registry {
+        &REGISTRY
+    }
+
+    /// Encodes all the metrics in the Prometheus text format.
+    #[cfg(feature = "prometheus-export")]
+    pub fn export_prometheus(&self) -> String {
+        use prometheus::Encoder;
+        let mut buf = vec![];
+        prometheus::TextEncoder::new()
+            .encode(&REGISTRY.gather(), &mut buf)
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    // Counts a value log GC run, `rewrote` if it rewrote a file.
+    pub(crate) fn record_value_log_gc(&self, rewrote: bool) {
+        self.num_vlog_gc_runs.inc();
+        if rewrote {
+            self.num_vlog_gc_rewrites.inc();
+        }
+    }
+
+    // Sets the value log space gauges, the files gone since are dropped.
+    pub(crate) fn record_value_log(&self, files: &[ValueLogInfo]) {
+        self.vlog_file_bytes.reset();
+        self.vlog_file_stale_bytes.reset();
+        for file in files {
+            let fid = file.fid.to_string();
+            self.vlog_file_bytes
+                .with_label_values(&[fid.as_str()])
+                .set(file.size as i64);
+            self.vlog_file_stale_bytes
+                .with_label_values(&[fid.as_str()])
+                .set(file.stale as i64);
+        }
+        let reclaimable = files.iter().map(|file| file.reclaimable()).sum::<u64>();
+        self.vlog_reclaimable_bytes.set(reclaimable as i64);
+    }
 }
 
 impl fmt::Display for EvMetrics {
@@ -100,6 +337,10 @@ impl fmt::Display for EvMetrics {
             label: "num_blocked_puts".to_owned(),
             value: self.num_blocked_puts.get().to_string(),
         });
+        kv.push(KeyPair {
+            label: "num_delayed_writes".to_owned(),
+            value: self.num_delayed_writes.get().to_string(),
+        });
         kv.push(KeyPair {
             label: "num_mem_tables_gets".to_owned(),
             value: self.num_mem_tables_gets.get().to_string(),
@@ -108,6 +349,42 @@ impl fmt::Display for EvMetrics {
             label: "block_hash_calc_cost".to_owned(),
             value: self.block_hash_calc_cost.get().to_string(),
         });
+        kv.push(KeyPair {
+            label: "num_index_cache_hits".to_owned(),
+            value: self.num_index_cache_hits.get().to_string(),
+        });
+        kv.push(KeyPair {
+            label: "num_index_cache_evictions".to_owned(),
+            value: self.num_index_cache_evictions.get().to_string(),
+        });
+        kv.push(KeyPair {
+            label: "num_index_cache_misses".to_owned(),
+            value: self.num_index_cache_misses.get().to_string(),
+        });
+        kv.push(KeyPair {
+            label: "num_block_cache_hits".to_owned(),
+            value: self.num_block_cache_hits.get().to_string(),
+        });
+        kv.push(KeyPair {
+            label: "num_block_cache_misses".to_owned(),
+            value: self.num_block_cache_misses.get().to_string(),
+        });
+        kv.push(KeyPair {
+            label: "num_vlog_gc_runs".to_owned(),
+            value: self.num_vlog_gc_runs.get().to_string(),
+        });
+        kv.push(KeyPair {
+            label: "num_vlog_gc_rewrites".to_owned(),
+            value: self.num_vlog_gc_rewrites.get().to_string(),
+        });
+        kv.push(KeyPair {
+            label: "get_latency_seconds_count".to_owned(),
+            value: self.get_latency_seconds.get_sample_count().to_string(),
+        });
+        kv.push(KeyPair {
+            label: "num_memtable_flushes".to_owned(),
+            value: self.num_memtable_flushes.get().to_string(),
+        });
         let table_str = Table::new(kv).to_string();
         f.write_str(&table_str)
     }
@@ -116,3 +393,67 @@ impl fmt::Display for EvMetrics {
 pub fn get_metrics() -> &'static EvMetrics {
     &EV
 }
+
+#[test]
+fn record_compaction() {
+    let metrics = get_metrics();
+    // No compaction runs at this level.
+    let level = 99;
+    metrics.record_compaction(level, 3, 100, 80, 2, Duration::from_millis(5));
+    metrics.record_compaction(level, 1, 0, 0, 0, Duration::ZERO);
+    let labels = &["99"];
+    assert_eq!(metrics.num_compactions.with_label_values(labels).get(), 2);
+    assert_eq!(
+        metrics
+            .num_compaction_tables
+            .with_label_values(labels)
+            .get(),
+        4
+    );
+    assert_eq!(
+        metrics
+            .compaction_bytes_read
+            .with_label_values(labels)
+            .get(),
+        100
+    );
+    assert_eq!(
+        metrics
+            .compaction_bytes_written
+            .with_label_values(labels)
+            .get(),
+        80
+    );
+    assert_eq!(
+        metrics
+            .compaction_entries_dropped
+            .with_label_values(labels)
+            .get(),
+        2
+    );
+    assert_eq!(
+        metrics.compaction_time_ms.with_label_values(labels).get(),
+        5
+    );
+}
+
+#[test]
+fn registry() {
+    let metrics = get_metrics();
+    metrics.record_value_log_gc(false);
+    metrics.record_value_log_gc(true);
+    assert!(metrics.num_vlog_gc_runs.get() >= 2);
+    assert!(metrics.num_vlog_gc_rewrites.get() >= 1);
+    let names = metrics
+        .registry()
+        .gather()
+        .iter()
+        .map(|family| family.get_name().to_owned())
+        .collect::<Vec<_>>();
+    assert!(names.contains(&"num_vlog_gc_runs".to_owned()));
+    assert!(names.contains(&"get_latency_seconds".to_owned()));
+    #[cfg(feature = "prometheus-export")]
+    assert!(metrics
+        .export_prometheus()
+        .contains("# TYPE get_latency_seconds histogram"));
+}
@@ -1,9 +1,10 @@
 use crate::iterator::PreFetchStatus::Prefetched;
 use crate::kv::_BADGER_PREFIX;
+use crate::log_file::LogFile;
 use crate::types::{ArcRW, Channel, Closer, TArcMx, TArcRW};
-use crate::{hex_str, ValueStruct, KV};
+use crate::{hex_str, key_with_ts, ValueStruct, KV};
 use crate::{
-    value_log::{MetaBit, ValuePointer},
+    value_log::{MetaBit, ValuePointer, ValueReader},
     Decode, MergeIterator, Result, Xiterator, EMPTY_SLICE,
 };
 
@@ -18,7 +19,7 @@ use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::{io::Cursor, sync::atomic::AtomicU64};
 use tokio::io::AsyncWriteExt;
-use tokio::sync::{RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::{RwLockReadGuard, RwLockWriteGuard, Semaphore};
 
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub(crate) enum PreFetchStatus {
@@ -57,21 +58,62 @@ impl KVItem {
         inner.get_value().await
     }
 
+    /// Returns a reader of the value. A value in the value log is streamed from its file
+    /// instead of loaded in memory, e.g. a blob of megabytes. The file isn't deleted by a GC
+    /// until the reader is dropped.
+    pub async fn value_reader(&self) -> Result<ValueReader> {
+        let inner = self.rl().await;
+        inner.value_reader().await
+    }
+
     pub async fn has_value(&self) -> bool {
         let inner = self.rl().await;
         inner.has_value()
     }
 
+    /// The CAS counter of the value, it's the version of the key, see `KV::compare_and_set`.
     pub async fn counter(&self) -> u64 {
         let inner = self.rl().await;
         inner.counter()
     }
 
+    /// The version of the key: its commit ts with `Options::managed_txns`, else its CAS
+    /// counter.
+    pub async fn version(&self) -> u64 {
+        let inner = self.rl().await;
+        if inner.kv.opt.managed_txns {
+            inner.version
+        } else {
+            inner.counter()
+        }
+    }
+
+    /// Whether the version was written with `Entry::with_discard`, the compactions drop the
+    /// older versions of the key then.
+    pub async fn discard_earlier_versions(&self) -> bool {
+        let inner = self.rl().await;
+        inner.meta() & MetaBit::BIT_DISCARD_EARLIER_VERSIONS.bits() != 0
+    }
+
+    /// The user meta set along with the value.
     pub async fn user_meta(&self) -> u8 {
         let inner = self.rl().await;
         inner.user_meta()
     }
 
+    /// The unix time in seconds the value expires at, 0 if it doesn't.
+    pub async fn expires_at(&self) -> u64 {
+        let inner = self.rl().await;
+        inner.expires_at()
+    }
+
+    /// The approximate size of the key and the value, known without reading the value, e.g.
+    /// to size a range of keys iterated without prefetching the values.
+    pub async fn estimated_size(&self) -> u64 {
+        let inner = self.rl().await;
+        inner.estimated_size()
+    }
+
     pub(crate) async fn rl(&self) -> RwLockReadGuard<'_, KVItemInner> {
         self.inner.read().await
     }
@@ -79,6 +121,25 @@ impl KVItem {
     pub(crate) async fn wl(&self) -> RwLockWriteGuard<'_, KVItemInner> {
         self.inner.write().await
     }
+
+    // Reads the value from the value log in a new task, bounded by `workers`. The value
+    // getters wait for it.
+    pub(crate) async fn pre_fetch_in_background(&self, workers: Arc<Semaphore>) {
+        self.rl().await.wg.add_running(1);
+        let item = self.clone();
+        tokio::spawn(async move {
+            // FIXME we are not handling errors here.
+            {
+                // Never closed.
+                let _permit = workers.acquire().await.unwrap();
+                let item = item.rl().await;
+                if let Err(err) = item.pre_fetch_value().await {
+                    log::error!("Failed to fetch value, {}", err);
+                }
+            }
+            item.rl().await.wg.done();
+        });
+    }
 }
 
 // Returned during iteration. Both the key() and value() output is only valid until
@@ -88,11 +149,14 @@ pub(crate) struct KVItemInner {
     status: Arc<Atomic<PreFetchStatus>>,
     kv: KV,
     key: Vec<u8>,
+    // The version of the key when keys are versioned, see `Options::managed_txns`, else 0.
+    pub(crate) version: u64,
     // TODO, Opz memory
     vptr: Vec<u8>,
     value: TArcMx<Vec<u8>>,
     meta: u8,
     user_meta: u8,
+    expires_at: u64,
     cas_counter: Arc<AtomicU64>,
     wg: Closer,
     err: Result<()>,
@@ -122,14 +186,21 @@ impl Debug for KVItemInner {
 
 impl KVItemInner {
     pub(crate) fn new(key: Vec<u8>, value: ValueStruct, kv: KV) -> KVItemInner {
+        Self::new_version(key, 0, value, kv)
+    }
+
+    // An item of `version` of `key`, when keys are versioned.
+    pub(crate) fn new_version(key: Vec<u8>, version: u64, value: ValueStruct, kv: KV) -> Self {
         Self {
             status: Arc::new(Atomic::new(PreFetchStatus::Empty)),
             kv,
             key,
+            version,
             value: TArcMx::new(Default::default()),
             vptr: value.value,
             meta: value.meta,
             user_meta: value.user_meta,
+            expires_at: value.expires_at,
             cas_counter: Arc::new(AtomicU64::new(value.cas_counter)),
             wg: Closer::new("kv".to_owned()),
             err: Ok(()),
@@ -141,6 +212,14 @@ impl KVItemInner {
         &self.key
     }
 
+    // Returns the key in the LSM tree, with its version if it has one.
+    pub(crate) fn lsm_key(&self) -> Vec<u8> {
+        if self.version == 0 {
+            return self.key.clone();
+        }
+        key_with_ts(&self.key, self.version)
+    }
+
     // Return value
     pub(crate) async fn get_value(&self) -> Result<Vec<u8>> {
         let ch = Channel::new(1);
@@ -181,6 +260,15 @@ impl KVItemInner {
         return self.kv.yield_item_value(self.clone(), consumer).await;
     }
 
+    pub(crate) async fn value_reader(&self) -> Result<ValueReader> {
+        self.wg.wait().await;
+        if self.status.load(Ordering::Acquire) == Prefetched {
+            self.err.clone()?;
+            return Ok(ValueReader::from_value(self.value.lock().await.clone()));
+        }
+        self.kv.item_value_reader(self.clone()).await
+    }
+
     pub(crate) fn has_value(&self) -> bool {
         if self.meta == 0 && self.vptr.is_empty() {
             return false;
@@ -243,20 +331,43 @@ impl KVItemInner {
         self.meta
     }
 
+    // Returns the unix time in seconds the value expires at, 0 if it doesn't.
+    pub(crate) fn expires_at(&self) -> u64 {
+        self.expires_at
+    }
+
+    pub(crate) fn is_deleted_or_expired(&self, now: u64) -> bool {
+        self.meta & MetaBit::BIT_DELETE.bits() != 0
+            || (self.expires_at != 0 && self.expires_at <= now)
+    }
+
     pub(crate) fn vptr(&self) -> &[u8] {
         &self.vptr
     }
+
+    // Whether the value was read, by a prefetch or because it's in the LSM tree.
+    pub(crate) fn is_prefetched(&self) -> bool {
+        self.status.load(Ordering::Acquire) == Prefetched
+    }
 }
 
 // Used to set options when iterating over Badger key-value stores.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct IteratorOptions {
     // Indicates whether we should prefetch values during iteration and store them.
     pub(crate) pre_fetch_values: bool,
     // How may KV pairs to prefetch while iterating. Valid only if PrefetchValues is true.
     pub(crate) pre_fetch_size: isize,
+    // How many values are read from the value log at the same time while prefetching.
+    pub(crate) pre_fetch_workers: usize,
     // Direction of iteration. False is forward, true is backward.
     pub(crate) reverse: bool,
+    // Only the keys with this prefix are iterated, by transaction iterators.
+    pub(crate) prefix: Vec<u8>,
+    // Every version of the keys is iterated, not only the newest, with managed transactions.
+    pub(crate) all_versions: bool,
+    // The versions older than this are skipped, with managed transactions.
+    pub(crate) since_ts: u64,
 }
 
 impl Default for IteratorOptions {
@@ -270,15 +381,53 @@ impl IteratorOptions {
         IteratorOptions {
             pre_fetch_values,
             pre_fetch_size,
+            pre_fetch_workers: DEF_ITERATOR_OPTIONS.pre_fetch_workers,
             reverse,
+            ..DEF_ITERATOR_OPTIONS
         }
     }
+
+    /// Iterates only the keys starting with `prefix`, see `Txn::iter`.
+    pub fn with_prefix(mut self, prefix: &[u8]) -> Self {
+        self.prefix = prefix.to_vec();
+        self
+    }
+
+    /// Iterates every version of the keys, newest first, deleted ones included, not only the
+    /// newest one, with `Options::managed_txns`, see `Txn::iter`.
+    pub fn with_all_versions(mut self, all_versions: bool) -> Self {
+        self.all_versions = all_versions;
+        self
+    }
+
+    /// Skips the versions older than `since_ts`, with `Options::managed_txns`, see `Txn::iter`.
+    pub fn with_since_ts(mut self, since_ts: u64) -> Self {
+        self.since_ts = since_ts;
+        self
+    }
+
+    /// Whether the values are read while iterating. Without, only the keys are read and a
+    /// value in the value log is read when it's asked for, e.g. by `KVItem::value`.
+    pub fn with_pre_fetch_values(mut self, pre_fetch_values: bool) -> Self {
+        self.pre_fetch_values = pre_fetch_values;
+        self
+    }
+
+    /// Sets how many values are read from the value log at the same time while prefetching.
+    pub fn with_pre_fetch_workers(mut self, pre_fetch_workers: usize) -> Self {
+        self.pre_fetch_workers = pre_fetch_workers.max(1);
+        self
+    }
 }
 
 pub(crate) const DEF_ITERATOR_OPTIONS: IteratorOptions = IteratorOptions {
     pre_fetch_size: 100,
     pre_fetch_values: true,
+    pre_fetch_workers: 8,
     reverse: false,
+    prefix: Vec::new(),
+    all_versions: false,
+    since_ts: 0,
 };
 
 /// Helps iterating over the KV pairs in a lexicographically sorted order.
@@ -294,6 +443,10 @@ pub struct IteratorExt {
     // Cache the prefetch keys, not inlcude current value
     data: ArcRW<std::collections::LinkedList<KVItem>>,
     has_rewind: ArcRW<bool>,
+    // Bounds the value log reads of the prefetching tasks.
+    workers: Arc<Semaphore>,
+    // The value log files there were when it was created, held until it's closed.
+    vlogs: ArcRW<Vec<TArcRW<LogFile>>>,
 }
 
 /// TODO FIXME
@@ -329,14 +482,21 @@ pub struct IteratorExt {
 // }
 
 impl IteratorExt {
-    pub(crate) fn new(kv: KV, itr: MergeIterator, opt: IteratorOptions) -> IteratorExt {
+    pub(crate) fn new(
+        kv: KV,
+        itr: MergeIterator,
+        opt: IteratorOptions,
+        vlogs: Vec<TArcRW<LogFile>>,
+    ) -> IteratorExt {
         IteratorExt {
             kv,
+            workers: Arc::new(Semaphore::new(opt.pre_fetch_workers.max(1))),
             opt,
             itr,
             data: ArcRW::default(),
             item: Arc::new(Default::default()),
             has_rewind: ArcRW::default(),
+            vlogs: Arc::new(parking_lot::RwLock::new(vlogs)),
         }
     }
 
@@ -357,7 +517,7 @@ impl IteratorExt {
             el.rl().await.wg.wait().await;
         }
         while let Some(el) = self.itr.seek(key) {
-            if el.key().starts_with(_BADGER_PREFIX) {
+            if self.is_skipped(el.key()) {
                 continue;
             }
             break;
@@ -378,7 +538,7 @@ impl IteratorExt {
         // rewind, next, rewind?, thie item is who!
         let mut item = self.itr.rewind();
         // filter internal data
-        while item.is_some() && item.as_ref().unwrap().key().starts_with(_BADGER_PREFIX) {
+        while item.is_some() && self.is_skipped(item.as_ref().unwrap().key()) {
             item = self.itr.next();
         }
         // Before every rewind, the item will be reset to None
@@ -389,7 +549,8 @@ impl IteratorExt {
         self.item.read().clone()
     }
 
-    // Advance the iterator by one (*NOTICE*: must be rewind when you call self.next())
+    // Advance the iterator by one and returns the item moved to (*NOTICE*: must be rewind when
+    // you call self.next())
     pub async fn next(&self) -> Option<KVItem> {
         // Ensure current item has load
         if let Some(el) = self.item.write().take() {
@@ -401,23 +562,21 @@ impl IteratorExt {
         }
         // Advance internal iterator until entry is not deleted
         while let Some(el) = self.itr.next() {
-            if el.key().starts_with(_BADGER_PREFIX) {
+            if self.is_skipped(el.key()) {
                 continue;
             }
-            if el.value().meta & MetaBit::BIT_DELETE.bits() == 0 {
+            if !self.is_hidden(el.value()) {
                 // Not deleted
                 break;
             }
         }
-        let item = self.itr.peek();
-        if item.is_none() {
-            return None;
+        if self.itr.peek().is_some() {
+            let xitem = self.new_item();
+            self.fill(xitem.clone()).await;
+            self.data.write().push_back(xitem);
         }
-
-        let xitem = self.new_item();
-        self.fill(xitem.clone()).await;
-        self.data.write().push_back(xitem.clone());
-        Some(xitem)
+        // The item read ahead is queued, the one moved to is returned.
+        self.item.read().clone()
     }
 
     pub async fn peek(&self) -> Option<KVItem> {
@@ -445,6 +604,7 @@ impl IteratorExt {
     pub async fn close(&self) -> Result<()> {
         // TODO: We could handle this error.
         self.kv.vlog.as_ref().unwrap().decr_iterator_count().await?;
+        self.vlogs.write().clear();
         Ok(())
     }
 
@@ -456,28 +616,36 @@ impl IteratorExt {
             let mut item = item.wl().await;
             item.meta = vs.meta;
             item.user_meta = vs.user_meta;
+            item.expires_at = vs.expires_at;
             item.cas_counter.store(vs.cas_counter, Ordering::Release);
             item.key.extend(self.itr.peek().as_ref().unwrap().key());
             item.vptr.extend(&vs.value);
-            item.value.lock().await.clear();
+            let mut value = item.value.lock().await;
+            value.clear();
+            // Nothing to read, the value is in the LSM tree.
+            if item.has_value() && vs.meta & MetaBit::BIT_VALUE_POINTER.bits() == 0 {
+                value.extend_from_slice(&vs.value);
+                item.status.store(Prefetched, Ordering::Release);
+            }
         }
 
         // need fetch value, use new coroutine to load value.
-        if self.opt.pre_fetch_values {
-            item.rl().await.wg.add_running(1);
-            tokio::spawn(async move {
-                // FIXME we are not handling errors here.
-                {
-                    let item = item.rl().await;
-                    if let Err(err) = item.pre_fetch_value().await {
-                        log::error!("Failed to fetch value, {}", err);
-                    }
-                }
-                item.rl().await.wg.done();
-            });
+        if self.opt.pre_fetch_values && item.rl().await.status.load(Ordering::Acquire) != Prefetched
+        {
+            item.pre_fetch_in_background(self.workers.clone()).await;
         }
     }
 
+    // The internal keys and the keys under a banned prefix aren't iterated.
+    fn is_skipped(&self, key: &[u8]) -> bool {
+        key.starts_with(_BADGER_PREFIX) || self.kv.is_banned(key)
+    }
+
+    // Deleted and expired values are skipped.
+    fn is_hidden(&self, vs: &ValueStruct) -> bool {
+        vs.meta & MetaBit::BIT_DELETE.bits() > 0 || vs.is_expired(self.kv.opt.clock.now())
+    }
+
     // Prefetch load items.
     async fn pre_fetch(&self) {
         let mut pre_fetch_size = 2;
@@ -488,11 +656,11 @@ impl IteratorExt {
         let itr = &self.itr;
         let mut count = 0;
         while let Some(item) = itr.peek() {
-            if item.key().starts_with(crate::kv::_BADGER_PREFIX) {
+            if self.is_skipped(item.key()) {
                 itr.next();
                 continue;
             }
-            if item.value().meta & MetaBit::BIT_DELETE.bits() > 0 {
+            if self.is_hidden(item.value()) {
                 itr.next();
                 continue;
             }
@@ -518,10 +686,12 @@ impl IteratorExt {
             status: Arc::new(Atomic::new(PreFetchStatus::Empty)),
             kv: self.kv.clone(),
             key: vec![],
+            version: 0,
             value: TArcMx::new(Default::default()),
             vptr: vec![],
             meta: 0,
             user_meta: 0,
+            expires_at: 0,
             cas_counter: Arc::new(Default::default()),
             wg: Closer::new("IteratorExt".to_owned()),
             err: Ok(()),
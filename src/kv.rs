@@ -1,19 +1,32 @@
+use crate::compaction::CompactionStatus;
 use crate::iterator::{IteratorExt, IteratorOptions, KVItem, KVItemInner};
 use crate::levels::LevelsController;
 use crate::manifest::{open_or_create_manifest_file, ManifestFile};
 use crate::options::Options;
+use crate::publisher::{KVChange, Publisher, Subscription};
+use crate::sequence::Sequence;
+use crate::stream::Stream;
+use crate::table::block_cache::CacheMetrics;
 use crate::table::builder::Builder;
-use crate::table::iterator::IteratorItem;
-use crate::table::table::{new_file_name, Table, TableCore};
-use crate::types::{ArcMx, Channel, Closer, TArcRW, XArc, XWeak};
+use crate::table::encryption::DataKey;
+use crate::table::file::create_table_file;
+use crate::table::iterator::{ConcatIterator, IteratorItem};
+use crate::table::table::{LevelInfo, Table, TableCore, TableInfo};
+use crate::txn::{Oracle, Snapshot, Txn};
+use crate::types::{Channel, Closer, TArcRW, XArc, XWeak};
 use crate::value_log::{
-    Entry, EntryType, MetaBit, Request, ValueLogCore, ValuePointer, MAX_KEY_SIZE,
+    Entry, EntryType, MetaBit, Request, ValueLogCore, ValueLogInfo, ValuePointer, ValueReader,
+    MAX_KEY_SIZE,
+};
+use crate::wal::MemTableWal;
+use crate::write_batch::WriteBatch;
+use crate::y::{
+    async_sync_directory, create_synced_file, key_with_ts, parse_key, Encode, Result, ValueStruct,
 };
-use crate::y::{async_sync_directory, create_synced_file, Encode, Result, ValueStruct};
 use crate::Error::{NotFound, Unexpected};
 use crate::{
-    event, hex_str, Decode, Error, MergeIterOverBuilder, Node, SkipList, SkipListManager,
-    UniIterator, Xiterator,
+    event, hex_str, Decode, Error, EvMetrics, MergeIterOverBuilder, MergeIterator, Node, SkipList,
+    SkipListManager, UniIterator, Xiterator,
 };
 
 use atomic::Atomic;
@@ -25,10 +38,11 @@ use log::{debug, error, info, warn};
 
 use parking_lot::Mutex;
 
+use std::collections::VecDeque;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::future::Future;
-use std::io::{Cursor, Write};
+use std::io::{Cursor, Read, Write};
 
 use std::path::Path;
 use std::pin::Pin;
@@ -38,20 +52,59 @@ use libc::difftime;
 use rand::random;
 use std::fmt::Formatter;
 use std::ops::Deref;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use std::{fmt, string, vec};
 use tokio::fs::create_dir_all;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::{RwLock, RwLockWriteGuard};
+use tokio::task::JoinHandle;
 
 ///
 pub const _BADGER_PREFIX: &[u8; 8] = b"!badger!";
 /// Prefix for internal keys used by badger.
 pub const _HEAD: &[u8; 12] = b"!badger!head"; // For Storing value offset for replay.
+/// Prefix of the keys the banned prefixes are persisted under, see `KV::ban_prefix`.
+const _BANNED: &[u8; 14] = b"!badger!banned";
 
 pub const KV_WRITE_CH_CAPACITY: usize = 1000;
+// The attempts of a failing memtable flush before it's given up.
+const FLUSH_ATTEMPTS: u32 = 8;
+// The longest wait between two attempts of a flush, the first one is 100ms, doubled on every
+// attempt.
+const MAX_FLUSH_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Pressure of the LSM tree on the write path, see `KV::write_throttle`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteThrottle {
+    pub level0_tables: usize,
+    /// Memtables waiting to be flushed to level 0 and their size in bytes.
+    pub pending_flushes: usize,
+    pub pending_flush_bytes: u64,
+    /// Delay added to every batch of writes, up to `Options::max_write_delay`.
+    pub delay: Duration,
+    /// True when writes wait for memtable flushes or level 0 compactions to go on.
+    pub stalled: bool,
+}
+
+/// The files found corrupt by `KV::verify_checksum`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChecksumReport {
+    pub tables_checked: usize,
+    pub value_logs_checked: usize,
+    /// Level, id and the blocks that failed of the corrupt tables.
+    pub corrupt_tables: Vec<(usize, u64, String)>,
+    /// Fid of the corrupt value log files, and the offset of their first corrupt entry.
+    pub corrupt_value_logs: Vec<(u32, u32)>,
+}
+
+impl ChecksumReport {
+    /// True if no file is corrupt.
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_tables.is_empty() && self.corrupt_value_logs.is_empty()
+    }
+}
 
 #[derive(Clone)]
 pub struct Closers {
@@ -61,12 +114,18 @@ pub struct Closers {
     // Wait flush job exit
     pub writes: Closer,
     pub value_gc: Closer,
+    pub value_sync: Closer,
+    pub value_gc_scheduler: Closer,
 }
 
 #[derive(Debug, Clone)]
 struct FlushTask {
     mt: Option<SkipList>,
     vptr: ValuePointer,
+    // Keys with any of these prefixes are left out of the table.
+    drop_prefixes: Vec<Vec<u8>>,
+    // The WALs deleted once the memtable is flushed.
+    wal_ids: Vec<u32>,
 }
 
 impl FlushTask {
@@ -107,6 +166,23 @@ pub struct KVCore {
     // we use an atomic op.
     pub(crate) last_used_cas_counter: Arc<AtomicU64>,
     share_lock: TArcRW<()>,
+    // Held shared by every batch write, `drop_prefix` holds it exclusively to block writes.
+    write_barrier: TArcRW<()>,
+    // The WAL of the mutable memtable, if `Options::mem_table_wal`.
+    wal: Option<Arc<Mutex<MemTableWal>>>,
+    // Set once a value is written only to the mutable memtable, neither to the value log nor
+    // to a WAL, see `KV::sync`.
+    unlogged_values: Arc<AtomicBool>,
+    // Callers of `pause_background_gc` that haven't resumed it yet.
+    gc_pauses: Arc<AtomicUsize>,
+    // Checks the conflicts of the transactions.
+    pub(crate) orc: Arc<Oracle>,
+    // The subscribers of the writes, see `KV::subscribe`.
+    publisher: Arc<Publisher>,
+    // The prefixes of the keys that can't be read or written, see `KV::ban_prefix`.
+    banned: Arc<parking_lot::RwLock<Vec<Vec<u8>>>>,
+    // The error of the memtable flush given up, no memtable is flushed after it.
+    flush_err: Arc<Mutex<Option<Error>>>,
 }
 
 impl Drop for KVCore {
@@ -121,8 +197,9 @@ impl std::fmt::Debug for KVCore {
     }
 }
 
+// Points to the KVCore, it's set again once the KVCore is moved to its final place.
 pub(crate) struct BoxKV {
-    pub kv: *const KVCore,
+    pub kv: AtomicPtr<KVCore>,
 }
 
 unsafe impl Send for BoxKV {}
@@ -131,7 +208,17 @@ unsafe impl Sync for BoxKV {}
 
 impl BoxKV {
     pub(crate) fn new(kv: *const KVCore) -> BoxKV {
-        BoxKV { kv }
+        BoxKV {
+            kv: AtomicPtr::new(kv as *mut KVCore),
+        }
+    }
+
+    pub(crate) fn set(&self, kv: *const KVCore) {
+        self.kv.store(kv as *mut KVCore, Ordering::Release);
+    }
+
+    pub(crate) fn get(&self) -> *const KVCore {
+        self.kv.load(Ordering::Acquire)
     }
 }
 
@@ -160,6 +247,7 @@ impl KVCore {
     #[inline]
     pub(crate) fn _get(&self, key: &[u8]) -> Result<ValueStruct> {
         event::get_metrics().num_gets.inc();
+        let _timer = event::get_metrics().get_latency_seconds.start_timer();
         let p = crossbeam_epoch::pin();
         let tables = self.get_mem_tables(&p);
         // Must dereference skip list
@@ -170,23 +258,19 @@ impl KVCore {
         };
         defer! {decref_tables()};
 
-        crate::event::get_metrics().num_gets.inc(); // TODO add metrics
-
         for tb in tables.iter() {
             event::get_metrics().num_mem_tables_gets.inc();
             let st = unsafe { tb.as_ref().unwrap() };
             let vs = st.get(key);
-            crate::event::get_metrics().num_mem_tables_gets.inc();
             if vs.is_none() {
                 continue;
             }
             let vs = vs.unwrap();
-            if (vs.meta & MetaBit::BIT_DELETE.bits()) > 0 {
+            if (vs.meta & MetaBit::BIT_DELETE.bits()) > 0 || vs.is_expired(self.opt.clock.now()) {
                 return Err(Error::NotFound);
             }
-            if !vs.value.is_empty() {
-                return Ok(vs);
-            }
+            // Found, even with an empty value.
+            return Ok(vs);
         }
         //#[cfg(test)]
         //info!(
@@ -195,12 +279,126 @@ impl KVCore {
         // self.must_lc()
         //);
         let vs = self.must_lc().get(key).ok_or(NotFound)?;
-        if (vs.meta & MetaBit::BIT_DELETE.bits()) > 0 {
+        if (vs.meta & MetaBit::BIT_DELETE.bits()) > 0 || vs.is_expired(self.opt.clock.now()) {
             return Err(Error::NotFound);
         }
         Ok(vs)
     }
 
+    // Like `_get` for every key of `keys`, in their order. The keys are looked up sorted, the
+    // memtables are pinned once and the tables read once for all the keys they may hold, see
+    // `LevelsController::multi_get`.
+    pub(crate) fn _multi_get(&self, keys: &[&[u8]]) -> Vec<Result<ValueStruct>> {
+        event::get_metrics().num_gets.inc_by(keys.len() as u64);
+        let mut order = (0..keys.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| keys[*a].cmp(keys[*b]));
+        let mut got: Vec<Result<ValueStruct>> = keys.iter().map(|_| Err(NotFound)).collect();
+        let p = crossbeam_epoch::pin();
+        let tables = self.get_mem_tables(&p);
+        defer! {
+            tables.iter().for_each(|tb| unsafe { tb.as_ref().unwrap().decr_ref() });
+        }
+        let now = self.opt.clock.now();
+        let mut on_disk = vec![];
+        'keys: for i in order {
+            for tb in tables.iter() {
+                event::get_metrics().num_mem_tables_gets.inc();
+                let st = unsafe { tb.as_ref().unwrap() };
+                let Some(vs) = st.get(keys[i]) else {
+                    continue;
+                };
+                if (vs.meta & MetaBit::BIT_DELETE.bits()) == 0 && !vs.is_expired(now) {
+                    got[i] = Ok(vs);
+                }
+                continue 'keys;
+            }
+            on_disk.push(i);
+        }
+        let disk_keys = on_disk.iter().map(|&i| keys[i]).collect::<Vec<_>>();
+        for (i, vs) in on_disk
+            .into_iter()
+            .zip(self.must_lc().multi_get(&disk_keys))
+        {
+            got[i] = match vs {
+                Some(vs) if vs.meta & MetaBit::BIT_DELETE.bits() == 0 && !vs.is_expired(now) => {
+                    Ok(vs)
+                }
+                _ => Err(NotFound),
+            };
+        }
+        got
+    }
+
+    // Returns the newest version of `key` not newer than `version`, with its versioned key, when
+    // keys are versioned (see `Options::managed_txns`). NotFound if it's deleted or expired.
+    pub(crate) fn _get_version(&self, key: &[u8], version: u64) -> Result<(Vec<u8>, ValueStruct)> {
+        event::get_metrics().num_gets.inc();
+        let _timer = event::get_metrics().get_latency_seconds.start_timer();
+        let p = crossbeam_epoch::pin();
+        let tables = self.get_mem_tables(&p);
+        let decref_tables = || {
+            tables
+                .iter()
+                .for_each(|tb| unsafe { tb.as_ref().unwrap().decr_ref() })
+        };
+        defer! {decref_tables()};
+
+        let mut newest = self.must_lc().get_version(key, version);
+        for tb in tables.iter() {
+            let st = unsafe { tb.as_ref().unwrap() };
+            if let Some((ts, vs)) = st.get_version(key, version) {
+                if newest.as_ref().map_or(true, |(newest, _)| ts > *newest) {
+                    newest = Some((ts, vs));
+                }
+            }
+        }
+        let (ts, vs) = newest.ok_or(NotFound)?;
+        if (vs.meta & MetaBit::BIT_DELETE.bits()) > 0 || vs.is_expired(self.opt.clock.now()) {
+            return Err(Error::NotFound);
+        }
+        Ok((key_with_ts(key, ts), vs))
+    }
+
+    // Like `_get_version` for every key of `keys`, in their order, with the version found
+    // instead of the versioned key. The keys are looked up sorted, the memtables are pinned
+    // once and the tables read once for all the keys they may hold, see
+    // `LevelsController::multi_get_version`.
+    pub(crate) fn _multi_get_version(
+        &self,
+        keys: &[&[u8]],
+        version: u64,
+    ) -> Vec<Result<(u64, ValueStruct)>> {
+        event::get_metrics().num_gets.inc_by(keys.len() as u64);
+        let mut order = (0..keys.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| keys[*a].cmp(keys[*b]));
+        let sorted = order.iter().map(|&i| keys[i]).collect::<Vec<_>>();
+        let p = crossbeam_epoch::pin();
+        let tables = self.get_mem_tables(&p);
+        defer! {
+            tables.iter().for_each(|tb| unsafe { tb.as_ref().unwrap().decr_ref() });
+        }
+        let now = self.opt.clock.now();
+        let mut got: Vec<Result<(u64, ValueStruct)>> = keys.iter().map(|_| Err(NotFound)).collect();
+        let on_disk = self.must_lc().multi_get_version(&sorted, version);
+        for (i, mut newest) in order.into_iter().zip(on_disk) {
+            for tb in tables.iter() {
+                let st = unsafe { tb.as_ref().unwrap() };
+                if let Some((ts, vs)) = st.get_version(keys[i], version) {
+                    if newest.as_ref().map_or(true, |(newest, _)| ts > *newest) {
+                        newest = Some((ts, vs));
+                    }
+                }
+            }
+            let Some((ts, vs)) = newest else {
+                continue;
+            };
+            if vs.meta & MetaBit::BIT_DELETE.bits() == 0 && !vs.is_expired(now) {
+                got[i] = Ok((ts, vs));
+            }
+        }
+        got
+    }
+
     // Sets the provided value for a given key. If key is not present, it is created.  If it is
     // present, the existing value is overwritten with the one provided.
     // Along with key and value, Set can also take an optional userMeta byte. This byte is stored
@@ -218,6 +416,10 @@ impl KVCore {
     }
 
     // Called serially by only on goroutine
+    #[cfg_attr(feature = "tracing-spans", tracing::instrument(
+        skip_all,
+        fields(requests = reqs.len())
+    ))]
     async fn write_requests(&self, reqs: Vec<Request>) -> Result<()> {
         if reqs.is_empty() {
             return Ok(());
@@ -244,8 +446,12 @@ impl KVCore {
             let entries = &req.entries;
             let counter_base = self.new_cas_counter(entries.len() as u64);
             for (idx, entry) in entries.iter().enumerate() {
+                let entry = entry.entry();
+                // A value moved by the value log GC keeps its counter.
+                if entry.meta & MetaBit::BIT_MOVE.bits() != 0 {
+                    continue;
+                }
                 entry
-                    .entry()
                     .cas_counter
                     .store(counter_base + idx as u64, Ordering::Release);
             }
@@ -260,13 +466,27 @@ impl KVCore {
 
         info!("Writing to memory table");
         let mut count = 0;
+        let mut acks: Vec<Channel<Result<()>>> = vec![];
+        let mut changes = vec![];
         let notify_write_request_chan = self.notify_write_request_chan.rx();
-        for mut req in reqs.into_iter() {
+        let mut reqs = reqs.into_iter();
+        while let Some(mut req) = reqs.next() {
             if req.entries.is_empty() {
                 continue;
             }
             count += req.entries.len();
             while let Err(err) = self.ensure_room_for_write().await {
+                // Nothing is flushed after a failed flush, there won't be room.
+                if let Some(err) = self.flush_err() {
+                    req.set_entries_resp(Err(err.clone())).await;
+                    for req in reqs {
+                        req.set_entries_resp(Err(err.clone())).await;
+                    }
+                    for resp_ch in acks {
+                        resp_ch.send(Err(err.clone())).await.unwrap();
+                    }
+                    return Err(err);
+                }
                 debug!("failed to ensure room for write!, err:{}", err);
                 tokio::select! {
                     _ = tokio::time::sleep(Duration::from_millis(10)) => {},
@@ -278,7 +498,20 @@ impl KVCore {
             // warn!("Waiting for write lsm, count {}", count);
             self.update_offset(&mut req.ptrs).await;
             // It should not fail
-            self.write_to_lsm(req).await.unwrap();
+            self.write_to_lsm(req, &mut acks, &mut changes)
+                .await
+                .unwrap();
+        }
+        // The whole group is acknowledged after a single WAL sync.
+        let res = self.sync_wal();
+        if let Err(err) = &res {
+            error!("Failed to write the WAL, err: {}", err);
+        }
+        for resp_ch in acks {
+            resp_ch.send(res.clone()).await.unwrap();
+        }
+        if res.is_ok() {
+            self.publisher.publish(&changes).await;
         }
         info!(
             "cost time at write request: {}ms",
@@ -299,64 +532,170 @@ impl KVCore {
                 warn!("receive a exit task!");
                 break;
             }
-            // TODO if is zero?
-            if !task.vptr.is_zero() {
-                let mut cur =
-                    std::io::Cursor::new(vec![0u8; ValuePointer::value_pointer_encoded_size()]);
-                let sz = task.vptr.enc(&mut cur).unwrap();
-                let offset = cur.into_inner();
-                assert_eq!(sz, offset.len());
-                // CAS counter is needed and is desirable -- it's the first value log entry
-                // we replay, so to speak, perhaps the only, and we use it to re-initialize
-                // the CAS counter.
-                //
-                // The write loop generates CAS counter values _before_ it sets vptr.  It
-                // is crucial that we read the cas counter here _after_ reading vptr.  That
-                // way, our value here is guaranteed to be >= the CASCounter values written
-                // before vptr (because they don't get replayed).
-                warn!(
-                    "Storing new vptr, fid:{}, len:{}, offset:{}",
-                    task.vptr.fid, task.vptr.len, task.vptr.offset
-                );
-                let value = ValueStruct {
-                    meta: 0,
-                    user_meta: 0,
-                    cas_counter: self.get_last_used_cas_counter(),
-                    value: offset,
-                };
-                task.must_mt().put(_HEAD, value);
+            // A memtable flushed after a failed one would move the value log head past the
+            // writes of the failed one. They stay in imm, and are replayed on the next open.
+            if self.flush_err().is_some() {
+                continue;
             }
-            let fid = self.must_lc().reserve_file_id();
-            let f_name = new_file_name(fid, &self.opt.dir);
-            let fp = create_synced_file(&f_name, true)?;
-            // Don't block just to sync the directory entry.
-            // let task1 = async_sync_directory(self.opt.dir.clone().to_string());
-            // let mut fp = tokio::fs::File::from_std(fp);
-            // let task2 = write_level0_table(&task.mt.as_ref().unwrap(), &mut fp);
-            // let (task1_res, task2_res) = tokio::join!(task1, task2);
-            // task1_res?;
-            // task2_res?;
-
-            async_sync_directory(self.opt.dir.clone().to_string()).await?;
-            let mut fp = tokio::fs::File::from_std(fp);
-            write_level0_table(&task.mt.as_ref().unwrap(), &f_name, &mut fp).await?;
-
-            debug!("Ready to advance im");
-            let fp = fp.into_std().await;
-            let tc = TableCore::open_table(fp, &f_name, self.opt.table_loading_mode)?;
-            let tb = Table::from(tc);
-            // We own a ref on tbl.
-            self.must_lc().add_level0_table(tb.clone()).await?;
-            debug!("Ready to advance im");
-            let _ = self.share_lock.write().await;
-            // This will incr_ref (if we don't error, sure)
-            tb.decr_ref(); // releases our ref.
-            self.mem_st_manger.advance_imm(task.must_mt()); // Update s.imm, need a lock.
-            task.must_mt().decr_ref(); // Return memory
+            if let Err(err) = self.flush_with_retries(&task, &lc).await {
+                error!("Failed to flush memtable, giving up, err: {}", err);
+                *self.flush_err.lock() = Some(err);
+                // Wake up the writers waiting for room, they fail now.
+                let _ = self.notify_write_request_chan.tx().try_send(());
+                continue;
+            }
+            event::get_metrics().num_memtable_flushes.inc();
+            // Wake up the writers waiting for room in the flush queue.
+            let _ = self.notify_write_request_chan.tx().try_send(());
+        }
+        Ok(())
+    }
+
+    // Flushes the memtable of `task`, then removes its WALs. A failing step is retried, see
+    // `wait_flush_retry`, the memtable stays readable in imm meanwhile and the writers are held
+    // back by the full flush queue.
+    async fn flush_with_retries(&self, task: &FlushTask, lc: &Closer) -> Result<()> {
+        let mut attempt = 1;
+        while let Err(err) = self.handle_flush_task(task).await {
+            error!(
+                "Failed to flush memtable, attempt {}, err: {}",
+                attempt, err
+            );
+            if !Self::wait_flush_retry(&mut attempt, lc).await {
+                return Err(err);
+            }
+        }
+        // A WAL left behind would be replayed over the newer data.
+        let mut attempt = 1;
+        while let Err(err) = MemTableWal::remove(&self.opt.dir, &task.wal_ids) {
+            error!(
+                "Failed to remove the WALs, attempt {}, err: {}",
+                attempt, err
+            );
+            if !Self::wait_flush_retry(&mut attempt, lc).await {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    // Waits before the next attempt of a failed flush step, see `MAX_FLUSH_BACKOFF`. False if
+    // it's given up: the attempts are used up, or the KV is being closed.
+    async fn wait_flush_retry(attempt: &mut u32, lc: &Closer) -> bool {
+        if *attempt >= FLUSH_ATTEMPTS {
+            return false;
+        }
+        let backoff = Duration::from_millis(100 << (*attempt - 1)).min(MAX_FLUSH_BACKOFF);
+        *attempt += 1;
+        let closed = lc.has_been_closed();
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => true,
+            _ = closed.recv() => false,
+        }
+    }
+
+    // The error of the flush given up, see `flush_mem_table`.
+    pub(crate) fn flush_err(&self) -> Option<Error> {
+        self.flush_err.lock().clone()
+    }
+
+    // Waits until the memtables in imm are flushed. Fails if a flush was given up, they won't
+    // be.
+    async fn wait_for_flushes(&self) -> Result<()> {
+        while !self.mem_st_manger.imm().is_empty() {
+            if let Some(err) = self.flush_err() {
+                return Err(err);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
         Ok(())
     }
 
+    // Builds a level 0 table from the memtable, adds it to the manifest and level 0, then
+    // releases the memtable.
+    #[cfg_attr(feature = "tracing-spans", tracing::instrument(
+        skip_all,
+        fields(memtable = task.mt.as_ref().map(|st| st.id()))
+    ))]
+    async fn handle_flush_task(&self, task: &FlushTask) -> Result<()> {
+        // TODO if is zero?
+        if !task.vptr.is_zero() {
+            let mut cur =
+                std::io::Cursor::new(vec![0u8; ValuePointer::value_pointer_encoded_size()]);
+            let sz = task.vptr.enc(&mut cur).unwrap();
+            let offset = cur.into_inner();
+            assert_eq!(sz, offset.len());
+            // CAS counter is needed and is desirable -- it's the first value log entry
+            // we replay, so to speak, perhaps the only, and we use it to re-initialize
+            // the CAS counter.
+            //
+            // The write loop generates CAS counter values _before_ it sets vptr.  It
+            // is crucial that we read the cas counter here _after_ reading vptr.  That
+            // way, our value here is guaranteed to be >= the CASCounter values written
+            // before vptr (because they don't get replayed).
+            warn!(
+                "Storing new vptr, fid:{}, len:{}, offset:{}",
+                task.vptr.fid, task.vptr.len, task.vptr.offset
+            );
+            let value = ValueStruct {
+                meta: 0,
+                user_meta: 0,
+                cas_counter: self.get_last_used_cas_counter(),
+                expires_at: 0,
+                value: offset,
+            };
+            task.must_mt().put(_HEAD, value);
+        }
+        let fid = self.must_lc().reserve_file_id();
+        let table_file = create_table_file(fid, &self.opt.dir)?;
+        let fp = table_file.try_clone()?;
+        // Don't block just to sync the directory entry.
+        // let task1 = async_sync_directory(self.opt.dir.clone().to_string());
+        // let mut fp = tokio::fs::File::from_std(fp);
+        // let task2 = write_level0_table(&task.must_mt(), &mut fp);
+        // let (task1_res, task2_res) = tokio::join!(task1, task2);
+        // task1_res?;
+        // task2_res?;
+
+        let mut fp = tokio::fs::File::from_std(fp);
+        write_level0_table(
+            task.must_mt(),
+            table_file.file_name(),
+            &mut fp,
+            &self.opt,
+            &task.drop_prefixes,
+            self.must_lc().data_key()?,
+        )
+        .await?;
+        // Wait for the pending writes before renaming.
+        drop(fp.into_std().await);
+
+        debug!("Ready to advance im");
+        let (fp, f_name) = table_file.finish()?;
+        let tc = TableCore::open_table_with_keys(
+            fp,
+            &f_name,
+            self.opt.table_loading_mode,
+            Some(self.must_lc().keys()),
+        )?
+        .with_block_checksums(self.opt.checksum_verification_mode.on_block_read())
+        .with_block_cache(self.must_lc().block_cache())
+        .with_index_cache(self.must_lc().index_cache());
+        if self.opt.checksum_verification_mode.on_table_read() {
+            tc.verify_checksum()?;
+        }
+        let tb = Table::from(tc);
+        // We own a ref on tbl.
+        self.must_lc().add_level0_table(tb.clone()).await?;
+        debug!("Ready to advance im");
+        let _ = self.share_lock.write().await;
+        // This will incr_ref (if we don't error, sure)
+        tb.decr_ref(); // releases our ref.
+        self.mem_st_manger.advance_imm(task.must_mt()); // Update s.imm, need a lock.
+        task.must_mt().decr_ref(); // Return memory
+        Ok(())
+    }
+
     // Applies a list of `badger.entries`. If a request level error occurs it will be returned. Errors are also set on each
     // `Entry` and must be checked individually.
     // Check(kv.batch_set(entries))
@@ -364,6 +703,13 @@ impl KVCore {
     //      Check(e.Error);
     // }
     pub(crate) async fn batch_set(&self, entries: Vec<Entry>) -> Vec<Result<()>> {
+        self.throttle_writes().await;
+        let _writes = self.write_barrier.read().await;
+        self.write_entries(entries).await
+    }
+
+    // Writes `entries` like `batch_set`, the caller holds `write_barrier`.
+    async fn write_entries(&self, entries: Vec<Entry>) -> Vec<Result<()>> {
         let mut count = 0;
         let mut sz = 0u64;
         let mut res = vec![Ok(()); entries.len()];
@@ -371,14 +717,11 @@ impl KVCore {
         let mut req_index = vec![];
         let mut bad_count = 0;
         // packet entries into internal request message and filter invalid entry.
-        for (i, entry) in entries.into_iter().enumerate() {
-            if entry.key.len() > MAX_KEY_SIZE {
-                res[i] = Err("Key too big".into());
-                bad_count += 1;
-                continue;
-            }
-            if entry.value.len() as u64 > self.opt.value_log_file_size {
-                res[i] = Err("Value to big".into());
+        let now = self.opt.clock.now();
+        for (i, mut entry) in entries.into_iter().enumerate() {
+            entry.resolve_ttl(now);
+            if let Err(err) = self.check_entry(&entry) {
+                res[i] = Err(err);
                 bad_count += 1;
                 continue;
             }
@@ -391,12 +734,16 @@ impl KVCore {
             }
 
             if count >= self.opt.max_batch_count || sz >= self.opt.max_batch_size {
-                assert!(!self.write_ch.is_close());
                 warn!("send tasks to write, entries: {}, count:{}, max_batch_count:{}, size:{}, max_batch_count:{}, free_count:{}", req.entries.len(), count, self.opt.max_batch_count, sz, self.opt.max_batch_size, self.must_mt().free_size());
                 let cost = SystemTime::now();
                 let resp_ch = req.get_resp_channel();
                 // batch process requests
-                self.write_ch.send(req).await.unwrap();
+                if self.write_ch.send(req).await.is_err() {
+                    // Closed, nothing from this request on is written.
+                    let first = req_index[0];
+                    res[first..].fill(Err(Error::DBClosed));
+                    return res;
+                }
                 event::get_metrics().num_puts.inc_by(count);
                 {
                     count = 0;
@@ -418,7 +765,12 @@ impl KVCore {
         // process remaining requests
         if !req.entries.is_empty() {
             let resp_ch = req.get_resp_channel();
-            self.write_ch.send(req).await.unwrap();
+            if self.write_ch.send(req).await.is_err() {
+                for index in req_index {
+                    res[index] = Err(Error::DBClosed);
+                }
+                return res;
+            }
             event::get_metrics().num_puts.inc_by(count);
             {
                 for (index, ch) in resp_ch.into_iter().enumerate() {
@@ -432,7 +784,72 @@ impl KVCore {
         res
     }
 
-    async fn write_to_lsm(&self, req: Request) -> Result<()> {
+    // Returns why `entry` can't be written, if it can't.
+    fn check_entry(&self, entry: &Entry) -> Result<()> {
+        // The entries moved by the value log GC were written before the ban.
+        if entry.meta & MetaBit::BIT_MOVE.bits() == 0
+            && !entry.key.starts_with(_BADGER_PREFIX)
+            && self.is_banned(self.user_key(&entry.key))
+        {
+            return Err(Error::BannedKey);
+        }
+        if entry.key.len() > MAX_KEY_SIZE {
+            return Err("Key too big".into());
+        }
+        if entry.value.len() as u64 > self.opt.value_log_file_size {
+            return Err("Value to big".into());
+        }
+        Ok(())
+    }
+
+    // Sends `entries` to the writer as one request, the requests are written in the order
+    // they're sent. Returns once it's sent, the results are waited for with `PendingBatch`.
+    // The entries have to fit in a batch, like the writes of a transaction.
+    pub(crate) async fn send_batch(&self, entries: Vec<Entry>) -> PendingBatch {
+        let writes = self.write_barrier.clone().read_owned().await;
+        let mut res = vec![Ok(()); entries.len()];
+        let mut req = Request::default();
+        let mut req_index = vec![];
+        let now = self.opt.clock.now();
+        for (i, mut entry) in entries.into_iter().enumerate() {
+            entry.resolve_ttl(now);
+            if let Err(err) = self.check_entry(&entry) {
+                res[i] = Err(err);
+                event::get_metrics().num_blocked_puts.inc();
+                continue;
+            }
+            req.entries.push(EntryType::from(entry));
+            req.ptrs.push(Arc::new(Atomic::new(None)));
+            req_index.push(i);
+        }
+        let mut acks = vec![];
+        if !req.entries.is_empty() {
+            let count = req.entries.len() as u64;
+            let resp_ch = req.get_resp_channel();
+            if self.write_ch.send(req).await.is_err() {
+                for index in req_index {
+                    res[index] = Err(Error::DBClosed);
+                }
+            } else {
+                event::get_metrics().num_puts.inc_by(count);
+                acks = req_index.into_iter().zip(resp_ch).collect();
+            }
+        }
+        PendingBatch {
+            res,
+            acks,
+            _writes: writes,
+        }
+    }
+
+    // The response channels of the entries written are pushed to `acks`, they're answered
+    // once the WAL is written. Their changes are pushed to `changes` for the subscribers.
+    async fn write_to_lsm(
+        &self,
+        req: Request,
+        acks: &mut Vec<Channel<Result<()>>>,
+        changes: &mut Vec<KVChange>,
+    ) -> Result<()> {
         assert_eq!(req.entries.len(), req.ptrs.len());
         // defer! {info!("exit write to lsm")}
 
@@ -486,15 +903,20 @@ impl KVCore {
                 }
             }
 
+            let change = self.publisher.change_of(&entry);
             let key;
             let value;
             if self.should_write_value_to_lsm(&entry) {
                 let cas = entry.get_cas_counter();
                 let (_key, _value) = (entry.key, entry.value);
                 key = _key;
-                value = ValueStruct::new(_value, entry.meta, entry.user_meta, cas);
+                value = ValueStruct::new(_value, entry.meta, entry.user_meta, cas)
+                    .with_expires_at(entry.expires_at);
                 // Will include deletion/tombstone case.
                 debug!("Lsm ok, the value not at vlog file");
+                if self.wal.is_none() && !self.opt.sync_writes {
+                    self.unlogged_values.store(true, Ordering::Release);
+                }
             } else {
                 let ptr = req.ptrs.get(i).unwrap().load(Ordering::Relaxed);
                 let ptr = ptr.unwrap();
@@ -507,9 +929,18 @@ impl KVCore {
                     entry.meta | MetaBit::BIT_VALUE_POINTER.bits(),
                     entry.user_meta,
                     cas,
-                );
+                )
+                .with_expires_at(entry.expires_at);
+            }
+            if let Some(wal) = &self.wal {
+                let res = wal.lock().append(&key, &value);
+                if let Err(err) = res {
+                    resp_ch.send(Err(err)).await.unwrap();
+                    continue;
+                }
             }
             self.must_mt().put(&key, value);
+            changes.extend(change);
 
             #[cfg(test)]
             debug!(
@@ -523,9 +954,77 @@ impl KVCore {
                 String::from_utf8_lossy(&debug_entry.value),
             );
 
-            resp_ch.send(Ok(())).await.unwrap();
+            acks.push(resp_ch);
         }
+        Ok(())
+    }
+
+    fn sync_wal(&self) -> Result<()> {
+        if let Some(wal) = &self.wal {
+            wal.lock().sync(self.opt.sync_writes)?;
+        }
+        Ok(())
+    }
 
+    // The WAL of the mutable memtable, to be deleted once it's flushed.
+    fn wal_ids(&self) -> Vec<u32> {
+        self.wal
+            .as_ref()
+            .map(|wal| vec![wal.lock().id()])
+            .unwrap_or_default()
+    }
+
+    // Starts the WAL of the next memtable.
+    fn rotate_wal(&self) -> Result<()> {
+        if let Some(wal) = &self.wal {
+            let mut wal = wal.lock();
+            wal.sync(false)?;
+            *wal = MemTableWal::create(&self.opt.dir, wal.id() + 1, self.must_lc().data_key()?)?;
+        }
+        Ok(())
+    }
+
+    // Puts the entries of the WALs `ids` of the last run into the memtable. They're logged
+    // again in the current WAL, or flushed without one, before the files are deleted.
+    async fn replay_wals(&self, ids: Vec<u32>) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let mut count = 0;
+        for id in ids.iter() {
+            for (key, value) in MemTableWal::read(&self.opt.dir, *id, self.must_lc().keys())? {
+                if self.get_last_used_cas_counter() < value.cas_counter {
+                    self.update_last_used_cas_counter(value.cas_counter);
+                }
+                while let Err(err) = self.ensure_room_for_write().await {
+                    debug!("No room for write, {}", err);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                if let Some(wal) = &self.wal {
+                    wal.lock().append(&key, &value)?;
+                }
+                self.must_mt().put(&key, value);
+                count += 1;
+            }
+        }
+        info!("Replayed {} entries of the WALs {:?}", count, ids);
+        if let Some(wal) = &self.wal {
+            wal.lock().sync(true)?;
+            return MemTableWal::remove(&self.opt.dir, &ids);
+        }
+        if self.must_mt().empty() {
+            return MemTableWal::remove(&self.opt.dir, &ids);
+        }
+        self.flush_chan
+            .send(FlushTask {
+                mt: Some(self.mem_st_manger.mt_clone()),
+                vptr: self.must_vptr(),
+                drop_prefixes: vec![],
+                wal_ids: ids,
+            })
+            .await
+            .map_err(|_| "Flush channel closed")?;
+        self.mem_st_manger.swap_st(self.opt.clone());
         Ok(())
     }
 
@@ -550,22 +1049,30 @@ impl KVCore {
         let flush_task = FlushTask {
             mt: Some(self.must_mt().clone()),
             vptr: self.must_vptr(),
+            drop_prefixes: vec![],
+            wal_ids: self.wal_ids(),
         };
+        // Only the write loop pushes memtables, the queue stays with room once seen with it.
+        if self.flush_chan.tx().is_full() {
+            return Err(Unexpected("No room for write".into()));
+        }
+        info!("Flushing value log to disk if async mode.");
+        // Ensure value log is synced to disk so this memtable's contents wouldn't be lost. It
+        // has to be before the flush persists the head pointer past them, a restart wouldn't
+        // replay them then.
+        self.must_vlog().sync().await?;
         let ret = self.flush_chan.try_send(flush_task);
         if ret.is_err() {
             //info!("No room for write, {:?}", ret.unwrap_err());
             return Err(Unexpected("No room for write".into()));
         }
-
-        info!("Flushing value log to disk if async mode.");
-        // Ensure value log is synced to disk so this memtable's contents wouldn't be lost.
-        self.must_vlog().sync().await?;
         info!(
             "Flushing memtable, mt.size={} size of flushChan: {}",
             self.must_mt().mem_size(),
             self.flush_chan.tx().len()
         );
         // We manage to push this task. Let's modify imm.
+        self.rotate_wal()?;
         self.mem_st_manger.swap_st(self.opt.clone());
         // New memtable is empty. We certainly have room.
         Ok(())
@@ -615,16 +1122,28 @@ impl KVCore {
         tables
     }
 
+    // True if the user key `key` is under a banned prefix, see `KV::ban_prefix`.
+    pub(crate) fn is_banned(&self, key: &[u8]) -> bool {
+        self.banned
+            .read()
+            .iter()
+            .any(|prefix| key.starts_with(prefix))
+    }
+
+    // The key without its version, see `Options::managed_txns`.
+    fn user_key<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        if self.opt.managed_txns {
+            parse_key(key)
+        } else {
+            key
+        }
+    }
+
     fn _exists(&self, key: &[u8]) -> Result<bool> {
         return match self._get(key) {
             Err(err) if err.is_not_found() => Ok(false),
             Err(err) => Err(err),
-            Ok(value) => {
-                if value.value.is_empty() && value.meta == 0 {
-                    return Ok(false);
-                }
-                Ok((value.meta & MetaBit::BIT_DELETE.bits()) == 0)
-            }
+            Ok(value) => Ok((value.meta & MetaBit::BIT_DELETE.bits()) == 0),
         };
     }
 
@@ -635,8 +1154,61 @@ impl KVCore {
     }
 
     fn should_write_value_to_lsm(&self, entry: &Entry) -> bool {
-        entry.value.len() < self.opt.value_threshold
+        self.opt.value_in_lsm(entry.value.len())
     }
+
+    // Slows down the caller before the LSM tree falls so far behind that writes stall. Done
+    // before the writes are sent, the writer isn't held up by it.
+    pub(crate) async fn throttle_writes(&self) {
+        let delay = self.write_throttle().delay;
+        if !delay.is_zero() {
+            event::get_metrics().num_delayed_writes.inc();
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn write_throttle(&self) -> WriteThrottle {
+        let level0_tables = self.must_lc().level0_tables();
+        let (pending_flushes, pending_flush_bytes) = self.mem_st_manger.pending_flushes();
+        let opt = &self.opt;
+        let delay = write_delay(
+            level0_tables,
+            opt.level_zero_slowdown_tables,
+            opt.num_level_zero_tables_stall,
+            opt.max_write_delay,
+        )
+        .max(write_delay(
+            pending_flushes,
+            opt.num_mem_tables.div_ceil(2),
+            opt.num_mem_tables,
+            opt.max_write_delay,
+        ));
+        WriteThrottle {
+            level0_tables,
+            pending_flushes,
+            pending_flush_bytes,
+            delay,
+            stalled: level0_tables >= opt.num_level_zero_tables_stall
+                || pending_flushes >= opt.num_mem_tables,
+        }
+    }
+}
+
+// Scales the delay of a batch of writes with `pressure`, from nothing below `slowdown` to `max`
+// right before `stall`. A `slowdown` of 0 adds no delay.
+pub(crate) fn write_delay(
+    pressure: usize,
+    slowdown: usize,
+    stall: usize,
+    max: Duration,
+) -> Duration {
+    if slowdown == 0 || pressure < slowdown {
+        return Duration::ZERO;
+    }
+    if pressure >= stall {
+        return max;
+    }
+    max.mul_f64((pressure - slowdown + 1) as f64 / (stall - slowdown + 1) as f64)
 }
 
 impl KVCore {
@@ -652,7 +1224,7 @@ impl KVCore {
         unsafe { &*st }
     }
 
-    fn must_vlog(&self) -> Arc<ValueLogCore> {
+    pub(crate) fn must_vlog(&self) -> Arc<ValueLogCore> {
         let vlog = self.vlog.clone().unwrap();
         vlog
     }
@@ -682,6 +1254,26 @@ impl KVCore {
 
 pub type WeakKV = XWeak<KVCore>;
 
+/// The entries of a batch sent to the writer, see `KVCore::send_batch`.
+pub(crate) struct PendingBatch {
+    res: Vec<Result<()>>,
+    // The index of every entry sent, with its response channel.
+    acks: Vec<(usize, Channel<Result<()>>)>,
+    // Blocks `drop_prefix` until the entries are written.
+    _writes: tokio::sync::OwnedRwLockReadGuard<()>,
+}
+
+impl PendingBatch {
+    /// Waits for the entries to be written, returns their results.
+    pub(crate) async fn wait(self) -> Vec<Result<()>> {
+        let mut res = self.res;
+        for (index, ch) in self.acks {
+            res[index] = ch.recv().await.unwrap();
+        }
+        res
+    }
+}
+
 /// DB handle
 /// ```
 /// use badger_rs::{Options, KV};
@@ -711,6 +1303,11 @@ pub struct KV {
     inner: XArc<KVCore>,
 }
 
+/// The database, the same handle as `KV`: `DB::open` opens the manifest, the tables of every
+/// level, the memtables and the value log, replaying what wasn't flushed, and `DB::close`
+/// flushes the memtables and stops the background tasks.
+pub type DB = KV;
+
 impl Deref for KV {
     type Target = KVCore;
 
@@ -728,35 +1325,44 @@ impl fmt::Debug for KV {
 impl KV {
     /// Async open a KV db with Options
     pub async fn open(mut opt: Options) -> Result<KV> {
+        opt.validate()?;
         opt.max_batch_size = (15 * opt.max_table_size) / 100;
         opt.max_batch_count = 2 * opt.max_batch_size / Node::align_size() as u64;
         create_dir_all(opt.dir.as_str()).await?;
         create_dir_all(opt.value_dir.as_str()).await?;
-        if !(opt.value_log_file_size <= 2 << 30 && opt.value_log_file_size >= 1 << 20) {
-            return Err(Error::ValueLogSize);
-        }
         let dir_lock_guard = OpenOptions::new()
             .write(true)
             .append(true)
             .create(true)
             .open(Path::new(opt.dir.as_str()).join("dir_lock_guard.lock"))?;
         dir_lock_guard.try_lock_exclusive().map_err(|_| {
-            crate::Error::Unexpected(
-                "Another program process is using the Badger databse".to_owned(),
-            )
+            crate::Error::Unexpected(format!(
+                "Another program process is using the Badger databse, dir: {}",
+                opt.dir
+            ))
         })?;
 
+        // Both directories are locked, the value dir may be shared by another database.
         let value_dir_guard = OpenOptions::new()
             .write(true)
             .append(true)
             .create(true)
             .open(Path::new(opt.value_dir.as_str()).join("value_dir_guard.lock"))?;
         value_dir_guard.try_lock_exclusive().map_err(|_| {
-            crate::Error::Unexpected(
-                "Anthoer program process is using the Bader databse".to_owned(),
-            )
+            crate::Error::Unexpected(format!(
+                "Another program process is using the Badger databse, value dir: {}",
+                opt.value_dir
+            ))
         })?;
+        if !opt.value_dir_is_dir() && KVCore::walk_dir(opt.dir.as_str()).await?.1 > 0 {
+            return Err(format!(
+                "Value log files found in {}, but the value dir is {}",
+                opt.dir, opt.value_dir
+            )
+            .into());
+        }
         let manifest_file = open_or_create_manifest_file(opt.dir.as_str()).await?;
+        let wal_ids = MemTableWal::list(opt.dir.as_str())?;
 
         let closers = Closers {
             update_size: Closer::new("update_size".to_owned()),
@@ -764,6 +1370,8 @@ impl KV {
             mem_table: Closer::new("mem_table".to_owned()),
             writes: Closer::new("writes".to_owned()),
             value_gc: Closer::new("value_gc".to_owned()),
+            value_sync: Closer::new("value_sync".to_owned()),
+            value_gc_scheduler: Closer::new("value_gc_scheduler".to_owned()),
         };
 
         let mut out = KVCore {
@@ -783,6 +1391,14 @@ impl KV {
             last_used_cas_counter: Arc::new(AtomicU64::new(1)),
             mem_st_manger: Arc::new(SkipListManager::new(opt.arena_size() as usize)),
             share_lock: TArcRW::new(tokio::sync::RwLock::new(())),
+            write_barrier: TArcRW::new(tokio::sync::RwLock::new(())),
+            wal: None,
+            unlogged_values: Arc::new(AtomicBool::new(false)),
+            gc_pauses: Arc::new(AtomicUsize::new(0)),
+            orc: Arc::new(Oracle::default()),
+            publisher: Arc::new(Publisher::default()),
+            banned: Arc::new(parking_lot::RwLock::new(vec![])),
+            flush_err: Arc::new(Mutex::new(None)),
         };
 
         let manifest = out.manifest.clone();
@@ -793,19 +1409,40 @@ impl KV {
             out.notify_try_compact_chan.clone(),
             out.zero_level_compact_chan.clone(),
             out.notify_write_request_chan.clone(),
+            out.orc.read_mark(),
             out.opt.clone(),
         )
         .await?;
-        lc.start_compact(out.closers.compactors.clone());
         out.lc.replace(lc);
+        // Created once the data keys are open, its entries are encrypted with the latest one.
+        if opt.mem_table_wal {
+            let id = wal_ids.last().map_or(0, |id| id + 1);
+            let wal = out
+                .must_lc()
+                .data_key()
+                .and_then(|data_key| MemTableWal::create(&opt.dir, id, data_key));
+            match wal {
+                Ok(wal) => out.wal = Some(Arc::new(Mutex::new(wal))),
+                Err(err) => {
+                    out.must_lc().close()?;
+                    return Err(err);
+                }
+            }
+        }
         let mut vlog = ValueLogCore::default();
         {
             let kv = &out as *const KVCore;
-            vlog.open(kv, opt.clone()).await?;
+            if let Err(err) = vlog.open(kv, opt.clone()).await {
+                // Release the tables, a table dropped with its last reference removes its file.
+                out.must_lc().close()?;
+                return Err(err);
+            }
         }
         out.vlog.replace(Arc::new(vlog));
+        out.must_lc().start_compact(out.closers.compactors.clone());
 
         let xout = KV::new(XArc::new(out));
+        xout.must_vlog().set_kv(xout.to_ref());
 
         // update size
         {
@@ -863,12 +1500,14 @@ impl KV {
         // replay data from vlog
         let mut first = true;
         let mut count = 0;
+        let replayed = Arc::new(AtomicU64::new(0));
         xout.inner
             .vlog
             .as_ref()
             .unwrap()
             .replay(&vptr, |entry, vptr| {
                 let xout = xout.get_inner_kv();
+                let replayed = replayed.clone();
                 Box::pin(async move {
                     if first {
                         warn!("First key={}", string::String::from_utf8_lossy(&entry.key));
@@ -899,6 +1538,7 @@ impl KV {
                         meta,
                         user_meta: entry.user_meta,
                         cas_counter: entry.get_cas_counter(),
+                        expires_at: entry.expires_at,
                         value: nv,
                     };
                     while let Err(err) = xout.ensure_room_for_write().await {
@@ -909,12 +1549,22 @@ impl KV {
                         tokio::time::sleep(Duration::from_millis(10)).await;
                     }
                     xout.must_mt().put(&entry.key, v);
+                    replayed.fetch_add(1, Ordering::Relaxed);
                     Ok(true)
                 })
             })
             .await?;
+        // Only the entries after the head, those not in a flushed table yet, are replayed.
+        info!(
+            "Replayed {} entries of the value log from {:?}",
+            replayed.load(Ordering::Relaxed),
+            vptr
+        );
         // Wait for replay to be applied first.
         replay_closer.signal_and_wait().await;
+        // The memtables the WALs were kept for come after the value log head.
+        xout.get_inner_kv().replay_wals(wal_ids).await?;
+        xout.load_banned_prefixes();
 
         // Mmap writeable log
         let max_fid = xout.get_inner_kv().must_vlog().get_max_fid();
@@ -936,6 +1586,15 @@ impl KV {
             });
         }
 
+        if !opt.sync_writes {
+            if let Some(interval) = opt.value_log_sync_interval {
+                let _out = xout.clone();
+                tokio::spawn(async move {
+                    _out.spawn_value_log_sync(interval).await;
+                });
+            }
+        }
+
         {
             let closer = xout.get_inner_kv().closers.value_gc.spawn();
             let _out = xout.get_inner_kv();
@@ -943,6 +1602,14 @@ impl KV {
                 _out.must_vlog().wait_on_gc(closer).await;
             });
         }
+
+        if let Some(interval) = opt.value_log_gc_interval {
+            let _out = xout.clone();
+            tokio::spawn(async move {
+                _out.spawn_value_log_gc(interval, opt.value_log_gc_discard_ratio)
+                    .await;
+            });
+        }
         Ok(xout)
     }
 
@@ -955,7 +1622,7 @@ impl KV {
         let mut tk = tokio::time::interval(tokio::time::Duration::from_secs(5 * 60));
 
         let opt = self.opt.clone();
-        let (dir, vdir) = (opt.dir, opt.value_dir);
+        let (dir, vdir) = (opt.dir.clone(), opt.value_dir.clone());
         loop {
             let c = lc.has_been_closed();
             tokio::select! {
@@ -963,10 +1630,11 @@ impl KV {
                     // If value directory is different from dir, we'd have to do another walk.
                     let (lsm_sz, mut vlog_sz) = KVCore::walk_dir(dir.as_str()).await.unwrap();
                     crate::event::get_metrics().lsm_size.with_label_values(&[dir.as_ref()]).set(lsm_sz as i64);
-                    if dir != vdir {
-                         vlog_sz = KVCore::walk_dir(dir.as_str()).await.unwrap().1;
+                    if !opt.value_dir_is_dir() {
+                         vlog_sz = KVCore::walk_dir(vdir.as_str()).await.unwrap().1;
                     }
                     crate::event::get_metrics().vlog_size.set(vlog_sz as i64);
+                    crate::event::get_metrics().record_value_log(&self.must_vlog().info().await);
                     let lsm_sz = crate::event::get_metrics().lsm_size.get_metric_with_label_values(&[dir.as_ref()]).unwrap().get();
                     info!("ready to update size, lsm_sz: {}, vlog_size: {}", lsm_sz, crate::event::get_metrics().vlog_size.get());
                 },
@@ -975,8 +1643,81 @@ impl KV {
         }
     }
 
+    // Syncs the value log every `interval`, when the writes don't.
+    async fn spawn_value_log_sync(&self, interval: Duration) {
+        let lc = self.closers.value_sync.spawn();
+        defer! {lc.done()}
+        let mut tk = tokio::time::interval(interval);
+        loop {
+            let c = lc.has_been_closed();
+            tokio::select! {
+                _ = tk.tick() => {
+                    if let Err(err) = self.must_vlog().sync().await {
+                        error!("Failed to sync the value log, err: {}", err);
+                    }
+                },
+                _ = c.recv() => {return;},
+            }
+        }
+    }
+
+    async fn spawn_value_log_gc(&self, interval: Duration, discard_ratio: f64) {
+        let lc = self.closers.value_gc_scheduler.spawn();
+        defer! {lc.done()}
+        let start = tokio::time::Instant::now() + interval;
+        let mut tk = tokio::time::interval_at(start, interval);
+        loop {
+            let c = lc.has_been_closed();
+            tokio::select! {
+                _ = tk.tick() => {},
+                _ = c.recv() => {return;},
+            }
+            // Rewrite files until there's nothing left to, or the writes need the disk.
+            loop {
+                if self.gc_pauses.load(Ordering::Acquire) > 0 {
+                    break;
+                }
+                let throttle = self.write_throttle();
+                if throttle.stalled || !throttle.delay.is_zero() {
+                    debug!(
+                        "Skip the value log GC, writes are throttled: {:?}",
+                        throttle
+                    );
+                    break;
+                }
+                match self.run_value_log_gc(discard_ratio).await {
+                    Ok(()) => {}
+                    Err(Error::ValueNoRewrite) | Err(Error::ValueRejected) => break,
+                    Err(err) => {
+                        error!("Failed to run the value log GC, err: {}", err);
+                        break;
+                    }
+                }
+                if lc.has_been_closed().is_close() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Pauses the background value log GC, see `Options::value_log_gc_interval`, until
+    /// `resume_background_gc` is called as many times. A GC already running finishes its file.
+    pub fn pause_background_gc(&self) {
+        self.gc_pauses.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Undoes a `pause_background_gc`.
+    pub fn resume_background_gc(&self) {
+        let _ = self
+            .gc_pauses
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+    }
+
     /// Return a value that will async load value, if want not return value, should be `exists`
     pub async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        if self.is_banned(key) {
+            return Err(Error::BannedKey);
+        }
         let got = self._get(key)?;
         if got.meta == MetaBit::BIT_DELETE.bits() {
             return Err(Error::NotFound);
@@ -985,6 +1726,24 @@ impl KV {
         inner.get_value().await
     }
 
+    /// Returns the item of `key`: its value, read from the value log if it isn't in the LSM tree,
+    /// its user meta and its CAS counter, the version of the key. NotFound if the key is
+    /// deleted.
+    pub async fn get_item(&self, key: &[u8]) -> Result<KVItem> {
+        if self.is_banned(key) {
+            return Err(Error::BannedKey);
+        }
+        let got = self._get(key)?;
+        if got.meta & MetaBit::BIT_DELETE.bits() != 0 {
+            return Err(Error::NotFound);
+        }
+        Ok(KVItem::from(KVItemInner::new(
+            key.to_vec(),
+            got,
+            self.clone(),
+        )))
+    }
+
     /// Set sets the provided value for a given key. If key is not present, it is created. If it is
     /// present, the existing value is overwritten with the one provided.
     /// Along with key and value, Set can also take an optional userMeta byte. This byte is stored
@@ -994,6 +1753,24 @@ impl KV {
         self.to_ref().set(key, value, user_meta).await
     }
 
+    /// Sets `value` of `key`, like `set`, expiring `ttl` from now by `Options::clock`, rounded
+    /// up to the second. The key reads as missing once expired.
+    pub async fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        user_meta: u8,
+        ttl: Duration,
+    ) -> Result<()> {
+        let entry = Entry::default()
+            .key(key)
+            .value(value)
+            .user_meta(user_meta)
+            .with_ttl(ttl);
+        let ret = self.batch_set(vec![entry]).await;
+        ret[0].to_owned()
+    }
+
     /// Sets value of key if key is not present.
     /// If it is present, it returns the key_exists error.
     /// TODO it should be atomic operate
@@ -1014,9 +1791,86 @@ impl KV {
 
     /// Return Ok(true) if key exists, Ok(false) if key not exists, Otherwise Err(err) if happen some error.
     pub async fn exists(&self, key: &[u8]) -> Result<bool> {
+        if self.is_banned(key) {
+            return Err(Error::BannedKey);
+        }
         return self._exists(key);
     }
 
+    /// Starts a transaction, see `Txn`. A read-only one, `update` false, can't be written to.
+    /// Fails with `ManagedTxns` with `Options::managed_txns`, see `new_transaction_at`.
+    pub fn new_transaction(&self, update: bool) -> Result<Txn> {
+        Txn::new(self.clone(), None, update)
+    }
+
+    /// Starts a transaction reading the versions committed at or before `read_ts`, with
+    /// `Options::managed_txns`. Fails with `NotManagedTxns` without.
+    pub fn new_transaction_at(&self, read_ts: u64, update: bool) -> Result<Txn> {
+        Txn::new(self.clone(), Some(read_ts), update)
+    }
+
+    /// Takes a snapshot at the last commit done, with `Options::managed_txns`, see `Snapshot`.
+    /// The compactions keep the versions it reads until it's dropped, the discard ts is
+    /// lowered to its read ts, see `set_discard_ts`.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Snapshot::new(self.clone())
+    }
+
+    /// Subscribes to the writes of the keys starting with one of `prefixes`. The subscription
+    /// receives the keys of each batch written that match, once they're written. The writes
+    /// wait for a subscription too far behind, it has to be read or dropped. It ends when the
+    /// KV is closed.
+    pub fn subscribe(&self, prefixes: Vec<Vec<u8>>) -> Subscription {
+        self.publisher.subscribe(prefixes)
+    }
+
+    /// Returns a stream of the keys, to scan them in parallel, see `Stream`. Fails with
+    /// `ManagedTxns` with `Options::managed_txns`, see `new_stream_at`.
+    pub fn new_stream(&self) -> Result<Stream> {
+        if self.opt.managed_txns {
+            return Err(Error::ManagedTxns);
+        }
+        Ok(Stream::new(self.clone(), None))
+    }
+
+    /// Returns a stream of the versions committed at or before `read_ts`, with
+    /// `Options::managed_txns`. Fails with `NotManagedTxns` without.
+    pub fn new_stream_at(&self, read_ts: u64) -> Result<Stream> {
+        if !self.opt.managed_txns {
+            return Err(Error::NotManagedTxns);
+        }
+        Ok(Stream::new(self.clone(), Some(read_ts)))
+    }
+
+    /// Returns a sequence of increasing numbers persisted under `key`, leasing `bandwidth` of
+    /// them at once, see `Sequence`. Not with `Options::managed_txns`.
+    pub async fn get_sequence(&self, key: Vec<u8>, bandwidth: u64) -> Result<Sequence> {
+        Sequence::new(self.clone(), key, bandwidth).await
+    }
+
+    /// Tells compactions the versions at or below `ts` aren't read anymore, with
+    /// `Options::managed_txns`: only the newest `Options::num_versions_to_keep` of them are
+    /// kept. It applies from the next compaction on, the ones running keep the ts they
+    /// started with. It isn't persisted, it's 0 after a reopen until set again. Fails with
+    /// `NotManagedTxns` without managed transactions.
+    pub fn set_discard_ts(&self, ts: u64) -> Result<()> {
+        if !self.opt.managed_txns {
+            return Err(Error::NotManagedTxns);
+        }
+        self.must_lc().set_discard_ts(ts);
+        Ok(())
+    }
+
+    /// Returns the ts set by `set_discard_ts`, 0 if none is.
+    pub fn discard_ts(&self) -> u64 {
+        self.must_lc().discard_ts()
+    }
+
+    /// Returns a batch for bulk writes, see `WriteBatch`.
+    pub fn new_write_batch(&self) -> WriteBatch {
+        WriteBatch::new(self.clone())
+    }
+
     /// Batch set entries, returns result sets
     pub async fn batch_set(&self, entries: Vec<Entry>) -> Vec<Result<()>> {
         self.inner.batch_set(entries).await
@@ -1062,10 +1916,12 @@ impl KV {
     /// result in a space reclaim. Every run would in the best case rewrite only one log file. So,
     /// repeated calls may be necessary.
     ///
-    /// The way it currently works is that it would randomly pick up a value log file, and sample it. If
-    /// the sample shows that we can discard at least discardRatio space of that file, it would be
-    /// rewritten. Else, an ErrNoRewrite error would be returned indicating that the GC didn't result in
-    /// any file rewrite.
+    /// The way it currently works is that it would pick the value log file with the most stale bytes
+    /// in the discard stats fed by compactions, or randomly pick one and sample it if there are no
+    /// stats yet. If at least discardRatio space of that file can be discarded, it would be
+    /// rewritten. Else the blocks of its stale ranges are freed by punching holes, if the file system
+    /// supports it, or an ErrNoRewrite error would be returned indicating that the GC didn't result in
+    /// any space reclaim.
     ///
     /// We recommend setting discardRatio to 0.5, thus indicating that a file be rewritten if half the
     /// space can be discarded.  This results in a lifetime value log write amplification of 2 (1 from
@@ -1082,68 +1938,293 @@ impl KV {
         if discard_ratio >= 1.0 || discard_ratio <= 0.0 {
             return Err(Error::ValueInvalidRequest);
         }
-        self.must_vlog().trigger_gc(discard_ratio).await
+        let res = self.must_vlog().trigger_gc(discard_ratio).await;
+        event::get_metrics().record_value_log_gc(res.is_ok());
+        event::get_metrics().record_value_log(&self.value_log_info().await);
+        res
+    }
+
+    /// Syncs the value log and the memtable WAL to disk, the writes acknowledged before are
+    /// durable once it returns. With `sync_writes` off it's the way to mark a durability
+    /// point. The values kept in the LSM tree (see `value_threshold`) are in neither without
+    /// `mem_table_wal`, the memtable holding them is flushed to level 0 then, writes are held
+    /// back meanwhile.
+    pub async fn sync(&self) -> Result<()> {
+        if self.unlogged_values.load(Ordering::Acquire) {
+            // Wait for the writes in flight, and block the new ones.
+            let _writes = self.write_barrier.write().await;
+            if self.unlogged_values.swap(false, Ordering::AcqRel) && !self.must_mt().empty() {
+                // The value log must be on disk before the head pointer moves past it.
+                self.must_vlog().sync().await?;
+                self.flush_chan
+                    .send(FlushTask {
+                        mt: Some(self.mem_st_manger.mt_clone()),
+                        vptr: self.must_vptr(),
+                        drop_prefixes: vec![],
+                        wal_ids: vec![],
+                    })
+                    .await
+                    .map_err(|_| "Flush channel closed")?;
+                self.mem_st_manger.swap_st(self.opt.clone());
+            }
+            return self.wait_for_flushes().await;
+        }
+        self.must_vlog().sync().await?;
+        if let Some(wal) = &self.wal {
+            wal.lock().sync(true)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the most entries written as one batch, derived from `Options::max_table_size`
+    /// on open. A transaction holds at most this many writes.
+    pub fn max_batch_count(&self) -> u64 {
+        self.opt.max_batch_count
+    }
+
+    /// Returns the most bytes written as one batch, see `Options::estimate_size` for how an
+    /// entry counts, derived from `Options::max_table_size` on open. A transaction holds at
+    /// most this many bytes of writes.
+    pub fn max_batch_size(&self) -> u64 {
+        self.opt.max_batch_size
+    }
+
+    /// Returns the bytes of the LSM tree tables and of the value log files, as written. The
+    /// memtables aren't counted.
+    pub async fn size(&self) -> (u64, u64) {
+        let lsm = self.must_lc().tables_size();
+        let vlog = self
+            .value_log_info()
+            .await
+            .iter()
+            .map(|info| info.size)
+            .sum();
+        (lsm, vlog)
+    }
+
+    /// Estimates the bytes of the keys with `prefix` in the LSM tree, from the table blocks
+    /// their range may be in, an upper bound at block granularity. The table indexes and
+    /// filters, internal keys and the values in the value log aren't counted.
+    pub fn estimate_size(&self, prefix: &[u8]) -> u64 {
+        self.must_lc().estimate_size(prefix)
+    }
+
+    /// Returns the metrics of the gets, writes, compactions, value log GCs and caches. They're
+    /// process wide, summed over all the KVs open.
+    pub fn metrics(&self) -> &'static EvMetrics {
+        event::get_metrics()
+    }
+
+    /// Returns the hits, misses and bytes held of the block and index caches shared by the
+    /// tables, since the KV was opened.
+    pub fn cache_metrics(&self) -> CacheMetrics {
+        let lc = self.must_lc();
+        CacheMetrics {
+            block_cache: lc.block_cache().map(|cache| cache.stats()),
+            index_cache: lc.index_cache().map(|cache| cache.stats()),
+        }
+    }
+
+    /// Returns the space usage of the value log files, by id: their live, stale and
+    /// reclaimable bytes, to tell when a value log GC is worth running.
+    pub async fn value_log_info(&self) -> Vec<ValueLogInfo> {
+        self.must_vlog().info().await
+    }
+
+    /// Returns the compactions running and the compaction scores of the levels. A level 0
+    /// over `num_level_zero_tables_stall` tables stalls the memtable flushes, and so writes.
+    pub fn compaction_status(&self) -> CompactionStatus {
+        self.must_lc().compaction_status()
+    }
+
+    /// Returns how much the writes are slowed down by the level 0 tables and memtables
+    /// waiting for compaction and flushes.
+    pub fn write_throttle(&self) -> WriteThrottle {
+        self.to_ref().write_throttle()
+    }
+
+    /// Reads every table and value log file through and checks the checksums of their blocks
+    /// and entries, in parallel, e.g. after a disk failure. Corrupt files are reported, not
+    /// failed on, the KV stays open. The value log files are checked up to where they're
+    /// written when it's called.
+    pub async fn verify_checksum(&self) -> Result<ChecksumReport> {
+        let (tables_checked, corrupt_tables) = self.must_lc().verify_checksum().await?;
+        let (value_logs_checked, corrupt_value_logs) = self.must_vlog().verify_checksum().await?;
+        Ok(ChecksumReport {
+            tables_checked,
+            value_logs_checked,
+            corrupt_tables,
+            corrupt_value_logs,
+        })
+    }
+
+    /// Returns the number of tables, sizes and compaction score of every level, to follow the
+    /// shape of the LSM tree. See `tables` for the tables themselves.
+    pub fn levels(&self) -> Vec<LevelInfo> {
+        self.must_lc().levels_info()
+    }
+
+    /// Returns the tables of every level, by level and id. Counting the keys reads every
+    /// table.
+    pub fn tables(&self, with_keys_count: bool) -> Result<Vec<TableInfo>> {
+        self.must_lc().tables(with_keys_count)
+    }
+
+    /// Returns up to `n - 1` keys with `prefix` that split the keys on disk in `n` ranges of
+    /// about the same size, e.g. to scan them in parallel. They're taken from the table
+    /// bounds, so there are fewer when there are few tables.
+    pub fn key_splits(&self, prefix: &[u8], n: usize) -> Vec<Vec<u8>> {
+        self.must_lc().key_splits(prefix, n)
+    }
+
+    /// Returns an iterator over the tables of `level`, key by key as they're stored, with
+    /// their tombstones and versions. The tables stay on disk until the iterator is closed,
+    /// even if a compaction replaces them. Level 0 isn't supported, its tables overlap.
+    pub fn level_iterator(&self, level: usize) -> Result<ConcatIterator> {
+        self.must_lc().level_iterator(level)
+    }
+
+    /// Bans the keys starting with `prefix`: from now on their reads and writes fail with
+    /// `BannedKey` and iterators skip them. They stay on disk, see `drop_prefix` to remove
+    /// them. The ban is persisted, it holds after a reopen, and can't be lifted.
+    pub async fn ban_prefix(&self, prefix: &[u8]) -> Result<()> {
+        if _BADGER_PREFIX.starts_with(prefix) || prefix.starts_with(_BADGER_PREFIX) {
+            return Err(format!("Can't ban the internal keys, prefix: {}", hex_str(prefix)).into());
+        }
+        let entry = Entry::default().key([_BANNED.as_slice(), prefix].concat());
+        let ret = self.batch_set(vec![entry]).await;
+        ret[0].to_owned()?;
+        let mut banned = self.banned.write();
+        if !banned.iter().any(|banned| banned == prefix) {
+            banned.push(prefix.to_vec());
+        }
+        Ok(())
     }
 
+    /// Returns the prefixes banned, see `ban_prefix`.
+    pub fn banned_prefixes(&self) -> Vec<Vec<u8>> {
+        self.banned.read().clone()
+    }
+
+    // Loads the prefixes banned before, see `ban_prefix`.
+    fn load_banned_prefixes(&self) {
+        let itr = self.merge_iterator(false, _BANNED);
+        let mut banned = vec![];
+        let mut item = itr.seek(_BANNED);
+        while let Some(el) = item {
+            if !el.key().starts_with(_BANNED) {
+                break;
+            }
+            if el.value().meta & MetaBit::BIT_DELETE.bits() == 0 {
+                banned.push(el.key()[_BANNED.len()..].to_vec());
+            }
+            item = itr.next();
+        }
+        banned.dedup();
+        *self.banned.write() = banned;
+    }
+
+    /// Drops all the keys with any of `prefixes`, writes are held back until it returns.
+    /// The keys in memory are left out when the memtable is flushed, then the tables
+    /// holding them are rewritten or deleted level by level, through the manifest.
+    pub async fn drop_prefix(&self, prefixes: Vec<Vec<u8>>) -> Result<()> {
+        for prefix in prefixes.iter() {
+            if _BADGER_PREFIX.starts_with(prefix) || prefix.starts_with(_BADGER_PREFIX) {
+                return Err(
+                    format!("Can't drop the internal keys, prefix: {}", hex_str(prefix)).into(),
+                );
+            }
+        }
+        if prefixes.is_empty() {
+            return Ok(());
+        }
+        // Wait for the writes in flight, and block the new ones.
+        let _writes = self.write_barrier.write().await;
+        if !self.must_mt().empty() {
+            // The value log must be on disk before the head pointer moves past it.
+            self.must_vlog().sync().await?;
+            self.flush_chan
+                .send(FlushTask {
+                    mt: Some(self.mem_st_manger.mt_clone()),
+                    vptr: self.must_vptr(),
+                    drop_prefixes: prefixes.clone(),
+                    wal_ids: self.wal_ids(),
+                })
+                .await
+                .map_err(|_| "Flush channel closed")?;
+            self.rotate_wal()?;
+            self.mem_st_manger.swap_st(self.opt.clone());
+        }
+        // All memtables have to be in level 0 before it's compacted.
+        self.wait_for_flushes().await?;
+        self.must_lc().drop_prefixes(prefixes).await?;
+        info!("Dropped the prefixes");
+        Ok(())
+    }
+
+    /// Deletes all the data: the memtables, the tables of every level and the value log
+    /// files, then goes on with an empty store. Writes and value log GC are held back
+    /// meanwhile, and iterators must be closed first.
+    pub async fn drop_all(&self) -> Result<()> {
+        // A running GC writes the values it moves, it has to finish before writes are blocked.
+        self.must_vlog().pause_gc().await;
+        let res = self.drop_all_data().await;
+        self.must_vlog().resume_gc().await;
+        res
+    }
+
+    async fn drop_all_data(&self) -> Result<()> {
+        let _writes = self.write_barrier.write().await;
+        // The iterators read the value log files.
+        if self.must_vlog().has_active_iterators() {
+            return Err("Unable to drop all data while iterators are open".into());
+        }
+        // Let the flushes in progress finish, their tables are deleted with the others.
+        self.wait_for_flushes().await?;
+        self.mem_st_manger.discard_mt(self.opt.clone());
+        let wal_ids = self.wal_ids();
+        self.rotate_wal()?;
+        MemTableWal::remove(&self.opt.dir, &wal_ids)?;
+        self.vptr
+            .store(Owned::new(ValuePointer::default()), Ordering::Release);
+        let tables = self.must_lc().drop_all().await?;
+        let vlogs = self.must_vlog().drop_all().await?;
+        info!("Dropped all, {} tables, {} value log files", tables, vlogs);
+        // The bans were dropped with the rest, they're written again to hold after a reopen.
+        let banned = self
+            .banned_prefixes()
+            .into_iter()
+            .map(|prefix| Entry::default().key([_BANNED.as_slice(), &prefix].concat()))
+            .collect::<Vec<_>>();
+        for res in self.write_entries(banned).await {
+            res?;
+        }
+        Ok(())
+    }
+
+    // The writer task: the requests queued while a group is written are written together as
+    // the next group, their callers wait for one value log write and sync.
     async fn do_writes(&self, lc: Closer, without_close_write_ch: bool) {
         info!("start do writes task!");
         defer! {info!("exit writes task!")}
         defer! {lc.done()}
-        // TODO add metrics
         let has_been_close = lc.has_been_closed();
         let write_ch = self.to_ref().write_ch.clone();
-        let reqs = ArcMx::<Vec<Request>>::new(Mutex::new(vec![]));
-
-        let mut wait_time = tokio::time::interval(Duration::from_millis(10));
-        let to_reqs = || {
-            let to_reqs = reqs
-                .lock()
-                .clone()
-                .into_iter()
-                .map(|req| req.clone())
-                .collect::<Vec<_>>();
-            reqs.lock().clear();
-            to_reqs
-        };
         loop {
-            tokio::select! {
+            let req = tokio::select! {
                 _ret = has_been_close.recv() => {
                     break;
                 },
-                _ = wait_time.tick() => {
-                    // info!("wait time trigger!, {}", reqs.lock().len());
-                },
-                req = write_ch.recv() => {
-                    if req.is_err() {
+                req = write_ch.recv() => match req {
+                    Ok(req) => req,
+                    Err(err) => {
                         assert!(write_ch.is_close());
-                        info!("receive a invalid write task, err: {:?}", req.unwrap_err());
+                        info!("receive a invalid write task, err: {:?}", err);
                         break;
                     }
-                    reqs.lock().push(req.unwrap());
-                }
-            }
-            wait_time.reset();
-            let to_reqs = if reqs.lock().len() >= 3 * KV_WRITE_CH_CAPACITY {
-                to_reqs()
-            } else {
-                // Try to get next one
-                if let Ok(req) = write_ch.try_recv() {
-                    reqs.lock().push(req);
-                    vec![]
-                } else {
-                    to_reqs()
-                }
+                },
             };
-
-            // TODO maybe currently
-            let reqs_len = to_reqs.len();
-            if !to_reqs.is_empty() {
-                self.to_ref()
-                    .write_requests(to_reqs)
-                    .await
-                    .expect("TODO: panic message");
-                event::get_metrics().pending_writes.set(reqs_len as i64);
-            }
+            self.write_group(&write_ch, req).await;
         }
 
         // clear future requests
@@ -1157,21 +2238,41 @@ impl KV {
                 assert!(err.is_closed() || err.is_empty(), "{:?}", err);
                 break;
             }
-            reqs.lock().push(req.unwrap());
-            let to_reqs = to_reqs();
-            self.to_ref()
-                .write_requests(to_reqs.clone())
-                .await
-                .expect("TODO: panic message");
-            event::get_metrics()
-                .pending_writes
-                .set(to_reqs.len() as i64);
+            self.write_group(&write_ch, req.unwrap()).await;
+        }
+    }
+
+    // Writes `req` with the requests queued behind it, up to a bound.
+    async fn write_group(&self, write_ch: &Channel<Request>, req: Request) {
+        let mut reqs = vec![req];
+        while reqs.len() < 3 * KV_WRITE_CH_CAPACITY {
+            match write_ch.try_recv() {
+                Ok(req) => reqs.push(req),
+                Err(_) => break,
+            }
+        }
+        let reqs_len = reqs.len();
+        event::get_metrics().pending_writes.set(reqs_len as i64);
+        // The requests are answered with the error, the writer keeps going.
+        if let Err(err) = self.to_ref().write_requests(reqs).await {
+            error!("Failed to write {} requests, err: {}", reqs_len, err);
         }
     }
 
     /// NewIterator returns a new iterator. Depending upon the options, either only keys, or both
     /// key-value pairs would be fetched. The keys are returned in lexicographically sorted order.
     pub async fn new_iterator(&self, opt: IteratorOptions) -> IteratorExt {
+        // add vlog reference.
+        self.must_vlog().incr_iterator_count();
+        let vlogs = self.must_vlog().pin_files().await;
+        let mitr = self.merge_iterator(opt.reverse, &[]);
+        IteratorExt::new(self.clone(), mitr, opt, vlogs)
+    }
+
+    // Returns an iterator merging the memtables and the tables of every level, but the tables
+    // without keys starting with `prefix`. The value log files it reads have to be held, see
+    // `ValueLogCore::pin_files`.
+    pub(crate) fn merge_iterator(&self, reverse: bool, prefix: &[u8]) -> MergeIterator {
         // Notice, the iterator is global iterator, so must incr reference for memtable(SikpList), sst(file), vlog(file).
         let p = crossbeam_epoch::pin();
         let tables = self.get_mem_tables(&p);
@@ -1179,33 +2280,43 @@ impl KV {
         defer! {
             tables.iter().for_each(|table| unsafe {table.as_ref().unwrap().decr_ref()});
         }
-        // add vlog reference.
-        self.must_vlog().incr_iterator_count();
 
         // Create iterators across all the tables involved first.
         let mut itrs: Vec<Box<dyn Xiterator<Output = IteratorItem>>> = vec![];
         for tb in tables.clone() {
             let st = unsafe { tb.as_ref().unwrap().clone() };
-            let iter = Box::new(UniIterator::new(st, opt.reverse));
+            let iter = Box::new(UniIterator::new(st, reverse));
             itrs.push(iter);
         }
         // Extend sst.table
-        itrs.extend(self.must_lc().as_iterator(opt.reverse));
-        let mitr = MergeIterOverBuilder::default().add_batch(itrs).build();
-        IteratorExt::new(self.clone(), mitr, opt)
+        itrs.extend(self.must_lc().as_iterator(reverse, prefix));
+        MergeIterOverBuilder::default()
+            .reverse(reverse)
+            .add_batch(itrs)
+            .build()
     }
     /// Closes a KV. It's crucial to call it to ensure all the pending updates
-    /// make their way to disk.
+    /// make their way to disk: the writes queued are written, the memtables are flushed to
+    /// level 0 and the directories are synced, so the next open finds them in the tables. The
+    /// writes made after fail with `DBClosed`.
     pub async fn close(&self) -> Result<()> {
         info!("Closing database");
         // Stop value GC first;
+        self.closers.value_gc_scheduler.signal_and_wait().await;
         self.closers.value_gc.signal_and_wait().await;
+        // End the subscriptions before stopping the writes, the writer may wait for a
+        // subscriber that isn't read.
+        self.publisher.close();
         // Stop writes next.
         self.closers.writes.signal_and_wait().await;
+        self.closers.value_sync.signal_and_wait().await;
 
         // Now close the value log.
         self.must_vlog().close().await?;
 
+        // A flush failing from now on isn't retried, else closing could wait on it forever.
+        self.closers.mem_table.signal();
+
         // Make sure that block writer is done pushing stuff into memtable!
         // Otherwise, you will have a race condition: we are trying to flush memtables
         // and remove them completely, while the block / memtable writer is still trying
@@ -1221,9 +2332,12 @@ impl KV {
                 .send(FlushTask {
                     mt: Some(self.mem_st_manger.mt_clone()),
                     vptr,
+                    drop_prefixes: vec![],
+                    wal_ids: self.wal_ids(),
                 })
                 .await
                 .unwrap();
+            self.rotate_wal()?;
             self.mem_st_manger.swap_st(self.opt.clone());
             warn!("Pushed to flush chan");
         }
@@ -1233,12 +2347,22 @@ impl KV {
             .send(FlushTask {
                 mt: None,
                 vptr: ValuePointer::default(),
+                drop_prefixes: vec![],
+                wal_ids: vec![],
             })
             .await
             .unwrap();
         self.closers.mem_table.signal_and_wait().await;
+        // The KV is closed anyway, the writes of the memtables not flushed are replayed on the
+        // next open.
+        let flush_res = match self.flush_err() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        };
         info!("Memtable flushed!");
 
+        // The flushes may have waited for compactions, they can be given up only now.
+        self.must_lc().abort_compactions();
         self.closers.compactors.signal_and_wait().await;
         info!("Compaction finished!");
 
@@ -1247,38 +2371,137 @@ impl KV {
         info!("Waiting for closer");
         self.closers.update_size.signal_and_wait().await;
 
-        self.dir_lock_guard.unlock()?;
-        self.value_dir_guard.unlock()?;
-
         self.manifest.write().await.close();
         // Fsync directions to ensure that lock file, and any other removed files whose directory
         // we haven't specifically fsynced, are guaranteed to have their directory entry removal
         // persisted to disk.
         async_sync_directory(self.opt.dir.clone().to_string()).await?;
-        async_sync_directory(self.opt.value_dir.clone().to_string()).await?;
+        if !self.opt.value_dir_is_dir() {
+            async_sync_directory(self.opt.value_dir.clone().to_string()).await?;
+        }
+
+        // Nothing touches the directories anymore, another process may open them.
+        self.dir_lock_guard.unlock()?;
+        self.value_dir_guard.unlock()?;
 
         warn!("metrics: \n{}", event::get_metrics());
-        Ok(())
+        flush_res
     }
 
-    pub async fn backup<W>(&self, mut wt: W) -> Result<()>
+    /// Writes the keys to `wt` as length-prefixed protobuf pairs, the versions at or after
+    /// `since_ts` only, and returns the largest version written, or `since_ts` if none. It's
+    /// the `since_ts` of the next backup for an incremental one.
+    ///
+    /// With `Options::managed_txns`, every version of a key is written, newest first, up to
+    /// its newest delete. Else the version of a key is its CAS counter.
+    pub async fn backup<W>(&self, mut wt: W, since_ts: u64) -> Result<u64>
     where
         W: Write,
     {
-        let itr = self.new_iterator(IteratorOptions::default()).await;
-        itr.rewind().await;
-        while let Some(item) = itr.peek().await {
-            let value = item.value().await?;
-            let mut entry = KVPair::new();
-            entry.key = item.key().await;
-            entry.value = value;
-            entry.userMeta.push(item.user_meta().await);
-            // Write entries to disk
-            crate::backup::write_to(&entry, &mut wt)?;
-            itr.next().await;
+        let (txn, opt) = if self.opt.managed_txns {
+            let opt = IteratorOptions::default()
+                .with_all_versions(true)
+                .with_since_ts(since_ts);
+            (self.new_transaction_at(u64::MAX, false)?, opt)
+        } else {
+            (self.new_transaction(false)?, IteratorOptions::default())
+        };
+        let mut itr = txn.iter(opt).await?;
+        let mut max_version = since_ts;
+        // The key whose older versions are deleted, they aren't written.
+        let mut deleted_key = None;
+        let mut item = itr.rewind().await;
+        while let Some(el) = item {
+            let entry = {
+                let inner = el.rl().await;
+                let version = if self.opt.managed_txns {
+                    inner.version
+                } else {
+                    inner.counter()
+                };
+                if deleted_key.as_deref() == Some(inner.key()) || version < since_ts {
+                    None
+                } else if inner.is_deleted_or_expired(self.opt.clock.now()) {
+                    deleted_key = Some(inner.key().to_vec());
+                    None
+                } else {
+                    let mut entry = KVPair::new();
+                    entry.key = inner.key().to_vec();
+                    entry.value = inner.get_value().await?;
+                    entry.userMeta.push(inner.user_meta());
+                    entry.version = version;
+                    entry.expiresAt = inner.expires_at();
+                    max_version = max_version.max(version);
+                    Some(entry)
+                }
+            };
+            if let Some(entry) = entry {
+                crate::backup::write_to(&entry, &mut wt)?;
+            }
+            item = itr.next().await;
+        }
+        itr.close().await?;
+        Ok(max_version)
+    }
+
+    /// Writes the keys of a backup read from `rd`, see `backup`, with at most
+    /// `max_pending_writes` batches written at the same time. With `Options::managed_txns`
+    /// the keys keep their versions, else they're new values. Backups are loaded in the order
+    /// they were taken, the full one first.
+    pub async fn load<R>(&self, mut rd: R, max_pending_writes: usize) -> Result<()>
+    where
+        R: Read,
+    {
+        let mut pending = VecDeque::new();
+        let mut entries = vec![];
+        let mut size = 0;
+        loop {
+            let pair = crate::backup::read_from(&mut rd)?;
+            if let Some(pair) = pair {
+                let key = if self.opt.managed_txns {
+                    key_with_ts(&pair.key, pair.version)
+                } else {
+                    pair.key
+                };
+                let entry = Entry::default()
+                    .key(key)
+                    .value(pair.value)
+                    .user_meta(pair.userMeta.first().copied().unwrap_or_default())
+                    .expires_at(pair.expiresAt);
+                size += self.opt.estimate_size(&entry) as u64;
+                entries.push(entry);
+                if (entries.len() as u64) < self.opt.max_batch_count
+                    && size < self.opt.max_batch_size
+                {
+                    continue;
+                }
+            } else if entries.is_empty() {
+                break;
+            }
+            if pending.len() >= max_pending_writes.max(1) {
+                Self::wait_load(pending.pop_front().unwrap()).await?;
+            }
+            let kv = self.clone();
+            let batch = std::mem::take(&mut entries);
+            size = 0;
+            pending.push_back(tokio::spawn(async move {
+                kv.batch_set(batch)
+                    .await
+                    .into_iter()
+                    .collect::<Result<()>>()
+            }));
+        }
+        for handle in pending {
+            Self::wait_load(handle).await?;
         }
         Ok(())
     }
+
+    async fn wait_load(handle: JoinHandle<Result<()>>) -> Result<()> {
+        handle
+            .await
+            .unwrap_or_else(|err| Err(format!("Load panicked, {}", err).into()))
+    }
 }
 
 impl KV {
@@ -1316,8 +2539,51 @@ impl KV {
         let mut vptr = ValuePointer::default();
         vptr.dec(&mut Cursor::new(item.vptr()))?;
         let vlog = self.inner.must_vlog();
-        vlog.async_read(&vptr, consumer).await?;
-        Ok(())
+        loop {
+            match vlog.async_read(&vptr, &mut consumer).await {
+                Err(Error::ValueRetry) => {}
+                res => return res,
+            }
+            let vs = self.moved_value(&item)?;
+            if (vs.meta & MetaBit::BIT_VALUE_POINTER.bits()) == 0 {
+                return consumer(&vs.value).await;
+            }
+            vptr.dec(&mut Cursor::new(&vs.value))?;
+        }
+    }
+
+    // Returns a reader of the item value, streamed from the value log if it's there.
+    pub(crate) async fn item_value_reader(&self, item: KVItemInner) -> Result<ValueReader> {
+        if !item.has_value() {
+            return Ok(ValueReader::from_value(vec![]));
+        }
+        if (item.meta() & MetaBit::BIT_VALUE_POINTER.bits()) == 0 {
+            return Ok(ValueReader::from_value(item.vptr().to_vec()));
+        }
+        let mut vptr = ValuePointer::default();
+        vptr.dec(&mut Cursor::new(item.vptr()))?;
+        let vlog = self.inner.must_vlog();
+        loop {
+            match vlog.value_reader(&vptr).await {
+                Err(Error::ValueRetry) => {}
+                res => return res,
+            }
+            let vs = self.moved_value(&item)?;
+            if (vs.meta & MetaBit::BIT_VALUE_POINTER.bits()) == 0 {
+                return Ok(ValueReader::from_value(vs.value));
+            }
+            vptr.dec(&mut Cursor::new(&vs.value))?;
+        }
+    }
+
+    // The value of `item` after the value log GC moved it and removed the file. The moved
+    // value keeps its CAS counter, anything else is a newer value and the item's is gone.
+    fn moved_value(&self, item: &KVItemInner) -> Result<ValueStruct> {
+        let vs = self._get(&item.lsm_key())?;
+        if vs.cas_counter != item.counter() {
+            return Err(Error::ValueRetry);
+        }
+        Ok(vs)
     }
 
     pub(crate) async fn get_with_ext(&self, key: &[u8]) -> Result<KVItem> {
@@ -1349,13 +2615,22 @@ pub(crate) async fn write_level0_table(
     st: &SkipList,
     f_name: &String,
     f: &mut tokio::fs::File,
+    opt: &Options,
+    drop_prefixes: &[Vec<u8>],
+    data_key: Option<DataKey>,
 ) -> Result<()> {
     defer! {info!("Finish write level zero table")}
     let st_id = st.id();
     let cur = st.new_cursor();
-    let mut builder = Builder::default();
+    let mut builder = Builder::new(opt.max_table_size, opt.block_size)
+        .with_compression(opt.compression, opt.zstd_compression_level)
+        .with_filter(opt.filter_type)
+        .with_data_key(data_key);
     while let Some(_) = cur.next() {
         let key = cur.key();
+        if !key.starts_with(_BADGER_PREFIX) && drop_prefixes.iter().any(|p| key.starts_with(p)) {
+            continue;
+        }
         let value = cur.value();
         builder.add(key, &value)?;
         #[cfg(test)]
@@ -13,21 +13,15 @@ use tokio_stream::StreamExt;
 use tracing_subscriber::fmt::format;
 
 use crate::iterator::IteratorOptions;
-use crate::test_util::{push_log, remove_push_log, tracing_log};
+use crate::test_util::{
+    get_test_option, push_log, remove_push_log, test_key, test_value, tracing_log,
+    write_test_entries,
+};
 use crate::types::{TArcMx, XArc};
 use crate::value_log::{Entry, MetaBit, MAX_KEY_SIZE};
 use crate::y::hex_str;
 use crate::{kv::KVCore, options::Options, Error, KV};
 
-fn get_test_option(dir: &str) -> Options {
-    let mut opt = Options::default();
-    opt.max_table_size = 1 << 15; // Force more compaction.
-    opt.level_one_size = 4 << 15; // Force more compaction.
-    opt.dir = Box::new(dir.to_string());
-    opt.value_dir = Box::new(dir.to_string());
-    opt
-}
-
 #[tokio::test]
 async fn t_1_write() {
     use crate::test_util::{random_tmp_dir, tracing_log};
@@ -175,6 +169,8 @@ async fn t_concurrent_write() {
             reverse: false,
             pre_fetch_values: true,
             pre_fetch_size: 10,
+            pre_fetch_workers: 2,
+            ..Default::default()
         })
         .await;
 
@@ -516,7 +512,7 @@ async fn kv_iterator_basic() {
     opt.pre_fetch_size = 10;
 
     {
-        let itr = kv.new_iterator(opt).await;
+        let itr = kv.new_iterator(opt.clone()).await;
         let mut count = 0;
         let mut rewind = true;
         info!("Startinh first basic iteration");
@@ -774,6 +770,35 @@ async fn t_kv_iterator_prefetch_size() {
     }
 }
 
+#[tokio::test]
+async fn t_kv_iterator_prefetch_vlog_values() {
+    tracing_log();
+    let kv = build_kv().await;
+    let bkey = |i: usize| format!("{:09}", i).as_bytes().to_vec();
+    // Every other value is over the value threshold and lives in the value log.
+    let bvalue = |i: usize| format!("{:0width$}", i, width = 10 + (i % 2) * 100).into_bytes();
+    let n = 100;
+    for i in 0..n {
+        kv.set(bkey(i), bvalue(i), 0).await.unwrap();
+    }
+
+    for workers in [1, 4] {
+        let opt = IteratorOptions::new(true, 10, false).with_pre_fetch_workers(workers);
+        let itr = kv.new_iterator(opt).await;
+        itr.rewind().await;
+        let mut i = 0;
+        while let Some(got) = itr.peek().await {
+            assert_eq!(got.key().await, bkey(i));
+            assert_eq!(got.value().await.unwrap(), bvalue(i));
+            i += 1;
+            itr.next().await;
+        }
+        assert_eq!(i, n);
+        itr.close().await.unwrap();
+    }
+    kv.close().await.unwrap();
+}
+
 #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
 async fn t_kv_get_set_race() {
     use rand::{thread_rng, Rng};
@@ -848,7 +873,7 @@ async fn t_kv_dump() {
         .create(true)
         .open(back_tmp.clone())
         .unwrap();
-    kv.backup(fp).await.unwrap();
+    kv.backup(fp, 0).await.unwrap();
     kv.close().await.unwrap();
     info!("backup: {:?}", back_tmp);
 }
@@ -889,6 +914,188 @@ async fn t_kv_dump() {
 //     );
 // }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_drop_prefix() {
+    use crate::test_util::random_tmp_dir;
+    tracing_log();
+    let dir = random_tmp_dir();
+    let kv = KV::open(get_test_option(&dir)).await.unwrap();
+    let key = |prefix: &str, i: usize| format!("{}{:06}", prefix, i).into_bytes();
+    let value = |i: usize| format!("{:025}", i).into_bytes();
+    let n = 5000;
+    for prefix in ["aa", "ab", "b"] {
+        let entries = (0..n)
+            .map(|i| Entry::default().key(key(prefix, i)).value(value(i)))
+            .collect::<Vec<_>>();
+        for chunk in entries.chunks(100) {
+            for res in kv.batch_set(chunk.to_vec()).await {
+                assert!(res.is_ok());
+            }
+        }
+    }
+    assert!(kv.drop_prefix(vec![b"!badger".to_vec()]).await.is_err());
+    kv.drop_prefix(vec![b"aa".to_vec(), b"b".to_vec()])
+        .await
+        .unwrap();
+    assert!(kv.must_lc().validate().is_ok());
+    for i in (0..n).step_by(7) {
+        assert!(kv.get(&key("aa", i)).await.unwrap_err().is_not_found());
+        assert!(kv.get(&key("b", i)).await.unwrap_err().is_not_found());
+        assert_eq!(kv.get(&key("ab", i)).await.unwrap(), value(i));
+    }
+    // Writes go on after the drop.
+    kv.set(key("aa", 0), b"value".to_vec(), 0).await.unwrap();
+    kv.close().await.unwrap();
+
+    // The dropped keys are not replayed from the value log.
+    let kv = KV::open(get_test_option(&dir)).await.unwrap();
+    assert_eq!(kv.get(&key("aa", 0)).await.unwrap(), b"value");
+    assert!(kv.get(&key("aa", 1)).await.unwrap_err().is_not_found());
+    assert_eq!(kv.get(&key("ab", 1)).await.unwrap(), value(1));
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_drop_all() {
+    use crate::test_util::random_tmp_dir;
+    tracing_log();
+    let dir = random_tmp_dir();
+    let kv = KV::open(get_test_option(&dir)).await.unwrap();
+    let n = 10000;
+    let entries = (0..n)
+        .map(|i| {
+            Entry::default()
+                .key(test_key(i))
+                .value(format!("{:025}", i).into_bytes())
+        })
+        .collect::<Vec<_>>();
+    for chunk in entries.chunks(100) {
+        for res in kv.batch_set(chunk.to_vec()).await {
+            assert!(res.is_ok());
+        }
+    }
+    kv.drop_all().await.unwrap();
+    assert!(kv.must_lc().validate().is_ok());
+    assert!(crate::table::table::get_id_map(&dir).is_empty());
+    for i in (0..n).step_by(7) {
+        assert!(kv.get(&test_key(i)).await.unwrap_err().is_not_found());
+    }
+    kv.set(test_key(1), b"value".to_vec(), 0).await.unwrap();
+    kv.close().await.unwrap();
+
+    let kv = KV::open(get_test_option(&dir)).await.unwrap();
+    assert_eq!(kv.get(&test_key(1)).await.unwrap(), b"value");
+    assert!(kv.get(&test_key(2)).await.unwrap_err().is_not_found());
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_drop_all_value_log_gc() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_log_file_size = 1 << 20;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let value = |i: usize, round: usize| format!("{}{:04095}", round, i).into_bytes();
+    let write = |round: usize| {
+        let kv = kv.clone();
+        async move {
+            let entries = (0..1000)
+                .map(|i| Entry::default().key(test_key(i)).value(value(i, round)))
+                .collect::<Vec<_>>();
+            for chunk in entries.chunks(20) {
+                for res in kv.batch_set(chunk.to_vec()).await {
+                    assert!(res.is_ok());
+                }
+            }
+        }
+    };
+    write(0).await;
+    write(1).await;
+    // The compactions find the first values superseded.
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let max_fid = kv.must_vlog().get_max_fid();
+    assert!((0..max_fid).any(|fid| kv.must_lc().discard_stats().get(fid) > 0));
+    kv.drop_all().await.unwrap();
+    assert!((0..=max_fid).all(|fid| kv.must_lc().discard_stats().get(fid) == 0));
+
+    // The new files reuse the ids, none of their entries is stale.
+    write(2).await;
+    let res = kv.must_vlog().trigger_gc(2.0).await;
+    assert!(matches!(res, Err(Error::ValueNoRewrite)), "{:?}", res);
+    let _ = kv.run_value_log_gc(0.1).await;
+    for i in (0..1000).step_by(7) {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), value(i, 2));
+    }
+    kv.close().await.unwrap();
+
+    let kv = KV::open(opt).await.unwrap();
+    let _ = kv.run_value_log_gc(0.1).await;
+    for i in (0..1000).step_by(7) {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), value(i, 2));
+    }
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_compaction_status() {
+    let kv = build_kv().await;
+    let entries = (0..20000)
+        .map(|i| {
+            Entry::default()
+                .key(format!("key{:06}", i).into_bytes())
+                .value(format!("{:025}", i).into_bytes())
+        })
+        .collect::<Vec<_>>();
+    for chunk in entries.chunks(100) {
+        for res in kv.batch_set(chunk.to_vec()).await {
+            assert!(res.is_ok());
+        }
+    }
+    let status = kv.compaction_status();
+    assert_eq!(status.levels.len(), kv.opt.max_levels);
+    let l0 = &status.levels[0];
+    assert_eq!(
+        l0.score,
+        l0.num_tables as f64 / kv.opt.num_level_zero_tables as f64
+    );
+    for level in status.levels[1..].iter() {
+        assert_eq!(level.score, level.total_size as f64 / level.max_size as f64);
+    }
+    for job in status.jobs.iter() {
+        assert!(job.this_level + 1 == job.next_level || job.this_level == job.next_level);
+        assert!(job.smallest <= job.biggest);
+    }
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_validate_after_compaction() {
+    use crate::test_util::random_tmp_dir;
+    tracing_log();
+    let dir = random_tmp_dir();
+    let mut opt = get_test_option(&dir);
+    opt.validate_after_compaction = true;
+    let kv = KV::open(opt).await.unwrap();
+    let value = |i: usize| format!("{:025}", i).into_bytes();
+    // Overwrites, so compactions merge tables rather than only move them.
+    for round in 0..2 {
+        let entries = (0..10000)
+            .map(|i| Entry::default().key(test_key(i)).value(value(i + round)))
+            .collect::<Vec<_>>();
+        for chunk in entries.chunks(100) {
+            for res in kv.batch_set(chunk.to_vec()).await {
+                assert!(res.is_ok());
+            }
+        }
+    }
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert!(kv.must_lc().validate().is_ok());
+    for i in (0..10000).step_by(13) {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), value(i + 1));
+    }
+    kv.close().await.unwrap();
+}
+
 async fn build_kv() -> KV {
     use crate::test_util::random_tmp_dir;
     tracing_log();
@@ -897,3 +1104,2459 @@ async fn build_kv() -> KV {
     let kv = kv.unwrap();
     kv
 }
+
+#[test]
+fn write_delays() {
+    let max = Duration::from_millis(40);
+    let delays = (0..12)
+        .map(|n| crate::kv::write_delay(n, 8, 10, max).as_millis())
+        .collect::<Vec<_>>();
+    assert_eq!(delays, vec![0, 0, 0, 0, 0, 0, 0, 0, 13, 26, 40, 40]);
+    assert!(crate::kv::write_delay(9, 0, 10, max).is_zero());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_write_throttle() {
+    let kv = build_kv().await;
+    let throttle = kv.write_throttle();
+    assert!(!throttle.stalled);
+    assert!(throttle.delay.is_zero());
+    assert_eq!(throttle.level0_tables, 0);
+    kv.close().await.unwrap();
+}
+
+struct ManualClock(std::sync::atomic::AtomicU64);
+
+impl crate::Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[tokio::test]
+async fn t_kv_expired_values() {
+    use crate::test_util::random_tmp_dir;
+    use crate::ValueStruct;
+    let clock = std::sync::Arc::new(ManualClock(100.into()));
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.clock = clock.clone();
+    let kv = KV::open(opt).await.unwrap();
+    kv.set(b"live".to_vec(), b"a".to_vec(), 0).await.unwrap();
+    kv.must_mt().put(
+        b"ttl",
+        ValueStruct::new(b"b".to_vec(), 0, 0, 1).with_expires_at(200),
+    );
+    assert_eq!(kv.get(b"ttl").await.unwrap(), b"b");
+
+    clock.0.store(200, Ordering::Relaxed);
+    assert!(kv.get(b"ttl").await.unwrap_err().is_not_found());
+    assert_eq!(kv.get(b"live").await.unwrap(), b"a");
+    let itr = kv.new_iterator(IteratorOptions::default()).await;
+    let mut keys = vec![];
+    itr.rewind().await;
+    while let Some(item) = itr.peek().await {
+        keys.push(item.key().await);
+        itr.next().await;
+    }
+    assert_eq!(keys, vec![b"live".to_vec()]);
+    itr.close().await.unwrap();
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_level_iterator() {
+    use crate::Xiterator;
+    let kv = build_kv().await;
+    let entries = (0..10000)
+        .map(|i| {
+            Entry::default()
+                .key(format!("key{:06}", i).into_bytes())
+                .value(format!("{:025}", i).into_bytes())
+        })
+        .collect::<Vec<_>>();
+    for chunk in entries.chunks(100) {
+        for res in kv.batch_set(chunk.to_vec()).await {
+            assert!(res.is_ok());
+        }
+    }
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    assert!(kv.level_iterator(0).is_err());
+    assert!(kv.level_iterator(kv.opt.max_levels).is_err());
+    let mut count = 0;
+    for level in 1..kv.opt.max_levels {
+        let itr = kv.level_iterator(level).unwrap();
+        let mut last = vec![];
+        itr.rewind();
+        while let Some(item) = itr.peek() {
+            assert!(item.key() > last.as_slice(), "level {}", level);
+            last = item.key().to_vec();
+            count += 1;
+            itr.next();
+        }
+        itr.close();
+    }
+    assert!(count > 0);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_tables() {
+    let kv = build_kv().await;
+    let entries = (0..5000)
+        .map(|i| {
+            Entry::default()
+                .key(format!("key{:06}", i).into_bytes())
+                .value(format!("{:025}", i).into_bytes())
+        })
+        .collect::<Vec<_>>();
+    for chunk in entries.chunks(100) {
+        for res in kv.batch_set(chunk.to_vec()).await {
+            assert!(res.is_ok());
+        }
+    }
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let tables = kv.tables(true).unwrap();
+    assert!(!tables.is_empty());
+    assert!(tables
+        .windows(2)
+        .all(|w| (w[0].level, w[0].id) < (w[1].level, w[1].id)));
+    assert!(tables
+        .iter()
+        .all(|tb| tb.smallest <= tb.biggest && tb.size > 0 && tb.key_count > 0));
+    assert!(kv.tables(false).unwrap().iter().all(|tb| tb.key_count == 0));
+    let levels = kv.levels();
+    assert_eq!(levels.len(), kv.opt.max_levels);
+    assert!(levels.iter().enumerate().all(|(i, level)| level.level == i));
+    assert_eq!(
+        levels.iter().filter(|level| level.is_base).count(),
+        1,
+        "{:?}",
+        levels
+    );
+    assert!(levels[1].is_base);
+    assert!(levels.iter().map(|level| level.num_tables).sum::<usize>() > 0);
+    assert!(levels
+        .iter()
+        .all(|level| (level.num_tables == 0) == (level.size == 0)));
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_size() {
+    use crate::test_util::random_tmp_dir;
+    let opt = get_test_option(&random_tmp_dir());
+    let kv = KV::open(opt.clone()).await.unwrap();
+    assert_eq!(kv.size().await.0, 0);
+    for i in 0..2000 {
+        kv.set(format!("key{:06}", i).into_bytes(), vec![1; 100], 0)
+            .await
+            .unwrap();
+    }
+    kv.close().await.unwrap();
+
+    let kv = KV::open(opt).await.unwrap();
+    let (lsm, vlog) = kv.size().await;
+    let tables = kv.tables(false).unwrap();
+    assert!(lsm > 0 && vlog > 0);
+    assert_eq!(lsm, tables.iter().map(|tb| tb.size).sum::<u64>());
+    let all = kv.estimate_size(b"");
+    assert!(all > 0 && all < lsm);
+    // Prorated by block, a narrow prefix is a small part of it.
+    let prefix = kv.estimate_size(b"key0001");
+    assert!(prefix > 0);
+    assert!(prefix * 4 < all);
+    assert_eq!(kv.estimate_size(b"zzz"), 0);
+    assert_eq!(kv.estimate_size(crate::kv::_BADGER_PREFIX), 0);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_metrics() {
+    use crate::test_util::random_tmp_dir;
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    let metrics = kv.metrics();
+    let (gets, latencies) = (
+        metrics.num_gets.get(),
+        metrics.get_latency_seconds.get_sample_count(),
+    );
+    kv.set(b"key".to_vec(), b"value".to_vec(), 0).await.unwrap();
+    assert_eq!(kv.get(b"key").await.unwrap(), b"value");
+    assert!(metrics.num_gets.get() > gets);
+    assert!(metrics.get_latency_seconds.get_sample_count() > latencies);
+    let runs = metrics.num_vlog_gc_runs.get();
+    let _ = kv.run_value_log_gc(0.5).await;
+    assert!(metrics.num_vlog_gc_runs.get() > runs);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_cache_metrics() {
+    use crate::test_util::random_tmp_dir;
+    use crate::CacheMetrics;
+    let mut opt = get_test_option(&random_tmp_dir());
+    let kv = KV::open(opt.clone()).await.unwrap();
+    assert_eq!(kv.cache_metrics(), CacheMetrics::default());
+    for i in 0..2000 {
+        kv.set(format!("key{:06}", i).into_bytes(), vec![1; 100], 0)
+            .await
+            .unwrap();
+    }
+    kv.close().await.unwrap();
+
+    opt.block_cache_size = 1 << 20;
+    opt.index_cache_size = 1 << 20;
+    let kv = KV::open(opt).await.unwrap();
+    for _ in 0..2 {
+        assert_eq!(kv.get(b"key001000").await.unwrap(), vec![1; 100]);
+    }
+    let metrics = kv.cache_metrics();
+    let block = metrics.block_cache.unwrap();
+    assert!(block.hits > 0 && block.misses > 0);
+    assert!(block.used > 0 && block.used <= block.budget);
+    assert!(block.hit_ratio() > 0.0 && block.hit_ratio() < 1.0);
+    let index = metrics.index_cache.unwrap();
+    assert!(index.used > 0 && index.budget == 1 << 20);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_compaction_events() {
+    use crate::test_util::random_tmp_dir;
+    use crate::CompactionEvent;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.compaction_listener = Some(std::sync::Arc::new(tx));
+    let kv = KV::open(opt).await.unwrap();
+    let entries = (0..10000)
+        .map(|i| {
+            Entry::default()
+                .key(format!("key{:06}", i).into_bytes())
+                .value(format!("{:025}", i).into_bytes())
+        })
+        .collect::<Vec<_>>();
+    for chunk in entries.chunks(100) {
+        for res in kv.batch_set(chunk.to_vec()).await {
+            assert!(res.is_ok());
+        }
+    }
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    kv.close().await.unwrap();
+
+    let mut events = vec![];
+    while let Ok(event) = rx.try_recv() {
+        events.push(event);
+    }
+    assert!(events
+        .iter()
+        .any(|ev| matches!(ev, CompactionEvent::Picked { level: 0, .. })));
+    // Every compaction that ended started before.
+    let mut started = HashSet::new();
+    let mut committed = 0;
+    for event in events {
+        match event {
+            CompactionEvent::Started { id, .. } => assert!(started.insert(id)),
+            CompactionEvent::Produced { id, .. } => assert!(started.contains(&id)),
+            CompactionEvent::Committed { id, .. } => {
+                assert!(started.contains(&id));
+                committed += 1;
+            }
+            CompactionEvent::Failed { id, .. } => assert!(started.contains(&id)),
+            CompactionEvent::Picked { .. } => {}
+        }
+    }
+    assert!(committed > 0);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_max_levels() {
+    use crate::test_util::random_tmp_dir;
+    let dir = random_tmp_dir();
+    let mut opt = get_test_option(&dir);
+    opt.max_levels = 3;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let entries = (0..10000)
+        .map(|i| {
+            Entry::default()
+                .key(format!("key{:06}", i).into_bytes())
+                .value(format!("{:025}", i).into_bytes())
+        })
+        .collect::<Vec<_>>();
+    for chunk in entries.chunks(100) {
+        for res in kv.batch_set(chunk.to_vec()).await {
+            assert!(res.is_ok());
+        }
+    }
+    // Wait for the compactions to push tables down to the last level.
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(60);
+    while kv.levels()[2].num_tables == 0 {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "no table reached level 2"
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(kv.compaction_status().levels.len(), 3);
+    let tables = kv.tables(false).unwrap();
+    assert!(tables.iter().all(|tb| tb.level < 3));
+    assert!(tables.iter().any(|tb| tb.level == 2));
+    kv.close().await.unwrap();
+
+    // The tables of level 2 have nowhere to go.
+    opt.max_levels = 2;
+    assert!(KV::open(opt).await.is_err());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_close_with_compactions() {
+    use crate::table::table::get_id_map;
+    use crate::test_util::random_tmp_dir;
+    let dir = random_tmp_dir();
+    let mut opt = get_test_option(&dir);
+    let kv = KV::open(opt.clone()).await.unwrap();
+    for round in 0..2 {
+        let entries = (0..10000)
+            .map(|i| {
+                Entry::default()
+                    .key(test_key(i))
+                    .value(format!("{:025}", i + round).into_bytes())
+            })
+            .collect::<Vec<_>>();
+        for chunk in entries.chunks(100) {
+            for res in kv.batch_set(chunk.to_vec()).await {
+                assert!(res.is_ok());
+            }
+        }
+    }
+    // Compactions are likely running.
+    kv.close().await.unwrap();
+
+    // Aborted compactions left no table behind, and the directory is unlocked.
+    let on_disk = get_id_map(&dir);
+    opt.do_not_compact = true;
+    let kv = KV::open(opt).await.unwrap();
+    let tables = kv.tables(false).unwrap();
+    assert_eq!(
+        on_disk,
+        tables.iter().map(|tb| tb.id).collect::<HashSet<_>>()
+    );
+    for i in (0..10000).step_by(7) {
+        assert_eq!(
+            kv.get(&test_key(i)).await.unwrap(),
+            format!("{:025}", i + 1).into_bytes()
+        );
+    }
+    kv.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn t_kv_value_threshold() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_threshold = crate::MAX_VALUE_THRESHOLD + 1;
+    assert!(matches!(
+        KV::open(opt.clone()).await.err(),
+        Some(Error::ValueThreshold)
+    ));
+
+    opt.value_threshold = 100;
+    let kv = KV::open(opt).await.unwrap();
+    let (small, big) = (vec![1u8; 99], vec![2u8; 100]);
+    kv.set(b"small".to_vec(), small.clone(), 0).await.unwrap();
+    kv.set(b"big".to_vec(), big.clone(), 0).await.unwrap();
+    let pointer = MetaBit::BIT_VALUE_POINTER.bits();
+    let vs = kv._get(b"small").unwrap();
+    assert_eq!((vs.meta & pointer, &vs.value), (0, &small));
+    assert_ne!(kv._get(b"big").unwrap().meta & pointer, 0);
+    assert_eq!(kv.get(b"small").await.unwrap(), small);
+    assert_eq!(kv.get(b"big").await.unwrap(), big);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_vlog_rotation() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_log_file_size = 1 << 20;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let value = |i: usize| format!("{:01024}", i).into_bytes();
+    let entries = (0..3000)
+        .map(|i| Entry::default().key(test_key(i)).value(value(i)))
+        .collect::<Vec<_>>();
+    for chunk in entries.chunks(50) {
+        for res in kv.batch_set(chunk.to_vec()).await {
+            assert!(res.is_ok());
+        }
+    }
+    let max_fid = kv.must_vlog().get_max_fid();
+    assert!(max_fid >= 2, "{}", max_fid);
+    for i in (0..3000).step_by(11) {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), value(i));
+    }
+    kv.close().await.unwrap();
+
+    let kv = KV::open(opt).await.unwrap();
+    assert_eq!(kv.must_vlog().get_max_fid(), max_fid);
+    for i in (0..3000).step_by(11) {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), value(i));
+    }
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_discard_stats() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_log_file_size = 1 << 20;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let value = |i: usize, round: usize| format!("{}{:01023}", round, i).into_bytes();
+    for round in 0..2 {
+        let entries = (0..3000)
+            .map(|i| Entry::default().key(test_key(i)).value(value(i, round)))
+            .collect::<Vec<_>>();
+        for chunk in entries.chunks(50) {
+            for res in kv.batch_set(chunk.to_vec()).await {
+                assert!(res.is_ok());
+            }
+        }
+    }
+    // The compactions find the first values superseded.
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let max_fid = kv.must_vlog().get_max_fid();
+    kv.close().await.unwrap();
+
+    let kv = KV::open(opt).await.unwrap();
+    let discarded = (0..max_fid)
+        .map(|fid| kv.must_lc().discard_stats().get(fid))
+        .sum::<u64>();
+    assert!(discarded > 0);
+    let info = kv.value_log_info().await;
+    assert_eq!(
+        info.iter().map(|file| file.fid).collect::<Vec<_>>(),
+        (0..=max_fid).collect::<Vec<_>>()
+    );
+    assert!(info.last().unwrap().writable);
+    assert_eq!(info.last().unwrap().reclaimable(), 0);
+    assert_eq!(
+        info.iter()
+            .filter(|file| !file.writable)
+            .map(|file| file.reclaimable())
+            .sum::<u64>(),
+        discarded
+    );
+    assert!(info.iter().all(|file| file.punched == 0));
+    assert!(info[0].size > 0);
+    let _ = kv.must_vlog().trigger_gc(0.5).await;
+    for i in (0..3000).step_by(11) {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), value(i, 1));
+    }
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_verify_checksum() {
+    use crate::table::table::new_file_name;
+    use crate::test_util::random_tmp_dir;
+    use std::os::unix::fs::FileExt;
+    let mut opt = get_test_option(&random_tmp_dir());
+    // The entries fill a value log of their own and the replay starts after it, else a
+    // corrupt entry would fail the open.
+    opt.value_log_max_entries = 100;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let fid = kv.must_vlog().get_max_fid();
+    let entries = (0..100)
+        .map(|i| {
+            Entry::default()
+                .key(format!("key{:06}", i).into_bytes())
+                .value(format!("{:0100}", i).into_bytes())
+        })
+        .collect::<Vec<_>>();
+    for res in kv.batch_set(entries).await {
+        assert!(res.is_ok());
+    }
+    kv.close().await.unwrap();
+
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let report = kv.verify_checksum().await.unwrap();
+    assert!(report.is_ok(), "{:?}", report);
+    assert!(report.tables_checked > 0 && report.value_logs_checked > 0);
+    let table = kv.tables(false).unwrap()[0].id;
+    // The head goes to a newer table, the corrupt one can't hide it.
+    kv.set(b"last".to_vec(), format!("{:0100}", 0).into_bytes(), 0)
+        .await
+        .unwrap();
+    kv.close().await.unwrap();
+
+    // Corrupt the first block of the table and the first entry of the value log.
+    let corrupt = |path: String| {
+        let fd = std::fs::OpenOptions::new().write(true).open(path).unwrap();
+        fd.write_at(&[0xff; 4], 20).unwrap();
+    };
+    corrupt(new_file_name(table, &opt.dir));
+    corrupt(format!("{}/{:06}.vlog", opt.value_dir, fid));
+    let kv = KV::open(opt).await.unwrap();
+    let report = kv.verify_checksum().await.unwrap();
+    assert!(!report.is_ok());
+    assert_eq!(report.corrupt_tables.len(), 1);
+    assert_eq!(report.corrupt_tables[0].1, table);
+    assert_eq!(report.corrupt_value_logs, vec![(fid, 0)]);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_vlog_truncate() {
+    use crate::test_util::random_tmp_dir;
+    use std::io::Write;
+    let mut opt = get_test_option(&random_tmp_dir());
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let entries = (0..100)
+        .map(|i| Entry::default().key(test_key(i)).value(test_value(i)))
+        .collect::<Vec<_>>();
+    for res in kv.batch_set(entries).await {
+        assert!(res.is_ok());
+    }
+    let vlog_path = format!("{}/{:06}.vlog", opt.value_dir, kv.must_vlog().get_max_fid());
+    kv.close().await.unwrap();
+    let append = |buf: &[u8]| {
+        let mut fd = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&vlog_path)
+            .unwrap();
+        fd.write_all(buf).unwrap();
+    };
+
+    // Preallocated space left by a crash is no corruption.
+    let size = std::fs::metadata(&vlog_path).unwrap().len();
+    append(&[0u8; 1000]);
+    let kv = KV::open(opt.clone()).await.unwrap();
+    kv.close().await.unwrap();
+    assert_eq!(std::fs::metadata(&vlog_path).unwrap().len(), size);
+
+    append(&[0xffu8; 1000]);
+    let err = KV::open(opt.clone()).await.unwrap_err();
+    assert!(err.to_string().contains("truncate"), "{}", err);
+    opt.truncate = true;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    for i in 0..100 {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), test_value(i));
+    }
+    kv.set(b"after".to_vec(), b"truncate".to_vec(), 0)
+        .await
+        .unwrap();
+    kv.close().await.unwrap();
+    let kv = KV::open(opt).await.unwrap();
+    assert_eq!(kv.get(b"after").await.unwrap(), b"truncate".to_vec());
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_mem_table_wal() {
+    use crate::test_util::random_tmp_dir;
+    // A copy of the files of a running KV is what a crash would leave.
+    let crash_copy = |dir: &str| {
+        let copy = crate::test_util::create_random_tmp_dir();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            if entry.file_type().unwrap().is_file() {
+                std::fs::copy(entry.path(), Path::new(&copy).join(entry.file_name())).unwrap();
+            }
+        }
+        copy
+    };
+    for mem_table_wal in [false, true] {
+        let mut opt = get_test_option(&random_tmp_dir());
+        opt.mem_table_wal = mem_table_wal;
+        let kv = KV::open(opt.clone()).await.unwrap();
+        for i in 0..100 {
+            kv.set(format!("key{}", i).into_bytes(), b"small".to_vec(), 0)
+                .await
+                .unwrap();
+        }
+        kv.set(b"big".to_vec(), vec![1u8; 100], 0).await.unwrap();
+        let copy = crash_copy(&opt.dir);
+        // Sync makes them durable either way, without the WAL by flushing the memtable.
+        kv.sync().await.unwrap();
+        kv.set(b"after".to_vec(), b"small".to_vec(), 0)
+            .await
+            .unwrap();
+        let synced = crash_copy(&opt.dir);
+        kv.close().await.unwrap();
+
+        let mut copy_opt = opt.clone();
+        copy_opt.dir = Box::new(copy.clone());
+        copy_opt.value_dir = Box::new(copy);
+        let kv = KV::open(copy_opt.clone()).await.unwrap();
+        assert_eq!(kv.get(b"big").await.unwrap(), vec![1u8; 100]);
+        assert_eq!(kv.get(b"key7").await.is_ok(), mem_table_wal);
+        kv.close().await.unwrap();
+
+        let mut synced_opt = opt.clone();
+        synced_opt.dir = Box::new(synced.clone());
+        synced_opt.value_dir = Box::new(synced);
+        let kv = KV::open(synced_opt).await.unwrap();
+        for i in 0..100 {
+            assert_eq!(
+                kv.get(format!("key{}", i).as_bytes()).await.unwrap(),
+                b"small".to_vec()
+            );
+        }
+        assert_eq!(kv.get(b"after").await.is_ok(), mem_table_wal);
+        kv.close().await.unwrap();
+        if !mem_table_wal {
+            continue;
+        }
+        // The replayed entries are flushed on close like the others, nothing is left to
+        // replay, and the data is still there without the WAL.
+        copy_opt.mem_table_wal = false;
+        let kv = KV::open(copy_opt).await.unwrap();
+        for i in 0..100 {
+            assert_eq!(
+                kv.get(format!("key{}", i).as_bytes()).await.unwrap(),
+                b"small".to_vec()
+            );
+        }
+        kv.close().await.unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_separate_value_dir() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_dir = Box::new(random_tmp_dir());
+    let files = |dir: &str| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>()
+    };
+    let kv = KV::open(opt.clone()).await.unwrap();
+    for i in 0..100 {
+        kv.set(format!("key{}", i).into_bytes(), vec![i as u8; 100], 0)
+            .await
+            .unwrap();
+    }
+    kv.close().await.unwrap();
+    assert!(files(&opt.dir).iter().any(|f| f == "MANIFEST"));
+    assert!(!files(&opt.dir).iter().any(|f| f.ends_with(".vlog")));
+    assert!(files(&opt.value_dir).iter().any(|f| f.ends_with(".vlog")));
+    assert!(!files(&opt.value_dir).iter().any(|f| f == "MANIFEST"));
+
+    let kv = KV::open(opt.clone()).await.unwrap();
+    // Both directories are locked.
+    let mut other = get_test_option(&random_tmp_dir());
+    other.value_dir = opt.value_dir.clone();
+    assert!(KV::open(other).await.is_err());
+    for i in 0..100 {
+        assert_eq!(
+            kv.get(format!("key{}", i).as_bytes()).await.unwrap(),
+            vec![i as u8; 100]
+        );
+    }
+    kv.close().await.unwrap();
+
+    // The value log must not be left behind when the value dir changes.
+    std::fs::copy(
+        format!("{}/000000.vlog", opt.value_dir),
+        format!("{}/000000.vlog", opt.dir),
+    )
+    .unwrap();
+    opt.value_dir = Box::new(random_tmp_dir());
+    let err = KV::open(opt).await.unwrap_err();
+    assert!(err.to_string().contains("Value log files found"), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_group_commit() {
+    use crate::test_util::random_tmp_dir;
+    for mem_table_wal in [false, true] {
+        let mut opt = get_test_option(&random_tmp_dir());
+        opt.sync_writes = true;
+        opt.mem_table_wal = mem_table_wal;
+        let kv = KV::open(opt.clone()).await.unwrap();
+        // Concurrent writers, their requests are written and synced together.
+        let mut handles = vec![];
+        for w in 0..16 {
+            let kv = kv.clone();
+            handles.push(tokio::spawn(async move {
+                for i in 0..50 {
+                    let key = format!("w{:02}k{:03}", w, i).into_bytes();
+                    kv.set(key, format!("{}", i).into_bytes(), 0).await.unwrap();
+                }
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        kv.close().await.unwrap();
+
+        let kv = KV::open(opt).await.unwrap();
+        for w in 0..16 {
+            for i in (0..50).step_by(7) {
+                let key = format!("w{:02}k{:03}", w, i).into_bytes();
+                assert_eq!(kv.get(&key).await.unwrap(), format!("{}", i).into_bytes());
+            }
+        }
+        kv.close().await.unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_value_log_max_entries() {
+    use crate::test_util::random_tmp_dir;
+    use crate::value_log::{ValueLogReader, ValuePointer};
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_log_max_entries = 10;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    write_test_entries(&kv, 0..48, 4).await;
+    kv.close().await.unwrap();
+    // The entries of the last file are counted again on open.
+    let kv = KV::open(opt.clone()).await.unwrap();
+    write_test_entries(&kv, 48..100, 4).await;
+    for i in 0..100 {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), test_value(i));
+    }
+    kv.close().await.unwrap();
+
+    let mut reader = ValueLogReader::open(&opt.value_dir).unwrap();
+    let counts = TArcMx::new(tokio::sync::Mutex::new(std::collections::HashMap::new()));
+    let xcounts = counts.clone();
+    reader
+        .replay(&ValuePointer::default(), |_, vp| {
+            let counts = xcounts.clone();
+            let fid = vp.get_fid();
+            Box::pin(async move {
+                *counts.lock().await.entry(fid).or_insert(0) += 1;
+                Ok(true)
+            })
+        })
+        .await
+        .unwrap();
+    let counts = counts.lock().await;
+    // A file is rotated after the batch reaching the limit, every 3 batches of 4 entries.
+    assert_eq!(counts.values().sum::<usize>(), 100);
+    assert!(counts.len() >= 9, "{:?}", counts);
+    assert!(counts.values().all(|n| *n < 10 + 4), "{:?}", counts);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_value_log_format() {
+    use crate::log_file::LogFile;
+    use crate::options::ValueLogFormat;
+    use crate::test_util::random_tmp_dir;
+    use crate::value_log::{ValueLogReader, ValuePointer};
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_log_format = ValueLogFormat::GoLegacy;
+    opt.value_log_max_entries = 10;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    write_test_entries(&kv, 0..12, 1).await;
+    kv.close().await.unwrap();
+
+    // The files in the legacy Go format are still read, and the last one appended, in it.
+    opt.value_log_format = ValueLogFormat::Native;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    write_test_entries(&kv, 12..30, 1).await;
+    for i in 0..30 {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), test_value(i));
+    }
+    kv.close().await.unwrap();
+
+    let mut reader = ValueLogReader::open(&opt.value_dir).unwrap();
+    let fids = reader.fids();
+    assert!(fids.len() >= 3, "{:?}", fids);
+    let format = |fid: u32| {
+        let path = format!("{}/{:06}.vlog", opt.value_dir, fid);
+        LogFile::new(&path).unwrap().detect_format().unwrap()
+    };
+    assert_eq!(format(0), Some(ValueLogFormat::GoLegacy));
+    assert_eq!(format(1), Some(ValueLogFormat::GoLegacy));
+    // The last file may be new, with no entry yet.
+    let last = fids.iter().rev().find_map(|fid| format(*fid));
+    assert_eq!(last, Some(ValueLogFormat::Native));
+    let count = TArcMx::new(tokio::sync::Mutex::new(0));
+    let xcount = count.clone();
+    reader
+        .replay(&ValuePointer::default(), |entry, _| {
+            let count = xcount.clone();
+            let user = entry.get_key().starts_with(b"key");
+            Box::pin(async move {
+                *count.lock().await += user as usize;
+                Ok(true)
+            })
+        })
+        .await
+        .unwrap();
+    assert_eq!(*count.lock().await, 30);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_value_log_gc_move() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_log_max_entries = 20;
+    let kv = KV::open(opt).await.unwrap();
+    for i in 0..40 {
+        kv.set(test_key(i), test_value(i), 0).await.unwrap();
+    }
+    // Its value in the first file is stale.
+    kv.set(test_key(1), test_value(1000), 0).await.unwrap();
+    // Read before the GC, the value pointer is into the first file.
+    let item = kv.get_with_ext(&test_key(0)).await.unwrap();
+    let cas = item.counter().await;
+
+    let vlog = kv.must_vlog();
+    let lf = vlog.pick_log_by_vlog_id(&0).await;
+    vlog.rewrite(lf, &kv).await.unwrap();
+    assert!(kv.value_log_info().await.iter().all(|info| info.fid != 0));
+
+    // The value is found again where it was moved to.
+    assert_eq!(item.value().await.unwrap(), test_value(0));
+    for i in 0..40 {
+        let want = if i == 1 {
+            test_value(1000)
+        } else {
+            test_value(i)
+        };
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), want);
+    }
+    // The moved value keeps its CAS counter.
+    let moved = kv.get_with_ext(&test_key(0)).await.unwrap();
+    assert_eq!(moved.counter().await, cas);
+    kv.compare_and_set(test_key(0), test_value(2000), cas)
+        .await
+        .unwrap();
+    assert_eq!(kv.get(&test_key(0)).await.unwrap(), test_value(2000));
+    // Superseded since, the item's value is gone.
+    assert!(matches!(item.value().await, Err(Error::ValueRetry)));
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_value_log_retire() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_log_max_entries = 20;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    for i in 0..40 {
+        kv.set(test_key(i), test_value(i), 0).await.unwrap();
+    }
+    let itr = kv
+        .new_iterator(IteratorOptions::new(false, 10, false))
+        .await;
+    itr.rewind().await;
+    let mut items = vec![];
+    while let Some(item) = itr.peek().await {
+        items.push(item);
+        itr.next().await;
+    }
+    assert_eq!(items.len(), 40);
+
+    // The file is retired, but the iterator holds it.
+    let vlog = kv.must_vlog();
+    let lf = vlog.pick_log_by_vlog_id(&0).await;
+    vlog.rewrite(lf, &kv).await.unwrap();
+    assert!(kv.value_log_info().await.iter().all(|info| info.fid != 0));
+    let path = format!("{}/{:06}.vlog", opt.value_dir, 0);
+    assert!(Path::new(&path).exists());
+    for (i, item) in items.iter().enumerate() {
+        assert_eq!(item.value().await.unwrap(), test_value(i));
+    }
+
+    // Deleted once the iterator lets it go.
+    itr.close().await.unwrap();
+    drop(items);
+    assert!(!Path::new(&path).exists());
+    for i in 0..40 {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), test_value(i));
+    }
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_value_reader() {
+    use crate::test_util::random_tmp_dir;
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    let blob = (0..4 << 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    kv.set(b"blob".to_vec(), blob.clone(), 0).await.unwrap();
+    kv.set(b"small".to_vec(), b"value".to_vec(), 0)
+        .await
+        .unwrap();
+
+    let item = kv.get_with_ext(b"blob").await.unwrap();
+    let mut rd = item.value_reader().await.unwrap();
+    assert_eq!(rd.remaining(), blob.len() as u64);
+    let mut got = vec![];
+    let mut chunk = vec![0u8; 64 << 10];
+    loop {
+        let n = rd.read(&mut chunk).unwrap();
+        if n == 0 {
+            break;
+        }
+        got.extend_from_slice(&chunk[..n]);
+    }
+    assert_eq!(rd.remaining(), 0);
+    assert!(got == blob);
+
+    let item = kv.get_with_ext(b"small").await.unwrap();
+    let mut got = vec![];
+    item.value_reader()
+        .await
+        .unwrap()
+        .read_to_end(&mut got)
+        .unwrap();
+    assert_eq!(got, b"value");
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_active_value_log_file_io() {
+    use crate::options::FileLoadingMode;
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.active_value_log_loading_mode = FileLoadingMode::FileIO;
+    opt.value_log_max_entries = 10;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    for i in 0..35 {
+        kv.set(test_key(i), test_value(i), 0).await.unwrap();
+        // Read back from the file written to, with pread.
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), test_value(i));
+    }
+    // Nothing is preallocated.
+    let info = kv.value_log_info().await;
+    let active = info.iter().find(|info| info.writable).unwrap();
+    let path = format!("{}/{:06}.vlog", opt.value_dir, active.fid);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), active.size);
+    kv.close().await.unwrap();
+
+    // The files are the same whatever the mode.
+    opt.active_value_log_loading_mode = FileLoadingMode::MemoryMap;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    for i in 35..50 {
+        kv.set(test_key(i), test_value(i), 0).await.unwrap();
+    }
+    for i in 0..50 {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), test_value(i));
+    }
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_value_log_gc_background() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_log_max_entries = 20;
+    opt.value_log_gc_interval = Some(Duration::from_millis(10));
+    opt.value_log_gc_discard_ratio = 1.0;
+    assert!(KV::open(opt.clone()).await.is_err());
+
+    opt.value_log_gc_discard_ratio = 0.1;
+    let value = |i: usize, v: usize| format!("{:0100}", i * 10 + v).into_bytes();
+    let kv = KV::open(opt.clone()).await.unwrap();
+    kv.pause_background_gc();
+    for v in 0..3 {
+        for i in 0..100 {
+            kv.set(test_key(i), value(i, v), 0).await.unwrap();
+        }
+    }
+    let info = kv.value_log_info().await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // Nothing is rewritten while paused.
+    assert_eq!(kv.value_log_info().await.len(), info.len());
+
+    kv.resume_background_gc();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    for i in 0..100 {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), value(i, 2));
+    }
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_head_pointer() {
+    use crate::kv::_HEAD;
+    use crate::test_util::random_tmp_dir;
+    use crate::value_log::ValuePointer;
+    use crate::y::Decode;
+    use std::io::Cursor;
+    let opt = get_test_option(&random_tmp_dir());
+    let head = |kv: &KV| {
+        let mut vptr = ValuePointer::default();
+        vptr.dec(&mut Cursor::new(kv._get(_HEAD).unwrap().value))
+            .unwrap();
+        vptr
+    };
+    let kv = KV::open(opt.clone()).await.unwrap();
+    // Several memtables are flushed.
+    for i in 0..1000 {
+        kv.set(test_key(i), test_value(i), 0).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!head(&kv).is_zero());
+    kv.close().await.unwrap();
+
+    // The last memtable is flushed on close, the head is at the end of the value log.
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let vptr = head(&kv);
+    let info = kv.value_log_info().await;
+    let active = info.iter().find(|info| info.writable).unwrap();
+    assert_eq!(vptr.fid, active.fid);
+    assert_eq!((vptr.offset + vptr.len) as u64, active.size);
+    for i in 0..1000 {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), test_value(i));
+    }
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_db_open_close() {
+    use crate::kv::DB;
+    use crate::test_util::random_tmp_dir;
+    let opt = get_test_option(&random_tmp_dir());
+    let db = DB::open(opt.clone()).await.unwrap();
+    db.set(b"foo".to_vec(), b"bar".to_vec(), 0).await.unwrap();
+    db.close().await.unwrap();
+    let db = DB::open(opt).await.unwrap();
+    assert_eq!(db.get(b"foo").await.unwrap(), b"bar".to_vec());
+    db.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_options_builder() {
+    use crate::test_util::random_tmp_dir;
+    let dir = random_tmp_dir();
+    let bad = Options::builder(&dir)
+        .max_table_size(1 << 10)
+        .value_threshold(1 << 10)
+        .build();
+    assert!(bad.unwrap_err().to_string().contains("value_threshold"));
+    assert!(Options::builder(&dir).max_levels(1).build().is_err());
+    assert!(Options::builder(&dir)
+        .level_zero_tables(5, 5)
+        .build()
+        .is_err());
+    // Nothing is created for invalid options.
+    let mut opt = Options::builder(&dir).build().unwrap();
+    opt.num_mem_tables = 0;
+    assert!(KV::open(opt).await.is_err());
+    assert!(!Path::new(&dir).exists());
+
+    let opt = Options::builder(&dir)
+        .max_table_size(1 << 15)
+        .sync_writes(true)
+        .build()
+        .unwrap();
+    assert_eq!(opt.value_dir.as_str(), dir.as_str());
+    let kv = KV::open(opt).await.unwrap();
+    kv.set(b"foo".to_vec(), b"bar".to_vec(), 0).await.unwrap();
+    assert_eq!(kv.get(b"foo").await.unwrap(), b"bar".to_vec());
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_get_item() {
+    use crate::test_util::random_tmp_dir;
+    let opt = get_test_option(&random_tmp_dir());
+    let kv = KV::open(opt).await.unwrap();
+    let big = vec![7u8; 1 << 10];
+    kv.set(b"small".to_vec(), b"v".to_vec(), 1).await.unwrap();
+    kv.set(b"big".to_vec(), big.clone(), 2).await.unwrap();
+    let small = kv.get_item(b"small").await.unwrap();
+    assert_eq!(small.value().await.unwrap(), b"v".to_vec());
+    assert_eq!(small.user_meta().await, 1);
+    let item = kv.get_item(b"big").await.unwrap();
+    assert_eq!(item.key().await, b"big".to_vec());
+    assert_eq!(item.value().await.unwrap(), big);
+    assert_eq!(item.user_meta().await, 2);
+    assert!(item.counter().await > small.counter().await);
+
+    kv.set(b"small".to_vec(), b"w".to_vec(), 1).await.unwrap();
+    assert!(kv.get_item(b"small").await.unwrap().counter().await > small.counter().await);
+    kv.delete(b"small").await.unwrap();
+    assert!(kv.get_item(b"small").await.unwrap_err().is_not_found());
+    assert!(kv.get_item(b"none").await.unwrap_err().is_not_found());
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_write_batch() {
+    use crate::test_util::random_tmp_dir;
+    let opt = get_test_option(&random_tmp_dir());
+    let kv = KV::open(opt).await.unwrap();
+    // Many chunks.
+    let mut wb = kv.new_write_batch();
+    for i in 0..2000 {
+        wb.set(test_key(i), test_value(i), 0).await.unwrap();
+    }
+    for i in (0..2000).step_by(2) {
+        wb.delete(test_key(i)).await.unwrap();
+    }
+    wb.flush().await.unwrap();
+    for i in 0..2000 {
+        let got = kv.get(&test_key(i)).await;
+        if i % 2 == 0 {
+            assert!(got.unwrap_err().is_not_found());
+        } else {
+            assert_eq!(got.unwrap(), test_value(i));
+        }
+    }
+
+    // The entries not written yet are dropped.
+    let mut wb = kv.new_write_batch();
+    wb.set(b"cancelled".to_vec(), b"v".to_vec(), 0)
+        .await
+        .unwrap();
+    wb.cancel().await;
+    assert!(matches!(
+        wb.set(b"cancelled".to_vec(), b"v".to_vec(), 0).await,
+        Err(Error::WriteBatchCancelled)
+    ));
+    assert!(wb.flush().await.is_err());
+    assert!(!kv.exists(b"cancelled").await.unwrap());
+
+    // A failed chunk fails the batch.
+    let mut wb = kv.new_write_batch();
+    wb.set(vec![0; MAX_KEY_SIZE + 1], b"v".to_vec(), 0)
+        .await
+        .unwrap();
+    assert!(wb.flush().await.is_err());
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_txn() {
+    use crate::test_util::random_tmp_dir;
+    let opt = get_test_option(&random_tmp_dir());
+    let kv = KV::open(opt).await.unwrap();
+    kv.set(b"a".to_vec(), b"1".to_vec(), 0).await.unwrap();
+
+    // The txn reads its own writes, the others don't see them before the commit.
+    let mut txn = kv.new_transaction(true).unwrap();
+    txn.set(b"b".to_vec(), b"2".to_vec(), 0).unwrap();
+    txn.delete(b"a".to_vec()).unwrap();
+    assert_eq!(txn.get(b"b").await.unwrap(), b"2".to_vec());
+    assert!(txn.get(b"a").await.unwrap_err().is_not_found());
+    assert_eq!(kv.get(b"a").await.unwrap(), b"1".to_vec());
+    assert!(!kv.exists(b"b").await.unwrap());
+    txn.commit().await.unwrap();
+    assert!(!kv.exists(b"a").await.unwrap());
+    assert_eq!(kv.get(b"b").await.unwrap(), b"2".to_vec());
+
+    // Both read b, the second commit conflicts with the first.
+    let mut t1 = kv.new_transaction(true).unwrap();
+    let mut t2 = kv.new_transaction(true).unwrap();
+    assert_eq!(t1.get(b"b").await.unwrap(), b"2".to_vec());
+    assert_eq!(t2.get(b"b").await.unwrap(), b"2".to_vec());
+    t1.set(b"b".to_vec(), b"3".to_vec(), 0).unwrap();
+    t2.set(b"b".to_vec(), b"4".to_vec(), 0).unwrap();
+    t1.commit().await.unwrap();
+    assert!(matches!(t2.commit().await, Err(Error::TxnConflict)));
+    assert_eq!(kv.get(b"b").await.unwrap(), b"3".to_vec());
+
+    // Blind writes and disjoint reads don't conflict.
+    let mut t1 = kv.new_transaction(true).unwrap();
+    let mut t2 = kv.new_transaction(true).unwrap();
+    t1.get(b"c").await.unwrap_err();
+    t1.set(b"d".to_vec(), b"1".to_vec(), 0).unwrap();
+    t2.set(b"c".to_vec(), b"1".to_vec(), 0).unwrap();
+    t1.get(b"b").await.unwrap();
+    let mut t3 = kv.new_transaction(true).unwrap();
+    t3.set(b"b".to_vec(), b"5".to_vec(), 0).unwrap();
+    t3.commit().await.unwrap();
+    // t1 read b before t3 wrote it.
+    assert!(matches!(t1.commit().await, Err(Error::TxnConflict)));
+    t2.commit().await.unwrap();
+
+    // A txn started after a commit doesn't conflict with it.
+    let mut txn = kv.new_transaction(true).unwrap();
+    assert_eq!(txn.get(b"b").await.unwrap(), b"5".to_vec());
+    txn.set(b"b".to_vec(), b"6".to_vec(), 0).unwrap();
+    txn.commit().await.unwrap();
+
+    // The commits are written concurrently, the read ts covers all of them once done.
+    let start = kv.new_transaction(false).unwrap().read_ts();
+    let mut handles = vec![];
+    for i in 0..20 {
+        let kv = kv.clone();
+        handles.push(tokio::spawn(async move {
+            let mut txn = kv.new_transaction(true).unwrap();
+            txn.set(format!("p{}", i).into_bytes(), b"1".to_vec(), 0)
+                .unwrap();
+            txn.commit().await.unwrap();
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    assert_eq!(kv.new_transaction(false).unwrap().read_ts(), start + 20);
+    assert_eq!(kv.get(b"p7").await.unwrap(), b"1".to_vec());
+
+    let mut ro = kv.new_transaction(false).unwrap();
+    assert!(matches!(
+        ro.set(b"x".to_vec(), b"1".to_vec(), 0),
+        Err(Error::ReadOnlyTxn)
+    ));
+    ro.discard();
+    assert!(matches!(ro.get(b"b").await, Err(Error::DiscardedTxn)));
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_managed_txn() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.managed_txns = true;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let big = |v: u8| vec![v; 1 << 10];
+    for ts in [10, 20, 30] {
+        let mut txn = kv.new_transaction_at(ts - 1, true).unwrap();
+        txn.set(b"a".to_vec(), format!("{}", ts).into_bytes(), 0)
+            .unwrap();
+        txn.set(b"big".to_vec(), big(ts as u8), 0).unwrap();
+        txn.commit_at(ts).await.unwrap();
+    }
+    let mut txn = kv.new_transaction_at(39, true).unwrap();
+    txn.delete(b"a".to_vec()).unwrap();
+    txn.commit_at(40).await.unwrap();
+
+    let check = |kv: KV| async move {
+        for (read_ts, want) in [(5, None), (10, Some(10)), (25, Some(20)), (35, Some(30))] {
+            let txn = kv.new_transaction_at(read_ts, false).unwrap();
+            match want {
+                None => assert!(txn.get(b"a").await.unwrap_err().is_not_found()),
+                Some(ts) => {
+                    assert_eq!(txn.get(b"a").await.unwrap(), format!("{}", ts).into_bytes());
+                    assert_eq!(txn.get(b"big").await.unwrap(), big(ts as u8));
+                }
+            }
+        }
+        let txn = kv.new_transaction_at(45, false).unwrap();
+        assert!(txn.get(b"a").await.unwrap_err().is_not_found());
+    };
+    check(kv.clone()).await;
+
+    // A commit at a ts after the read ts of a reader of the key conflicts.
+    let mut t1 = kv.new_transaction_at(50, true).unwrap();
+    let mut t2 = kv.new_transaction_at(50, true).unwrap();
+    t1.get(b"big").await.unwrap();
+    t2.get(b"big").await.unwrap();
+    t1.set(b"big".to_vec(), big(1), 0).unwrap();
+    t2.set(b"big".to_vec(), big(2), 0).unwrap();
+    t1.commit_at(51).await.unwrap();
+    assert!(matches!(t2.commit_at(52).await, Err(Error::TxnConflict)));
+    assert!(kv
+        .new_transaction_at(60, true)
+        .unwrap()
+        .commit()
+        .await
+        .is_err());
+    kv.close().await.unwrap();
+
+    // The versions are in the tables after a restart.
+    let kv = KV::open(opt).await.unwrap();
+    check(kv.clone()).await;
+    let txn = kv.new_transaction_at(60, false).unwrap();
+    assert_eq!(txn.get(b"big").await.unwrap(), big(1));
+    drop(txn);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_txn_without_conflicts() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.detect_conflicts = false;
+    let kv = KV::open(opt).await.unwrap();
+    kv.set(b"b".to_vec(), b"2".to_vec(), 0).await.unwrap();
+    let mut t1 = kv.new_transaction(true).unwrap();
+    let mut t2 = kv.new_transaction(true).unwrap();
+    assert_eq!(t1.get(b"b").await.unwrap(), b"2".to_vec());
+    assert_eq!(t2.get(b"b").await.unwrap(), b"2".to_vec());
+    t1.set(b"b".to_vec(), b"3".to_vec(), 0).unwrap();
+    t2.set(b"b".to_vec(), b"4".to_vec(), 0).unwrap();
+    t1.commit().await.unwrap();
+    t2.commit().await.unwrap();
+    assert_eq!(kv.get(b"b").await.unwrap(), b"4".to_vec());
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_txn_too_big() {
+    use crate::test_util::random_tmp_dir;
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    assert!(kv.max_batch_count() > 0 && kv.max_batch_size() > 0);
+    let mut txn = kv.new_transaction(true).unwrap();
+    let mut written = 0;
+    let err = loop {
+        match txn.set(format!("key{:06}", written).into_bytes(), vec![1; 10], 0) {
+            Ok(()) => written += 1,
+            Err(err) => break err,
+        }
+    };
+    assert!(matches!(err, Error::TxnTooBig));
+    assert!(written > 0 && written <= kv.max_batch_count());
+    // A key set again doesn't count twice.
+    txn.set(b"key000000".to_vec(), vec![2; 10], 0).unwrap();
+    txn.commit().await.unwrap();
+    assert_eq!(kv.get(b"key000000").await.unwrap(), vec![2; 10]);
+    let last = format!("key{:06}", written - 1).into_bytes();
+    assert_eq!(kv.get(&last).await.unwrap(), vec![1; 10]);
+    assert!(kv
+        .get(format!("key{:06}", written).as_bytes())
+        .await
+        .unwrap_err()
+        .is_not_found());
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_snapshot() {
+    use crate::test_util::random_tmp_dir;
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    let mut txn = kv.new_transaction(true).unwrap();
+    txn.set(b"a".to_vec(), b"1".to_vec(), 0).unwrap();
+    txn.commit().await.unwrap();
+    // The values aren't versioned, a snapshot couldn't keep the ones it reads.
+    assert!(matches!(kv.snapshot(), Err(Error::NotManagedTxns)));
+    assert!(matches!(
+        kv.new_transaction_at(1, false),
+        Err(Error::NotManagedTxns)
+    ));
+    assert!(matches!(kv.set_discard_ts(1), Err(Error::NotManagedTxns)));
+    assert_eq!(
+        kv.must_lc().compaction_discard_ts(),
+        kv.must_lc().discard_ts()
+    );
+    kv.close().await.unwrap();
+
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.managed_txns = true;
+    let kv = KV::open(opt).await.unwrap();
+    assert!(matches!(kv.new_transaction(false), Err(Error::ManagedTxns)));
+    let mut txn = kv.new_transaction_at(1, true).unwrap();
+    txn.set(b"a".to_vec(), b"1".to_vec(), 0).unwrap();
+    txn.commit_at(10).await.unwrap();
+    let snap = kv.snapshot().unwrap();
+    assert_eq!(snap.read_ts(), 10);
+
+    let mut txn = kv.new_transaction_at(10, true).unwrap();
+    txn.set(b"a".to_vec(), b"2".to_vec(), 0).unwrap();
+    txn.set(b"b".to_vec(), b"2".to_vec(), 0).unwrap();
+    txn.commit_at(20).await.unwrap();
+    // The compactions keep the versions the snapshot reads.
+    kv.set_discard_ts(20).unwrap();
+    assert_eq!(kv.must_lc().compaction_discard_ts(), 10);
+    assert_eq!(snap.get(b"a").await.unwrap(), b"1".to_vec());
+    assert!(snap.get(b"b").await.unwrap_err().is_not_found());
+    let mut itr = snap.iter(IteratorOptions::default()).await.unwrap();
+    let mut got = vec![];
+    let mut item = itr.rewind().await;
+    while let Some(el) = item {
+        got.push((el.key().await, el.value().await.unwrap()));
+        item = itr.next().await;
+    }
+    itr.close().await.unwrap();
+    assert_eq!(got, vec![(b"a".to_vec(), b"1".to_vec())]);
+    assert_eq!(
+        kv.snapshot().unwrap().get(b"b").await.unwrap(),
+        b"2".to_vec()
+    );
+    drop(snap);
+    assert_eq!(kv.must_lc().compaction_discard_ts(), 20);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_item_versions() {
+    use crate::test_util::random_tmp_dir;
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    kv.set(b"k".to_vec(), b"v".to_vec(), 0).await.unwrap();
+    let item = kv.get_item(b"k").await.unwrap();
+    assert_eq!(item.version().await, item.counter().await);
+    kv.close().await.unwrap();
+
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.managed_txns = true;
+    let kv = KV::open(opt).await.unwrap();
+    for ts in [10, 20, 30] {
+        let mut txn = kv.new_transaction_at(ts - 1, true).unwrap();
+        let mut entry = Entry::default().key(b"k".to_vec()).value(b"v".to_vec());
+        if ts == 20 {
+            entry = entry.with_discard();
+        }
+        txn.set_entry(entry).unwrap();
+        txn.commit_at(ts).await.unwrap();
+    }
+    let txn = kv.new_transaction_at(30, false).unwrap();
+    let mut itr = txn
+        .iter(IteratorOptions::default().with_all_versions(true))
+        .await
+        .unwrap();
+    let mut got = vec![];
+    let mut item = itr.rewind().await;
+    while let Some(el) = item {
+        got.push((el.version().await, el.discard_earlier_versions().await));
+        item = itr.next().await;
+    }
+    itr.close().await.unwrap();
+    assert_eq!(got, vec![(30, false), (20, true), (10, false)]);
+    drop(txn);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_num_versions_to_keep() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.managed_txns = true;
+    opt.num_versions_to_keep = 2;
+    let kv = KV::open(opt).await.unwrap();
+    for ts in [10, 20, 30, 40, 50] {
+        let mut txn = kv.new_transaction_at(ts - 1, true).unwrap();
+        txn.set(b"k".to_vec(), format!("{}", ts).into_bytes(), 0)
+            .unwrap();
+        txn.commit_at(ts).await.unwrap();
+    }
+    let versions = || async {
+        let txn = kv.new_transaction_at(50, false).unwrap();
+        let mut itr = txn
+            .iter(IteratorOptions::default().with_all_versions(true))
+            .await
+            .unwrap();
+        let mut got = vec![];
+        let mut item = itr.rewind().await;
+        while let Some(el) = item {
+            got.push(el.version().await);
+            item = itr.next().await;
+        }
+        itr.close().await.unwrap();
+        got
+    };
+    assert_eq!(versions().await, vec![50, 40, 30, 20, 10]);
+    kv.set_discard_ts(40).unwrap();
+    assert_eq!(versions().await, vec![50, 40, 30]);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_txn_iterator() {
+    use crate::test_util::random_tmp_dir;
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    for i in 0..10 {
+        let key = format!("a{}", i).into_bytes();
+        kv.set(key, vec![i; 1 << 10], 0).await.unwrap();
+    }
+    kv.set(b"b0".to_vec(), b"b".to_vec(), 0).await.unwrap();
+    let mut txn = kv.new_transaction(true).unwrap();
+    txn.set(b"a5".to_vec(), b"new".to_vec(), 0).unwrap();
+    txn.set(b"a55".to_vec(), b"pending".to_vec(), 0).unwrap();
+    txn.delete(b"a3".to_vec()).unwrap();
+    let collect = |opt: IteratorOptions| {
+        let txn = &txn;
+        async move {
+            let mut itr = txn.iter(opt).await.unwrap();
+            let mut got = vec![];
+            let mut item = itr.rewind().await;
+            while let Some(el) = item {
+                got.push((el.key().await, el.value().await.unwrap()));
+                item = itr.next().await;
+            }
+            itr.close().await.unwrap();
+            got
+        }
+    };
+    let got = collect(IteratorOptions::default().with_prefix(b"a")).await;
+    let keys = got.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+    let want = ["a0", "a1", "a2", "a4", "a5", "a55", "a6", "a7", "a8", "a9"];
+    assert_eq!(keys, want.map(|k| k.as_bytes().to_vec()));
+    assert_eq!(got[4].1, b"new");
+    assert_eq!(got[5].1, b"pending");
+    assert_eq!(got[9].1, vec![9; 1 << 10]);
+
+    let got = collect(IteratorOptions::new(false, 0, true).with_prefix(b"a")).await;
+    let keys = got.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    let mut want = want.map(|k| k.as_bytes().to_vec()).to_vec();
+    want.reverse();
+    assert_eq!(keys, want);
+    assert_eq!(collect(IteratorOptions::default()).await.len(), 11);
+    drop(txn);
+    kv.close().await.unwrap();
+
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.managed_txns = true;
+    let kv = KV::open(opt).await.unwrap();
+    for ts in [10, 20, 30] {
+        let mut txn = kv.new_transaction_at(ts - 1, true).unwrap();
+        txn.set(b"k".to_vec(), format!("{}", ts).into_bytes(), 0)
+            .unwrap();
+        txn.commit_at(ts).await.unwrap();
+    }
+    let txn = kv.new_transaction_at(25, false).unwrap();
+    let versions = |opt: IteratorOptions| {
+        let txn = &txn;
+        async move {
+            let mut itr = txn.iter(opt).await.unwrap();
+            let mut got = vec![];
+            let mut item = itr.rewind().await;
+            while let Some(el) = item {
+                got.push(el.value().await.unwrap());
+                item = itr.next().await;
+            }
+            itr.close().await.unwrap();
+            got
+        }
+    };
+    assert_eq!(
+        versions(IteratorOptions::default()).await,
+        vec![b"20".to_vec()]
+    );
+    let all = IteratorOptions::default().with_all_versions(true);
+    assert_eq!(
+        versions(all.clone()).await,
+        vec![b"20".to_vec(), b"10".to_vec()]
+    );
+    assert_eq!(versions(all.with_since_ts(15)).await, vec![b"20".to_vec()]);
+    assert!(versions(IteratorOptions::default().with_since_ts(21))
+        .await
+        .is_empty());
+    drop(txn);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_keys_only_iterator() {
+    use crate::test_util::random_tmp_dir;
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    let n = 100;
+    for i in 0..n {
+        let key = format!("key{:03}", i).into_bytes();
+        kv.set(key, vec![i as u8; 1 << 10], 0).await.unwrap();
+    }
+    let opt = IteratorOptions::default().with_pre_fetch_values(false);
+    let itr = kv.new_iterator(opt.clone()).await;
+    let (mut count, mut size) = (0, 0);
+    let mut item = itr.rewind().await;
+    while let Some(el) = item {
+        assert!(!el.rl().await.is_prefetched());
+        size += el.estimated_size().await;
+        count += 1;
+        item = itr.next().await;
+    }
+    assert_eq!(count, n);
+    assert!(size >= (n << 10) as u64);
+    // The value is read when asked for.
+    let el = itr.seek(b"key042").await.unwrap();
+    assert_eq!(el.value().await.unwrap(), vec![42; 1 << 10]);
+    itr.close().await.unwrap();
+
+    let txn = kv.new_transaction(false).unwrap();
+    let mut itr = txn.iter(opt.with_prefix(b"key04")).await.unwrap();
+    let mut item = itr.rewind().await;
+    let mut keys = vec![];
+    while let Some(el) = item {
+        assert!(!el.rl().await.is_prefetched());
+        keys.push(el.key().await);
+        item = itr.next().await;
+    }
+    assert_eq!(keys.len(), 10);
+    let el = itr.seek(b"key045").await.unwrap();
+    assert_eq!(el.value().await.unwrap(), vec![45; 1 << 10]);
+    itr.close().await.unwrap();
+    drop(txn);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_set_with_ttl() {
+    use crate::test_util::random_tmp_dir;
+    use crate::Clock;
+    let clock = std::sync::Arc::new(ManualClock(1000.into()));
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.clock = clock.clone();
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let big = vec![7; 1 << 10];
+    kv.set_with_ttl(b"a".to_vec(), big.clone(), 0, Duration::from_secs(10))
+        .await
+        .unwrap();
+    let mut txn = kv.new_transaction(true).unwrap();
+    let entry = Entry::default()
+        .key(b"b".to_vec())
+        .value(b"b".to_vec())
+        .expires_at(1020);
+    txn.set_entry(entry).unwrap();
+    txn.commit().await.unwrap();
+    assert_eq!(kv.get(b"a").await.unwrap(), big);
+    assert_eq!(
+        kv.get_item(b"b").await.unwrap().value().await.unwrap(),
+        b"b"
+    );
+
+    clock.0.store(1010, Ordering::Relaxed);
+    assert!(kv.get(b"a").await.unwrap_err().is_not_found());
+    assert_eq!(kv.get(b"b").await.unwrap(), b"b");
+    // A pending write expires too.
+    let mut txn = kv.new_transaction(true).unwrap();
+    let entry = Entry::default().key(b"c".to_vec()).expires_at(1010);
+    txn.set_entry(entry).unwrap();
+    assert!(txn.get(b"c").await.unwrap_err().is_not_found());
+    drop(txn);
+    kv.close().await.unwrap();
+
+    // The expiry is kept in the tables and the value log.
+    let kv = KV::open(opt).await.unwrap();
+    assert!(kv.get(b"a").await.unwrap_err().is_not_found());
+    assert_eq!(kv.get(b"b").await.unwrap(), b"b");
+    clock.0.store(1020, Ordering::Relaxed);
+    assert!(kv.get(b"b").await.unwrap_err().is_not_found());
+
+    // A ttl under a second doesn't expire right away, and Entry::with_ttl goes by the clock
+    // when it's written too.
+    kv.set_with_ttl(b"d".to_vec(), b"d".to_vec(), 0, Duration::from_millis(900))
+        .await
+        .unwrap();
+    let entry = Entry::default()
+        .key(b"e".to_vec())
+        .value(b"e".to_vec())
+        .with_ttl(Duration::from_secs(5));
+    clock.0.store(1030, Ordering::Relaxed);
+    let mut txn = kv.new_transaction(true).unwrap();
+    txn.set_entry(entry).unwrap();
+    txn.commit().await.unwrap();
+    clock.0.store(1020, Ordering::Relaxed);
+    assert_eq!(kv.get(b"d").await.unwrap(), b"d");
+    assert_eq!(kv.get_item(b"e").await.unwrap().expires_at().await, 1035);
+    clock.0.store(1021, Ordering::Relaxed);
+    assert!(kv.get(b"d").await.unwrap_err().is_not_found());
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_sequence() {
+    use crate::test_util::random_tmp_dir;
+    let opt = get_test_option(&random_tmp_dir());
+    let kv = KV::open(opt.clone()).await.unwrap();
+    assert!(kv.get_sequence(b"seq".to_vec(), 0).await.is_err());
+    let seq = kv.get_sequence(b"seq".to_vec(), 10).await.unwrap();
+    for i in 0..25 {
+        assert_eq!(seq.next().await.unwrap(), i);
+    }
+    assert_eq!(kv.get(b"seq").await.unwrap(), 30u64.to_be_bytes());
+    seq.release().await.unwrap();
+    assert_eq!(kv.get(b"seq").await.unwrap(), 25u64.to_be_bytes());
+
+    // A sequence not released loses the rest of its lease.
+    let seq = kv.get_sequence(b"seq".to_vec(), 10).await.unwrap();
+    assert_eq!(seq.next().await.unwrap(), 25);
+    drop(seq);
+    kv.close().await.unwrap();
+    let kv = KV::open(opt).await.unwrap();
+    let seq = kv.get_sequence(b"seq".to_vec(), 10).await.unwrap();
+    assert_eq!(seq.next().await.unwrap(), 35);
+    drop(seq);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_subscribe() {
+    use crate::test_util::random_tmp_dir;
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    let sub = kv.subscribe(vec![b"a".to_vec(), b"c".to_vec()]);
+    let entries = ["a1", "b1", "a2", "c1"]
+        .iter()
+        .map(|k| {
+            Entry::default()
+                .key(k.as_bytes().to_vec())
+                .value(b"v".to_vec())
+        })
+        .collect::<Vec<_>>();
+    for res in kv.batch_set(entries).await {
+        res.unwrap();
+    }
+    kv.delete(b"a1").await.unwrap();
+    let mut got = vec![];
+    while got.len() < 4 {
+        got.extend(sub.recv().await.unwrap());
+    }
+    let keys = got.iter().map(|c| c.key.clone()).collect::<Vec<_>>();
+    let want = ["a1", "a2", "c1", "a1"].map(|k| k.as_bytes().to_vec());
+    assert_eq!(keys, want);
+    assert_eq!(got[0].value, b"v");
+    assert!(!got[0].deleted);
+    assert!(got[3].deleted);
+    drop(sub);
+
+    // A dropped subscription doesn't hold the writes.
+    for i in 0..200 {
+        kv.set(format!("a{}", i).into_bytes(), b"v".to_vec(), 0)
+            .await
+            .unwrap();
+    }
+    let sub = kv.subscribe(vec![b"".to_vec()]);
+    kv.set(b"z".to_vec(), b"v".to_vec(), 0).await.unwrap();
+    kv.close().await.unwrap();
+    assert_eq!(sub.recv().await.unwrap()[0].key, b"z");
+    assert!(sub.recv().await.is_none());
+    assert!(kv.subscribe(vec![b"".to_vec()]).recv().await.is_none());
+
+    // A subscription that isn't read holds the writes, not the close.
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    let sub = kv.subscribe(vec![b"".to_vec()]);
+    let writer = {
+        let kv = kv.clone();
+        tokio::spawn(async move {
+            for i in 0..200 {
+                let _ = kv
+                    .set(format!("a{}", i).into_bytes(), b"v".to_vec(), 0)
+                    .await;
+            }
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    tokio::time::timeout(Duration::from_secs(10), kv.close())
+        .await
+        .unwrap()
+        .unwrap();
+    writer.await.unwrap();
+    assert!(sub.recv().await.is_some());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_backup_since() {
+    use crate::pb::backup::KVPair;
+    use crate::test_util::random_tmp_dir;
+    use byteorder::{LittleEndian, ReadBytesExt};
+    use protobuf::Message;
+    let pairs = |buf: Vec<u8>| {
+        let mut rd = std::io::Cursor::new(buf);
+        let mut pairs = vec![];
+        while let Ok(n) = rd.read_u64::<LittleEndian>() {
+            let mut pb = vec![0; n as usize];
+            rd.read_exact(&mut pb).unwrap();
+            let pair = KVPair::parse_from_bytes(&pb).unwrap();
+            pairs.push((pair.key, pair.value, pair.version, pair.expiresAt));
+        }
+        pairs
+    };
+
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    kv.set(b"a".to_vec(), b"1".to_vec(), 0).await.unwrap();
+    kv.set_with_ttl(
+        b"b".to_vec(),
+        vec![2; 1 << 10],
+        0,
+        Duration::from_secs(3600),
+    )
+    .await
+    .unwrap();
+    let mut buf = vec![];
+    let since = kv.backup(&mut buf, 0).await.unwrap();
+    let got = pairs(buf);
+    assert_eq!(got.len(), 2);
+    assert_eq!(
+        (got[0].0.as_slice(), got[0].1.as_slice()),
+        (&b"a"[..], &b"1"[..])
+    );
+    assert_eq!(got[1].1, vec![2; 1 << 10]);
+    assert!(got[1].3 > 0);
+    assert_eq!(since, got[1].2);
+
+    // An incremental backup has the keys written since.
+    kv.set(b"c".to_vec(), b"3".to_vec(), 0).await.unwrap();
+    let mut buf = vec![];
+    let next = kv.backup(&mut buf, since + 1).await.unwrap();
+    let got = pairs(buf);
+    assert_eq!(got.len(), 1);
+    assert_eq!(got[0].0, b"c");
+    assert!(next > since);
+    kv.close().await.unwrap();
+
+    // Every version with managed transactions, up to the newest delete.
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.managed_txns = true;
+    let kv = KV::open(opt).await.unwrap();
+    for (ts, delete) in [(10, false), (20, true), (30, false), (40, false)] {
+        let mut txn = kv.new_transaction_at(ts - 1, true).unwrap();
+        if delete {
+            txn.delete(b"k".to_vec()).unwrap();
+        } else {
+            txn.set(b"k".to_vec(), format!("{}", ts).into_bytes(), 0)
+                .unwrap();
+        }
+        txn.commit_at(ts).await.unwrap();
+    }
+    let mut buf = vec![];
+    assert_eq!(kv.backup(&mut buf, 0).await.unwrap(), 40);
+    let versions = pairs(buf).into_iter().map(|p| p.2).collect::<Vec<_>>();
+    assert_eq!(versions, vec![40, 30]);
+    let mut buf = vec![];
+    assert_eq!(kv.backup(&mut buf, 35).await.unwrap(), 40);
+    assert_eq!(pairs(buf).len(), 1);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_backup_load() {
+    use crate::test_util::random_tmp_dir;
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    let key = |i: usize| format!("key{:04}", i).into_bytes();
+    // The first value is empty, it's still a value.
+    for i in 0..500 {
+        kv.set(key(i), vec![i as u8; i], (i % 7) as u8)
+            .await
+            .unwrap();
+    }
+    assert_eq!(kv.get(&key(0)).await.unwrap(), Vec::<u8>::new());
+    assert!(kv.exists(&key(0)).await.unwrap());
+    kv.delete(&key(3)).await.unwrap();
+    kv.set_with_ttl(b"ttl".to_vec(), b"v".to_vec(), 0, Duration::from_secs(3600))
+        .await
+        .unwrap();
+    let mut full = vec![];
+    let since = kv.backup(&mut full, 0).await.unwrap();
+    kv.set(key(1000), b"later".to_vec(), 0).await.unwrap();
+    let mut incremental = vec![];
+    kv.backup(&mut incremental, since + 1).await.unwrap();
+    kv.close().await.unwrap();
+
+    let opt = get_test_option(&random_tmp_dir());
+    let kv = KV::open(opt).await.unwrap();
+    kv.load(full.as_slice(), 2).await.unwrap();
+    kv.load(incremental.as_slice(), 2).await.unwrap();
+    for i in (0..500).filter(|i| *i != 3) {
+        let item = kv.get_item(&key(i)).await.unwrap();
+        assert_eq!(item.value().await.unwrap(), vec![i as u8; i]);
+        assert_eq!(item.user_meta().await, (i % 7) as u8);
+    }
+    assert!(kv.get(&key(3)).await.unwrap_err().is_not_found());
+    assert_eq!(kv.get(&key(1000)).await.unwrap(), b"later");
+    assert!(kv.get_item(b"ttl").await.unwrap().expires_at().await > 0);
+    assert!(kv.load(&b"\x05\0\0\0\0\0\0\0ab"[..], 1).await.is_err());
+    kv.close().await.unwrap();
+
+    // The versions are kept with managed transactions.
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.managed_txns = true;
+    let kv = KV::open(opt).await.unwrap();
+    for ts in [10, 20] {
+        let mut txn = kv.new_transaction_at(ts - 1, true).unwrap();
+        txn.set(b"k".to_vec(), format!("{}", ts).into_bytes(), 0)
+            .unwrap();
+        txn.commit_at(ts).await.unwrap();
+    }
+    let mut buf = vec![];
+    kv.backup(&mut buf, 0).await.unwrap();
+    kv.close().await.unwrap();
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.managed_txns = true;
+    let kv = KV::open(opt).await.unwrap();
+    kv.load(buf.as_slice(), 1).await.unwrap();
+    let txn = kv.new_transaction_at(15, false).unwrap();
+    assert_eq!(txn.get(b"k").await.unwrap(), b"10");
+    let txn = kv.new_transaction_at(25, false).unwrap();
+    assert_eq!(txn.get(b"k").await.unwrap(), b"20");
+    drop(txn);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_stream() {
+    use crate::test_util::random_tmp_dir;
+    use crate::KVList;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    let kv = KV::open(get_test_option(&random_tmp_dir())).await.unwrap();
+    let key = |prefix: &str, i: usize| format!("{}{:04}", prefix, i).into_bytes();
+    for i in 0..1000 {
+        kv.set(key("a", i), vec![i as u8; 100], 0).await.unwrap();
+        kv.set(key("b", i), vec![i as u8; 100], 0).await.unwrap();
+    }
+    kv.delete(&key("a", 7)).await.unwrap();
+
+    let got = Arc::new(Mutex::new(KVList::new()));
+    let sink = got.clone();
+    kv.new_stream()
+        .unwrap()
+        .prefix(b"a")
+        .num_go(4)
+        .orchestrate(move |list| {
+            sink.lock().extend(list);
+            Box::pin(async { Ok(()) })
+        })
+        .await
+        .unwrap();
+    let keys = got.lock().iter().map(|c| c.key.clone()).collect::<Vec<_>>();
+    let want = (0..1000)
+        .filter(|i| *i != 7)
+        .map(|i| key("a", i))
+        .collect::<Vec<_>>();
+    assert_eq!(keys, want);
+    assert_eq!(got.lock()[1].value, vec![1; 100]);
+
+    // The items can be skipped.
+    let got = Arc::new(Mutex::new(KVList::new()));
+    let sink = got.clone();
+    kv.new_stream()
+        .unwrap()
+        .key_to_list(|item| {
+            Box::pin(async move {
+                let key = item.key().await;
+                if key.ends_with(b"0") {
+                    Ok(vec![crate::KVChange {
+                        key,
+                        value: vec![],
+                        user_meta: 0,
+                        deleted: false,
+                        expires_at: 0,
+                    }])
+                } else {
+                    Ok(vec![])
+                }
+            })
+        })
+        .orchestrate(move |list| {
+            sink.lock().extend(list);
+            Box::pin(async { Ok(()) })
+        })
+        .await
+        .unwrap();
+    assert_eq!(got.lock().len(), 200);
+
+    // The error of `send` stops the stream.
+    let res = kv
+        .new_stream()
+        .unwrap()
+        .num_go(2)
+        .orchestrate(|_| Box::pin(async { Err("stop".into()) }))
+        .await;
+    assert!(res.is_err());
+
+    // A panic of a range fails the stream, its keys aren't silently left out.
+    let res = kv
+        .new_stream()
+        .unwrap()
+        .key_to_list(|item| {
+            Box::pin(async move {
+                if item.key().await.ends_with(b"7") {
+                    panic!("key_to_list");
+                }
+                Ok(vec![])
+            })
+        })
+        .orchestrate(|_| Box::pin(async { Ok(()) }))
+        .await;
+    assert!(res.unwrap_err().to_string().contains("panicked"));
+    assert!(kv.new_stream_at(10).is_err());
+    kv.close().await.unwrap();
+
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.managed_txns = true;
+    let kv = KV::open(opt).await.unwrap();
+    assert!(kv.new_stream().is_err());
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_value_log_reader() {
+    use crate::test_util::random_tmp_dir;
+    use crate::value_log::{ValueLogReader, ValuePointer};
+    let opt = get_test_option(&random_tmp_dir());
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let entries = (0..100)
+        .map(|i| Entry::default().key(test_key(i)).value(test_value(i)))
+        .collect::<Vec<_>>();
+    for res in kv.batch_set(entries).await {
+        assert!(res.is_ok());
+    }
+    kv.close().await.unwrap();
+
+    let mut reader = ValueLogReader::open(&opt.value_dir).unwrap();
+    assert_eq!(reader.fids(), vec![0]);
+    let got = TArcMx::new(tokio::sync::Mutex::new(vec![]));
+    let xgot = got.clone();
+    reader
+        .replay(&ValuePointer::default(), |entry, vp| {
+            let got = xgot.clone();
+            let item = (entry.get_key().to_vec(), entry.get_value().to_vec(), *vp);
+            Box::pin(async move {
+                got.lock().await.push(item);
+                Ok(true)
+            })
+        })
+        .await
+        .unwrap();
+    let all = got.lock().await.drain(..).collect::<Vec<_>>();
+    let user = all
+        .iter()
+        .filter(|(k, _, _)| k.starts_with(b"key"))
+        .collect::<Vec<_>>();
+    assert_eq!(user.len(), 100);
+    for (i, (k, v, _)) in user.iter().enumerate() {
+        assert_eq!((k, v), (&test_key(i), &test_value(i)));
+    }
+
+    // From an entry on, until the visitor stops.
+    let from = user[10].2;
+    reader
+        .replay(&from, |entry, _| {
+            let got = xgot.clone();
+            let key = entry.get_key().to_vec();
+            Box::pin(async move {
+                let mut got = got.lock().await;
+                got.push((key, vec![], ValuePointer::default()));
+                Ok(got.len() < 5)
+            })
+        })
+        .await
+        .unwrap();
+    let keys = got
+        .lock()
+        .await
+        .iter()
+        .map(|e| e.0.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(keys, (11..16).map(test_key).collect::<Vec<_>>());
+
+    // The space preallocated by a running KV ends the file, other bytes are corruption.
+    let vlog_path = format!("{}/{:06}.vlog", opt.value_dir, 0);
+    let append = |buf: &[u8]| {
+        let mut fd = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&vlog_path)
+            .unwrap();
+        fd.write_all(buf).unwrap();
+    };
+    let count = |mut reader: ValueLogReader| async move {
+        let n = TArcMx::new(tokio::sync::Mutex::new(0));
+        let xn = n.clone();
+        let res = reader
+            .replay(&ValuePointer::default(), |_, _| {
+                let n = xn.clone();
+                Box::pin(async move {
+                    *n.lock().await += 1;
+                    Ok(true)
+                })
+            })
+            .await;
+        let n = *n.lock().await;
+        res.map(|_| n)
+    };
+    append(&[0u8; 1000]);
+    let n = count(ValueLogReader::open(&opt.value_dir).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(n, all.len());
+    append(&[0xffu8; 10]);
+    let err = count(ValueLogReader::open(&opt.value_dir).unwrap())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("corrupt"), "{}", err);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_sync_writes() {
+    use crate::test_util::random_tmp_dir;
+    for sync_writes in [true, false] {
+        let mut opt = get_test_option(&random_tmp_dir());
+        opt.sync_writes = sync_writes;
+        opt.value_log_sync_interval = Some(Duration::from_millis(10));
+        let kv = KV::open(opt.clone()).await.unwrap();
+        kv.set(b"small-key".to_vec(), b"v".to_vec(), 0)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let vlog_path = format!("{}/{:06}.vlog", opt.value_dir, kv.must_vlog().get_max_fid());
+        let data = std::fs::read(&vlog_path).unwrap();
+        // Small values go to the value log only to be synced.
+        let found = data.windows(9).any(|w| w == b"small-key");
+        assert_eq!(found, sync_writes);
+        kv.close().await.unwrap();
+
+        let kv = KV::open(opt).await.unwrap();
+        assert_eq!(kv.get(b"small-key").await.unwrap(), b"v".to_vec());
+        kv.close().await.unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_write_pipeline_completes_batched_requests() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.sync_writes = true;
+    let kv = KV::open(opt).await.unwrap();
+    let mut handles = vec![];
+    for i in 0..64 {
+        let kv = kv.clone();
+        handles.push(tokio::spawn(async move {
+            for j in 0..20 {
+                let key = format!("key{:02}-{:02}", i, j).into_bytes();
+                kv.set(key, vec![j as u8; 64], 0).await.unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.await.unwrap();
+    }
+    for i in 0..64 {
+        for j in 0..20 {
+            let key = format!("key{:02}-{:02}", i, j).into_bytes();
+            assert_eq!(kv.get(&key).await.unwrap(), vec![j as u8; 64]);
+        }
+    }
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_close_flushes() {
+    use crate::test_util::random_tmp_dir;
+    let opt = get_test_option(&random_tmp_dir());
+    let kv = KV::open(opt.clone()).await.unwrap();
+    for i in 0..100 {
+        kv.set(format!("key{:03}", i).into_bytes(), b"v".to_vec(), 0)
+            .await
+            .unwrap();
+    }
+    kv.close().await.unwrap();
+    assert!(matches!(
+        kv.set(b"late".to_vec(), b"v".to_vec(), 0).await,
+        Err(Error::DBClosed)
+    ));
+    assert!(!kv.tables(false).unwrap().is_empty());
+
+    let kv = KV::open(opt).await.unwrap();
+    assert!(kv.must_mt().empty());
+    for i in 0..100 {
+        let key = format!("key{:03}", i).into_bytes();
+        assert_eq!(kv.get(&key).await.unwrap(), b"v".to_vec());
+    }
+    assert!(kv.get(b"late").await.unwrap_err().is_not_found());
+    kv.close().await.unwrap();
+}
+
+#[cfg(feature = "encryption")]
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_encryption() {
+    use crate::test_util::random_tmp_dir;
+    let files = |dir: &str, exts: &[&str]| {
+        std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| {
+                let ext = path
+                    .extension()
+                    .map(|ext| ext.to_string_lossy().to_string());
+                exts.iter().any(|want| ext.as_deref() == Some(*want))
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.encryption_key = vec![3; 32];
+    opt.sync_writes = true;
+    opt.mem_table_wal = true;
+    let big = b"secret".repeat(opt.value_threshold / 6 + 1);
+    let kv = KV::open(opt.clone()).await.unwrap();
+    for i in 0..100 {
+        kv.set(format!("key{:03}", i).into_bytes(), b"secret".to_vec(), 0)
+            .await
+            .unwrap();
+    }
+    kv.set(b"big".to_vec(), big.clone(), 0).await.unwrap();
+    // What a crash would leave, the writes are only in the value log and the WAL.
+    let copy = crate::test_util::create_random_tmp_dir();
+    for entry in std::fs::read_dir(opt.dir.as_str()).unwrap() {
+        let entry = entry.unwrap();
+        std::fs::copy(entry.path(), Path::new(&copy).join(entry.file_name())).unwrap();
+    }
+    kv.close().await.unwrap();
+    let tables = files(&opt.dir, &["sst"]);
+    assert!(!tables.is_empty());
+    let logs = files(&copy, &["vlog", "mem"]);
+    assert_eq!(logs.len(), 2);
+    for path in tables
+        .into_iter()
+        .chain(logs)
+        .chain(files(&opt.dir, &["vlog"]))
+    {
+        let data = std::fs::read(path).unwrap();
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+    }
+
+    let mut copy_opt = opt.clone();
+    copy_opt.dir = Box::new(copy.clone());
+    copy_opt.value_dir = Box::new(copy);
+    let kv = KV::open(copy_opt).await.unwrap();
+    assert_eq!(kv.get(b"key042").await.unwrap(), b"secret".to_vec());
+    assert_eq!(kv.get(b"big").await.unwrap(), big);
+    kv.close().await.unwrap();
+
+    let kv = KV::open(opt.clone()).await.unwrap();
+    assert_eq!(kv.get(b"key042").await.unwrap(), b"secret".to_vec());
+    assert_eq!(kv.get(b"big").await.unwrap(), big);
+    kv.close().await.unwrap();
+    opt.encryption_key = vec![4; 32];
+    assert!(KV::open(opt).await.is_err());
+
+    // Set on a plain DB, the writes go to a new encrypted value log file.
+    let mut opt = get_test_option(&random_tmp_dir());
+    let kv = KV::open(opt.clone()).await.unwrap();
+    kv.set(b"plain".to_vec(), vec![1; opt.value_threshold], 0)
+        .await
+        .unwrap();
+    kv.close().await.unwrap();
+    opt.encryption_key = vec![3; 16];
+    let kv = KV::open(opt.clone()).await.unwrap();
+    assert_eq!(kv.must_vlog().get_max_fid(), 1);
+    kv.set(b"big".to_vec(), big.clone(), 0).await.unwrap();
+    kv.close().await.unwrap();
+    let data = std::fs::read(Path::new(opt.value_dir.as_str()).join("000001.vlog")).unwrap();
+    assert!(!data.windows(6).any(|w| w == b"secret"));
+    let kv = KV::open(opt).await.unwrap();
+    assert_eq!(
+        kv.get(b"plain").await.unwrap(),
+        vec![1; kv.opt.value_threshold]
+    );
+    assert_eq!(kv.get(b"big").await.unwrap(), big);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_sync() {
+    use crate::test_util::random_tmp_dir;
+    for mem_table_wal in [true, false] {
+        let mut opt = get_test_option(&random_tmp_dir());
+        opt.sync_writes = false;
+        opt.mem_table_wal = mem_table_wal;
+        let kv = KV::open(opt.clone()).await.unwrap();
+        let big = vec![7; opt.value_threshold + 1];
+        kv.set(b"small".to_vec(), b"v".to_vec(), 0).await.unwrap();
+        kv.set(b"big".to_vec(), big.clone(), 0).await.unwrap();
+        kv.sync().await.unwrap();
+        let vlog_path = format!("{}/{:06}.vlog", opt.value_dir, kv.must_vlog().get_max_fid());
+        let data = std::fs::read(&vlog_path).unwrap();
+        assert!(data.windows(3).any(|w| w == b"big"));
+        kv.close().await.unwrap();
+
+        let kv = KV::open(opt).await.unwrap();
+        assert_eq!(kv.get(b"small").await.unwrap(), b"v".to_vec());
+        assert_eq!(kv.get(b"big").await.unwrap(), big);
+        kv.close().await.unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_vlog_punch_holes() {
+    use crate::test_util::random_tmp_dir;
+    use std::os::unix::fs::MetadataExt;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_log_file_size = 1 << 20;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    let value = |i: usize, round: usize| format!("{}{:04095}", round, i).into_bytes();
+    for round in 0..2 {
+        let entries = (0..1000)
+            .map(|i| Entry::default().key(test_key(i)).value(value(i, round)))
+            .collect::<Vec<_>>();
+        for chunk in entries.chunks(20) {
+            for res in kv.batch_set(chunk.to_vec()).await {
+                assert!(res.is_ok());
+            }
+        }
+    }
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let max_fid = kv.must_vlog().get_max_fid();
+    let allocated = || {
+        (0..max_fid)
+            .map(|fid| {
+                let meta = std::fs::metadata(format!("{}/{:06}.vlog", opt.value_dir, fid)).unwrap();
+                (meta.len(), meta.blocks() * 512)
+            })
+            .fold((0, 0), |(len, used), (l, u)| (len + l, used + u))
+    };
+    let (len, used) = allocated();
+    // Never worth a rewrite, the stale values are punched out instead.
+    kv.must_vlog().trigger_gc(2.0).await.unwrap();
+    let (punched_len, punched_used) = allocated();
+    assert_eq!(punched_len, len);
+    assert!(punched_used < used, "{} {}", punched_used, used);
+    for i in (0..1000).step_by(7) {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), value(i, 1));
+    }
+    // The holes are skipped by a rewrite.
+    let _ = kv.run_value_log_gc(0.1).await;
+    for i in (0..1000).step_by(7) {
+        assert_eq!(kv.get(&test_key(i)).await.unwrap(), value(i, 1));
+    }
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_ban_prefix() {
+    use crate::test_util::random_tmp_dir;
+    let dir = random_tmp_dir();
+    let kv = KV::open(get_test_option(&dir)).await.unwrap();
+    for key in ["a1", "b1", "b2", "c1"] {
+        kv.set(key.as_bytes().to_vec(), b"v".to_vec(), 0)
+            .await
+            .unwrap();
+    }
+    assert!(kv.ban_prefix(b"!badger").await.is_err());
+    kv.ban_prefix(b"b").await.unwrap();
+    assert_eq!(kv.banned_prefixes(), vec![b"b".to_vec()]);
+
+    assert!(matches!(kv.get(b"b1").await, Err(Error::BannedKey)));
+    assert!(matches!(kv.exists(b"b2").await, Err(Error::BannedKey)));
+    assert!(matches!(
+        kv.set(b"b3".to_vec(), b"v".to_vec(), 0).await,
+        Err(Error::BannedKey)
+    ));
+    let mut txn = kv.new_transaction(true).unwrap();
+    assert!(matches!(txn.get(b"b1").await, Err(Error::BannedKey)));
+    assert!(matches!(
+        txn.set(b"b1".to_vec(), b"v".to_vec(), 0),
+        Err(Error::BannedKey)
+    ));
+    let opt = IteratorOptions::default();
+    assert!(matches!(
+        txn.iter(opt.clone().with_prefix(b"b1")).await,
+        Err(Error::BannedKey)
+    ));
+    txn.discard();
+    assert_eq!(kv.get(b"a1").await.unwrap(), b"v");
+
+    let txn = kv.new_transaction(false).unwrap();
+    let mut itr = txn.iter(opt).await.unwrap();
+    let mut keys = vec![];
+    let mut item = itr.rewind().await;
+    while let Some(el) = item {
+        keys.push(el.key().await);
+        item = itr.next().await;
+    }
+    itr.close().await.unwrap();
+    assert_eq!(keys, vec![b"a1".to_vec(), b"c1".to_vec()]);
+    kv.close().await.unwrap();
+
+    // The ban holds after a reopen.
+    let kv = KV::open(get_test_option(&dir)).await.unwrap();
+    assert_eq!(kv.banned_prefixes(), vec![b"b".to_vec()]);
+    assert!(matches!(kv.get(b"b1").await, Err(Error::BannedKey)));
+    assert_eq!(kv.get(b"c1").await.unwrap(), b"v");
+
+    // And after everything is dropped.
+    kv.drop_all().await.unwrap();
+    assert_eq!(kv.banned_prefixes(), vec![b"b".to_vec()]);
+    assert!(kv.get(b"c1").await.unwrap_err().is_not_found());
+    kv.close().await.unwrap();
+    let kv = KV::open(get_test_option(&dir)).await.unwrap();
+    assert_eq!(kv.banned_prefixes(), vec![b"b".to_vec()]);
+    assert!(matches!(kv.get(b"b1").await, Err(Error::BannedKey)));
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_txn_multi_get() {
+    use crate::test_util::random_tmp_dir;
+    let dir = random_tmp_dir();
+    let kv = KV::open(get_test_option(&dir)).await.unwrap();
+    let key = |i: usize| format!("key{:05}", i).into_bytes();
+    let entries = (0..3000)
+        .map(|i| Entry::default().key(key(i)).value(test_value(i)))
+        .collect::<Vec<_>>();
+    for chunk in entries.chunks(100) {
+        for res in kv.batch_set(chunk.to_vec()).await {
+            res.unwrap();
+        }
+    }
+    kv.close().await.unwrap();
+
+    // The keys are in the tables, a few are overwritten or deleted in the memtable.
+    let kv = KV::open(get_test_option(&dir)).await.unwrap();
+    kv.set(key(10), b"new".to_vec(), 0).await.unwrap();
+    kv.delete(&key(20)).await.unwrap();
+    let mut txn = kv.new_transaction(true).unwrap();
+    txn.set(key(30), b"pending".to_vec(), 0).unwrap();
+    let keys = vec![
+        key(2999),
+        key(10),
+        key(5000),
+        key(20),
+        key(30),
+        key(0),
+        key(1500),
+        key(10),
+    ];
+    let got = txn.multi_get(&keys).await;
+    assert_eq!(got.len(), keys.len());
+    assert_eq!(got[1].as_ref().unwrap(), b"new");
+    assert_eq!(got[4].as_ref().unwrap(), b"pending");
+    assert_eq!(got[6].as_ref().unwrap(), &test_value(1500));
+    for (key, got) in keys.iter().zip(got) {
+        match txn.get(key).await {
+            Ok(value) => assert_eq!(got.unwrap(), value),
+            Err(err) => assert!(err.is_not_found() && got.unwrap_err().is_not_found()),
+        }
+    }
+    txn.discard();
+    assert!(matches!(
+        txn.multi_get(&keys).await[0],
+        Err(Error::DiscardedTxn)
+    ));
+    kv.close().await.unwrap();
+
+    // With managed transactions, the versions read are the newest not after the read ts, in
+    // the tables or the memtable.
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.managed_txns = true;
+    let kv = KV::open(opt.clone()).await.unwrap();
+    // A transaction has to fit in a batch, the versions are written by several.
+    for ts in [10, 20] {
+        for chunk in (0..1000).collect::<Vec<_>>().chunks(50) {
+            let mut txn = kv.new_transaction_at(ts - 1, true).unwrap();
+            for &i in chunk {
+                txn.set(key(i), format!("{}-{}", i, ts).into_bytes(), 0)
+                    .unwrap();
+            }
+            txn.commit_at(ts).await.unwrap();
+        }
+    }
+    kv.close().await.unwrap();
+    let kv = KV::open(opt).await.unwrap();
+    let mut txn = kv.new_transaction_at(29, true).unwrap();
+    txn.set(key(1), b"30".to_vec(), 0).unwrap();
+    txn.delete(key(2)).unwrap();
+    txn.commit_at(30).await.unwrap();
+    let keys = vec![key(999), key(1), key(2), key(5000), key(0)];
+    for read_ts in [5, 15, 25, 35] {
+        let txn = kv.new_transaction_at(read_ts, false).unwrap();
+        let got = txn.multi_get(&keys).await;
+        for (key, got) in keys.iter().zip(got) {
+            match txn.get(key).await {
+                Ok(value) => assert_eq!(got.unwrap(), value),
+                Err(err) => assert!(err.is_not_found() && got.unwrap_err().is_not_found()),
+            }
+        }
+    }
+    let txn = kv.new_transaction_at(15, false).unwrap();
+    let got = txn.multi_get(&keys).await;
+    assert_eq!(got[0].as_ref().unwrap(), b"999-10");
+    assert_eq!(got[2].as_ref().unwrap(), b"2-10");
+    let txn = kv.new_transaction_at(25, false).unwrap();
+    let got = txn.multi_get(&keys).await;
+    assert_eq!(got[0].as_ref().unwrap(), b"999-20");
+    assert_eq!(got[1].as_ref().unwrap(), b"1-20");
+    assert!(got[3].as_ref().unwrap_err().is_not_found());
+    let txn = kv.new_transaction_at(35, false).unwrap();
+    let got = txn.multi_get(&keys).await;
+    assert_eq!(got[1].as_ref().unwrap(), b"30");
+    assert!(got[2].as_ref().unwrap_err().is_not_found());
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn t_kv_close_with_failing_flush() {
+    use crate::test_util::random_tmp_dir;
+    let mut opt = get_test_option(&random_tmp_dir());
+    opt.value_dir = Box::new(random_tmp_dir());
+    let kv = KV::open(opt.clone()).await.unwrap();
+    kv.set(b"key".to_vec(), b"value".to_vec(), 0).await.unwrap();
+    // The table of the memtable can't be created anymore.
+    std::fs::remove_dir_all(opt.dir.as_str()).unwrap();
+    let res = tokio::time::timeout(Duration::from_secs(30), kv.close()).await;
+    assert!(res.expect("close waits on the failing flush").is_err());
+    assert!(kv.flush_err().is_some());
+}
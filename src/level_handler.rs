@@ -4,7 +4,8 @@ use crate::table::iterator::{IteratorImpl, IteratorItem};
 use crate::table::table::Table;
 use crate::types::XArc;
 
-use crate::{event, hex_str, Result};
+use crate::y::{key_with_ts, parse_ts, ValueStruct};
+use crate::{event, hex_str, Result, Xiterator};
 use core::slice::SlicePattern;
 use std::fmt::Display;
 
@@ -153,6 +154,8 @@ impl LevelHandler {
     pub(crate) fn init_tables(&self, tables: Vec<Table>) {
         let total_size = tables.iter().fold(0, |acc, table| acc + table.size());
         self.total_size.store(total_size as u64, Ordering::Relaxed);
+        let hint = self.opt.access_hint(self.level());
+        tables.iter().for_each(|tb| tb.advise(hint));
         let mut tb_wl = self.tables_wl();
         (*tb_wl) = tables;
         if self.level() == 0 {
@@ -234,7 +237,9 @@ impl LevelHandler {
         }
         // TODO Add lock (think of level's sharing lock)
         // Increase total_size first.
+        let hint = self.opt.access_hint(self.level());
         for tb in &new_tables {
+            tb.advise(hint);
             self.incr_total_size(tb.size() as u64);
             // add table reference
             tb.incr_ref();
@@ -286,6 +291,7 @@ impl LevelHandler {
             // Too many tables at zero level need compact
             return false;
         }
+        t.advise(self.opt.access_hint(0));
         t.incr_ref();
         self.incr_total_size(t.size() as u64);
         tw.push(t);
@@ -322,12 +328,13 @@ impl LevelHandler {
                 event::get_metrics().num_lsm_gets.inc();
                 let it = IteratorImpl::new(tb.clone(), false);
                 let item = it.seek(key);
+                it.close();
                 tb.decr_ref();
-                if let Some(item) = item {
-                    if item.key() != key {
-                        continue;
+                match item {
+                    Some(item) if item.key() == key => return Some(item),
+                    _ => {
+                        tb.record_useless_probe();
                     }
-                    return Some(item);
                 }
             }
             None
@@ -354,12 +361,14 @@ impl LevelHandler {
             event::get_metrics().num_lsm_gets.inc();
             let it = IteratorImpl::new(tb.clone(), false);
             let item = it.seek(key);
+            it.close();
             tb.decr_ref();
             if let Some(item) = item {
                 if item.key() == key {
                     return Some(item);
                 }
             }
+            tb.record_useless_probe();
             return None;
         };
     }
@@ -368,6 +377,146 @@ impl LevelHandler {
         self.get_table_for_key(key)
     }
 
+    // Looks up the `keys`, sorted, grouped by the table that may hold them: a table is read
+    // once for all its keys, see `IteratorImpl::get_sorted`. Returns the items in the order of
+    // the keys.
+    pub(crate) fn multi_get(&self, keys: &[&[u8]]) -> Vec<Option<IteratorItem>> {
+        let mut got: Vec<Option<IteratorItem>> = keys.iter().map(|_| None).collect();
+        let tw = self.tables_rd();
+        let groups: Vec<(&Table, Vec<usize>)> = if self.get_level() == 0 {
+            // The tables overlap, every key may be in any of them, the newest first.
+            tw.iter()
+                .rev()
+                .map(|tb| (tb, (0..keys.len()).collect()))
+                .collect()
+        } else {
+            let mut groups: Vec<(&Table, Vec<usize>)> = vec![];
+            for (i, key) in keys.iter().enumerate() {
+                let index = tw
+                    .binary_search_by(|tb| tb.biggest().cmp(key))
+                    .unwrap_or_else(|n| n);
+                // The keys left are all after the last table.
+                let Some(tb) = tw.get(index) else {
+                    break;
+                };
+                match groups.last_mut() {
+                    Some((last, ids)) if last.id() == tb.id() => ids.push(i),
+                    _ => groups.push((tb, vec![i])),
+                }
+            }
+            groups
+        };
+        for (tb, ids) in groups {
+            let ids = ids
+                .into_iter()
+                .filter(|&i| {
+                    if got[i].is_some() {
+                        return false;
+                    }
+                    if tb.does_not_have(keys[i]) {
+                        event::get_metrics().num_lsm_bloom_hits.inc();
+                        return false;
+                    }
+                    true
+                })
+                .collect::<Vec<_>>();
+            if ids.is_empty() {
+                continue;
+            }
+            event::get_metrics().num_lsm_gets.inc_by(ids.len() as u64);
+            let it = IteratorImpl::new(tb.clone(), false);
+            let tb_keys = ids.iter().map(|&i| keys[i]).collect::<Vec<_>>();
+            for (i, item) in ids.into_iter().zip(it.get_sorted(&tb_keys)) {
+                match item {
+                    Some(item) => got[i] = Some(item),
+                    None => {
+                        tb.record_useless_probe();
+                    }
+                }
+            }
+            it.close();
+        }
+        got
+    }
+
+    // Returns the newest version of `key` not newer than `version` in the level, and its
+    // version. The filters are built on the versioned keys, they can't rule a table out.
+    pub(crate) fn get_version(&self, key: &[u8], version: u64) -> Option<(u64, ValueStruct)> {
+        let tw = self.tables_rd();
+        let tables = if self.get_level() == 0 {
+            &tw[..]
+        } else {
+            let seek_key = key_with_ts(key, version);
+            let index = tw
+                .binary_search_by(|tb| tb.biggest().cmp(&seek_key))
+                .unwrap_or_else(|n| n);
+            &tw[index.min(tw.len())..(index + 1).min(tw.len())]
+        };
+        let mut newest: Option<(u64, ValueStruct)> = None;
+        for tb in tables {
+            event::get_metrics().num_lsm_gets.inc();
+            let it = IteratorImpl::new(tb.clone(), false);
+            let item = it.seek_to_version(key, version);
+            it.close();
+            if let Some(item) = item {
+                let ts = parse_ts(item.key());
+                if newest.as_ref().map_or(true, |(newest, _)| ts > *newest) {
+                    newest = Some((ts, item.value().clone()));
+                }
+            }
+        }
+        newest
+    }
+
+    // Like `get_version` for every key of `keys`, sorted: a table is read by one iterator for
+    // all the keys it may hold. Returns the versions in the order of the keys.
+    pub(crate) fn multi_get_version(
+        &self,
+        keys: &[&[u8]],
+        version: u64,
+    ) -> Vec<Option<(u64, ValueStruct)>> {
+        let mut got: Vec<Option<(u64, ValueStruct)>> = keys.iter().map(|_| None).collect();
+        let tw = self.tables_rd();
+        let groups: Vec<(&Table, Vec<usize>)> = if self.get_level() == 0 {
+            tw.iter()
+                .map(|tb| (tb, (0..keys.len()).collect()))
+                .collect()
+        } else {
+            let mut groups: Vec<(&Table, Vec<usize>)> = vec![];
+            for (i, key) in keys.iter().enumerate() {
+                let seek_key = key_with_ts(key, version);
+                let index = tw
+                    .binary_search_by(|tb| tb.biggest().cmp(&seek_key))
+                    .unwrap_or_else(|n| n);
+                // The versioned keys may not be sorted like the keys, a key after the last
+                // table doesn't mean the next ones are.
+                let Some(tb) = tw.get(index) else {
+                    continue;
+                };
+                match groups.last_mut() {
+                    Some((last, ids)) if last.id() == tb.id() => ids.push(i),
+                    _ => groups.push((tb, vec![i])),
+                }
+            }
+            groups
+        };
+        for (tb, ids) in groups {
+            event::get_metrics().num_lsm_gets.inc_by(ids.len() as u64);
+            let it = IteratorImpl::new(tb.clone(), false);
+            for i in ids {
+                let Some(item) = it.seek_to_version(keys[i], version) else {
+                    continue;
+                };
+                let ts = parse_ts(item.key());
+                if got[i].as_ref().map_or(true, |(newest, _)| ts > *newest) {
+                    got[i] = Some((ts, item.value().clone()));
+                }
+            }
+            it.close();
+        }
+        got
+    }
+
     // returns current level
     pub(crate) fn level(&self) -> usize {
         self.level.load(Ordering::Relaxed) as usize
@@ -1,51 +1,86 @@
-use crate::compaction::{CompactStatus, KeyRange, LevelCompactStatus, INFO_RANGE};
+use crate::compaction::{
+    CompactStatus, CompactionDecision, CompactionEvent, CompactionFilter, CompactionStatus,
+    KeyRange, LevelCompactStatus, LevelCompactionStatus, RunningCompactions, INFO_RANGE,
+};
 
+use crate::discard_stats::{add_discarded, DiscardStats, Discarded};
+use crate::event::get_metrics;
+use crate::kv::_BADGER_PREFIX;
 use crate::level_handler::{LevelHandler, LevelHandlerInner};
 use crate::manifest::{Manifest, ManifestChangeBuilder, ManifestFile};
-use crate::options::Options;
+use crate::options::{AccessHint, Options};
 use crate::pb::badgerpb3::ManifestChange;
+use crate::table::block_cache::BlockCache;
 use crate::table::builder::Builder;
+use crate::table::encryption::{DataKey, KeyRegistry};
+use crate::table::file::{create_table_file, FileIdAllocator};
+use crate::table::index_cache::IndexCache;
 use crate::table::iterator::{ConcatIterator, IteratorImpl, IteratorItem};
-use crate::table::table::{get_id_map, new_file_name, Table, TableCore};
+use crate::table::table::{
+    get_id_map, id_to_filename, new_file_name, LevelInfo, Table, TableCore, TableInfo, FILE_SUFFIX,
+};
 use crate::types::{Channel, Closer, TArcMx, TArcRW, XArc};
+use crate::value_log::MetaBit;
 use crate::y::{
-    async_sync_directory, create_synced_file, open_existing_synced_file, sync_directory,
+    async_sync_directory, create_synced_file, key_with_ts, open_existing_synced_file, parse_key,
+    parse_ts, sync_directory, RateLimiter, TEMP_FILE_SUFFIX,
 };
+use crate::MergeIterOverBuilder;
 use crate::Xiterator;
-use crate::{hex_str, MergeIterOverBuilder};
-use crate::{Result, ValueStruct};
+use crate::{Error, Result, ValueStruct};
+use async_channel::Sender;
 use atomic::Ordering;
-use awaitgroup::WaitGroup;
+use awaitgroup::{WaitGroup, Worker};
 use drop_cell::defer;
 use log::{debug, error, info, warn};
 use parking_lot::lock_api::RawRwLock;
-use tracing::instrument;
 
+use crate::pb::badgerpb3::manifest_change::Operation::{CREATE, DELETE};
 use itertools::Itertools;
-use rand::random;
 use std::collections::HashSet;
 use std::fmt::{Debug, Display, Formatter};
-use std::fs::remove_file;
+use std::fs::{create_dir_all, read_dir, remove_file, rename};
 use std::io::Write;
 use std::ops::Deref;
-use std::sync::atomic::AtomicU64;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64};
 use std::sync::{Arc, RwLockReadGuard};
 use std::time::{Duration, SystemTime};
 use std::vec;
 use tokio::macros::support::thread_rng_n;
-use tokio::sync::{RwLock, RwLockWriteGuard};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{RwLock, RwLockWriteGuard, Semaphore};
 use tokio::time::sleep;
-use crate::pb::badgerpb3::manifest_change::Operation::{CREATE, DELETE};
+
+// Sub directory of the orphan tables, see `Options::quarantine_orphan_tables`.
+const QUARANTINE_DIR: &str = "quarantine";
 
 #[derive(Clone)]
 pub(crate) struct LevelsController {
     // The following are initialized once and const
     levels: Arc<Vec<LevelHandler>>,
-    next_file_id: Arc<AtomicU64>,
+    file_ids: FileIdAllocator,
+    // Holds the table indexes when `index_cache_size` is set.
+    index_cache: Option<IndexCache>,
+    // Holds the data blocks read when `block_cache_size` is set.
+    block_cache: Option<BlockCache>,
+    // Throttles the compaction IO when `compaction_throughput_bytes_per_sec` is set.
+    compaction_limiter: Option<RateLimiter>,
     // For ending compactions.
     compact_worker_wg: Arc<WaitGroup>,
     // Store compact status that will be run or has running
     c_status: Arc<CompactStatus>,
+    running: RunningCompactions,
+    // See `set_discard_ts`.
+    discard_ts: Arc<AtomicU64>,
+    // The read ts of the oldest snapshot, 0 without any, see `Oracle`.
+    read_mark: Arc<AtomicU64>,
+    // Set when the DB closes, the running compactions give up, see `abort_compactions`.
+    aborted: Arc<AtomicBool>,
+    // Stale bytes of the value log files, fed by the compactions.
+    discard_stats: Arc<DiscardStats>,
+    // The data keys of the encrypted tables, see `Options::encryption_key`.
+    keys: KeyRegistry,
     manifest: TArcRW<ManifestFile>,
     opt: Options,
     last_unstalled: TArcRW<SystemTime>,
@@ -71,6 +106,7 @@ impl LevelsController {
         notify_try_compact_chan: Channel<()>,
         zero_level_compact_chan: Channel<()>,
         notify_write_request_chan: Channel<()>,
+        read_mark: Arc<AtomicU64>,
         opt: Options,
     ) -> Result<LevelsController> {
         assert!(opt.num_level_zero_tables_stall > opt.num_level_zero_tables);
@@ -79,56 +115,97 @@ impl LevelsController {
         for i in 0..opt.max_levels {
             let lh = LevelHandlerInner::new(opt.clone(), i);
             levels.push(LevelHandler::from(lh));
-            if i == 0 {
-                // Do nothing
-            } else if i == 1 {
-                // Level 1 probably shouldn't be too much bigger than level 0.
-                levels[i]
-                    .max_total_size
-                    .store(opt.level_one_size, Ordering::Relaxed);
-            } else {
-                levels[i].max_total_size.store(
-                    levels[i - 1].max_total_size.load(Ordering::Relaxed)
-                        * opt.level_size_multiplier,
-                    Ordering::Relaxed,
-                );
-            }
+            levels[i]
+                .max_total_size
+                .store(opt.level_max_size(i), Ordering::Relaxed);
             cstatus.wl().push(LevelCompactStatus::default());
         }
         // Compare manifest against directory, check for existent/non-existent files, and remove.
         let mf = manifest.read().await.manifest.clone();
-        {
-            revert_to_manifest(opt.dir.as_str(), &mf, get_id_map(&opt.dir)).await?;
-        }
+        let id_map = get_id_map(&opt.dir);
+        let file_ids = FileIdAllocator::new(0);
+        // Never hand out the id of a file seen on disk, even if it's removed now.
+        id_map.iter().for_each(|id| file_ids.observe(*id));
+        revert_to_manifest(opt.dir.as_str(), &mf, id_map, opt.quarantine_orphan_tables).await?;
+
+        let discard_stats = Arc::new(DiscardStats::open(&opt.value_dir)?);
+        let keys = KeyRegistry::open(&opt.dir, &opt.encryption_key, opt.encryption_key_rotation)?;
 
         // Some files may be deleted. Let's reload.
         let mut tables: Vec<Vec<Table>> = vec![vec![]; levels.len()];
-        let mut max_file_id = 0;
+        let index_cache = if opt.index_cache_size > 0 {
+            Some(IndexCache::new(opt.index_cache_size as usize))
+        } else {
+            None
+        };
+        let block_cache =
+            (opt.block_cache_size > 0).then(|| BlockCache::new(opt.block_cache_size as usize));
+        let mut corrupted = vec![];
         {
             let mf = mf.write().await;
             for (file_id, table_manifest) in &mf.tables {
+                if table_manifest.level as usize >= levels.len() {
+                    return Err(format!(
+                        "Table {} is at level {}, the DB has {} levels, see max_levels",
+                        file_id,
+                        table_manifest.level,
+                        levels.len()
+                    )
+                    .into());
+                }
                 let file_name = new_file_name(*file_id, opt.dir.as_str());
                 let fd = open_existing_synced_file(&file_name, true)
                     .map_err(|err| format!("Openfile file: {}, err: {}", file_name, err))?;
-                let tb = TableCore::open_table(fd, &file_name, opt.table_loading_mode)
-                    .map_err(|err| format!("Open file: {}, err :{}", file_name, err))?;
-                tables[table_manifest.level as usize].push(Table::new(tb));
-                if *file_id > max_file_id {
-                    max_file_id = *file_id;
+                let tb = TableCore::open_table_with_keys(
+                    fd,
+                    &file_name,
+                    opt.table_loading_mode,
+                    Some(&keys),
+                )
+                .map_err(|err| format!("Open file: {}, err :{}", file_name, err))?
+                .with_block_checksums(opt.checksum_verification_mode.on_block_read())
+                .with_block_cache(block_cache.clone())
+                .with_index_cache(index_cache.clone());
+                if opt.checksum_verification_mode.on_table_read() {
+                    if let Err(err) = tb.verify_checksum() {
+                        error!("{}", err);
+                        corrupted.push(err.to_string());
+                    }
                 }
+                tables[table_manifest.level as usize].push(Table::new(tb));
+                file_ids.observe(*file_id);
             }
         }
 
-        let next_file_id = max_file_id + 1;
+        if !corrupted.is_empty() {
+            // Release the references without deleting the files, so they can be inspected.
+            tables.iter().flatten().for_each(|tb| tb.decr_ref());
+            return Err(Error::ChecksumMismatch(format!(
+                "{} corrupted tables: {}",
+                corrupted.len(),
+                corrupted.join("\n")
+            )));
+        }
+
         for (i, tbs) in tables.into_iter().enumerate() {
             levels[i].init_tables(tbs);
         }
         // Make sure key ranges do not overlap etc.
         let level_controller = LevelsController {
             levels: Arc::new(levels),
-            next_file_id: Arc::new(AtomicU64::new(next_file_id)),
+            file_ids,
+            index_cache,
+            block_cache,
+            compaction_limiter: (opt.compaction_throughput_bytes_per_sec > 0)
+                .then(|| RateLimiter::new(opt.compaction_throughput_bytes_per_sec)),
             compact_worker_wg: Arc::new(Default::default()),
             c_status: Arc::new(cstatus),
+            running: RunningCompactions::default(),
+            discard_ts: Arc::new(AtomicU64::new(0)),
+            read_mark,
+            aborted: Arc::new(AtomicBool::new(false)),
+            discard_stats,
+            keys,
             manifest,
             opt: opt.clone(),
             last_unstalled: Arc::new(tokio::sync::RwLock::new(SystemTime::now())),
@@ -158,6 +235,7 @@ impl LevelsController {
     }
 
     pub(crate) fn close(&self) -> Result<()> {
+        self.discard_stats.sync()?;
         self.cleanup_levels()
     }
 
@@ -192,6 +270,72 @@ impl LevelsController {
         None
     }
 
+    // Looks up the `keys`, sorted, like `get`: the levels are read from 0 on upward and a key
+    // found isn't looked up in the next ones. Returns the values in the order of the keys.
+    pub(crate) fn multi_get(&self, keys: &[&[u8]]) -> Vec<Option<ValueStruct>> {
+        let mut got: Vec<Option<ValueStruct>> = keys.iter().map(|_| None).collect();
+        let mut pending = (0..keys.len()).collect::<Vec<_>>();
+        for h in self.levels.iter() {
+            if pending.is_empty() {
+                break;
+            }
+            let level_keys = pending.iter().map(|&i| keys[i]).collect::<Vec<_>>();
+            h.lock_shared();
+            let items = h.multi_get(&level_keys);
+            h.unlock_shared();
+            let mut left = vec![];
+            for (i, item) in pending.into_iter().zip(items) {
+                match item {
+                    Some(item) => got[i] = Some(item.value().clone()),
+                    None => left.push(i),
+                }
+            }
+            pending = left;
+        }
+        got
+    }
+
+    // Returns the newest version of `key` not newer than `version`, and its version. The
+    // versions aren't ordered by level, they're given by the application, all levels are read.
+    pub(crate) fn get_version(&self, key: &[u8], version: u64) -> Option<(u64, ValueStruct)> {
+        let mut newest: Option<(u64, ValueStruct)> = None;
+        for h in self.levels.iter() {
+            h.lock_shared();
+            let got = h.get_version(key, version);
+            h.unlock_shared();
+            if let Some((ts, vs)) = got {
+                if newest.as_ref().map_or(true, |(newest, _)| ts > *newest) {
+                    newest = Some((ts, vs));
+                }
+            }
+        }
+        newest
+    }
+
+    // Like `get_version` for every key of `keys`, sorted, every level is read once for all
+    // of them. Returns the versions in the order of the keys.
+    pub(crate) fn multi_get_version(
+        &self,
+        keys: &[&[u8]],
+        version: u64,
+    ) -> Vec<Option<(u64, ValueStruct)>> {
+        let mut newest: Vec<Option<(u64, ValueStruct)>> = keys.iter().map(|_| None).collect();
+        for h in self.levels.iter() {
+            h.lock_shared();
+            let got = h.multi_get_version(keys, version);
+            h.unlock_shared();
+            for (newest, got) in newest.iter_mut().zip(got) {
+                let Some((ts, vs)) = got else {
+                    continue;
+                };
+                if newest.as_ref().map_or(true, |(newest, _)| ts > *newest) {
+                    *newest = Some((ts, vs));
+                }
+            }
+        }
+        newest
+    }
+
     // cleanup all level's handler
     fn cleanup_levels(&self) -> Result<()> {
         for level in self.levels.iter() {
@@ -202,17 +346,17 @@ impl LevelsController {
 
     // start compact
     pub(crate) fn start_compact(&self, lc: Closer) {
-        for _i in 0..self.opt.num_compactors {
+        for id in 0..self.opt.num_compactors as usize {
             let lc = lc.spawn();
             let _self = self.clone();
             tokio::spawn(async move {
-                _self.run_worker(lc).await;
+                _self.run_worker(id, lc).await;
             });
         }
     }
 
     // compact worker
-    async fn run_worker(&self, lc: Closer) {
+    async fn run_worker(&self, id: usize, lc: Closer) {
         defer! {lc.done()};
         if self.opt.do_not_compact {
             return;
@@ -223,8 +367,9 @@ impl LevelsController {
             let duration = thread_rng_n(1000);
             tokio::time::sleep(Duration::from_millis(duration as u64)).await;
         }
-        // 1 seconds to check compact
-        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        // 1 seconds to check compact, shifted a bit per worker so they don't tick together.
+        let period = Duration::from_millis(1000 + thread_rng_n(100) as u64);
+        let mut interval = tokio::time::interval(period);
         let zero_level_compact_chan = self.zero_level_compact_chan.tx();
         let notify_try_compact_chan = self.notify_try_compact_chan.rx();
         loop {
@@ -232,55 +377,11 @@ impl LevelsController {
             let done = lc.has_been_closed();
             tokio::select! {
                 _ = notify_try_compact_chan.recv() => {
-                    let pick: Vec<CompactionPriority> = self.pick_compact_levels();
-                    info!("Try to compact levels, {:?}", pick);
-                    if pick.is_empty() {
-                        zero_level_compact_chan.try_send(());
-                    }
-                    for p in pick {
-                        match self.do_compact(p.clone()).await {
-                            Ok(true) => {
-                                info!("Succeed to compacted");
-                                if p.level == 0 {
-                                    // zero level has compacted, memory SkipList can continue handle *write request*
-                                    zero_level_compact_chan.try_send(());
-                                }
-                            },
-                            Ok(false) => {
-                                info!("Skip to do compacted");
-                                break;
-                            },
-                            Err(err) => { // TODO handle error
-                                error!("Failed to do compacted, {:?}", err);
-                            },
-                        }
-                    }
+                    self.run_compaction_round(id, &zero_level_compact_chan).await;
                     interval.reset();
                 },
                 _ = interval.tick() => {
-                    let pick: Vec<CompactionPriority> = self.pick_compact_levels();
-                    info!("Try to compact levels, {:?}", pick);
-                    if pick.is_empty() {
-                        // No table need to compact, notify `KV` continue handle *write request*
-                        zero_level_compact_chan.try_send(());
-                    }
-                    for p in pick {
-                        match self.do_compact(p.clone()).await {
-                            Ok(true) => {
-                                info!("Succeed to compacted");
-                                if p.level == 0 {
-                                    zero_level_compact_chan.try_send(());
-                                }
-                            },
-                            Ok(false) => {
-                                info!("Skip to do compacted");
-                                break;
-                            },
-                            Err(err) => { // TODO handle error
-                                error!("Failed to do compacted, {:?}", err);
-                            },
-                        }
-                    }
+                    self.run_compaction_round(id, &zero_level_compact_chan).await;
                 },
                 _ =  done.recv() => {
                     info!("receive a closer signal for closing compact job");
@@ -291,7 +392,58 @@ impl LevelsController {
         }
     }
 
+    // Compacts the levels that need it, the most urgent first. Worker 0 always starts with
+    // level 0 when it needs a compaction, so a busy lower level can't stall the writes.
+    async fn run_compaction_round(&self, id: usize, zero_level_compact_chan: &Sender<()>) {
+        let mut pick: Vec<CompactionPriority> = self.pick_compact_levels();
+        info!("Worker {} tries to compact levels, {:?}", id, pick);
+        if pick.is_empty() {
+            // No table need to compact, notify `KV` continue handle *write request*
+            let _ = zero_level_compact_chan.try_send(());
+        }
+        if id == 0 {
+            if let Some(i) = pick.iter().position(|p| p.level == 0) {
+                let p = pick.remove(i);
+                pick.insert(0, p);
+            }
+        }
+        for p in pick {
+            if self.aborted.load(Ordering::Acquire) {
+                return;
+            }
+            match self.do_compact(p.clone()).await {
+                Ok(true) => {
+                    info!("Succeed to compacted");
+                    if p.level == 0 {
+                        // zero level has compacted, memory SkipList can continue handle *write request*
+                        let _ = zero_level_compact_chan.try_send(());
+                    }
+                }
+                Ok(false) => {
+                    info!("Skip to do compacted");
+                    break;
+                }
+                Err(Error::CompactionPreempted) => {
+                    info!("Level {} compaction preempted by level 0", p.level);
+                    let _ = self.notify_try_compact_chan.tx().try_send(());
+                }
+                Err(Error::CompactionAborted) => {
+                    info!("Level {} compaction aborted", p.level);
+                    return;
+                }
+                Err(err) => {
+                    // TODO handle error
+                    error!("Failed to do compacted, {:?}", err);
+                }
+            }
+        }
+    }
+
     // Picks some table on level l and compacts it away to the next level.
+    #[cfg_attr(feature = "tracing-spans", tracing::instrument(
+        skip_all,
+        fields(level = p.level, score = p.score)
+    ))]
     async fn do_compact(&self, p: CompactionPriority) -> Result<bool> {
         let l = p.level;
         assert!(l + 1 < self.opt.max_levels, "Sanity check"); //  Sanity check.
@@ -307,7 +459,7 @@ impl LevelsController {
                 return Ok(false);
             }
         } else {
-            if !self.fill_tables(&mut cd) {
+            if !self.fill_tables(&mut cd, p.table) {
                 info!(
                     "failed to fill tables for level {}, the compact priority: {:?}",
                     l, p
@@ -316,6 +468,12 @@ impl LevelsController {
             }
         }
         let level = cd.this_level.level();
+        self.emit(|| CompactionEvent::Picked {
+            level,
+            score: p.score,
+            top_tables: cd.top.iter().map(|tb| tb.id()).collect(),
+            bot_tables: cd.bot.iter().map(|tb| tb.id()).collect(),
+        });
         info!("Running for level: {}", level);
         self.c_status.to_log();
         let cd = TArcRW::new(tokio::sync::RwLock::new(cd));
@@ -327,11 +485,10 @@ impl LevelsController {
             self.c_status.delete(cd);
         }
         // TODO add clear
-        if compacted_res.is_err() {
-            error!(
-                "LOG Compact FAILED with error: {}",
-                compacted_res.unwrap_err().to_string()
-            );
+        match compacted_res {
+            Err(err @ (Error::CompactionPreempted | Error::CompactionAborted)) => return Err(err),
+            Err(err) => error!("LOG Compact FAILED with error: {}", err),
+            Ok(()) => {}
         }
         // Done with compaction. So, remove the ranges from compaction status.
         info!("Compaction for level: {} DONE", level);
@@ -340,48 +497,50 @@ impl LevelsController {
 
     /// Handle compact deference
     async fn run_compact_def(&self, l: usize, cd: Arc<RwLock<CompactDef>>) -> Result<()> {
+        let job = {
+            let cd = cd.read().await;
+            let job = self.running.start(cd.deref());
+            self.emit(|| CompactionEvent::Started {
+                id: job,
+                this_level: cd.this_level.level(),
+                next_level: cd.next_level.level(),
+                top_tables: cd.top.iter().map(|tb| tb.id()).collect(),
+                bot_tables: cd.bot.iter().map(|tb| tb.id()).collect(),
+            });
+            job
+        };
+        defer! {self.running.finish(job)}
+        match self.compact_def(job, l, cd).await {
+            Ok(tables) => {
+                self.emit(|| CompactionEvent::Committed { id: job, tables });
+                Ok(())
+            }
+            Err(err) => {
+                self.emit(|| CompactionEvent::Failed {
+                    id: job,
+                    error: err.to_string(),
+                });
+                Err(err)
+            }
+        }
+    }
+
+    // Runs the compaction `job` of `cd`, returns the ids of the tables added to the next level.
+    async fn compact_def(
+        &self,
+        job: u64,
+        l: usize,
+        cd: Arc<RwLock<CompactDef>>,
+    ) -> Result<Vec<u64>> {
         let time_start = SystemTime::now();
         let this_level = cd.read().await.this_level.clone();
         let next_level = cd.read().await.next_level.clone();
 
         {
             let cd = cd.read().await;
-            if this_level.level() >= 1 && cd.bot.is_empty() {
-                assert_eq!(cd.top.len(), 1);
-                let table_lck = cd.top[0].clone();
-                // We write to the manifest _before_ we delete files (and after we created files).
-                // The order matters here -- you can't temporarily have two copies of the same
-                // table id when reloading the manifest.
-                // TODO Why?
-                let delete_change = ManifestChangeBuilder::new(table_lck.id())
-                    .with_op(DELETE)
-                    .build();
-                let create_change = ManifestChangeBuilder::new(table_lck.id())
-                    .with_level(next_level.level() as u32)
-                    .with_op(CREATE)
-                    .build();
-                let changes = vec![delete_change, create_change];
-                let mut manifest = self.manifest.write().await;
-                manifest.add_changes(changes).await?;
-                // We have to add to next_level before we remove from this_level, not after. This way, we
-                // don't have a bug where reads would see keys missing from both levels.
-                //
-                // Note: It's critical that we add tables (replace them) in next_level before deleting them
-                // in this_level. (We could finagle it atomically somehow.) Also, when reading we must
-                // read, or at least acquire s.rlock(), in increasing order by level, so that we don't skip
-                // a compaction.
-                next_level.replace_tables(cd.top.clone())?;
-                let top_ids = cd.top.iter().map(|tb| tb.id()).collect::<Vec<_>>();
-                this_level.delete_tables(top_ids);
-                info!(
-                    "LOG Compact-Move {}->{} smallest:{} biggest:{} took {}",
-                    l,
-                    l + 1,
-                    hex_str(table_lck.smallest()),
-                    hex_str(table_lck.biggest()),
-                    time_start.elapsed().unwrap().as_millis(),
-                );
-                return Ok(());
+            if self.can_move_tables(&cd) {
+                self.move_tables(&cd, time_start).await?;
+                return Ok(cd.top.iter().map(|tb| tb.id()).collect());
             }
         }
 
@@ -389,16 +548,31 @@ impl LevelsController {
         let new_tables = self.compact_build_tables(l, cd.clone()).await?;
         let deref_tables = || new_tables.iter().for_each(|tb| tb.decr_ref());
         defer! {deref_tables();}
+        let new_ids = new_tables.iter().map(|tb| tb.id()).collect::<Vec<_>>();
+        self.emit(|| CompactionEvent::Produced {
+            id: job,
+            tables: new_ids.clone(),
+        });
 
         // TODO add a change commit
         info!("manifest file {:?}", self.opt);
         let cd = cd.write().await;
         let change_set = Self::build_change_set(&cd, &new_tables);
 
-        // We write to the manifest _before_ we delete files (and after we created files)
+        // We write to the manifest _before_ we delete files (and after we created files). The
+        // creations and deletions go in one change set, they're all replayed or none of them.
         {
             let mut manifest = self.manifest.write().await;
-            manifest.add_changes(change_set).await?;
+            if let Err(err) = manifest.add_changes(change_set).await {
+                // Nothing refers to the new tables, the compaction is as if it never ran.
+                remove_table_files(&new_tables);
+                return Err(err);
+            }
+        }
+
+        // The values the compaction left out are garbage in the value log now.
+        if let Err(err) = self.discard_stats.update(&cd.discarded.lock()) {
+            warn!("Failed to update the discard stats, err: {}", err);
         }
 
         // See comment earlier in this function about the ordering of these ops, and the order in which
@@ -406,6 +580,13 @@ impl LevelsController {
         next_level.replace_tables(new_tables.clone())?;
         let top_ids = cd.top.iter().map(|tb| tb.id()).collect::<Vec<_>>();
         this_level.delete_tables(top_ids);
+        // replace_tables only removes the tables overlapping the new ones, the bottom tables
+        // whose keys were all dropped or shadowed are still there.
+        next_level.delete_tables(cd.bot.iter().map(|tb| tb.id()).collect());
+        cd.top
+            .iter()
+            .chain(cd.bot.iter())
+            .for_each(|tb| tb.mark_deleted());
 
         // Note: For level 0, while do_compact is running, it is possible that new tables are added.
         // However, the tables are added only to the end, so it is ok to just delete the first table.
@@ -417,14 +598,153 @@ impl LevelsController {
             new_tables.len(),
             time_start.elapsed().unwrap().as_millis()
         );
+        if self.opt.validate_after_compaction {
+            self.validate_compaction(&cd, &new_tables).await?;
+        }
+        get_metrics().record_compaction(
+            l,
+            cd.top.len() + cd.bot.len(),
+            cd.bytes_read.load(Ordering::Relaxed),
+            new_tables.iter().map(|tb| tb.size() as u64).sum(),
+            cd.entries_dropped.load(Ordering::Relaxed),
+            time_start.elapsed().unwrap_or_default(),
+        );
         info!("this level: {:?}", this_level.to_log());
         info!("next level: {:?}", next_level.to_log());
+        Ok(new_ids)
+    }
+
+    pub(crate) fn discard_stats(&self) -> &DiscardStats {
+        &self.discard_stats
+    }
+
+    // Passes the event to the compaction listener, if any.
+    fn emit(&self, event: impl FnOnce() -> CompactionEvent) {
+        if let Some(listener) = self.opt.compaction_listener.as_ref() {
+            listener.on_event(event());
+        }
+    }
+
+    // Checks what the compaction of `cd` into `new_tables` is to keep: both levels sorted without
+    // overlaps, the key ranges of the new tables matching their blocks, and the levels and
+    // the manifest agreeing on the tables added to the next level and the ones gone.
+    async fn validate_compaction(&self, cd: &CompactDef, new_tables: &[Table]) -> Result<()> {
+        let invalid = |err: Error| -> Error {
+            format!("Invalid state after compaction {}, err: {}", cd, err).into()
+        };
+        cd.this_level.validate().map_err(invalid)?;
+        cd.next_level.validate().map_err(invalid)?;
+        for tb in new_tables {
+            tb.validate_key_range().map_err(invalid)?;
+        }
+        let in_level = |level: &LevelHandler, id: u64| {
+            level.to_ref().tables.read().iter().any(|tb| tb.id() == id)
+        };
+        let manifest = self.manifest.read().await.manifest.clone();
+        let manifest = manifest.read().await;
+        let new_ids = new_tables.iter().map(|tb| tb.id()).collect::<HashSet<_>>();
+        for id in new_ids.iter() {
+            let level = manifest.tables.get(id).map(|tm| tm.level as usize);
+            if level != Some(cd.next_level.level()) || !in_level(&cd.next_level, *id) {
+                return Err(invalid(
+                    format!(
+                        "Table {} is not in level {}, manifest level: {:?}",
+                        id,
+                        cd.next_level.level(),
+                        level
+                    )
+                    .into(),
+                ));
+            }
+        }
+        for tb in cd.top.iter().chain(cd.bot.iter()) {
+            if new_ids.contains(&tb.id()) {
+                continue;
+            }
+            if manifest.tables.contains_key(&tb.id())
+                || in_level(&cd.this_level, tb.id())
+                || in_level(&cd.next_level, tb.id())
+            {
+                return Err(invalid(
+                    format!("Compacted table {} is still there", tb.id()).into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // Returns true if the top tables of `cd` can go to the next level as they are: nothing
+    // overlaps them there nor among them, and no entry has to be dropped or filtered.
+    fn can_move_tables(&self, cd: &CompactDef) -> bool {
+        if cd.top.is_empty()
+            || !cd.bot.is_empty()
+            || !cd.drop_prefixes.is_empty()
+            || self.opt.compaction_filter.is_some()
+            || cd.this_level.level() == cd.next_level.level()
+        {
+            return false;
+        }
+        disjoint_ranges(
+            cd.top
+                .iter()
+                .map(|tb| (tb.smallest(), tb.biggest()))
+                .collect(),
+        )
+    }
+
+    // Moves the top tables of `cd` to the next level, only the manifest is written.
+    async fn move_tables(&self, cd: &CompactDef, time_start: SystemTime) -> Result<()> {
+        let (this_level, next_level) = (&cd.this_level, &cd.next_level);
+        // A table id is deleted before it's created again, you can't temporarily have two
+        // copies of the same table id when reloading the manifest.
+        let changes = cd
+            .top
+            .iter()
+            .flat_map(|tb| {
+                [
+                    ManifestChangeBuilder::new(tb.id()).with_op(DELETE).build(),
+                    ManifestChangeBuilder::new(tb.id())
+                        .with_level(next_level.level() as u32)
+                        .with_op(CREATE)
+                        .build(),
+                ]
+            })
+            .collect::<Vec<_>>();
+        self.manifest.write().await.add_changes(changes).await?;
+        // We have to add to next_level before we remove from this_level, not after. This way, we
+        // don't have a bug where reads would see keys missing from both levels.
+        let mut top = cd.top.clone();
+        top.sort_by(|a, b| a.smallest().cmp(b.smallest()));
+        next_level.replace_tables(top)?;
+        this_level.delete_tables(cd.top.iter().map(|tb| tb.id()).collect());
+        info!(
+            "LOG Compact-Move {}->{} tables:{:?} took {}ms",
+            this_level.level(),
+            next_level.level(),
+            cd.top.iter().map(|tb| tb.id()).collect::<Vec<_>>(),
+            time_start.elapsed().unwrap().as_millis(),
+        );
+        if self.opt.validate_after_compaction {
+            self.validate_compaction(cd, &cd.top).await?;
+        }
+        get_metrics().record_compaction(
+            this_level.level(),
+            cd.top.len(),
+            0,
+            0,
+            0,
+            time_start.elapsed().unwrap_or_default(),
+        );
         Ok(())
     }
 
     // async to add level0 table
     pub(crate) async fn add_level0_table(&self, table: Table) -> Result<()> {
         defer! {warn!("Finish add level0 table, fid: {}", table.id())}
+        // Wait for room before the table goes to the manifest, a flush that gives up on a
+        // stall must leave no trace.
+        self.wait_level0_room(self.opt.level_zero_stall_timeout)
+            .await?;
         // We update the manifest _before_ the table becomes part of a levelHandler, because at that
         // point it could get used in some compaction.  This ensures the manifest file gets updated in
         // the proper order. (That means this update happens before that of some compaction which
@@ -438,9 +758,23 @@ impl LevelsController {
                 .build()])
             .await?;
         info!("Ready add level0 table, id:{}", table.id());
+        while !self.levels[0].try_add_level0_table(table.clone()).await {
+            // Filled up meanwhile, the table is in the manifest already so wait for good.
+            self.wait_level0_room(None).await?;
+        }
+        // Wake up a compaction worker, level 0 may need a compaction now.
+        let _ = self.notify_try_compact_chan.tx().try_send(());
+        let _ = self.notify_write_request_chan.tx().try_send(());
+        Ok(())
+    }
+
+    // Stalls while level 0 has `num_level_zero_tables_stall` tables, until compaction brings
+    // it back under `num_level_zero_tables`. Gives up with `LevelZeroStalled` after `timeout`.
+    async fn wait_level0_room(&self, timeout: Option<Duration>) -> Result<()> {
+        let deadline = timeout.map(|timeout| SystemTime::now() + timeout);
         let zero_level_compact_chan = self.zero_level_compact_chan.rx();
         let notify_try_compact_chan = self.notify_try_compact_chan.tx();
-        while !self.levels[0].try_add_level0_table(table.clone()).await {
+        while self.is_level0_stalled() {
             // Notify compact job
             notify_try_compact_chan.try_send(());
             // Stall. Make sure all levels are healthy before we unstall.
@@ -481,6 +815,9 @@ impl LevelsController {
                 if !self.is_level0_compactable() && !self.levels[1].is_compactable(del_size) {
                     break;
                 }
+                if deadline.map_or(false, |deadline| SystemTime::now() >= deadline) {
+                    return Err(Error::LevelZeroStalled);
+                }
                 tokio::select! {
                      _ = tokio::time::sleep(Duration::from_millis(10)) => {},
                     _ = zero_level_compact_chan.recv() => {
@@ -498,24 +835,36 @@ impl LevelsController {
             );
             *self.last_unstalled.write().await = SystemTime::now();
         }
-        let _ = self.notify_write_request_chan.tx().try_send(());
         Ok(())
     }
 
     pub(crate) fn as_iterator(
         &self,
         reverse: bool,
+        prefix: &[u8],
     ) -> Vec<Box<dyn Xiterator<Output = IteratorItem>>> {
         let mut itrs: Vec<Box<dyn Xiterator<Output = IteratorItem>>> = vec![];
         for level in self.levels.iter() {
             if level.level() == 0 {
                 for table in level.tables.read().iter().rev() {
-                    let itr = Box::new(IteratorImpl::new(table.clone(), reverse));
+                    if !table.may_contain_prefix(prefix) {
+                        continue;
+                    }
+                    let itr = Box::new(
+                        IteratorImpl::new(table.clone(), reverse)
+                            .prefetch(self.opt.prefetch_blocks),
+                    );
                     itrs.push(itr);
                 }
             } else {
                 for table in level.tables.read().iter() {
-                    let itr = Box::new(IteratorImpl::new(table.clone(), reverse));
+                    if !table.may_contain_prefix(prefix) {
+                        continue;
+                    }
+                    let itr = Box::new(
+                        IteratorImpl::new(table.clone(), reverse)
+                            .prefetch(self.opt.prefetch_blocks),
+                    );
                     itrs.push(itr);
                 }
             }
@@ -524,7 +873,7 @@ impl LevelsController {
     }
 
     // Merge top tables and bot tables to from a List of new tables.
-    #[instrument(skip(self))]
+    #[cfg_attr(feature = "tracing-spans", tracing::instrument(skip(self)))]
     pub(crate) async fn compact_build_tables(
         &self,
         l: usize,
@@ -543,8 +892,6 @@ impl LevelsController {
             let cd = cd.read().await;
             let mut top_tables = cd.top.clone();
             let bot_tables = cd.bot.clone();
-            // Create iterators across all the tables involved first.
-            let mut itr: Vec<Box<dyn Xiterator<Output = IteratorItem>>> = vec![];
             if l == 0 {
                 info!(
                     "reverse the table, {:?}",
@@ -555,116 +902,81 @@ impl LevelsController {
                     "after reverse the table, {:?}",
                     top_tables.iter().map(|tb| tb.id()).collect::<Vec<_>>()
                 );
-            } else {
+            } else if cd.drop_prefixes.is_empty() {
                 assert_eq!(1, top_tables.len());
             }
-            let is_empty = bot_tables.is_empty();
-            for tb in top_tables {
-                let iter = Box::new(IteratorImpl::new(tb, false));
-                itr.push(iter);
+            // The inputs are read once from start to end, and deleted afterwards.
+            top_tables
+                .iter()
+                .chain(bot_tables.iter())
+                .for_each(|tb| tb.advise(AccessHint::Sequential));
+            let discard_ts = self.compaction_discard_ts();
+            // Nothing older than the compacted keys below the output level, their tombstones
+            // shadow nothing.
+            let drop_tombstones = {
+                let tables = top_tables
+                    .iter()
+                    .chain(bot_tables.iter())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let kr = KeyRange::get_range(&tables);
+                self.levels[cd.next_level.level() + 1..]
+                    .iter()
+                    .all(|level| level.overlapping_tables(&kr) == (0, 0))
+            };
+            let mut ranges = subcompaction_ranges(
+                &bot_tables
+                    .iter()
+                    .map(|tb| tb.smallest())
+                    .collect::<Vec<_>>(),
+                self.opt.max_subcompactions,
+            );
+            if discard_ts > 0 {
+                // All the versions of a key go to the same range, before its newest one.
+                let split =
+                    |key: Option<Vec<u8>>| key.map(|key| key_with_ts(parse_key(&key), u64::MAX));
+                ranges = ranges
+                    .into_iter()
+                    .map(|(left, right)| (split(left), split(right)))
+                    .collect();
             }
-            // Next level has level>=1, so we can use ConcatIterator as key ranges do not overlap.
-            let citr = ConcatIterator::new(bot_tables, false);
-            itr.push(Box::new(citr));
-            let mitr = MergeIterOverBuilder::default().add_batch(itr).build();
-            // Important to close the iterator to do ref counting.
-            defer! {mitr.close()}
-            // {
-            //     mitr.rewind();
-            //     mitr.export_disk_ext();
-            // }
-            mitr.rewind();
-            let tid = random::<u32>();
-            let mut count = 0;
+            info!("Split compaction into {} key ranges", ranges.len());
             let cur = tokio::runtime::Handle::current();
-            loop {
-                // #[cfg(test)]
-                // let mut keys = vec![];
-                let start_time = SystemTime::now();
-                let mut builder = Builder::default();
-                while let Some(value) = mitr.peek() {
-                    count += 1;
-                    assert!(builder.add(value.key(), value.value()).is_ok());
-                    mitr.next();
-                    if builder.reached_capacity(self.opt.max_table_size) {
-                        break;
-                    }
-
-                    // #[cfg(test)]
-                    // {
-                    //     // error!("merge, mitr{}, key {}", mitr.id(), hex_str(value.key()));
-                    //     {
-                    //         crate::test_util::push_log(
-                    //             format!(
-                    //                 "tid:{}, mitr:{}, key:{}",
-                    //                 tid,
-                    //                 mitr.id(),
-                    //                 hex_str(value.key())
-                    //             )
-                    //             .as_bytes(),
-                    //             false,
-                    //         );
-                    //     }
-                    //     keys.push(value.key().to_vec());
-                    // }
-                }
-                if builder.is_zero_bytes() {
-                    warn!("Builder is empty");
-                    break;
-                }
-
-                let file_id = self.reserve_file_id();
-                // It was true that it.Valid() at least once in the loop above, which means we
-                // called Add() at least once, and builder is not Empty().
-                info!(
-                    "LOG Compacted: Iteration to generate one table [{}] took: {}ms",
-                    file_id,
-                    start_time.elapsed().unwrap().as_millis()
-                );
-
-                let dir = self.opt.dir.clone();
-                let file_name = new_file_name(file_id, &dir);
-                // #[cfg(test)]
-                // {
-                //     let str = keys.into_iter().map(|key| hex_str(&key)).join(",");
-                //     debug!("Save keys into {} {}", file_name, str);
-                // }
-                let worker = g.worker();
+            // The iterators stay on the thread they're made on, so every shard builds its own
+            // over the same tables.
+            let mut shards = Vec::with_capacity(ranges.len());
+            for (left, right) in ranges {
+                let lc = self.clone();
+                let job = SubCompaction {
+                    top: top_tables.clone(),
+                    bot: bot_tables.clone(),
+                    left,
+                    right,
+                    drop_prefixes: cd.drop_prefixes.clone(),
+                    level: cd.next_level.level(),
+                    bytes_read: cd.bytes_read.clone(),
+                    entries_dropped: cd.entries_dropped.clone(),
+                    discarded: cd.discarded.clone(),
+                    // Level 1 ranges held by the compaction block level 0 compactions.
+                    preemptible: l == 1 && cd.drop_prefixes.is_empty(),
+                    discard: DiscardFilter::new(
+                        discard_ts,
+                        self.opt.num_versions_to_keep,
+                        drop_tombstones,
+                    ),
+                };
                 let tx = tx.clone();
-                let loading_mode = self.opt.table_loading_mode;
-                tokio::spawn(async move {
-                    defer! {worker.done();}
-                    let fd = create_synced_file(&file_name, true);
-                    if let Err(err) = fd {
-                        tx.send(Err(format!(
-                            "While opening new table: {}, err: {}",
-                            file_id, err
-                        )
-                        .into()))
-                            .unwrap();
-                        return;
-                    }
-                    if let Err(err) = fd.as_ref().unwrap().write_all(&builder.finish()) {
-                        tx.send(Err(format!(
-                            "Unable to write to file: {}, err: {}",
-                            file_id, err
-                        )
-                        .into()))
-                            .unwrap();
-                        return;
-                    }
-                    let tbl = TableCore::open_table(fd.unwrap(), &file_name, loading_mode);
-                    if let Err(err) = tbl {
-                        tx.send(Err(format!(
-                            "Unable to open table: {}, err: {}",
-                            file_name, err
-                        )
-                        .into()))
-                            .unwrap();
-                    } else {
-                        tx.send(Ok(Table::new(tbl.unwrap()))).unwrap();
-                    }
-                });
+                let worker = g.worker();
+                let cur = cur.clone();
+                shards.push(tokio::task::spawn_blocking(move || {
+                    lc.build_range_tables(job, &cur, &tx, worker)
+                }));
+            }
+            for shard in shards {
+                if let Err(err) = shard.await {
+                    tx.send(Err(format!("Subcompaction failed, err: {}", err).into()))
+                        .unwrap();
+                }
             }
         }
         g.wait().await;
@@ -706,7 +1018,11 @@ impl LevelsController {
         if first_err.is_err() {
             // An error happened. Delete all the newly created table files (by calling Decref
             // -- we're the only holders of a ref).
-            let _ = new_tables.iter().map(|tb| tb.decr_ref());
+            new_tables.iter().for_each(|tb| tb.decr_ref());
+            remove_table_files(&new_tables);
+            if let Err(Error::CompactionPreempted) = first_err {
+                return Err(Error::CompactionPreempted);
+            }
             return Err(format!(
                 "While running compaction for: {}, err: {}",
                 cd.read().await,
@@ -717,6 +1033,359 @@ impl LevelsController {
         Ok(new_tables)
     }
 
+    // Builds the tables of the keys of the `job` range. The tables are written on `cur` and
+    // sent to `tx`, `worker` is held until they're done.
+    fn build_range_tables(
+        &self,
+        job: SubCompaction,
+        cur: &tokio::runtime::Handle,
+        tx: &UnboundedSender<Result<Table>>,
+        worker: Worker,
+    ) {
+        let SubCompaction {
+            top: top_tables,
+            bot: bot_tables,
+            left,
+            right,
+            drop_prefixes,
+            level,
+            bytes_read,
+            entries_dropped,
+            discarded,
+            preemptible,
+            mut discard,
+        } = job;
+        // Create iterators across all the tables involved first.
+        let mut itr: Vec<Box<dyn Xiterator<Output = IteratorItem>>> = vec![];
+        for tb in top_tables {
+            let iter = Box::new(IteratorImpl::new(tb, false).prefetch(self.opt.prefetch_blocks));
+            itr.push(iter);
+        }
+        // Next level has level>=1, so we can use ConcatIterator as key ranges do not overlap.
+        let citr = ConcatIterator::new_with_prefetch(bot_tables, false, self.opt.prefetch_blocks);
+        itr.push(Box::new(citr));
+        let mitr = {
+            let discarded = discarded.clone();
+            let right = right.clone();
+            MergeIterOverBuilder::default()
+                .add_batch(itr)
+                .on_shadowed(move |item| {
+                    // The key at `right` belongs to the next range.
+                    if right
+                        .as_ref()
+                        .map_or(true, |right| item.key() < right.as_slice())
+                    {
+                        add_discarded(&discarded, item.value());
+                    }
+                })
+                .build()
+        };
+        // Important to close the iterator to do ref counting.
+        defer! {mitr.close()}
+        match left.as_ref() {
+            Some(left) => mitr.seek(left),
+            None => mitr.rewind(),
+        };
+        // Bytes read and not yet accounted to the limiter, it's taken in chunks.
+        let mut read = 0;
+        let now = self.opt.clock.now();
+        // The user key of the last version kept, the older versions kept behind it are stale.
+        let mut last_key: Vec<u8> = vec![];
+        loop {
+            if self.aborted.load(Ordering::Acquire) {
+                tx.send(Err(Error::CompactionAborted)).unwrap();
+                return;
+            }
+            if preemptible && self.is_level0_stalled() {
+                tx.send(Err(Error::CompactionPreempted)).unwrap();
+                return;
+            }
+            let start_time = SystemTime::now();
+            let data_key = match self.data_key() {
+                Ok(data_key) => data_key,
+                Err(err) => {
+                    tx.send(Err(err)).unwrap();
+                    return;
+                }
+            };
+            let mut builder = Builder::new(self.opt.table_size(level), self.opt.block_size)
+                .with_compression(self.opt.compression, self.opt.zstd_compression_level)
+                .with_filter(self.opt.filter_type)
+                .with_data_key(data_key);
+            while let Some(value) = mitr.peek() {
+                if let Some(right) = right.as_ref() {
+                    if value.key() >= right.as_slice() {
+                        break;
+                    }
+                }
+                let n = value.key().len() + value.value().size();
+                bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+                if let Some(limiter) = self.compaction_limiter.as_ref() {
+                    read += n;
+                    if read >= 64 << 10 {
+                        limiter.wait_blocking(read as u64);
+                        read = 0;
+                    }
+                }
+                // An expired value is a tombstone, older versions must not show up again.
+                let expired = value.value().is_expired(now);
+                let meta = if expired {
+                    MetaBit::BIT_DELETE.bits()
+                } else {
+                    value.value().meta
+                };
+                if drop_prefixes.iter().any(|p| value.key().starts_with(p))
+                    || !discard.keep(value.key(), meta)
+                {
+                    entries_dropped.fetch_add(1, Ordering::Relaxed);
+                    add_discarded(&discarded, value.value());
+                    mitr.next();
+                    continue;
+                }
+                // The internal keys are written without a version.
+                let stale = self.opt.managed_txns
+                    && !value.key().starts_with(_BADGER_PREFIX)
+                    && parse_key(value.key()) == last_key.as_slice();
+                if !stale {
+                    last_key.clear();
+                    last_key.extend_from_slice(parse_key(value.key()));
+                }
+                let add = |builder: &mut Builder, vs: &ValueStruct| {
+                    let res = if stale {
+                        builder.add_stale_key(value.key(), vs)
+                    } else {
+                        builder.add(value.key(), vs)
+                    };
+                    assert!(res.is_ok());
+                };
+                let tombstone;
+                let vs = if expired {
+                    add_discarded(&discarded, value.value());
+                    tombstone = ValueStruct::new(vec![], meta, 0, value.value().cas_counter);
+                    &tombstone
+                } else {
+                    value.value()
+                };
+                match self.opt.compaction_filter.as_ref() {
+                    Some(filter) => {
+                        match apply_compaction_filter(
+                            filter.as_ref(),
+                            level,
+                            value.key(),
+                            vs,
+                            discard.drop_tombstones,
+                        ) {
+                            Some(new_vs) => {
+                                if new_vs.meta & MetaBit::BIT_VALUE_POINTER.bits() == 0 {
+                                    add_discarded(&discarded, vs);
+                                }
+                                add(&mut builder, &new_vs);
+                            }
+                            None => {
+                                entries_dropped.fetch_add(1, Ordering::Relaxed);
+                                add_discarded(&discarded, vs);
+                            }
+                        }
+                    }
+                    None => add(&mut builder, vs),
+                }
+                mitr.next();
+                if builder.should_finish() {
+                    break;
+                }
+            }
+            if builder.is_zero_bytes() {
+                break;
+            }
+
+            let file_id = self.reserve_file_id();
+            // It was true that it.Valid() at least once in the loop above, which means we
+            // called Add() at least once, and builder is not Empty().
+            info!(
+                "LOG Compacted: Iteration to generate one table [{}] took: {}ms",
+                file_id,
+                start_time.elapsed().unwrap().as_millis()
+            );
+
+            let dir = self.opt.dir.clone();
+            let worker = worker.clone();
+            let tx = tx.clone();
+            let loading_mode = self.opt.table_loading_mode;
+            let verification = self.opt.checksum_verification_mode;
+            let index_cache = self.index_cache.clone();
+            let block_cache = self.block_cache.clone();
+            let limiter = self.compaction_limiter.clone();
+            let keys = self.keys.clone();
+            cur.spawn(async move {
+                defer! {worker.done();}
+                let data = builder.finish();
+                if let Some(limiter) = limiter {
+                    limiter.wait(data.len() as u64).await;
+                }
+                let fd = create_table_file(file_id, &dir);
+                if let Err(err) = fd {
+                    tx.send(Err(format!(
+                        "While opening new table: {}, err: {}",
+                        file_id, err
+                    )
+                    .into()))
+                        .unwrap();
+                    return;
+                }
+                let mut fd = fd.unwrap();
+                if let Err(err) = fd.write_all(&data) {
+                    tx.send(Err(format!(
+                        "Unable to write to file: {}, err: {}",
+                        file_id, err
+                    )
+                    .into()))
+                        .unwrap();
+                    return;
+                }
+                let (fd, file_name) = match fd.finish() {
+                    Ok(ret) => ret,
+                    Err(err) => {
+                        tx.send(Err(err)).unwrap();
+                        return;
+                    }
+                };
+                let tbl =
+                    TableCore::open_table_with_keys(fd, &file_name, loading_mode, Some(&keys))
+                        .and_then(|tb| {
+                            if verification.on_table_read() {
+                                tb.verify_checksum()?;
+                            }
+                            Ok(tb
+                                .with_block_checksums(verification.on_block_read())
+                                .with_block_cache(block_cache))
+                        });
+                if let Err(err) = tbl {
+                    tx.send(Err(format!(
+                        "Unable to open table: {}, err: {}",
+                        file_name, err
+                    )
+                    .into()))
+                        .unwrap();
+                } else {
+                    tx.send(Ok(Table::new(tbl.unwrap().with_index_cache(index_cache))))
+                        .unwrap();
+                }
+            });
+        }
+    }
+
+    /// Removes the keys with any of `prefixes` from all levels, compactions are paused
+    /// meanwhile. Level 0 is compacted into level 1 leaving the keys out. On the other levels
+    /// the tables holding only such keys are deleted, and the runs of tables that may hold
+    /// some are rewritten in place without them.
+    pub(crate) async fn drop_prefixes(&self, prefixes: Vec<Vec<u8>>) -> Result<()> {
+        self.pause_compactions().await;
+        defer! {self.resume_compactions()}
+        let l0_tables = self.levels[0].to_ref().tables.read().to_vec();
+        if !l0_tables.is_empty() {
+            let mut cd = CompactDef::new(self.levels[0].clone(), self.levels[1].clone());
+            cd.this_range = INFO_RANGE;
+            let kr = KeyRange::get_range(&l0_tables);
+            let (left, right) = cd.next_level.overlapping_tables(&kr);
+            cd.bot = cd.next_level.to_ref().tables.read()[left..right].to_vec();
+            cd.top = l0_tables;
+            cd.drop_prefixes = prefixes.clone();
+            self.run_compact_def(0, TArcRW::new(RwLock::new(cd)))
+                .await?;
+        }
+        for level in self.levels[1..].iter() {
+            let mut to_delete = vec![];
+            let mut groups = vec![vec![]];
+            for tb in level.to_ref().tables.read().iter() {
+                let covered = prefixes
+                    .iter()
+                    .any(|p| tb.smallest().starts_with(p) && tb.biggest().starts_with(p));
+                if covered {
+                    to_delete.push(tb.clone());
+                } else if contains_any_prefix(tb.smallest(), tb.biggest(), &prefixes) {
+                    groups.last_mut().unwrap().push(tb.clone());
+                    continue;
+                }
+                if !groups.last().unwrap().is_empty() {
+                    groups.push(vec![]);
+                }
+            }
+            if !to_delete.is_empty() {
+                let ids = to_delete.iter().map(|tb| tb.id()).collect::<Vec<_>>();
+                let changes = ids
+                    .iter()
+                    .map(|id| ManifestChangeBuilder::new(*id).with_op(DELETE).build())
+                    .collect::<Vec<_>>();
+                self.manifest.write().await.add_changes(changes).await?;
+                info!(
+                    "Drop prefixes, delete tables {:?} of level {}",
+                    ids,
+                    level.level()
+                );
+                level.delete_tables(ids);
+                to_delete.iter().for_each(|tb| tb.mark_deleted());
+            }
+            for group in groups.into_iter().filter(|group| !group.is_empty()) {
+                let mut cd = CompactDef::new(level.clone(), level.clone());
+                cd.bot = group;
+                cd.next_range = KeyRange::get_range(&cd.bot);
+                cd.drop_prefixes = prefixes.clone();
+                self.run_compact_def(level.level(), TArcRW::new(RwLock::new(cd)))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the tables of all levels with compactions paused, and rewrites the manifest
+    /// empty. Returns the number of tables deleted.
+    pub(crate) async fn drop_all(&self) -> Result<usize> {
+        self.pause_compactions().await;
+        defer! {self.resume_compactions()}
+        let tables = self
+            .levels
+            .iter()
+            .map(|level| level.to_ref().tables.read().clone())
+            .collect::<Vec<_>>();
+        let changes = tables
+            .iter()
+            .flatten()
+            .map(|tb| ManifestChangeBuilder::new(tb.id()).with_op(DELETE).build())
+            .collect::<Vec<_>>();
+        let count = changes.len();
+        {
+            let mut manifest = self.manifest.write().await;
+            manifest.add_changes(changes).await?;
+            manifest.rewrite().await?;
+        }
+        for (level, tables) in self.levels.iter().zip(tables) {
+            level.delete_tables(tables.iter().map(|tb| tb.id()).collect());
+            tables.iter().for_each(|tb| tb.mark_deleted());
+        }
+        Ok(count)
+    }
+
+    // Waits for the running compactions to finish and keeps new ones from starting. A level
+    // is held as soon as it has no compaction, which also holds off the compactions into it.
+    async fn pause_compactions(&self) {
+        let mut paused = vec![false; self.levels.len()];
+        loop {
+            for (level, paused) in paused.iter_mut().enumerate() {
+                if !*paused {
+                    *paused = self.c_status.try_hold_level(level);
+                }
+            }
+            if paused.iter().all(|paused| *paused) {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn resume_compactions(&self) {
+        (0..self.levels.len()).for_each(|level| self.c_status.release_level(level));
+    }
+
     fn build_change_set(cd: &CompactDef, new_tables: &Vec<Table>) -> Vec<ManifestChange> {
         // new tables to CREATE
         let mut changes = new_tables
@@ -782,8 +1451,8 @@ impl LevelsController {
         true
     }
 
-    // fill tables for compactDef, and locked them KeyRange
-    fn fill_tables(&self, cd: &mut CompactDef) -> bool {
+    // fill tables for compactDef, and locked them KeyRange. The `preferred` table is tried first.
+    fn fill_tables(&self, cd: &mut CompactDef, preferred: Option<u64>) -> bool {
         // lock current level and next levels, So there is at most one compression process per layer
         cd.lock_exclusive_levels();
         let mut tables = cd.this_level.to_ref().tables.read().to_vec();
@@ -792,10 +1461,20 @@ impl LevelsController {
             cd.unlock_exclusive_levels();
             return false;
         }
-        // Find the biggest table, and compact that first.
-        // TODO: Try other table picking strategies.
-        tables.sort_by(|a, b| b.size().cmp(&a.size()));
+        // Find the table with the most stale data, and compact that first, then fall back to the biggest one.
+        tables.sort_by(|a, b| {
+            b.stale_data_size()
+                .cmp(&a.stale_data_size())
+                .then_with(|| b.size().cmp(&a.size()))
+        });
+        if let Some(i) = tables.iter().position(|t| Some(t.id()) == preferred) {
+            let t = tables.remove(i);
+            tables.insert(0, t);
+        }
         for t in tables {
+            if self.c_status.is_compacting(t.id()) {
+                continue;
+            }
             let this_range = KeyRange {
                 left: t.smallest().to_vec(),
                 right: t.biggest().to_vec(),
@@ -813,6 +1492,7 @@ impl LevelsController {
             }
             cd.this_size.store(t.size() as u64, Ordering::Relaxed);
             cd.this_range = this_range;
+            cd.seek = Some(t.id()) == preferred;
             {
                 cd.top.clear();
                 cd.top.push(t);
@@ -862,38 +1542,284 @@ impl LevelsController {
 
     // Determines which level to compact.
     // Base on https://github.com/facebook/rocksdb/wiki/Leveled-Compaction.
-    #[instrument(skip(self))]
+    #[cfg_attr(feature = "tracing-spans", tracing::instrument(skip(self)))]
     fn pick_compact_levels(&self) -> Vec<CompactionPriority> {
         // This function must use identical criteria for guaranteeing compaction's progress that
         // add_level0_table use.
 
-        let mut prios = vec![];
+        let prios = (0..self.levels.len())
+            .map(|level| CompactionPriority::new(level, self.level_score(level)))
+            .collect::<Vec<_>>();
+        let mut prios = adjust_compaction_priorities(prios);
+        self.add_seek_compactions(&mut prios);
+        if self.is_level0_stalled() {
+            escalate_level0(&mut prios);
+        }
         // c_status is checked to see if level 0's tables are already being compacted.
         // *NOTICE* level 0 only has one compact job
-        if !self.c_status.overlaps_with(0, &INFO_RANGE) && self.is_level0_compactable() {
-            prios.push(CompactionPriority {
-                level: 0,
-                score: (self.levels[0].num_tables() as f64)
-                    / (self.opt.num_level_zero_tables as f64),
-            });
-            info!("level0 will be compacted");
-        }
-        // stats level 1..n
-        for (i, level) in self.levels[1..].iter().enumerate() {
-            // Don't consider those tables that are already being compacted right now.
-            // let del_size = self.c_status.del_size(i + 1);
-            let del_size = 0;
-            if level.is_compactable(del_size) {
-                prios.push(CompactionPriority {
-                    level: i + 1,
-                    score: ((level.get_total_size() - del_size) as f64
-                        / level.get_max_total_size() as f64),
+        if self.c_status.overlaps_with(0, &INFO_RANGE) {
+            prios.retain(|p| p.level != 0);
+        }
+        prios
+    }
+
+    // Picks on every level but the last the table with the most useless probes past its
+    // allowance, see `TableCore::record_useless_probe`. Its level is compacted after the
+    // levels over their limit, starting from that table.
+    fn add_seek_compactions(&self, prios: &mut Vec<CompactionPriority>) {
+        for level in 1..self.levels.len() - 1 {
+            let table = self.levels[level]
+                .tables
+                .read()
+                .iter()
+                .filter(|tb| {
+                    self.opt
+                        .allowed_useless_probes(tb.size() as u64)
+                        .is_some_and(|allowed| tb.useless_probes() >= allowed)
+                })
+                .max_by_key(|tb| tb.useless_probes())
+                .map(|tb| tb.id());
+            if table.is_none() {
+                continue;
+            }
+            match prios.iter_mut().find(|p| p.level == level) {
+                Some(p) => p.table = table,
+                None => {
+                    let mut p = CompactionPriority::new(level, self.level_score(level));
+                    p.table = table;
+                    prios.push(p);
+                }
+            }
+        }
+    }
+
+    /// Versions at or below the discard ts are not read anymore, compactions keep only
+    /// `num_versions_to_keep` of them. 0, the default, means keys are not versioned.
+    pub(crate) fn set_discard_ts(&self, ts: u64) {
+        self.discard_ts.store(ts, Ordering::Release);
+    }
+
+    pub(crate) fn discard_ts(&self) -> u64 {
+        self.discard_ts.load(Ordering::Acquire)
+    }
+
+    // The discard ts, lowered to the read ts of the oldest snapshot so its versions are kept.
+    pub(crate) fn compaction_discard_ts(&self) -> u64 {
+        match self.read_mark.load(Ordering::Acquire) {
+            0 => self.discard_ts(),
+            mark => self.discard_ts().min(mark),
+        }
+    }
+
+    pub(crate) fn keys(&self) -> &KeyRegistry {
+        &self.keys
+    }
+
+    // The data key the new tables are encrypted with, None if they're plain.
+    pub(crate) fn data_key(&self) -> Result<Option<DataKey>> {
+        self.keys.latest_data_key(self.opt.clock.now())
+    }
+
+    // Level 0 is scored by its number of tables, the other levels by their size.
+    fn level_score(&self, level: usize) -> f64 {
+        let handler = &self.levels[level];
+        if level == 0 {
+            return handler.num_tables() as f64 / self.opt.num_level_zero_tables as f64;
+        }
+        // Don't consider those tables that are already being compacted right now.
+        // let del_size = self.c_status.del_size(i + 1);
+        let del_size = 0;
+        (handler.get_total_size() - del_size) as f64 / handler.get_max_total_size() as f64
+    }
+
+    pub(crate) fn compaction_status(&self) -> CompactionStatus {
+        let levels = self
+            .levels
+            .iter()
+            .map(|handler| {
+                let num_tables = handler.num_tables();
+                let total_size = handler.get_total_size();
+                let max_size = handler.get_max_total_size();
+                // The same as level_score, from a single read of the level.
+                let score = if handler.level() == 0 {
+                    num_tables as f64 / self.opt.num_level_zero_tables as f64
+                } else {
+                    total_size as f64 / max_size as f64
+                };
+                LevelCompactionStatus {
+                    level: handler.level(),
+                    num_tables,
+                    total_size,
+                    max_size,
+                    score,
+                }
+            })
+            .collect();
+        CompactionStatus {
+            jobs: self.running.jobs(),
+            levels,
+        }
+    }
+
+    pub(crate) fn levels_info(&self) -> Vec<LevelInfo> {
+        self.compaction_status()
+            .levels
+            .into_iter()
+            .map(|status| {
+                let stale_size = self.levels[status.level]
+                    .tables
+                    .read()
+                    .iter()
+                    .map(|tb| tb.stale_data_size())
+                    .sum();
+                LevelInfo {
+                    level: status.level,
+                    num_tables: status.num_tables,
+                    size: status.total_size,
+                    stale_size,
+                    max_size: status.max_size,
+                    score: status.score,
+                    // The levels have fixed sizes, level 0 always goes to level 1.
+                    is_base: status.level == 1,
+                }
+            })
+            .collect()
+    }
+
+    pub(crate) fn tables(&self, with_keys_count: bool) -> Result<Vec<TableInfo>> {
+        let mut tables = vec![];
+        for handler in self.levels.iter() {
+            for tb in handler.tables.read().iter() {
+                let key_count = if with_keys_count {
+                    tb.stats()?.entries
+                } else {
+                    0
+                };
+                tables.push(TableInfo {
+                    id: tb.id(),
+                    level: handler.level(),
+                    smallest: tb.smallest().to_vec(),
+                    biggest: tb.biggest().to_vec(),
+                    size: tb.size() as u64,
+                    key_count,
                 });
             }
         }
-        // sort from big to small.
-        prios.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
-        prios
+        tables.sort_by_key(|tb| (tb.level, tb.id));
+        Ok(tables)
+    }
+
+    // Returns the bytes of the table files of every level.
+    pub(crate) fn tables_size(&self) -> u64 {
+        self.levels
+            .iter()
+            .flat_map(|handler| handler.tables.read().clone())
+            .map(|tb| tb.size() as u64)
+            .sum()
+    }
+
+    // Returns the bytes of the blocks whose keys may start with `prefix`, see
+    // `TableCore::prefix_size`. Internal keys aren't counted.
+    pub(crate) fn estimate_size(&self, prefix: &[u8]) -> u64 {
+        if prefix.starts_with(_BADGER_PREFIX) {
+            return 0;
+        }
+        let cut = |key: &[u8]| key[..key.len().min(prefix.len())].to_vec();
+        let mut size = 0;
+        for handler in self.levels.iter() {
+            for tb in handler.tables.read().iter() {
+                if cut(tb.smallest()).as_slice() <= prefix && cut(tb.biggest()).as_slice() >= prefix
+                {
+                    size += tb.prefix_size(prefix);
+                }
+            }
+        }
+        size
+    }
+
+    pub(crate) fn key_splits(&self, prefix: &[u8], n: usize) -> Vec<Vec<u8>> {
+        let mut bounds = vec![];
+        for handler in self.levels.iter() {
+            for tb in handler.tables.read().iter() {
+                let key = tb.smallest();
+                if key.starts_with(prefix) && !key.starts_with(_BADGER_PREFIX) {
+                    bounds.push((key.to_vec(), tb.size() as u64));
+                }
+            }
+        }
+        split_keys(bounds, n)
+    }
+
+    // Verifies the blocks of every table on blocking threads, a few at a time. Returns the
+    // tables checked and the corrupt ones, by level and id, with the blocks that failed.
+    pub(crate) async fn verify_checksum(&self) -> Result<(usize, Vec<(usize, u64, String)>)> {
+        let tables = self
+            .levels
+            .iter()
+            .flat_map(|handler| {
+                let level = handler.level();
+                let tables = handler.tables.read().clone();
+                tables.into_iter().map(move |tb| (level, tb))
+            })
+            .collect::<Vec<_>>();
+        let parallel = std::thread::available_parallelism().map_or(4, |n| n.get());
+        let workers = Arc::new(Semaphore::new(parallel));
+        let mut checks = Vec::with_capacity(tables.len());
+        for (level, tb) in tables.iter() {
+            // Never closed.
+            let permit = workers.clone().acquire_owned().await.unwrap();
+            let (id, tb) = (tb.id(), tb.clone());
+            let check = tokio::task::spawn_blocking(move || {
+                let res = tb.verify_checksum();
+                drop(permit);
+                res
+            });
+            checks.push((*level, id, check));
+        }
+        let mut corrupt = vec![];
+        for (level, id, check) in checks {
+            let res = check
+                .await
+                .map_err(|err| format!("Table verification panicked, {}", err))?;
+            if let Err(err) = res {
+                corrupt.push((level, id, err.to_string()));
+            }
+        }
+        corrupt.sort();
+        Ok((tables.len(), corrupt))
+    }
+
+    pub(crate) fn level_iterator(&self, level: usize) -> Result<ConcatIterator> {
+        if level == 0 || level >= self.levels.len() {
+            return Err(format!(
+                "Invalid level {} to iterate, expected 1 to {}",
+                level,
+                self.levels.len() - 1
+            )
+            .into());
+        }
+        // The iterator owns a handle of every table, their files outlive a compaction.
+        let tables = self.levels[level].tables.read().clone();
+        Ok(ConcatIterator::new_with_prefetch(
+            tables,
+            false,
+            self.opt.prefetch_blocks,
+        ))
+    }
+
+    /// Makes the running compactions give up between two tables, and no new ones start.
+    /// The tables they built are removed, the levels are left as they were.
+    pub(crate) fn abort_compactions(&self) {
+        self.aborted.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn level0_tables(&self) -> usize {
+        self.levels[0].num_tables()
+    }
+
+    // Returns true if level 0 has so many tables the memtable flushes stall.
+    fn is_level0_stalled(&self) -> bool {
+        self.level0_tables() >= self.opt.num_level_zero_tables_stall
     }
 
     // Return true if level zero may be compacted, without accounting for compactions that already
@@ -913,8 +1839,15 @@ impl LevelsController {
 
     // calc next file id
     pub(crate) fn reserve_file_id(&self) -> u64 {
-        let id = self.next_file_id.fetch_add(1, Ordering::Relaxed);
-        id
+        self.file_ids.reserve()
+    }
+
+    pub(crate) fn index_cache(&self) -> Option<IndexCache> {
+        self.index_cache.clone()
+    }
+
+    pub(crate) fn block_cache(&self) -> Option<BlockCache> {
+        self.block_cache.clone()
     }
 
     pub(crate) fn print_level_fids(&self) {
@@ -930,7 +1863,232 @@ impl LevelsController {
 #[derive(Debug, Clone)]
 struct CompactionPriority {
     level: usize,
+    // Number of tables of level 0 or size of the level, relative to their limit.
     score: f64,
+    // Score divided by the score of the next level, see `adjust_compaction_priorities`.
+    adjusted: f64,
+    // A table read uselessly too often, compacted first, see `add_seek_compactions`.
+    table: Option<u64>,
+}
+
+impl CompactionPriority {
+    fn new(level: usize, score: f64) -> Self {
+        CompactionPriority {
+            level,
+            score,
+            adjusted: score,
+            table: None,
+        }
+    }
+}
+
+// Takes the scores of all levels in order and returns the levels that need a compaction, the
+// most urgent first. A level over its limit is less urgent when the next level is over its
+// limit too, compacting into it would only make the next level worse, so the score of every
+// level over its limit is divided by the score of the next level. The last level has nowhere
+// to compact to.
+fn adjust_compaction_priorities(mut prios: Vec<CompactionPriority>) -> Vec<CompactionPriority> {
+    // Avoids absurdly large scores when the next level is (almost) empty.
+    const MIN_SCORE: f64 = 0.01;
+    for i in 0..prios.len().saturating_sub(1) {
+        if prios[i].adjusted >= 1.0 {
+            prios[i].adjusted /= prios[i + 1].adjusted.max(MIN_SCORE);
+        }
+    }
+    prios.pop();
+    // Levels over their limit are picked whatever their adjusted score.
+    prios.retain(|p| p.score >= 1.0);
+    prios.sort_by(|a, b| b.adjusted.total_cmp(&a.adjusted));
+    prios
+}
+
+// Puts level 0 first when it stalls the writes. Level 1 is left out, its compactions would
+// hold level 1 ranges level 0 needs and be preempted anyway.
+fn escalate_level0(prios: &mut Vec<CompactionPriority>) {
+    prios.retain(|p| p.level != 1);
+    if let Some(i) = prios.iter().position(|p| p.level == 0) {
+        let p = prios.remove(i);
+        prios.insert(0, p);
+    }
+}
+
+// A key range of a compaction, [left, right) with None unbounded, built on its own.
+struct SubCompaction {
+    top: Vec<Table>,
+    bot: Vec<Table>,
+    left: Option<Vec<u8>>,
+    right: Option<Vec<u8>>,
+    drop_prefixes: Vec<Vec<u8>>,
+    // The level the tables are written to.
+    level: usize,
+    bytes_read: Arc<AtomicU64>,
+    entries_dropped: Arc<AtomicU64>,
+    discarded: Arc<parking_lot::Mutex<Discarded>>,
+    // Given up between two tables when level 0 stalls.
+    preemptible: bool,
+    discard: DiscardFilter,
+}
+
+// Decides which entries of a compaction are left out, they're passed in iteration order. With
+// a discard ts, keys are versioned (see `key_with_ts`) and the versions at or below it are
+// seen by no reader: the newest `num_versions_to_keep` of them are kept, and none older than
+// a tombstone or a version marked to discard the earlier ones. Without, every key is its only
+// version. Tombstones themselves are dropped only when nothing older can be below the output
+// level.
+struct DiscardFilter {
+    discard_ts: u64,
+    num_versions_to_keep: usize,
+    drop_tombstones: bool,
+    last_key: Vec<u8>,
+    num_versions: usize,
+    skip_key: bool,
+}
+
+impl DiscardFilter {
+    fn new(discard_ts: u64, num_versions_to_keep: usize, drop_tombstones: bool) -> Self {
+        DiscardFilter {
+            discard_ts,
+            num_versions_to_keep: num_versions_to_keep.max(1),
+            drop_tombstones,
+            last_key: vec![],
+            num_versions: 0,
+            skip_key: false,
+        }
+    }
+
+    fn keep(&mut self, key: &[u8], meta: u8) -> bool {
+        let deleted = meta & MetaBit::BIT_DELETE.bits() != 0;
+        // The internal keys are written without a version.
+        if self.discard_ts == 0 || key.starts_with(_BADGER_PREFIX) {
+            return !(deleted && self.drop_tombstones);
+        }
+        let (user_key, version) = (parse_key(key), parse_ts(key));
+        if user_key != self.last_key.as_slice() {
+            self.last_key = user_key.to_vec();
+            self.num_versions = 0;
+            self.skip_key = false;
+        }
+        if self.skip_key {
+            return false;
+        }
+        if version > self.discard_ts {
+            return true;
+        }
+        self.num_versions += 1;
+        let discard_earlier = meta & MetaBit::BIT_DISCARD_EARLIER_VERSIONS.bits() != 0;
+        if deleted || discard_earlier || self.num_versions >= self.num_versions_to_keep {
+            // The older versions are all hidden by this one.
+            self.skip_key = true;
+            return !(deleted && self.drop_tombstones);
+        }
+        true
+    }
+}
+
+// Runs the user filter on an entry written to `level`, returns the value to write, or None
+// to leave the entry out. Tombstones and internal keys aren't filtered.
+fn apply_compaction_filter(
+    filter: &dyn CompactionFilter,
+    level: usize,
+    key: &[u8],
+    vs: &ValueStruct,
+    drop_tombstones: bool,
+) -> Option<ValueStruct> {
+    if vs.meta & MetaBit::BIT_DELETE.bits() != 0 || key.starts_with(_BADGER_PREFIX) {
+        return Some(vs.clone());
+    }
+    let value = if vs.meta & MetaBit::BIT_VALUE_POINTER.bits() == 0 {
+        Some(vs.value.as_slice())
+    } else {
+        None
+    };
+    match filter.filter(level, key, value, vs.user_meta) {
+        CompactionDecision::Keep => Some(vs.clone()),
+        CompactionDecision::Remove if drop_tombstones => None,
+        CompactionDecision::Remove => Some(ValueStruct::new(
+            vec![],
+            MetaBit::BIT_DELETE.bits(),
+            0,
+            vs.cas_counter,
+        )),
+        CompactionDecision::ChangeValue(value) => Some(
+            ValueStruct::new(
+                value,
+                vs.meta & !MetaBit::BIT_VALUE_POINTER.bits(),
+                vs.user_meta,
+                vs.cas_counter,
+            )
+            .with_expires_at(vs.expires_at),
+        ),
+    }
+}
+
+// Removes the files of tables built by a compaction that is given up, they're in no level nor
+// in the manifest.
+fn remove_table_files(tables: &[Table]) {
+    for tb in tables {
+        if let Err(err) = remove_file(tb.filename()) {
+            warn!("Failed to remove table {}, err: {}", tb.filename(), err);
+        }
+    }
+}
+
+// Returns true if none of the key ranges [smallest, biggest] overlap.
+fn disjoint_ranges(mut ranges: Vec<(&[u8], &[u8])>) -> bool {
+    ranges.sort();
+    ranges.windows(2).all(|w| w[0].1 < w[1].0)
+}
+
+// Returns true if a table of the keys [smallest, biggest] may hold keys with one of the
+// prefixes.
+fn contains_any_prefix(smallest: &[u8], biggest: &[u8], prefixes: &[Vec<u8>]) -> bool {
+    prefixes.iter().any(|p| {
+        let smallest = &smallest[..smallest.len().min(p.len())];
+        let biggest = &biggest[..biggest.len().min(p.len())];
+        smallest <= p.as_slice() && p.as_slice() <= biggest
+    })
+}
+
+// Splits the key range of a compaction into at most `max` ranges, [left, right) with None
+// unbounded, at the smallest keys of the next level tables so every range rewrites about
+// the same number of them.
+fn subcompaction_ranges(
+    bot_smallest: &[&[u8]],
+    max: usize,
+) -> Vec<(Option<Vec<u8>>, Option<Vec<u8>>)> {
+    let max = max.max(1);
+    let width = ((bot_smallest.len() + max - 1) / max).max(1);
+    let mut ranges = vec![];
+    let mut left = None;
+    for key in bot_smallest.iter().skip(width).step_by(width) {
+        let right = Some(key.to_vec());
+        ranges.push((left, right.clone()));
+        left = right;
+    }
+    ranges.push((left, None));
+    ranges
+}
+
+// Takes the smallest keys and sizes of tables, returns at most `n - 1` of the keys, sorted,
+// that split the tables in `n` ranges of about the same size.
+fn split_keys(mut bounds: Vec<(Vec<u8>, u64)>, n: usize) -> Vec<Vec<u8>> {
+    bounds.sort();
+    let total = bounds.iter().map(|(_, size)| size).sum::<u64>();
+    let mut splits: Vec<Vec<u8>> = vec![];
+    // Bytes of the tables before the key.
+    let mut before = 0;
+    for (key, size) in bounds {
+        let share = total * (splits.len() as u64 + 1);
+        if splits.len() + 1 < n
+            && before > 0
+            && before * n as u64 >= share
+            && splits.last() != Some(&key)
+        {
+            splits.push(key);
+        }
+        before += size;
+    }
+    splits
 }
 
 // Compact deference
@@ -944,6 +2102,16 @@ pub(crate) struct CompactDef {
     pub(crate) this_range: KeyRange,
     pub(crate) next_range: KeyRange,
     pub(crate) this_size: AtomicU64, // the compacted table's size(NOTE: this level compacted table is only one, exclude zero level)
+    // Keys with any of these prefixes are left out of the new tables.
+    pub(crate) drop_prefixes: Vec<Vec<u8>>,
+    // Bytes of keys and values read so far.
+    pub(crate) bytes_read: Arc<AtomicU64>,
+    // Entries left out of the new tables so far.
+    pub(crate) entries_dropped: Arc<AtomicU64>,
+    // Bytes of the value log values left out so far, by file.
+    pub(crate) discarded: Arc<parking_lot::Mutex<Discarded>>,
+    // The top table is read uselessly too often, it's compacted whatever the level size.
+    pub(crate) seek: bool,
 }
 
 impl Debug for CompactDef {
@@ -986,6 +2154,11 @@ impl CompactDef {
             this_range: KeyRange::default(),
             next_range: KeyRange::default(),
             this_size: Default::default(),
+            drop_prefixes: vec![],
+            bytes_read: Arc::new(AtomicU64::new(0)),
+            entries_dropped: Arc::new(AtomicU64::new(0)),
+            discarded: Arc::default(),
+            seek: false,
         }
     }
 
@@ -1015,9 +2188,15 @@ impl CompactDef {
 }
 
 // Checks that all necessary table files exist and removes all table files not
-// referenced by the manifest. id_map is a set of table file id's that were read from the directory
-// listing.
-async fn revert_to_manifest(dir: &str, mf: &TArcRW<Manifest>, id_map: HashSet<u64>) -> Result<()> {
+// referenced by the manifest, or moves them to the quarantine directory. id_map is a set of
+// table file id's that were read from the directory listing. Temporary files of tables that
+// were never finished are removed too.
+async fn revert_to_manifest(
+    dir: &str,
+    mf: &TArcRW<Manifest>,
+    id_map: HashSet<u64>,
+    quarantine: bool,
+) -> Result<()> {
     let tables = mf.write().await;
     // 1. Check all files in manifest exist.
     for id in &tables.tables {
@@ -1031,13 +2210,472 @@ async fn revert_to_manifest(dir: &str, mf: &TArcRW<Manifest>, id_map: HashSet<u6
         if !tables.tables.contains_key(id) {
             error!("table file {} not referenced in MANIFEST", id);
             let file_name = new_file_name(*id, dir);
-            if let Err(err) = remove_file(file_name) {
+            if quarantine {
+                let quarantine_dir = Path::new(dir).join(QUARANTINE_DIR);
+                create_dir_all(&quarantine_dir)?;
+                let to = quarantine_dir.join(id_to_filename(*id));
+                if let Err(err) = rename(&file_name, &to) {
+                    error!("While moving table {} to {:?}, err: {}", id, to, err);
+                }
+            } else if let Err(err) = remove_file(file_name) {
                 error!("While removing table {}, err: {}", id, err);
             }
         }
     }
+
+    // 3. Delete tables that were still being written.
+    let tmp_suffix = format!("{}{}", FILE_SUFFIX, TEMP_FILE_SUFFIX);
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        let is_tmp = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| name.ends_with(&tmp_suffix));
+        if is_tmp {
+            warn!("Remove unfinished table file {:?}", path);
+            if let Err(err) = remove_file(&path) {
+                error!("While removing {:?}, err: {}", path, err);
+            }
+        }
+    }
     Ok(())
 }
 
 #[test]
 fn it() {}
+
+#[test]
+fn compaction_priorities() {
+    let levels = |scores: &[f64]| {
+        let prios = scores
+            .iter()
+            .enumerate()
+            .map(|(level, score)| CompactionPriority::new(level, *score))
+            .collect::<Vec<_>>();
+        adjust_compaction_priorities(prios)
+            .into_iter()
+            .map(|p| p.level)
+            .collect::<Vec<_>>()
+    };
+    // Only levels over their limit, never the last one.
+    assert_eq!(levels(&[0.5, 0.9, 0.2, 3.0]), Vec::<usize>::new());
+    assert_eq!(levels(&[1.2, 0.5, 2.0, 0.1]), vec![2, 0]);
+    // L0 is over its limit but L1 is far worse, so L1 goes first.
+    assert_eq!(levels(&[2.0, 4.0, 0.5, 0.0]), vec![1, 0]);
+    // An empty next level doesn't make the score infinite.
+    let prios = adjust_compaction_priorities(vec![
+        CompactionPriority::new(0, 1.5),
+        CompactionPriority::new(1, 0.0),
+    ]);
+    assert!((prios[0].adjusted - 150.0).abs() < 1e-9);
+}
+
+#[tokio::test]
+async fn revert_to_manifest_orphans() {
+    use crate::manifest::TableManifest;
+    use crate::table::table::new_temp_file_name;
+
+    for quarantine in [false, true] {
+        let dir = crate::test_util::create_random_tmp_dir();
+        for id in [1, 2] {
+            std::fs::write(new_file_name(id, &dir), b"table").unwrap();
+        }
+        std::fs::write(new_temp_file_name(3, &dir), b"table").unwrap();
+        let mut mf = Manifest::new();
+        mf.tables.insert(1, TableManifest { level: 0 });
+        let mf = Arc::new(RwLock::new(mf));
+        revert_to_manifest(&dir, &mf, get_id_map(&dir), quarantine)
+            .await
+            .unwrap();
+        assert!(Path::new(&new_file_name(1, &dir)).exists());
+        assert!(!Path::new(&new_file_name(2, &dir)).exists());
+        assert!(!Path::new(&new_temp_file_name(3, &dir)).exists());
+        let quarantined = Path::new(&dir).join(QUARANTINE_DIR).join(id_to_filename(2));
+        assert_eq!(quarantined.exists(), quarantine);
+
+        // A table of the manifest is missing.
+        mf.write()
+            .await
+            .tables
+            .insert(4, TableManifest { level: 1 });
+        assert!(revert_to_manifest(&dir, &mf, get_id_map(&dir), quarantine)
+            .await
+            .is_err());
+    }
+}
+
+#[test]
+fn subcompaction_ranges_split() {
+    let keys = (0..10).map(|i| format!("key{:02}", i)).collect::<Vec<_>>();
+    let keys = keys.iter().map(|k| k.as_bytes()).collect::<Vec<_>>();
+    let ranges = subcompaction_ranges(&keys, 4);
+    let bounds = |i: usize| Some(keys[i].to_vec());
+    assert_eq!(
+        ranges,
+        vec![
+            (None, bounds(3)),
+            (bounds(3), bounds(6)),
+            (bounds(6), bounds(9)),
+            (bounds(9), None),
+        ]
+    );
+    // A single range when the compaction is not split, or has no next level tables.
+    assert_eq!(subcompaction_ranges(&keys, 1), vec![(None, None)]);
+    assert_eq!(subcompaction_ranges(&keys, 0), vec![(None, None)]);
+    assert_eq!(subcompaction_ranges(&[], 4), vec![(None, None)]);
+    assert_eq!(subcompaction_ranges(&keys[..2], 4).len(), 2);
+}
+
+#[test]
+fn discard_filter() {
+    let delete = MetaBit::BIT_DELETE.bits();
+    // Not versioned, only the tombstones may go.
+    let mut filter = DiscardFilter::new(0, 1, false);
+    assert!(filter.keep(b"a", 0) && filter.keep(b"b", delete));
+    let mut filter = DiscardFilter::new(0, 1, true);
+    assert!(filter.keep(b"a", 0) && !filter.keep(b"b", delete));
+
+    // Versions come newest first, the ones above the discard ts are all kept.
+    let entries = |key: &[u8]| {
+        [(9, 0), (7, 0), (5, 0), (4, delete), (2, 0)]
+            .into_iter()
+            .map(|(ts, meta)| (key_with_ts(key, ts), meta))
+            .collect::<Vec<_>>()
+    };
+    let kept = |filter: &mut DiscardFilter| {
+        [b"a".as_slice(), b"b"]
+            .into_iter()
+            .flat_map(|key| entries(key))
+            .filter(|(key, meta)| filter.keep(key, *meta))
+            .map(|(key, _)| (parse_key(&key).to_vec(), parse_ts(&key)))
+            .collect::<Vec<_>>()
+    };
+    let versions = |ts: &[u64]| {
+        [b"a", b"b"]
+            .into_iter()
+            .flat_map(|key| ts.iter().map(move |ts| (key.to_vec(), *ts)))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        kept(&mut DiscardFilter::new(6, 1, false)),
+        versions(&[9, 7, 5])
+    );
+    assert_eq!(
+        kept(&mut DiscardFilter::new(6, 3, false)),
+        versions(&[9, 7, 5, 4])
+    );
+    assert_eq!(
+        kept(&mut DiscardFilter::new(6, 3, true)),
+        versions(&[9, 7, 5])
+    );
+    assert_eq!(
+        kept(&mut DiscardFilter::new(4, 3, true)),
+        versions(&[9, 7, 5])
+    );
+    assert_eq!(
+        kept(&mut DiscardFilter::new(1, 1, true)),
+        versions(&[9, 7, 5, 4, 2])
+    );
+
+    // A version marked to discard the earlier ones hides them once below the discard ts.
+    let discard = MetaBit::BIT_DISCARD_EARLIER_VERSIONS.bits();
+    let mut filter = DiscardFilter::new(8, 3, false);
+    let kept = [(9, discard), (7, discard), (5, 0)]
+        .into_iter()
+        .filter(|(ts, meta)| filter.keep(&key_with_ts(b"a", *ts), *meta))
+        .map(|(ts, _)| ts)
+        .collect::<Vec<_>>();
+    assert_eq!(kept, vec![9, 7]);
+}
+
+#[test]
+fn compaction_filter_decisions() {
+    struct Filter;
+    impl CompactionFilter for Filter {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn filter(&self, _: usize, key: &[u8], value: Option<&[u8]>, _: u8) -> CompactionDecision {
+            match (key, value) {
+                (b"remove", _) => CompactionDecision::Remove,
+                (b"change", Some(value)) => CompactionDecision::ChangeValue([value, b"!"].concat()),
+                (b"change", None) => CompactionDecision::ChangeValue(b"pointer".to_vec()),
+                _ => CompactionDecision::Keep,
+            }
+        }
+    }
+    let pointer = MetaBit::BIT_VALUE_POINTER.bits();
+    let delete = MetaBit::BIT_DELETE.bits();
+    let vs = ValueStruct::new(b"v".to_vec(), 0, 3, 7);
+    assert_eq!(
+        apply_compaction_filter(&Filter, 1, b"keep", &vs, false),
+        Some(vs.clone())
+    );
+    // Removed keys leave a tombstone unless nothing is below.
+    let removed = apply_compaction_filter(&Filter, 1, b"remove", &vs, false).unwrap();
+    assert_eq!((removed.meta, removed.cas_counter), (delete, 7));
+    assert_eq!(
+        apply_compaction_filter(&Filter, 1, b"remove", &vs, true),
+        None
+    );
+    let changed = apply_compaction_filter(&Filter, 1, b"change", &vs, false).unwrap();
+    assert_eq!((changed.value, changed.user_meta), (b"v!".to_vec(), 3));
+    // A value log value is replaced by one in the LSM tree.
+    let vs = ValueStruct::new(vec![0; 10], pointer, 0, 8);
+    let changed = apply_compaction_filter(&Filter, 1, b"change", &vs, false).unwrap();
+    assert_eq!((changed.value, changed.meta), (b"pointer".to_vec(), 0));
+    // Tombstones and internal keys are left alone.
+    let vs = ValueStruct::new(vec![], delete, 0, 9);
+    assert_eq!(
+        apply_compaction_filter(&Filter, 1, b"remove", &vs, true),
+        Some(vs.clone())
+    );
+    let key = [_BADGER_PREFIX.as_slice(), b"remove"].concat();
+    let vs = ValueStruct::new(b"v".to_vec(), 0, 0, 10);
+    assert_eq!(
+        apply_compaction_filter(&Filter, 1, &key, &vs, true),
+        Some(vs.clone())
+    );
+}
+
+#[test]
+#[allow(deprecated)]
+fn level_sizes() {
+    let mut opt = Options::default();
+    opt.base_level_size = 10 << 20;
+    opt.level_size_multiplier = 4;
+    opt.max_table_size = 2 << 20;
+    opt.level_table_sizes = vec![1 << 20, 1 << 20, 4 << 20];
+    assert_eq!(
+        (0..4).map(|l| opt.level_max_size(l)).collect::<Vec<_>>(),
+        vec![0, 10 << 20, 40 << 20, 160 << 20]
+    );
+    assert_eq!(
+        (0..4).map(|l| opt.table_size(l)).collect::<Vec<_>>(),
+        vec![1 << 20, 1 << 20, 4 << 20, 2 << 20]
+    );
+    // The options set before the rename still apply.
+    opt.level_one_size = 20 << 20;
+    assert_eq!(opt.level_max_size(2), 80 << 20);
+    // Inconsistent sizes are rejected on open rather than panicking.
+    assert!(opt.validate().is_ok());
+    opt.level_size_multiplier = 0;
+    assert!(opt.validate().is_err());
+    opt.level_size_multiplier = 4;
+    opt.level_table_sizes = vec![0];
+    assert!(opt.validate().is_err());
+}
+
+#[test]
+fn disjoint_key_ranges() {
+    let range = |left: &'static str, right: &'static str| (left.as_bytes(), right.as_bytes());
+    assert!(disjoint_ranges(vec![]));
+    assert!(disjoint_ranges(vec![
+        range("d", "f"),
+        range("a", "c"),
+        range("g", "g")
+    ]));
+    assert!(!disjoint_ranges(vec![range("d", "f"), range("a", "d")]));
+    assert!(!disjoint_ranges(vec![range("a", "z"), range("c", "d")]));
+}
+
+#[test]
+fn escalate_stalled_level0() {
+    let mut prios = [(2, 3.0), (1, 2.0), (0, 1.5), (3, 1.2)]
+        .into_iter()
+        .map(|(level, score)| CompactionPriority::new(level, score))
+        .collect::<Vec<_>>();
+    escalate_level0(&mut prios);
+    assert_eq!(
+        prios.iter().map(|p| p.level).collect::<Vec<_>>(),
+        vec![0, 2, 3]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn seek_compaction() {
+    use crate::value_log::Entry;
+    let dir = crate::test_util::random_tmp_dir();
+    let mut opt = Options::default();
+    opt.dir = Box::new(dir.clone());
+    opt.value_dir = Box::new(dir);
+    opt.max_table_size = 1 << 15;
+    opt.do_not_compact = true;
+    // 100 useless probes whatever the table size.
+    opt.seek_compaction_bytes_per_probe = u64::MAX;
+    let kv = crate::KV::open(opt).await.unwrap();
+    let entries = (0..5000)
+        .map(|i| {
+            Entry::default()
+                .key(format!("key{:06}", i).into_bytes())
+                .value(format!("{:025}", i).into_bytes())
+        })
+        .collect::<Vec<_>>();
+    for chunk in entries.chunks(100) {
+        for res in kv.batch_set(chunk.to_vec()).await {
+            assert!(res.is_ok());
+        }
+    }
+    let lc = kv.must_lc();
+    for _ in 0..50 {
+        if lc.levels[0].num_tables() > 1 {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(lc
+        .do_compact(CompactionPriority::new(0, 1.0))
+        .await
+        .unwrap());
+    let has_level1 = |prios: &[CompactionPriority]| prios.iter().any(|p| p.level == 1);
+    assert!(!has_level1(&lc.pick_compact_levels()));
+
+    let tb = lc.levels[1].tables.read().last().unwrap().clone();
+    for _ in 0..99 {
+        tb.record_useless_probe();
+    }
+    assert!(!has_level1(&lc.pick_compact_levels()));
+    tb.record_useless_probe();
+    let p = lc
+        .pick_compact_levels()
+        .into_iter()
+        .find(|p| p.level == 1)
+        .unwrap();
+    assert_eq!(p.table, Some(tb.id()));
+    // Level 2 is empty, the table picked is moved down as is.
+    assert!(lc.do_compact(p).await.unwrap());
+    assert!(lc.levels[2].tables.read().iter().any(|t| t.id() == tb.id()));
+    kv.close().await.unwrap();
+}
+
+#[test]
+fn split_keys_by_size() {
+    let key = |i: usize| format!("key{:02}", i).into_bytes();
+    let bounds = (0..10).map(|i| (key(i), 100)).rev().collect::<Vec<_>>();
+    assert_eq!(
+        split_keys(bounds.clone(), 5),
+        vec![key(2), key(4), key(6), key(8)]
+    );
+    assert!(split_keys(bounds.clone(), 1).is_empty());
+    assert!(split_keys(vec![], 4).is_empty());
+    // A big table takes a range of its own, a key shared by tables splits once.
+    let mut bounds = vec![(key(0), 1000), (key(1), 100), (key(1), 100), (key(2), 100)];
+    assert_eq!(split_keys(bounds.clone(), 4), vec![key(1), key(2)]);
+    bounds.truncate(1);
+    assert!(split_keys(bounds, 4).is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn discard_ts_compaction() {
+    use crate::Xiterator;
+    use std::collections::HashMap;
+    let dir = crate::test_util::random_tmp_dir();
+    let mut opt = Options::default();
+    opt.dir = Box::new(dir.clone());
+    opt.value_dir = Box::new(dir);
+    opt.max_table_size = 1 << 15;
+    opt.managed_txns = true;
+    opt.do_not_compact = true;
+    // Writes the versions of every key, then reopens so they're all in level 0.
+    let write = |kv: crate::KV, versions: std::ops::Range<u64>| {
+        let opt = opt.clone();
+        async move {
+            for ts in versions {
+                for chunk in (0..1000).collect::<Vec<_>>().chunks(100) {
+                    let mut txn = kv.new_transaction_at(ts - 1, true).unwrap();
+                    for i in chunk {
+                        let key = format!("key{:06}", i).into_bytes();
+                        txn.set(key, vec![ts as u8; 25], 0).unwrap();
+                    }
+                    txn.commit_at(ts).await.unwrap();
+                }
+            }
+            kv.close().await.unwrap();
+            crate::KV::open(opt).await.unwrap()
+        }
+    };
+    // The most versions at or below `ts` of a key of level 1.
+    let max_versions_below = |lc: &LevelsController, ts: u64| {
+        let itr = lc.level_iterator(1).unwrap();
+        let mut counts = HashMap::new();
+        itr.rewind();
+        while let Some(item) = itr.peek() {
+            if parse_ts(item.key()) <= ts {
+                *counts.entry(parse_key(item.key()).to_vec()).or_insert(0) += 1;
+            }
+            itr.next();
+        }
+        itr.close();
+        counts.into_values().max().unwrap_or(0)
+    };
+
+    let kv = write(crate::KV::open(opt.clone()).await.unwrap(), 1..4).await;
+    let lc = kv.must_lc();
+    assert!(lc
+        .do_compact(CompactionPriority::new(0, 1.0))
+        .await
+        .unwrap());
+    assert_eq!(max_versions_below(lc, 3), 3);
+
+    let kv = write(kv, 4..7).await;
+    let lc = kv.must_lc();
+    // The next compaction drops the versions hidden by the newest one at or below the ts.
+    kv.set_discard_ts(3).unwrap();
+    assert_eq!(kv.discard_ts(), 3);
+    assert!(lc
+        .do_compact(CompactionPriority::new(0, 1.0))
+        .await
+        .unwrap());
+    assert_eq!(max_versions_below(lc, 3), 1);
+    assert_eq!(max_versions_below(lc, 6), 4);
+    kv.close().await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn stale_data_size_compaction() {
+    use crate::table::builder::Header;
+    use crate::Xiterator;
+    let dir = crate::test_util::random_tmp_dir();
+    let mut opt = Options::default();
+    opt.dir = Box::new(dir.clone());
+    opt.value_dir = Box::new(dir);
+    opt.managed_txns = true;
+    opt.do_not_compact = true;
+    // Every key is overwritten twice, all the versions are kept without a discard ts. Each
+    // version is in its own level 0 table, so they're merged rather than moved.
+    let mut kv = crate::KV::open(opt.clone()).await.unwrap();
+    for ts in 1..4 {
+        let mut txn = kv.new_transaction_at(ts - 1, true).unwrap();
+        for i in 0..500 {
+            let key = format!("key{:06}", i).into_bytes();
+            txn.set(key, vec![ts as u8; 25], 0).unwrap();
+        }
+        txn.commit_at(ts).await.unwrap();
+        kv.close().await.unwrap();
+        kv = crate::KV::open(opt.clone()).await.unwrap();
+    }
+    let lc = kv.must_lc();
+    assert!(lc
+        .do_compact(CompactionPriority::new(0, 1.0))
+        .await
+        .unwrap());
+
+    // The versions behind the newest one of their key.
+    let itr = lc.level_iterator(1).unwrap();
+    let (mut expected, mut stale_versions) = (0, 0);
+    let mut last_key = vec![];
+    itr.rewind();
+    while let Some(item) = itr.peek() {
+        if parse_key(item.key()) == last_key.as_slice() {
+            expected += (Header::size() + item.key().len() + item.value().size()) as u64;
+            stale_versions += 1;
+        }
+        last_key = parse_key(item.key()).to_vec();
+        itr.next();
+    }
+    itr.close();
+    assert_eq!(stale_versions, 1000);
+    assert_eq!(kv.levels()[1].stale_size, expected);
+    kv.close().await.unwrap();
+}
@@ -23,7 +23,6 @@
 #![feature(test)]
 #![feature(atomic_from_ptr, pointer_is_aligned)]
 
-
 /// Badger DB is an embedded keyvalue database.
 ///
 /// Badger DB is a library written in Rust that implements a badger-go [https://github.com/dgraph-io/badger]
@@ -48,20 +47,42 @@ mod y;
 mod compaction;
 // #[cfg(test)]
 // mod kv_test;
+mod backup;
+mod discard_stats;
 #[cfg(test)]
 mod kv_test;
 mod levels;
 mod pb;
+mod publisher;
+mod sequence;
 mod st_manager;
+mod stream;
 #[cfg(test)]
 mod test_util;
-mod backup;
+mod txn;
+mod vlog_registry;
+mod wal;
+mod write_batch;
 
+pub use compaction::{
+    CompactionDecision, CompactionEvent, CompactionFilter, CompactionJob, CompactionListener,
+    CompactionStatus, LevelCompactionStatus,
+};
+pub use event::{get_metrics, EvMetrics};
 pub use iterator::*;
 pub use kv::*;
 pub use options::*;
+pub use publisher::{KVChange, KVList, Subscription};
+pub use sequence::Sequence;
 pub use skl::*;
 pub use st_manager::*;
+pub use stream::Stream;
+pub use table::block_cache::{CacheMetrics, CacheStats};
+pub use table::iterator::{ConcatIterator, IteratorItem};
+pub use table::table::{summarize_tables, LevelInfo, TableInfo, TableStats};
+pub use txn::{Snapshot, Txn, TxnIterator};
+pub use value_log::{Entry, MetaBit, ValueLogInfo, ValueLogReader, ValuePointer, ValueReader};
+pub use write_batch::WriteBatch;
 pub use y::*;
 
 #[allow(dead_code)]
@@ -76,4 +97,4 @@ pub(crate) fn must_align<T>(ptr: *const T) {
 pub(crate) fn cals_size_with_align(sz: usize, align_sz: usize) -> usize {
     let size = (sz + align_sz) & !align_sz;
     size
-}
\ No newline at end of file
+}
@@ -1,23 +1,27 @@
+use crate::options::{FileLoadingMode, ValueLogFormat};
+use crate::table::encryption::{FileCipher, KeyRegistry, FILE_HEADER_SIZE};
 use crate::types::Closer;
-use crate::value_log::{Entry, Header, ValuePointer};
-use crate::y::{create_synced_file, Result};
-use crate::y::{is_eof, Decode};
+use crate::value_log::{Entry, Header, ValuePointer, MAX_KEY_SIZE};
+use crate::y::Decode;
+use crate::y::{create_synced_file, read_at, sync_directory, write_at, Result};
 use std::env::temp_dir;
 
 use async_channel::Sender;
-use byteorder::{BigEndian, ReadBytesExt};
 use drop_cell::defer;
 use either::Either;
-use log::{debug, info};
+use log::{debug, info, warn};
 use memmap::{Mmap, MmapMut};
 
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
-use std::fs::File;
+use std::fs::{remove_file, File};
 use std::future::Future;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write};
 use std::ops::Deref;
+use std::path::Path;
 use std::pin::Pin;
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::event;
 use tokio::select;
@@ -74,6 +78,40 @@ pub(crate) struct LogFile {
     pub(crate) fid: u32,
     pub(crate) _mmap: Option<MmapType>,
     pub(crate) sz: u32,
+    // How the file is read once it's done writing, see `Options::value_log_loading_mode`.
+    pub(crate) loading_mode: FileLoadingMode,
+    // How the file is accessed while it's written to, see
+    // `Options::active_value_log_loading_mode`.
+    pub(crate) write_mode: FileLoadingMode,
+    // The layout of the entries, a file is read and appended in the format it was created in.
+    pub(crate) format: ValueLogFormat,
+    // Punched ranges [start, end) of stale entries, skipped when reading the entries through.
+    pub(crate) holes: BTreeMap<u32, u32>,
+    // Encrypts the entries, the file starts with its header then, see `Options::encryption_key`.
+    pub(crate) cipher: Option<FileCipher>,
+    // Set once the file is retired from the value log, see `ValueLog::retire`.
+    pub(crate) delete_on_drop: AtomicBool,
+}
+
+impl Drop for LogFile {
+    fn drop(&mut self) {
+        if !self.delete_on_drop.load(Ordering::Acquire) {
+            return;
+        }
+        self._mmap.take();
+        self.fd.take();
+        let path = Path::new(self._path.as_str());
+        if let Err(err) = remove_file(path) {
+            warn!("Unable to delete value log {}, err: {}", self._path, err);
+            return;
+        }
+        if let Some(dir) = path.parent().and_then(|dir| dir.to_str()) {
+            if let Err(err) = sync_directory(dir) {
+                warn!("Unable to sync dir {}, err: {}", dir, err);
+            }
+        }
+        info!("Deleted value log {}", self._path);
+    }
 }
 
 impl Debug for LogFile {
@@ -93,15 +131,22 @@ impl LogFile {
         offset: u32,
         n: usize,
     ) -> Result<(Vec<(Entry, ValuePointer)>, u32)> {
-        let m = self.mmap_slice();
-        let mut cursor_offset = offset;
+        let mut cursor_offset = offset.max(self.data_start());
         let mut v = vec![];
-        while cursor_offset < m.len() as u32 && v.len() < n {
-            let entry = Entry::from_slice(cursor_offset, m)?;
+        while cursor_offset < self.sz && v.len() < n {
+            if let Some(end) = self.holes.get(&cursor_offset) {
+                cursor_offset = *end;
+                continue;
+            }
+            let header = self.read_at(cursor_offset, self.format.header_size() as u32)?;
+            let mut h = Header::default();
+            h.dec(&mut Cursor::new(header.as_ref()))?;
+            let len = self.format.entry_size(h.k_len, h.v_len) as u32;
+            let mut entry = Entry::from_slice(self.format, 0, &self.read_at(cursor_offset, len)?)?;
+            entry.offset = cursor_offset;
             let mut vpt = ValuePointer::default();
             vpt.fid = self.fid;
-            vpt.len =
-                Header::encoded_size() as u32 + (entry.key.len() + entry.value.len()) as u32 + 4;
+            vpt.len = len;
             vpt.offset = cursor_offset;
             cursor_offset += vpt.len;
             v.push((entry, vpt))
@@ -119,7 +164,13 @@ impl LogFile {
         defer! {notify.close();}
         let has_been_close = ctx.has_been_closed();
         loop {
-            let (v, next) = self.read_entries(offset, 1).await.unwrap();
+            let (v, next) = match self.read_entries(offset, 1).await {
+                Ok(ret) => ret,
+                Err(err) => {
+                    warn!("Stop iterating value log {}, err: {}", self._path, err);
+                    return;
+                }
+            };
             offset = next;
             if v.is_empty() {
                 return;
@@ -159,7 +210,8 @@ impl LogFile {
         }
     }
 
-    // It should be call by one thread.
+    // It should be call by one thread. Returns the offset of the first entry that is torn or
+    // fails its checksum, if any, nothing from there is passed to `f`.
     pub(crate) async fn iterate(
         &mut self,
         offset: u32,
@@ -167,65 +219,167 @@ impl LogFile {
             &'a Entry,
             &'a ValuePointer,
         ) -> Pin<Box<dyn Future<Output = Result<bool>> + 'a>>,
-    ) -> Result<()> {
-        let mut fd = self.fd.as_mut().unwrap();
+    ) -> Result<Option<u32>> {
+        let offset = offset.max(self.data_start());
+        let cipher = self.cipher.clone();
+        let fd = self.fd.as_mut().unwrap();
+        let end = fd.metadata()?.len();
         fd.seek(SeekFrom::Start(offset as u64))?;
-        let mut entry = Entry::default();
-        let _truncate = false; // because maybe abort before write
+        let mut rd = BufReader::new(fd);
         let mut record_offset = offset;
-        loop {
-            let mut h = Header::default();
-            let ok = h.dec(&mut fd);
-            if ok.is_err() && ok.as_ref().unwrap_err().is_io_eof() {
-                break;
+        let header_size = self.format.header_size();
+        let mut buf = vec![0u8; header_size];
+        while (record_offset as u64) < end {
+            if let Some(hole_end) = self.holes.get(&record_offset) {
+                record_offset = *hole_end;
+                rd.seek(SeekFrom::Start(record_offset as u64))?;
+                continue;
             }
-            // todo add truncate currenct
-            ok?;
-            if h.k_len as usize > entry.key.capacity() {
-                entry.key = vec![0u8; h.k_len as usize];
+            let remain = end - record_offset as u64;
+            buf.resize(header_size, 0);
+            if remain < buf.len() as u64 {
+                return Ok(Some(record_offset));
             }
-            if h.v_len as usize > entry.value.capacity() {
-                entry.value = vec![0u8; h.v_len as usize];
+            rd.read_exact(&mut buf)?;
+            if let Some(cipher) = &cipher {
+                cipher.xor(record_offset as u64, &mut buf)?;
             }
-            entry.key.clear();
-            entry.value.clear();
-
-            let ok = fd.read(&mut entry.key);
-            if is_eof(&ok) {
-                break;
+            let mut h = Header::default();
+            h.dec(&mut Cursor::new(&buf))?;
+            let len = self.format.entry_size(h.k_len, h.v_len);
+            if len > remain {
+                return Ok(Some(record_offset));
             }
-            ok?;
-
-            let ok = fd.read(&mut entry.value);
-            if is_eof(&ok) {
-                break;
+            buf.resize(len as usize, 0);
+            rd.read_exact(&mut buf[header_size..])?;
+            if let Some(cipher) = &cipher {
+                let offset = record_offset as u64 + header_size as u64;
+                cipher.xor(offset, &mut buf[header_size..])?;
             }
-            ok?;
+            let mut entry = match Entry::from_slice(self.format, 0, &buf) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    warn!("Value log {}, err: {}", self._path, err);
+                    return Ok(Some(record_offset));
+                }
+            };
             entry.offset = record_offset;
-            entry.meta = h.meta;
-            entry.user_meta = h.user_mata;
-            entry.cas_counter = AtomicU64::new(h.cas_counter);
-            entry.cas_counter_check = h.cas_counter_check;
-            let ok = fd.read_u32::<BigEndian>();
-            if is_eof(&ok) {
+            let mut vp = ValuePointer::default();
+            vp.len = len as u32;
+            vp.offset = record_offset;
+            vp.fid = self.fid;
+            record_offset += vp.len;
+
+            if !f(&entry, &vp).await? {
                 break;
             }
-            let _crc = ok?;
+        }
+        Ok(None)
+    }
 
-            let mut vp = ValuePointer::default();
-            vp.len = Header::encoded_size() as u32 + h.k_len + h.v_len + 4;
-            record_offset += vp.len;
+    // The format of the entries, told by the first one that decodes in either. None if the
+    // file has no whole entry, e.g. it's new or torn in the first one.
+    pub(crate) fn detect_format(&self) -> Result<Option<ValueLogFormat>> {
+        let size = self.fd.as_ref().unwrap().metadata()?.len();
+        let mut offset = self.data_start();
+        while let Some(end) = self.holes.get(&offset) {
+            offset = *end;
+        }
+        for format in [ValueLogFormat::Native, ValueLogFormat::GoLegacy] {
+            if offset as u64 + format.header_size() as u64 > size {
+                continue;
+            }
+            let header = self.read_at(offset, format.header_size() as u32)?;
+            let mut h = Header::default();
+            h.dec(&mut Cursor::new(header.as_ref()))?;
+            let len = format.entry_size(h.k_len, h.v_len);
+            if h.k_len as usize > MAX_KEY_SIZE || offset as u64 + len > size {
+                continue;
+            }
+            let buf = self.read_at(offset, len as u32)?;
+            if Entry::from_slice(format, 0, &buf).is_ok() {
+                return Ok(Some(format));
+            }
+        }
+        Ok(None)
+    }
 
-            vp.offset = entry.offset;
-            vp.fid = self.fid;
+    // Returns the offset of the first entry before `end` that is torn or fails its checksum,
+    // if any. The entries after it can't be told apart, they aren't checked.
+    pub(crate) fn verify(&self, end: u32) -> Result<Option<u32>> {
+        let header_size = self.format.header_size() as u32;
+        let mut offset = self.data_start();
+        while offset < end {
+            if let Some(hole_end) = self.holes.get(&offset) {
+                offset = *hole_end;
+                continue;
+            }
+            if end - offset < header_size {
+                return Ok(Some(offset));
+            }
+            let header = self.read_at(offset, header_size)?;
+            let mut h = Header::default();
+            h.dec(&mut Cursor::new(header.as_ref()))?;
+            let len = self.format.entry_size(h.k_len, h.v_len);
+            if len > (end - offset) as u64 {
+                return Ok(Some(offset));
+            }
+            let buf = self.read_at(offset, len as u32)?;
+            if Entry::from_slice(self.format, 0, &buf).is_err() {
+                return Ok(Some(offset));
+            }
+            offset += len as u32;
+        }
+        Ok(None)
+    }
 
-            let _continue = f(&entry, &vp).await?;
-            if !_continue {
-                break;
+    // Returns true if the file has only zeros from `offset` on, e.g. the space preallocated
+    // for the writes.
+    pub(crate) fn is_zero_from(&mut self, offset: u32) -> Result<bool> {
+        let fd = self.fd.as_mut().unwrap();
+        fd.seek(SeekFrom::Start(offset as u64))?;
+        let mut rd = BufReader::new(fd);
+        loop {
+            let buf = rd.fill_buf()?;
+            if buf.is_empty() {
+                return Ok(true);
+            }
+            if buf.iter().any(|b| *b != 0) {
+                return Ok(false);
             }
+            let n = buf.len();
+            rd.consume(n);
+        }
+    }
+
+    // The offset of the first entry, after the header of an encrypted file.
+    pub(crate) fn data_start(&self) -> u32 {
+        if self.cipher.is_some() {
+            FILE_HEADER_SIZE as u32
+        } else {
+            0
         }
+    }
+
+    // Reads the cipher of the file from its header, if it's encrypted. Done before its
+    // entries are read.
+    pub(crate) fn read_cipher(&mut self, keys: &KeyRegistry) -> Result<()> {
+        self.cipher = None;
+        let size = self.fd.as_ref().unwrap().metadata()?.len();
+        if size < FILE_HEADER_SIZE as u64 {
+            return Ok(());
+        }
+        let cipher = FileCipher::read_header(&self.read_at(0, FILE_HEADER_SIZE as u32)?, keys)?;
+        self.cipher = cipher;
+        Ok(())
+    }
 
-        // todo add truncate
+    // Encrypts the entries of the new, still empty, file with `cipher` and writes its header.
+    pub(crate) fn write_cipher(&mut self, cipher: FileCipher) -> Result<()> {
+        let fd = self.fd.as_ref().unwrap();
+        write_at(fd, &cipher.header(), 0)?;
+        fd.sync_all()?;
+        self.cipher = Some(cipher);
         Ok(())
     }
 }
@@ -239,49 +393,104 @@ impl LogFile {
             fid: 0,
             _mmap: None,
             sz: 0,
+            loading_mode: FileLoadingMode::MemoryMap,
+            write_mode: FileLoadingMode::MemoryMap,
+            format: ValueLogFormat::Native,
+            holes: BTreeMap::new(),
+            cipher: None,
+            delete_on_drop: AtomicBool::new(false),
         };
         lf.open_read_only()?;
         Ok(lf)
     }
 
-    // open only read permission
+    // open only read permission, memory mapped unless the loading mode is `FileIO`. A file
+    // that can't be mapped is read with pread.
     pub(crate) fn open_read_only(&mut self) -> Result<()> {
         let fd = std::fs::OpenOptions::new()
             .read(true)
             .open(self._path.as_ref())?;
         let meta = fd.metadata()?;
         let file_sz = meta.len();
-        let mut _mmap = unsafe { Mmap::map(&fd)? };
-        self._mmap.replace(_mmap.into());
+        self._mmap.take();
+        if self.loading_mode != FileLoadingMode::FileIO {
+            match unsafe { Mmap::map(&fd) } {
+                Ok(_mmap) => {
+                    self._mmap.replace(_mmap.into());
+                }
+                Err(err) => warn!(
+                    "Unable to mmap value log {}, reading it with pread, err: {}",
+                    self._path, err
+                ),
+            }
+        }
         self.fd.replace(fd);
         self.sz = file_sz as u32;
         Ok(())
     }
 
     // Acquire lock on mmap if you are calling this.
-    pub(crate) fn read(&self, p: &ValuePointer) -> Result<&[u8]> {
+    pub(crate) fn read(&self, p: &ValuePointer) -> Result<Cow<'_, [u8]>> {
         #[cfg(test)]
         debug!(
-            "ready to read bytes from mmap, {}, {:?}",
-            self._mmap.as_ref().unwrap().is_left(),
+            "ready to read bytes, mmap: {}, {:?}",
+            self._mmap.is_some(),
             p
         );
-        let offset = p.offset;
-        let mmp = self._mmap.as_ref().unwrap();
         event::get_metrics().num_reads.inc();
         event::get_metrics().num_bytes_read.inc_by(p.len as u64);
-        // todo add metrics
-        match mmp.0 {
-            Either::Left(ref m) => Ok(&m.as_ref()[offset as usize..(offset + p.len) as usize]),
-            Either::Right(ref m) => Ok(&m.as_ref()[offset as usize..(offset + p.len) as usize]),
+        self.read_at(p.offset, p.len)
+    }
+
+    // Reads `len` bytes at `offset`, decrypted if the file is encrypted.
+    pub(crate) fn read_at(&self, offset: u32, len: u32) -> Result<Cow<'_, [u8]>> {
+        let data = self.read_raw_at(offset, len)?;
+        let Some(cipher) = &self.cipher else {
+            return Ok(data);
+        };
+        let mut data = data.into_owned();
+        cipher.xor(offset as u64, &mut data)?;
+        Ok(Cow::Owned(data))
+    }
+
+    // Reads `len` bytes at `offset` as stored, from the mmap if the file is mapped.
+    fn read_raw_at(&self, offset: u32, len: u32) -> Result<Cow<'_, [u8]>> {
+        let (start, end) = (offset as usize, offset as usize + len as usize);
+        if let Some(mmap) = self._mmap.as_ref() {
+            let m: &[u8] = match mmap.0 {
+                Either::Left(ref m) => m.as_ref(),
+                Either::Right(ref m) => m.as_ref(),
+            };
+            if end > m.len() {
+                return Err(format!(
+                    "Read past the end of value log {}, offset: {}, len: {}, size: {}",
+                    self._path,
+                    offset,
+                    len,
+                    m.len()
+                )
+                .into());
+            }
+            return Ok(Cow::Borrowed(&m[start..end]));
         }
+        let mut buf = vec![0u8; len as usize];
+        let n = read_at(self.file_ref(), &mut buf, offset as u64)?;
+        if n != buf.len() {
+            return Err(format!(
+                "Read past the end of value log {}, offset: {}, len: {}, read: {}",
+                self._path, offset, len, n
+            )
+            .into());
+        }
+        Ok(Cow::Owned(buf))
     }
 
     // Done written, reopen with read only permisson for file and mmap.
     pub(crate) fn done_writing(&mut self, offset: u32) -> Result<()> {
         self.sync()?;
-        let mut_mmap = self.mut_mmap();
-        mut_mmap.flush_async()?;
+        if let Some(MmapType(Either::Right(ref m))) = self._mmap {
+            m.flush_async()?;
+        }
         self.fd.as_mut().unwrap().set_len(offset as u64)?;
         self.fd.as_mut().unwrap().sync_all()?;
         {
@@ -292,6 +501,11 @@ impl LogFile {
     }
 
     pub(crate) fn set_write(&mut self, sz: u64) -> Result<()> {
+        if self.write_mode == FileLoadingMode::FileIO {
+            // Written at its end with pwrite, nothing is preallocated.
+            self._mmap.take();
+            return Ok(());
+        }
         self.fd.as_mut().unwrap().set_len(sz as u64)?;
         info!("reset file size:{}", sz);
         let mut _mmap = unsafe { Mmap::map(&self.fd.as_ref().unwrap())?.make_mut()? };
@@ -310,7 +524,7 @@ impl LogFile {
     }
 
     // return file reference
-    fn file_ref(&self) -> &File {
+    pub(crate) fn file_ref(&self) -> &File {
         self.fd.as_ref().unwrap()
     }
 
@@ -320,6 +534,27 @@ impl LogFile {
     }
 
     pub(crate) fn write_buffer(&mut self, buffer: &[u8], offset: usize) -> Result<usize> {
+        let encrypted;
+        let buffer = match &self.cipher {
+            Some(cipher) => {
+                let mut data = buffer.to_vec();
+                cipher.xor(offset as u64, &mut data)?;
+                encrypted = data;
+                encrypted.as_slice()
+            }
+            None => buffer,
+        };
+        if self._mmap.is_none() {
+            write_at(self.file_ref(), buffer, offset as u64)?;
+            return Ok(buffer.len());
+        }
+        // A batch bigger than the space left, the file grows and is mapped again.
+        let need = (offset + buffer.len()) as u64;
+        if need > self.mut_mmap().len() as u64 {
+            let sz = need.max(2 * self.sz as u64);
+            info!("Growing value log {} to {} bytes", self._path, sz);
+            self.set_write(sz)?;
+        }
         let wt = self.mut_mmap();
         let mut wt = &mut wt[offset..];
         wt.write(buffer).map_err(|err| err.into())
@@ -329,6 +564,21 @@ impl LogFile {
         self._mmap.as_ref().unwrap().get_mmap()
     }
 
+    // Flushes the written bytes [offset, offset + len) to disk, a file done writing is synced
+    // already.
+    pub(crate) fn sync_range(&self, offset: usize, len: usize) -> Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        match self._mmap {
+            Some(MmapType(Either::Right(ref m))) => m.flush_range(offset, len)?,
+            // Written with pwrite.
+            None => self.file_ref().sync_data()?,
+            _ => {}
+        }
+        Ok(())
+    }
+
     // You must hold lf.lock to sync()
     fn sync(&mut self) -> Result<()> {
         self.fd.as_mut().unwrap().sync_all()?;
@@ -389,3 +639,125 @@ fn test_write_file() {
         String::from_utf8_lossy(vlog.mmap_slice())
     );
 }
+
+#[test]
+fn read_modes_and_grow() {
+    let dir = crate::test_util::create_random_tmp_dir();
+    let path = format!("{}/000000.vlog", dir);
+    let mut lf = LogFile {
+        _path: Box::new(path.clone()),
+        fd: Some(create_synced_file(&path, false).unwrap()),
+        fid: 0,
+        _mmap: None,
+        sz: 0,
+        loading_mode: FileLoadingMode::FileIO,
+        write_mode: FileLoadingMode::MemoryMap,
+        format: ValueLogFormat::Native,
+        holes: BTreeMap::new(),
+        cipher: None,
+        delete_on_drop: AtomicBool::new(false),
+    };
+    lf.set_write(16).unwrap();
+    // Past the mapped size, the file grows.
+    let data = (0..100u8).collect::<Vec<_>>();
+    assert_eq!(lf.write_buffer(&data, 10).unwrap(), data.len());
+    let vp = ValuePointer {
+        fid: 0,
+        len: 100,
+        offset: 10,
+    };
+    assert_eq!(lf.read(&vp).unwrap().as_ref(), data.as_slice());
+    lf.done_writing(110).unwrap();
+    // Read with pread once done.
+    assert!(lf._mmap.is_none());
+    assert_eq!(lf.read(&vp).unwrap().as_ref(), data.as_slice());
+    let past = ValuePointer {
+        fid: 0,
+        len: 100,
+        offset: 20,
+    };
+    assert!(lf.read(&past).is_err());
+
+    lf.loading_mode = FileLoadingMode::MemoryMap;
+    lf.open_read_only().unwrap();
+    assert!(lf._mmap.is_some());
+    assert_eq!(lf.read(&vp).unwrap().as_ref(), data.as_slice());
+    assert!(lf.read(&past).is_err());
+}
+
+#[tokio::test]
+async fn go_format_entries() {
+    use crate::table::go_table::crc32c;
+    use crate::y::Encode;
+    let entry = Entry::default()
+        .key(b"key".to_vec())
+        .value(b"value".to_vec())
+        .user_meta(7)
+        .cas_counter(9);
+    // The legacy layout of Go badger, before v1.0.
+    let mut want = vec![0, 0, 0, 3, 0, 0, 0, 5, 0, 7];
+    want.extend_from_slice(&9u64.to_be_bytes());
+    want.extend_from_slice(&0u64.to_be_bytes());
+    want.extend_from_slice(b"keyvalue");
+    let crc = crc32c(&want);
+    want.extend_from_slice(&crc.to_be_bytes());
+    let mut buf = vec![];
+    assert_eq!(
+        entry.enc_with(ValueLogFormat::GoLegacy, &mut buf).unwrap(),
+        want.len()
+    );
+    assert_eq!(buf, want);
+    let mut native = vec![];
+    entry.enc(&mut native).unwrap();
+    assert_eq!(native.len(), Header::encoded_size() + 8 + 4);
+    assert!(Entry::from_slice(ValueLogFormat::Native, 0, &buf).is_err());
+    assert!(Entry::from_slice(ValueLogFormat::GoLegacy, 0, &native).is_err());
+
+    let dir = crate::test_util::create_random_tmp_dir();
+    let path = format!("{}/000000.vlog", dir);
+    buf.extend_from_slice(&want);
+    std::fs::write(&path, &buf).unwrap();
+    let mut lf = LogFile::new(&path).unwrap();
+    assert_eq!(lf.detect_format().unwrap(), Some(ValueLogFormat::GoLegacy));
+    lf.format = ValueLogFormat::GoLegacy;
+    let mut offsets = vec![];
+    let end = lf
+        .iterate(0, &mut |entry, vp| {
+            assert_eq!(entry.get_key(), b"key");
+            assert_eq!(entry.get_cas_counter(), 9);
+            offsets.push((vp.offset, vp.len));
+            Box::pin(async { Ok(true) })
+        })
+        .await
+        .unwrap();
+    assert!(end.is_none());
+    assert_eq!(offsets, vec![(0, 38), (38, 38)]);
+
+    std::fs::write(&path, &native).unwrap();
+    let lf = LogFile::new(&path).unwrap();
+    assert_eq!(lf.detect_format().unwrap(), Some(ValueLogFormat::Native));
+    std::fs::write(&path, b"").unwrap();
+    let lf = LogFile::new(&path).unwrap();
+    assert_eq!(lf.detect_format().unwrap(), None);
+}
+
+#[test]
+fn entry_expires_at() {
+    use crate::y::Encode;
+    let entry = Entry::default()
+        .key(b"key".to_vec())
+        .value(b"value".to_vec())
+        .expires_at(1 << 40);
+    let mut native = vec![];
+    entry.enc(&mut native).unwrap();
+    let got = Entry::from_slice(ValueLogFormat::Native, 0, &native).unwrap();
+    assert_eq!(got.get_expires_at(), 1 << 40);
+    let mut got = Entry::default();
+    got.dec(&mut Cursor::new(&native)).unwrap();
+    assert_eq!(got.get_expires_at(), 1 << 40);
+    // The legacy Go layout has no room for it.
+    let mut buf = vec![];
+    entry.enc_with(ValueLogFormat::GoLegacy, &mut buf).unwrap();
+    let got = Entry::from_slice(ValueLogFormat::GoLegacy, 0, &buf).unwrap();
+    assert_eq!(got.get_expires_at(), 0);
+}
@@ -76,12 +76,13 @@ impl ManifestFile {
         }
         let mut mf_changes = ManifestChangeSet::default();
         mf_changes.changes.extend(changes);
-        // info!("{:?}", self.manifest.read().await.tables.keys());
-        // Maybe we could user O_APPEND instead (on certain file systems)
-        apply_manifest_change_set(self.manifest.clone(), &mf_changes).await?;
+        // The changes go to a copy first, a change set that doesn't apply in full or can't be
+        // written leaves the manifest as it was.
+        let next = Arc::new(RwLock::new(self.manifest.read().await.clone()));
+        apply_manifest_change_set(next.clone(), &mf_changes).await?;
         // Rewrite manifest if it'd shrink by 1/10, and it's big enough to care
         let rewrite = {
-            let mf_lck = self.manifest.read().await;
+            let mf_lck = next.read().await;
             info!("{}, {}", mf_lck.creations, mf_lck.deletions);
             mf_lck.deletions
                 > self
@@ -92,7 +93,7 @@ impl ManifestFile {
         };
         if rewrite {
             info!("need to rewrite manifest file");
-            self.rewrite().await?;
+            self.rewrite_from(&next).await?;
         } else {
             let mf_set_content = convert_manifest_set_to_vec(&mf_changes);
             let mut buffer = Vec::with_capacity(mf_set_content.len() + 4 + 4);
@@ -100,20 +101,39 @@ impl ManifestFile {
             let crc32 = crc32fast::hash(&mf_set_content);
             buffer.write_u32(crc32).await?;
             tokio::io::AsyncWriteExt::write_all(&mut buffer, &mf_set_content).await?;
-            self.fp.as_mut().unwrap().write_all(&buffer).await?;
+            let fp = self.fp.as_mut().unwrap();
+            let offset = fp.seek(SeekFrom::End(0)).await?;
+            let written: std::io::Result<()> = async {
+                fp.write_all(&buffer).await?;
+                fp.flush().await?;
+                fp.sync_data().await
+            }
+            .await;
+            if let Err(err) = written {
+                // Cut the change set written in part, the ones after it would not be replayed.
+                fp.set_len(offset).await?;
+                fp.seek(SeekFrom::End(0)).await?;
+                return Err(err.into());
+            }
         }
-        self.fp.as_mut().unwrap().flush().await?;
+        std::mem::swap(&mut *self.manifest.write().await, &mut *next.write().await);
         Ok(())
     }
 
     /// Must be called while appendLock is held.
     pub async fn rewrite(&mut self) -> Result<()> {
+        let manifest = self.manifest.clone();
+        self.rewrite_from(&manifest).await
+    }
+
+    // Rewrites the manifest file with the content of `manifest`.
+    async fn rewrite_from(&mut self, manifest: &TArcRW<Manifest>) -> Result<()> {
         {
             self.fp.take();
         }
-        let (fp, n) = Self::help_rewrite(&self.directory, &self.manifest).await?;
+        let (fp, n) = Self::help_rewrite(&self.directory, manifest).await?;
         self.fp = Some(fp);
-        let mut m_lck = self.manifest.write().await;
+        let mut m_lck = manifest.write().await;
         m_lck.creations = n;
         m_lck.deletions = 0;
         info!("Finished rewrite manifest file, tables count: {}", n);
@@ -151,6 +171,7 @@ impl ManifestFile {
         }
 
         // persistent
+        wt.flush().await?;
         fp.write_all(&*wt.into_inner()).await?;
         fp.sync_all().await?;
         drop(fp);
@@ -552,4 +573,38 @@ mod tests {
                 .unwrap();
         }
     }
+
+    #[tokio::test]
+    async fn t_manifest_change_set_all_or_nothing() {
+        let fpath = create_random_tmp_dir();
+        let mut mf = super::ManifestFile::open_or_create_manifest_file(&fpath, 10)
+            .await
+            .unwrap();
+        let create = |id: u64| {
+            ManifestChangeBuilder::new(id)
+                .with_op(CREATE)
+                .with_level(1)
+                .build()
+        };
+        let delete = |id: u64| ManifestChangeBuilder::new(id).with_op(DELETE).build();
+        mf.add_changes(vec![create(1), create(2)]).await.unwrap();
+        // The deletion of a missing table fails the whole set.
+        assert!(mf
+            .add_changes(vec![create(3), delete(2), delete(4)])
+            .await
+            .is_err());
+        mf.add_changes(vec![delete(1), create(5)]).await.unwrap();
+        let tables = |mf: &super::Manifest| {
+            let mut ids = mf.tables.keys().copied().collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        assert_eq!(tables(&*mf.manifest.read().await), vec![2, 5]);
+        mf.close();
+
+        let mf = super::ManifestFile::open_or_create_manifest_file(&fpath, 10)
+            .await
+            .unwrap();
+        assert_eq!(tables(&*mf.manifest.read().await), vec![2, 5]);
+    }
 }
@@ -1,9 +1,14 @@
+use crate::compaction::{CompactionFilter, CompactionListener};
 use crate::skl::PtrAlign;
 use crate::value_log::Entry;
-use crate::y::{CAS_SIZE, META_SIZE, USER_META_SIZE};
+use crate::y::{
+    Clock, Error, Result, SystemClock, CAS_SIZE, MAX_VALUE_THRESHOLD, META_SIZE, USER_META_SIZE,
+};
 use crate::{cals_size_with_align, Node, ValueStruct};
 use rand::random;
 use std::env::temp_dir;
+use std::sync::Arc;
+use std::time::Duration;
 
 /// Specifies how data in LSM table files and value log files should
 /// be loaded.
@@ -17,6 +22,110 @@ pub enum FileLoadingMode {
     MemoryMap,
 }
 
+/// When the checksums of the blocks of LSM table files are verified.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChecksumVerificationMode {
+    /// Never
+    NoVerification,
+    /// Every block of a table when the table is opened, a table that fails isn't used
+    OnTableRead,
+    /// Every block when it's read
+    OnBlockRead,
+    /// Both
+    OnTableAndBlockRead,
+}
+
+impl ChecksumVerificationMode {
+    pub(crate) fn on_table_read(self) -> bool {
+        matches!(
+            self,
+            ChecksumVerificationMode::OnTableRead | ChecksumVerificationMode::OnTableAndBlockRead
+        )
+    }
+
+    pub(crate) fn on_block_read(self) -> bool {
+        matches!(
+            self,
+            ChecksumVerificationMode::OnBlockRead | ChecksumVerificationMode::OnTableAndBlockRead
+        )
+    }
+}
+
+/// Specifies how the blocks of LSM table files are compressed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompressionType {
+    /// Blocks are stored as is
+    None,
+    /// Blocks are compressed with ZSTD
+    ZSTD,
+}
+
+impl CompressionType {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            CompressionType::None => 0,
+            CompressionType::ZSTD => 1,
+        }
+    }
+
+    pub(crate) fn from_u8(b: u8) -> Option<CompressionType> {
+        match b {
+            0 => Some(CompressionType::None),
+            1 => Some(CompressionType::ZSTD),
+            _ => None,
+        }
+    }
+}
+
+/// Specifies the filter of LSM tables that is probed before reading their blocks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterType {
+    /// Bloom filter
+    Bloom,
+    /// Xor filter, a lower false positive rate than bloom for the same size
+    Xor,
+}
+
+impl FilterType {
+    pub(crate) fn to_u8(self) -> u8 {
+        match self {
+            FilterType::Bloom => 0,
+            FilterType::Xor => 1,
+        }
+    }
+
+    pub(crate) fn from_u8(b: u8) -> Option<FilterType> {
+        match b {
+            0 => Some(FilterType::Bloom),
+            1 => Some(FilterType::Xor),
+            _ => None,
+        }
+    }
+}
+
+/// Access pattern of memory mapped LSM tables, passed to the OS with `madvise`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessHint {
+    /// The OS default, some read ahead
+    Normal,
+    /// Point lookups, no read ahead
+    Random,
+    /// Scans, aggressive read ahead
+    Sequential,
+}
+
+/// The layout of the entries in the value log files. A file is read in the format it was
+/// written in, whatever the option, so a value dir may hold files of both.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueLogFormat {
+    /// Header padded to 32 bytes, CRC32 (IEEE) checksum
+    Native,
+    /// The legacy layout of Go badger before v1.0: 26 bytes header, CRC32 (Castagnoli)
+    /// checksum. The files can be moved between the two implementations. Go badger v1.0 and
+    /// later write other headers, their files aren't read.
+    GoLegacy,
+}
+
 /// Params for creating DB object.
 #[derive(Debug, Clone)]
 pub struct Options {
@@ -24,26 +133,109 @@ pub struct Options {
     /// -------------------
     /// Directory to store the data in. Should exist and be writable.
     pub dir: Box<String>,
-    /// Directory to store the value log in. Can be the same as Dir. Should
+    /// Directory to store the value log in. Can be the same as Dir, or on another file
+    /// system, e.g. the value log on cheap disks and the tables on fast ones. Should
     /// exist and be writable.
     pub value_dir: Box<String>,
     /// 2. Frequently modified flags
     /// -----------------------------
-    /// Sync all writes to disk. Setting this to true would slow down data
+    /// Sync all writes to disk, every batch, small values included, is in the value log
+    /// (or the memtable WAL, see `mem_table_wal`) and synced before it's acknowledged. Setting this to true would slow down data
     /// loading significantly.
     pub sync_writes: bool,
+    /// Keeps a write-ahead log of every memtable in `dir`, so the values stored in the LSM
+    /// tree (see `value_threshold`) are on disk before they're acknowledged without being
+    /// written to the value log. It's synced like the value log, see `sync_writes`.
+    pub mem_table_wal: bool,
+    /// How often the value log is synced in background when `sync_writes` is off, it bounds
+    /// the writes a crash of the machine may lose. None leaves it to the OS and the memtable
+    /// flushes.
+    pub value_log_sync_interval: Option<Duration>,
     /// How should LSM tree be accessed.
     pub table_loading_mode: FileLoadingMode,
+    /// How the value log files are read. `MemoryMap` maps them, `FileIO` reads them with
+    /// pread, `LoadToRADM` is the same as `MemoryMap`. A file that can't be mapped is read with
+    /// pread. The file written to is set apart, see `active_value_log_loading_mode`.
+    pub value_log_loading_mode: FileLoadingMode,
+    /// How the value log file being written to is accessed. `MemoryMap` preallocates and maps
+    /// it, the writes are copies into the map. `FileIO` writes the batches with pwrite and
+    /// reads with pread, nothing is preallocated, e.g. for reads that mostly hit recent values
+    /// through the page cache anyway. `LoadToRADM` is the same as `MemoryMap`.
+    pub active_value_log_loading_mode: FileLoadingMode,
+    /// The format new value log files are written in, see `ValueLogFormat`.
+    pub value_log_format: ValueLogFormat,
+    /// The application gives the timestamps of the transactions, see `KV::new_transaction_at`
+    /// and `Txn::commit_at`. The keys are versioned by the commit timestamps, they're read and
+    /// written only through transactions then.
+    pub managed_txns: bool,
+    /// Checks the conflicts of the update transactions at commit, see `Txn`. Without, their
+    /// reads aren't tracked and their commits never fail with `TxnConflict`, the last one
+    /// wins.
+    pub detect_conflicts: bool,
+    /// When the block checksums of the tables are verified. On table read, the tables of
+    /// the manifest are verified when the DB is opened, open fails if any is corrupted, and
+    /// the ones written by flushes and compactions once written. On block read, a corrupted
+    /// block fails its read with `ChecksumMismatch`.
+    pub checksum_verification_mode: ChecksumVerificationMode,
+    /// Master key, of 16, 24 or 32 bytes, encrypting the data keys the tables, value log
+    /// files and memtable WALs are encrypted with, see `encryption_key_rotation`. The data
+    /// keys are kept in the `KEYREGISTRY` file of `dir`, a file records the id of its key in
+    /// its header. Empty leaves the data plain. Set on a plain DB, the files written from
+    /// then on are encrypted. Needs the `encryption` feature.
+    pub encryption_key: Vec<u8>,
+    /// How long a data key encrypts the new files before the next one is made.
+    pub encryption_key_rotation: Duration,
+    /// Moves the table files the manifest doesn't know about, e.g. left by a crashed
+    /// compaction, to `{dir}/quarantine` at open instead of deleting them.
+    pub quarantine_orphan_tables: bool,
+    /// Truncates the value log at the first torn or corrupt entry found when opening the
+    /// DB, losing the entries after it. If false, open fails with what would be lost.
+    pub truncate: bool,
+    /// Access hint of the tables of every level, indexed by level, when tables are
+    /// memory mapped. Levels past the end use `Normal`. Compaction inputs are always
+    /// read as `Sequential`.
+    pub level_access_hints: Vec<AccessHint>,
     /// 3. Flags that user might want to review
     /// ----------------------------------------
     /// The following affect all levels of LSM tree.
-    /// Each table (or file) is at most this size.
+    /// Each table (or file) is at most this size, it's also the size of a memtable and so
+    /// of the level 0 tables.
     pub max_table_size: u64,
+    /// Size of the tables compactions write to every level, indexed by level. Levels past
+    /// the end use `max_table_size`.
+    pub level_table_sizes: Vec<u64>,
+    /// Each block in a table is roughly this size, blocks are also cut
+    /// every 100 keys.
+    pub block_size: usize,
+    /// Number of blocks a table iterator reads ahead in background during
+    /// scans and compactions, on a few threads shared by all the iterators. 0, the
+    /// default, disables it.
+    pub prefetch_blocks: usize,
+    /// Bytes of table indexes and bloom filters kept in memory, they're cached
+    /// apart from data blocks. 0 pins the indexes of all tables.
+    pub index_cache_size: u64,
+    /// Bytes of decoded table blocks kept in memory, shared by all the tables.
+    /// 0 reads every block from its table.
+    pub block_cache_size: u64,
+    /// Compression of table blocks, blocks are compressed on background
+    /// threads while the table is built.
+    pub compression: CompressionType,
+    /// Level used when `compression` is ZSTD.
+    pub zstd_compression_level: i32,
+    /// Filter built for new tables, tables keep the filter they were built with.
+    pub filter_type: FilterType,
     /// Equals SizeOf(Li+1)/SizeOf(Li).
     pub level_size_multiplier: u64,
-    /// Maximum number of levels of compaction.
+    /// Number of levels of the LSM tree, at least 2. The last level has no size limit. A DB
+    /// can't be opened with fewer levels than the tables it has use.
     pub max_levels: usize,
-    /// If value size >= this threshold, only store value offsets in tree.
+    /// Versions of a key compactions keep among the ones at or below the discard ts, when
+    /// keys are versioned, see `KV::set_discard_ts`. The iterators over all the versions
+    /// skip the others, whether a compaction dropped them yet or not. The more are kept, the
+    /// larger the levels and the slower the reads of old keys.
+    pub num_versions_to_keep: usize,
+    /// Values smaller than this are stored in the LSM tree, the others are only in the value
+    /// log and the tree has a pointer to them. At most `MAX_VALUE_THRESHOLD`.
     pub value_threshold: usize,
     /// Maximum number of tables to keep in memory, before stalling.
     pub num_mem_tables: usize,
@@ -55,17 +247,70 @@ pub struct Options {
     /// compacted away.
     pub num_level_zero_tables_stall: usize,
 
-    /// Maximum total size for L1.
+    /// Number of level 0 tables from which writes are slowed down, the more so the closer
+    /// level 0 gets to `num_level_zero_tables_stall`. 0 disables the slowdown.
+    pub level_zero_slowdown_tables: usize,
+
+    /// Longest delay added to a batch of writes under pressure, right before writes stall.
+    pub max_write_delay: Duration,
+
+    /// How long a memtable flush waits for a stalled level 0 before it fails with
+    /// `LevelZeroStalled` and is retried. None waits until compaction catches up.
+    pub level_zero_stall_timeout: Option<Duration>,
+
+    /// Maximum total size of level 1, the first level below level 0. Every next level
+    /// is `level_size_multiplier` times bigger.
+    pub base_level_size: u64,
+    /// The former name of `base_level_size`, it's used instead when it isn't 0.
+    #[deprecated(note = "Renamed to `base_level_size`")]
     pub level_one_size: u64,
 
     /// Size of single value log file.
     pub value_log_file_size: u64,
 
+    /// Maximum number of entries of a value log file, it's rotated on whichever of this and
+    /// `value_log_file_size` comes first. Bounds the work of a replay or GC of a file when
+    /// the values are small.
+    pub value_log_max_entries: u32,
+
+    /// How often a background task runs the value log GC, see `KV::run_value_log_gc`. Once
+    /// a run rewrote a file it runs again right away, until there's nothing left to rewrite,
+    /// and then waits this long. It skips the runs while writes are throttled or it's
+    /// paused, see `KV::pause_background_gc`. None never runs it in background.
+    pub value_log_gc_interval: Option<Duration>,
+
+    /// The discard ratio of the background value log GC, in (0, 1).
+    pub value_log_gc_discard_ratio: f64,
+
     /// Number of compaction workers to run concurrently.
     pub num_compactors: u64,
 
+    /// Maximum number of key ranges a compaction is split into, the ranges are built
+    /// in parallel, each into its own tables. Split at the next level's table bounds.
+    pub max_subcompactions: usize,
+
+    /// Bytes per second compactions may read and write, shared by all the compaction
+    /// workers, so they don't starve the foreground reads and writes. 0 is unlimited.
+    pub compaction_throughput_bytes_per_sec: u64,
+
+    /// Called on the entries rewritten by compactions to keep, remove or change them.
+    pub compaction_filter: Option<Arc<dyn CompactionFilter>>,
+
+    /// Told when compactions are picked, start, write their tables, commit or fail.
+    pub compaction_listener: Option<Arc<dyn CompactionListener>>,
+
+    /// A table of level 1 or below is compacted once reads went past its filter without
+    /// finding their key once per this many bytes of it, at least 100 times, even if its
+    /// level is under its limit. 0 disables these seek compactions.
+    pub seek_compaction_bytes_per_probe: u64,
+
     /// 4. Flags for testing purposes
     /// ------------------------------
+    /// Checks the levels, the tables written and the manifest after every compaction,
+    /// a compaction that broke them fails.
+    pub validate_after_compaction: bool,
+    /// Time that the expiry of values is checked against, by reads and compactions.
+    pub clock: Arc<dyn Clock>,
     /// Stops LSM tree from compactions.
     pub do_not_compact: bool,
     /// max entries in batch
@@ -78,7 +323,7 @@ impl Options {
     // TODO FIXME
     pub fn estimate_size(&self, entry: &Entry) -> usize {
         let key_size = entry.key.len();
-        if entry.value.len() < self.value_threshold {
+        if self.value_in_lsm(entry.value.len()) {
             key_size + entry.value.len()
         } else {
             let value_size = ValueStruct::header_size();
@@ -86,14 +331,294 @@ impl Options {
         }
     }
 
+    /// Returns true if the value log is kept in `dir`.
+    pub fn value_dir_is_dir(&self) -> bool {
+        match (
+            std::fs::canonicalize(self.dir.as_str()),
+            std::fs::canonicalize(self.value_dir.as_str()),
+        ) {
+            (Ok(dir), Ok(value_dir)) => dir == value_dir,
+            _ => self.dir == self.value_dir,
+        }
+    }
+
+    /// Returns true if a value of `value_len` bytes is stored in the LSM tree, see
+    /// `value_threshold`.
+    pub fn value_in_lsm(&self, value_len: usize) -> bool {
+        value_len < self.value_threshold
+    }
+
+    /// Returns the access hint of the tables of `level`.
+    pub fn access_hint(&self, level: usize) -> AccessHint {
+        self.level_access_hints
+            .get(level)
+            .copied()
+            .unwrap_or(AccessHint::Normal)
+    }
+
+    /// Returns the size of the tables compactions write to `level`.
+    pub fn table_size(&self, level: usize) -> u64 {
+        self.level_table_sizes
+            .get(level)
+            .copied()
+            .unwrap_or(self.max_table_size)
+    }
+
+    // The size of level 1, from `level_one_size` for the options set before its rename.
+    #[allow(deprecated)]
+    fn base_level_size(&self) -> u64 {
+        if self.level_one_size != 0 {
+            self.level_one_size
+        } else {
+            self.base_level_size
+        }
+    }
+
+    /// Returns the maximum total size of `level`, level 0 is limited by its number of
+    /// tables instead and has none.
+    pub fn level_max_size(&self, level: usize) -> u64 {
+        match level {
+            0 => 0,
+            _ => (1..level).fold(self.base_level_size(), |size, _| {
+                size.saturating_mul(self.level_size_multiplier)
+            }),
+        }
+    }
+
+    /// Returns the number of useless probes after which a table of `table_size` bytes is
+    /// compacted, None if seek compactions are disabled.
+    pub fn allowed_useless_probes(&self, table_size: u64) -> Option<u64> {
+        match self.seek_compaction_bytes_per_probe {
+            0 => None,
+            n => Some((table_size / n).max(100)),
+        }
+    }
+
     /// Return the size of allocator arena
     pub fn arena_size(&self) -> u64 {
         self.max_table_size
             + self.max_batch_size
             + self.max_batch_count * (Node::size() as u64)
     }
+
+    /// Returns a builder of the options of a DB in `dir`, the others are the defaults.
+    pub fn builder(dir: &str) -> OptionsBuilder {
+        let mut opt = Options::default();
+        opt.dir = Box::new(dir.to_string());
+        opt.value_dir = Box::new(dir.to_string());
+        OptionsBuilder { opt }
+    }
+
+    /// Checks the options alone and against each other, it's done by `KV::open` before
+    /// any file is touched.
+    pub fn validate(&self) -> Result<()> {
+        if self.dir.is_empty() || self.value_dir.is_empty() {
+            return Err("Invalid dir or value_dir, they must not be empty".into());
+        }
+        if !(self.value_log_file_size <= 2 << 30 && self.value_log_file_size >= 1 << 20) {
+            return Err(Error::ValueLogSize);
+        }
+        if self.value_threshold > MAX_VALUE_THRESHOLD {
+            return Err(Error::ValueThreshold);
+        }
+        if self.value_threshold as u64 >= self.max_table_size {
+            return Err(format!(
+                "Invalid value_threshold {}, it must be less than max_table_size {}, the values \
+                stored in the LSM tree have to fit in a memtable",
+                self.value_threshold, self.max_table_size
+            )
+            .into());
+        }
+        if self.value_log_max_entries == 0 {
+            return Err("Invalid value_log_max_entries, it must be positive".into());
+        }
+        if self.value_log_gc_interval.is_some()
+            && (self.value_log_gc_discard_ratio <= 0.0 || self.value_log_gc_discard_ratio >= 1.0)
+        {
+            return Err("Invalid value_log_gc_discard_ratio, it must be in (0, 1)".into());
+        }
+        if self.block_size == 0 {
+            return Err("Invalid block_size, it must be positive".into());
+        }
+        if ![0, 16, 24, 32].contains(&self.encryption_key.len()) {
+            return Err(format!(
+                "Invalid encryption_key of {} bytes, expected 16, 24 or 32",
+                self.encryption_key.len()
+            )
+            .into());
+        }
+        if self.num_mem_tables == 0 {
+            return Err("Invalid num_mem_tables, it must be positive".into());
+        }
+        if self.max_levels < 2 {
+            return Err(format!(
+                "Invalid max_levels {}, at least 2 are needed",
+                self.max_levels
+            )
+            .into());
+        }
+        if self.base_level_size() == 0 {
+            return Err("Invalid base_level_size, it must be positive".into());
+        }
+        if self.level_size_multiplier == 0 {
+            return Err("Invalid level_size_multiplier, it must be positive".into());
+        }
+        if self.level_table_sizes.contains(&0) {
+            return Err("Invalid level_table_sizes, they must be positive".into());
+        }
+        if self.num_level_zero_tables_stall <= self.num_level_zero_tables {
+            return Err(format!(
+                "Invalid num_level_zero_tables_stall {}, it must be more than \
+                num_level_zero_tables {}, level 0 is compacted before writes stall",
+                self.num_level_zero_tables_stall, self.num_level_zero_tables
+            )
+            .into());
+        }
+        if self.compression == CompressionType::ZSTD
+            && !(1..=22).contains(&self.zstd_compression_level)
+        {
+            return Err(format!(
+                "Invalid zstd_compression_level {}, it must be in [1, 22]",
+                self.zstd_compression_level
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Builds `Options`, see `Options::builder`. `build` validates them.
+#[derive(Debug, Clone)]
+pub struct OptionsBuilder {
+    opt: Options,
+}
+
+impl OptionsBuilder {
+    /// See `Options::value_dir`.
+    pub fn value_dir(mut self, value_dir: &str) -> Self {
+        self.opt.value_dir = Box::new(value_dir.to_string());
+        self
+    }
+
+    /// See `Options::sync_writes`.
+    pub fn sync_writes(mut self, sync_writes: bool) -> Self {
+        self.opt.sync_writes = sync_writes;
+        self
+    }
+
+    /// See `Options::max_table_size`.
+    pub fn max_table_size(mut self, max_table_size: u64) -> Self {
+        self.opt.max_table_size = max_table_size;
+        self
+    }
+
+    /// See `Options::block_size`.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.opt.block_size = block_size;
+        self
+    }
+
+    /// See `Options::encryption_key` and `Options::encryption_key_rotation`.
+    pub fn encryption_key(mut self, key: &[u8], rotation: Duration) -> Self {
+        self.opt.encryption_key = key.to_vec();
+        self.opt.encryption_key_rotation = rotation;
+        self
+    }
+
+    /// See `Options::value_threshold`.
+    pub fn value_threshold(mut self, value_threshold: usize) -> Self {
+        self.opt.value_threshold = value_threshold;
+        self
+    }
+
+    /// See `Options::compression`, `level` is used by ZSTD.
+    pub fn compression(mut self, compression: CompressionType, level: i32) -> Self {
+        self.opt.compression = compression;
+        self.opt.zstd_compression_level = level;
+        self
+    }
+
+    /// See `Options::num_compactors`.
+    pub fn num_compactors(mut self, num_compactors: u64) -> Self {
+        self.opt.num_compactors = num_compactors;
+        self
+    }
+
+    /// See `Options::index_cache_size`.
+    pub fn index_cache_size(mut self, index_cache_size: u64) -> Self {
+        self.opt.index_cache_size = index_cache_size;
+        self
+    }
+
+    /// See `Options::block_cache_size`.
+    pub fn block_cache_size(mut self, block_cache_size: u64) -> Self {
+        self.opt.block_cache_size = block_cache_size;
+        self
+    }
+
+    /// See `Options::num_mem_tables`.
+    pub fn num_mem_tables(mut self, num_mem_tables: usize) -> Self {
+        self.opt.num_mem_tables = num_mem_tables;
+        self
+    }
+
+    /// See `Options::num_level_zero_tables` and `Options::num_level_zero_tables_stall`.
+    pub fn level_zero_tables(mut self, compact: usize, stall: usize) -> Self {
+        self.opt.num_level_zero_tables = compact;
+        self.opt.num_level_zero_tables_stall = stall;
+        self
+    }
+
+    /// See `Options::max_levels`.
+    pub fn max_levels(mut self, max_levels: usize) -> Self {
+        self.opt.max_levels = max_levels;
+        self
+    }
+
+    /// See `Options::num_versions_to_keep`.
+    pub fn num_versions_to_keep(mut self, num_versions_to_keep: usize) -> Self {
+        self.opt.num_versions_to_keep = num_versions_to_keep;
+        self
+    }
+
+    /// See `Options::value_log_file_size`.
+    pub fn value_log_file_size(mut self, value_log_file_size: u64) -> Self {
+        self.opt.value_log_file_size = value_log_file_size;
+        self
+    }
+
+    /// See `Options::value_log_max_entries`.
+    pub fn value_log_max_entries(mut self, value_log_max_entries: u32) -> Self {
+        self.opt.value_log_max_entries = value_log_max_entries;
+        self
+    }
+
+    /// See `Options::table_loading_mode`.
+    pub fn table_loading_mode(mut self, mode: FileLoadingMode) -> Self {
+        self.opt.table_loading_mode = mode;
+        self
+    }
+
+    /// See `Options::value_log_loading_mode`.
+    pub fn value_log_loading_mode(mut self, mode: FileLoadingMode) -> Self {
+        self.opt.value_log_loading_mode = mode;
+        self
+    }
+
+    /// See `Options::truncate`.
+    pub fn truncate(mut self, truncate: bool) -> Self {
+        self.opt.truncate = truncate;
+        self
+    }
+
+    /// Returns the options, or why they can't be used.
+    pub fn build(self) -> Result<Options> {
+        self.opt.validate()?;
+        Ok(self.opt)
+    }
 }
 
+#[allow(deprecated)]
 impl Default for Options {
     fn default() -> Self {
         let id = random::<u64>();
@@ -101,17 +626,53 @@ impl Default for Options {
             dir: Box::new(id.to_string()),
             value_dir: Box::new(id.to_string()),
             sync_writes: false,
+            mem_table_wal: false,
+            value_log_sync_interval: Some(Duration::from_secs(1)),
             table_loading_mode: FileLoadingMode::LoadToRADM,
+            value_log_loading_mode: FileLoadingMode::MemoryMap,
+            active_value_log_loading_mode: FileLoadingMode::MemoryMap,
+            value_log_format: ValueLogFormat::Native,
+            managed_txns: false,
+            detect_conflicts: true,
+            checksum_verification_mode: ChecksumVerificationMode::NoVerification,
+            encryption_key: vec![],
+            encryption_key_rotation: Duration::from_secs(10 * 24 * 3600),
+            quarantine_orphan_tables: false,
+            truncate: false,
+            level_access_hints: vec![],
             max_table_size: 64 << 20,
+            level_table_sizes: vec![],
+            block_size: 4 << 10,
+            prefetch_blocks: 0,
+            index_cache_size: 0,
+            block_cache_size: 0,
+            compression: CompressionType::None,
+            zstd_compression_level: 1,
+            filter_type: FilterType::Bloom,
             level_size_multiplier: 10,
             max_levels: 7,
+            num_versions_to_keep: 1,
             value_threshold: 20,
             num_mem_tables: 5,
             num_level_zero_tables: 5,
             num_level_zero_tables_stall: 10,
-            level_one_size: 256 << 20,
+            level_zero_slowdown_tables: 8,
+            max_write_delay: Duration::from_millis(10),
+            level_zero_stall_timeout: None,
+            base_level_size: 256 << 20,
+            level_one_size: 0,
             value_log_file_size: 1 << 30,
+            value_log_max_entries: 1000000,
+            value_log_gc_interval: None,
+            value_log_gc_discard_ratio: 0.5,
             num_compactors: 3,
+            max_subcompactions: 4,
+            compaction_throughput_bytes_per_sec: 0,
+            compaction_filter: None,
+            compaction_listener: None,
+            seek_compaction_bytes_per_probe: 16 << 10,
+            validate_after_compaction: false,
+            clock: Arc::new(SystemClock),
             do_not_compact: false,
             max_batch_count: 200,
             max_batch_size: 1 << 13,
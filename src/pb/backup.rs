@@ -31,6 +31,8 @@ pub struct KVPair {
     pub key: ::std::vec::Vec<u8>,
     pub value: ::std::vec::Vec<u8>,
     pub userMeta: ::std::vec::Vec<u8>,
+    pub version: u64,
+    pub expiresAt: u64,
     // special fields
     pub unknown_fields: ::protobuf::UnknownFields,
     pub cached_size: ::protobuf::rt::CachedSize,
@@ -64,6 +66,16 @@ impl KVPair {
             |m: &KVPair| { &m.userMeta },
             |m: &mut KVPair| { &mut m.userMeta },
         ));
+        fields.push(::protobuf::reflect::rt::v2::make_simpler_field_accessor::<_, _>(
+            "version",
+            |m: &KVPair| { &m.version },
+            |m: &mut KVPair| { &mut m.version },
+        ));
+        fields.push(::protobuf::reflect::rt::v2::make_simpler_field_accessor::<_, _>(
+            "expiresAt",
+            |m: &KVPair| { &m.expiresAt },
+            |m: &mut KVPair| { &mut m.expiresAt },
+        ));
         ::protobuf::reflect::GeneratedMessageDescriptorData::new_2::<KVPair>(
             "KVPair",
             0,
@@ -99,6 +111,18 @@ impl ::protobuf::Message for KVPair {
                     }
                     self.userMeta = is.read_bytes()?;
                 },
+                4 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    self.version = is.read_uint64()?;
+                },
+                5 => {
+                    if wire_type != ::protobuf::wire_format::WireTypeVarint {
+                        return ::std::result::Result::Err(::protobuf::rt::unexpected_wire_type(wire_type));
+                    }
+                    self.expiresAt = is.read_uint64()?;
+                },
                 _ => {
                     ::protobuf::rt::read_unknown_or_skip_group(field_number, wire_type, is, self.mut_unknown_fields())?;
                 },
@@ -120,6 +144,12 @@ impl ::protobuf::Message for KVPair {
         if !self.userMeta.is_empty() {
             my_size += ::protobuf::rt::bytes_size(3, &self.userMeta);
         }
+        if self.version != 0 {
+            my_size += ::protobuf::rt::value_size(4, self.version, ::protobuf::wire_format::WireTypeVarint);
+        }
+        if self.expiresAt != 0 {
+            my_size += ::protobuf::rt::value_size(5, self.expiresAt, ::protobuf::wire_format::WireTypeVarint);
+        }
         my_size += ::protobuf::rt::unknown_fields_size(self.get_unknown_fields());
         self.cached_size.set(my_size);
         my_size
@@ -135,6 +165,12 @@ impl ::protobuf::Message for KVPair {
         if !self.userMeta.is_empty() {
             os.write_bytes(3, &self.userMeta)?;
         }
+        if self.version != 0 {
+            os.write_uint64(4, self.version)?;
+        }
+        if self.expiresAt != 0 {
+            os.write_uint64(5, self.expiresAt)?;
+        }
         os.write_unknown_fields(self.get_unknown_fields())?;
         ::std::result::Result::Ok(())
     }
@@ -164,6 +200,8 @@ impl ::protobuf::Message for KVPair {
             key: ::std::vec::Vec::new(),
             value: ::std::vec::Vec::new(),
             userMeta: ::std::vec::Vec::new(),
+            version: 0,
+            expiresAt: 0,
             unknown_fields: ::protobuf::UnknownFields::new(),
             cached_size: ::protobuf::rt::CachedSize::new(),
         };
@@ -176,6 +214,8 @@ impl ::protobuf::Clear for KVPair {
         self.key.clear();
         self.value.clear();
         self.userMeta.clear();
+        self.version = 0;
+        self.expiresAt = 0;
         self.unknown_fields.clear();
     }
 }
@@ -191,10 +231,11 @@ impl ::protobuf::reflect::ProtobufValue for KVPair {
 }
 
 static file_descriptor_proto_data: &'static [u8] = b"\
-    \n\x13src/pb/backup.proto\x12\x06protos\"L\n\x06KVPair\x12\x10\n\x03key\
-    \x18\x01\x20\x01(\x0cR\x03key\x12\x14\n\x05value\x18\x02\x20\x01(\x0cR\
-    \x05value\x12\x1a\n\x08userMeta\x18\x03\x20\x01(\x0cR\x08userMetab\x06pr\
-    oto3\
+    \n\x13src/pb/backup.proto\x12\x06protos\"\x84\x01\n\x06KVPair\x12\x10\n\
+    \x03key\x18\x01\x20\x01(\x0cR\x03key\x12\x14\n\x05value\x18\x02\x20\x01\
+    (\x0cR\x05value\x12\x1a\n\x08userMeta\x18\x03\x20\x01(\x0cR\x08userMeta\
+    \x12\x18\n\x07version\x18\x04\x20\x01(\x04R\x07version\x12\x1c\n\x09exp\
+    iresAt\x18\x05\x20\x01(\x04R\x09expiresAtb\x06proto3\
 ";
 
 /// `FileDescriptorProto` object which was a source for this generated file
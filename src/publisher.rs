@@ -0,0 +1,150 @@
+use crate::kv::_BADGER_PREFIX;
+use crate::value_log::{Entry, MetaBit};
+use async_channel::{bounded, Receiver, Sender};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// The batches a subscriber can be behind before the writes wait for it.
+const SUBSCRIBER_CAPACITY: usize = 64;
+
+/// A key and its value, as delivered to the subscribers of its prefix, see `KV::subscribe`,
+/// and by streams, see `Stream`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KVChange {
+    pub key: Vec<u8>,
+    /// Empty if the key was deleted.
+    pub value: Vec<u8>,
+    pub user_meta: u8,
+    pub deleted: bool,
+    pub expires_at: u64,
+}
+
+/// A batch of changes: the keys of a write batch that match the prefixes of a subscriber, in
+/// the order written, or a batch of a stream.
+pub type KVList = Vec<KVChange>;
+
+/// Delivers the batches written to the subscribers of their prefixes.
+#[derive(Default)]
+pub(crate) struct Publisher {
+    subscribers: Mutex<HashMap<u64, Subscriber>>,
+    // The subscribers, read without the lock on every write.
+    count: AtomicUsize,
+    next_id: AtomicU64,
+    // Set by `close`, the subscriptions taken after are already ended.
+    closed: AtomicBool,
+}
+
+struct Subscriber {
+    prefixes: Vec<Vec<u8>>,
+    tx: Sender<KVList>,
+}
+
+impl Subscriber {
+    fn matches(&self, key: &[u8]) -> bool {
+        self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+}
+
+impl Publisher {
+    pub(crate) fn subscribe(self: &Arc<Self>, prefixes: Vec<Vec<u8>>) -> Subscription {
+        let (tx, rx) = bounded(SUBSCRIBER_CAPACITY);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut subscribers = self.subscribers.lock();
+        if self.closed.load(Ordering::Acquire) {
+            tx.close();
+        } else {
+            subscribers.insert(id, Subscriber { prefixes, tx });
+            self.count.store(subscribers.len(), Ordering::Release);
+        }
+        Subscription {
+            id,
+            publisher: self.clone(),
+            rx,
+        }
+    }
+
+    fn unsubscribe(&self, id: u64) {
+        let mut subscribers = self.subscribers.lock();
+        subscribers.remove(&id);
+        self.count.store(subscribers.len(), Ordering::Release);
+    }
+
+    // Returns the change of `entry` if a subscriber may want it. The entries the value log GC
+    // moves aren't changes.
+    pub(crate) fn change_of(&self, entry: &Entry) -> Option<KVChange> {
+        if self.count.load(Ordering::Acquire) == 0
+            || entry.meta & MetaBit::BIT_MOVE.bits() != 0
+            || entry.key.starts_with(_BADGER_PREFIX)
+        {
+            return None;
+        }
+        let deleted = entry.meta & MetaBit::BIT_DELETE.bits() != 0;
+        Some(KVChange {
+            key: entry.key.clone(),
+            value: if deleted { vec![] } else { entry.value.clone() },
+            user_meta: entry.user_meta,
+            deleted,
+            expires_at: entry.expires_at,
+        })
+    }
+
+    // Sends every subscriber the changes matching its prefixes, waiting for the ones that
+    // are behind. The subscribers dropped or closed meanwhile are skipped.
+    pub(crate) async fn publish(&self, changes: &[KVChange]) {
+        if changes.is_empty() {
+            return;
+        }
+        let lists = {
+            let subscribers = self.subscribers.lock();
+            subscribers
+                .values()
+                .filter_map(|sub| {
+                    let list = changes
+                        .iter()
+                        .filter(|change| sub.matches(&change.key))
+                        .cloned()
+                        .collect::<KVList>();
+                    (!list.is_empty()).then(|| (sub.tx.clone(), list))
+                })
+                .collect::<Vec<_>>()
+        };
+        for (tx, list) in lists {
+            let _ = tx.send(list).await;
+        }
+    }
+
+    // Ends the subscriptions, their receivers get the batches sent before. A `publish`
+    // waiting for a subscriber behind returns.
+    pub(crate) fn close(&self) {
+        let mut subscribers = self.subscribers.lock();
+        self.closed.store(true, Ordering::Release);
+        for sub in subscribers.values() {
+            sub.tx.close();
+        }
+        subscribers.clear();
+        self.count.store(0, Ordering::Release);
+    }
+}
+
+/// The batches written under some prefixes, see `KV::subscribe`. Dropping it unsubscribes.
+pub struct Subscription {
+    id: u64,
+    publisher: Arc<Publisher>,
+    rx: Receiver<KVList>,
+}
+
+impl Subscription {
+    /// Returns the next batch, None once the KV is closed.
+    pub async fn recv(&self) -> Option<KVList> {
+        self.rx.recv().await.ok()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.rx.close();
+        self.publisher.unsubscribe(self.id);
+    }
+}
@@ -0,0 +1,83 @@
+use crate::kv::KV;
+use crate::{Error, Result};
+use tokio::sync::Mutex;
+
+/// Hands out increasing u64s, see `KV::get_sequence`. Ranges of `bandwidth` numbers are
+/// leased at once: the end of the range is written under the key, so the numbers keep
+/// increasing after a restart, but the ones leased and not handed out are lost unless the
+/// sequence is released.
+pub struct Sequence {
+    kv: KV,
+    key: Vec<u8>,
+    bandwidth: u64,
+    lease: Mutex<Lease>,
+}
+
+#[derive(Default)]
+struct Lease {
+    next: u64,
+    // The end of the range leased, not included.
+    leased: u64,
+}
+
+impl Sequence {
+    pub(crate) async fn new(kv: KV, key: Vec<u8>, bandwidth: u64) -> Result<Sequence> {
+        if bandwidth == 0 {
+            return Err("The bandwidth of a sequence must be greater than zero".into());
+        }
+        if kv.opt.managed_txns {
+            return Err("Sequences can't be used with managed transactions".into());
+        }
+        let seq = Sequence {
+            kv,
+            key,
+            bandwidth,
+            lease: Mutex::new(Lease::default()),
+        };
+        {
+            let mut lease = seq.lease.lock().await;
+            seq.update_lease(&mut lease).await?;
+        }
+        Ok(seq)
+    }
+
+    /// Returns the next number, leasing a new range once the current one is used up.
+    pub async fn next(&self) -> Result<u64> {
+        let mut lease = self.lease.lock().await;
+        if lease.next >= lease.leased {
+            self.update_lease(&mut lease).await?;
+        }
+        let next = lease.next;
+        lease.next += 1;
+        Ok(next)
+    }
+
+    /// Gives back the numbers leased and not handed out, the next sequence on the key starts
+    /// with them. This one shouldn't be used after.
+    pub async fn release(&self) -> Result<()> {
+        let lease = self.lease.lock().await;
+        let mut txn = self.kv.new_transaction(true)?;
+        txn.set(self.key.clone(), lease.next.to_be_bytes().to_vec(), 0)?;
+        txn.commit().await
+    }
+
+    async fn update_lease(&self, lease: &mut Lease) -> Result<()> {
+        let mut txn = self.kv.new_transaction(true)?;
+        let next = match txn.get(&self.key).await {
+            Ok(value) => {
+                let value: [u8; 8] = value.as_slice().try_into().map_err(|_| {
+                    Error::from(format!("Invalid sequence value of {} bytes", value.len()))
+                })?;
+                u64::from_be_bytes(value)
+            }
+            Err(err) if err.is_not_found() => 0,
+            Err(err) => return Err(err),
+        };
+        let leased = next + self.bandwidth;
+        txn.set(self.key.clone(), leased.to_be_bytes().to_vec(), 0)?;
+        txn.commit().await?;
+        lease.next = next;
+        lease.leased = leased;
+        Ok(())
+    }
+}
@@ -105,5 +105,5 @@ impl DoubleAlloc {
 #[test]
 fn t() {
     let size = (1 + 0) & !0;
-    println!("{}, {}, {}", (1 + 0) & !0, (0 + 0) & !0, (3 + 0) & !0);
+    log::debug!("{}, {}, {}", (1 + 0) & !0, (0 + 0) & !0, (3 + 0) & !0);
 }
@@ -150,6 +150,7 @@ mod tests {
             meta: 1,
             user_meta: 1,
             cas_counter: 2,
+            expires_at: 1 << 40,
             value: v,
         };
         let (start, n) = arena.put_val(&value);
@@ -195,7 +196,7 @@ mod tests {
             .map(|join| join.join().unwrap())
             .collect::<Vec<_>>();
         offsets.sort();
-        println!("offsets: {:?}", offsets);
+        info!("offsets: {:?}", offsets);
     }
 
     #[test]
@@ -1,6 +1,6 @@
 use crate::skl::{Cursor, HEIGHT_INCREASE, MAX_HEIGHT};
 use crate::table::iterator::IteratorItem;
-use crate::y::ValueStruct;
+use crate::y::{key_with_ts, parse_key, parse_ts, ValueStruct};
 use crate::{Allocate, Xiterator};
 
 use log::{info, warn};
@@ -388,6 +388,19 @@ impl SkipList {
         None
     }
 
+    /// Returns the newest version of `key` not newer than `version`, and its version, when keys
+    /// carry their version (see `key_with_ts`).
+    pub(crate) fn get_version(&self, key: &[u8], version: u64) -> Option<(u64, ValueStruct)> {
+        let (node, _) = self.find_near(&key_with_ts(key, version), false, true);
+        let node = node?;
+        let got = node.key(&self.arena);
+        if parse_key(got) != key {
+            return None;
+        }
+        let (offset, size) = node.get_value_offset();
+        Some((parse_ts(got), self.arena.get_val(offset, size)))
+    }
+
     /// Returns a SkipList cursor. You have to close() the cursor.
     pub fn new_cursor(&self) -> Cursor<'_> {
         self._ref.fetch_add(1, Ordering::Relaxed);
@@ -789,7 +802,7 @@ mod tests {
         st.put(b"key2", ValueStruct::new(val2.to_vec(), 56, 0, 60001));
         st.put(b"key3", ValueStruct::new(val3.to_vec(), 57, 0, 60002));
 
-        println!("{}", st);
+        log::debug!("{}", st);
 
         let v = st.get(b"key");
         assert!(v.is_none());
@@ -71,6 +71,22 @@ impl SkipListManager {
         self.imm.write()
     }
 
+    /// Returns the number of immutable memtables waiting to be flushed and their size in bytes.
+    pub fn pending_flushes(&self) -> (usize, u64) {
+        self.lock_exclusive();
+        defer! {self.unlock_exclusive()}
+        let p = crossbeam_epoch::pin();
+        let imm = self.imm.read();
+        let bytes = imm
+            .iter()
+            .map(|st| {
+                unsafe { st.load(Ordering::Relaxed, &p).as_ref() }
+                    .map_or(0, |st| st.mem_size() as u64)
+            })
+            .sum();
+        (imm.len(), bytes)
+    }
+
     // TODO
     pub fn swap_st(&self, opt: Options) {
         self.lock_exclusive();
@@ -86,6 +102,20 @@ impl SkipListManager {
         self.mt_seq.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Replaces the mutable memtable with an empty one, throwing its content away.
+    pub fn discard_mt(&self, opt: Options) {
+        self.lock_exclusive();
+        defer! {self.unlock_exclusive()}
+        let old = self.mt_clone();
+        let st = SkipList::new(opt.arena_size() as usize);
+        self.mt
+            .as_ref()
+            .unwrap()
+            .store(crossbeam_epoch::Owned::new(st), Ordering::Relaxed);
+        self.mt_seq.fetch_add(1, Ordering::Relaxed);
+        old.decr_ref();
+    }
+
     pub fn advance_imm(&self, _mt: &SkipList) {
         self.lock_exclusive();
         defer! {self.unlock_exclusive()};
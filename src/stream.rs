@@ -0,0 +1,220 @@
+use crate::iterator::{IteratorOptions, KVItem};
+use crate::kv::KV;
+use crate::publisher::{KVChange, KVList};
+use crate::y::parse_key;
+use crate::Result;
+use async_channel::{bounded, Sender};
+use std::future::Future;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+// The bytes of keys and values a batch is sent at.
+const STREAM_BATCH_SIZE: usize = 4 << 20;
+// The batches a range can be ahead of the one being sent.
+const RANGE_BATCHES: usize = 4;
+// The ranges per worker, smaller ones keep the workers busy when their sizes differ.
+const RANGES_PER_WORKER: usize = 4;
+
+type KeyToList =
+    Arc<dyn Fn(KVItem) -> Pin<Box<dyn Future<Output = Result<KVList>> + Send>> + Send + Sync>;
+
+/// Scans the keys in parallel, see `KV::new_stream`. The keys are split in ranges of about
+/// the same size, see `KV::key_splits`, `num_go` ranges are iterated at the same time, and
+/// their batches are sent in key order.
+#[derive(Clone)]
+pub struct Stream {
+    kv: KV,
+    read_ts: Option<u64>,
+    prefix: Vec<u8>,
+    num_go: usize,
+    key_to_list: Option<KeyToList>,
+}
+
+impl Stream {
+    pub(crate) fn new(kv: KV, read_ts: Option<u64>) -> Stream {
+        Stream {
+            kv,
+            read_ts,
+            prefix: vec![],
+            num_go: 8,
+            key_to_list: None,
+        }
+    }
+
+    /// Streams only the keys starting with `prefix`.
+    pub fn prefix(mut self, prefix: &[u8]) -> Self {
+        self.prefix = prefix.to_vec();
+        self
+    }
+
+    /// Sets how many ranges are iterated at the same time.
+    pub fn num_go(mut self, num_go: usize) -> Self {
+        self.num_go = num_go.max(1);
+        self
+    }
+
+    /// Turns an item into the changes sent for it, none to skip it. By default it's its key
+    /// and value.
+    pub fn key_to_list(
+        mut self,
+        key_to_list: impl Fn(KVItem) -> Pin<Box<dyn Future<Output = Result<KVList>> + Send>>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.key_to_list = Some(Arc::new(key_to_list));
+        self
+    }
+
+    /// Iterates the keys and passes their batches to `send`, in key order. Stops at the first
+    /// error, of an iterator, of `send` or a panic of a range.
+    pub async fn orchestrate(
+        self,
+        mut send: impl FnMut(KVList) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>,
+    ) -> Result<()> {
+        let ranges = self.ranges();
+        let mut receivers = Vec::with_capacity(ranges.len());
+        let mut senders = Vec::with_capacity(ranges.len());
+        for _ in 0..ranges.len() {
+            let (tx, rx) = bounded(RANGE_BATCHES);
+            senders.push(tx);
+            receivers.push(rx);
+        }
+        // The ranges start in order, so the one being sent always runs.
+        let workers = Arc::new(Semaphore::new(self.num_go));
+        let stream = self.clone();
+        let dispatcher = tokio::spawn(async move {
+            let mut producers = vec![];
+            for (range, tx) in ranges.into_iter().zip(senders) {
+                // Never closed.
+                let permit = workers.clone().acquire_owned().await.unwrap();
+                if tx.is_closed() {
+                    break;
+                }
+                let stream = stream.clone();
+                let cur = tokio::runtime::Handle::current();
+                // The iterators aren't Send, every range is iterated on a blocking thread.
+                producers.push(tokio::task::spawn_blocking(move || {
+                    // A panic fails the stream at its range too, it isn't taken for its end.
+                    let res = catch_unwind(AssertUnwindSafe(|| {
+                        cur.block_on(stream.produce(range, &tx))
+                    }))
+                    .unwrap_or_else(|_| Err("A stream producer panicked".into()));
+                    if let Err(err) = res {
+                        let _ = cur.block_on(tx.send(Err(err)));
+                    }
+                    drop(permit);
+                }));
+            }
+            let mut res: Result<()> = Ok(());
+            for producer in producers {
+                if let Err(err) = producer.await {
+                    res = res.and(Err(format!("A stream producer failed, {}", err).into()));
+                }
+            }
+            res
+        });
+        let res = async {
+            for rx in receivers {
+                while let Ok(batch) = rx.recv().await {
+                    send(batch?).await?;
+                }
+            }
+            Ok(())
+        }
+        .await;
+        // The receivers are dropped, the producers left stop and close their iterators.
+        let dispatched = match dispatcher.await {
+            Ok(dispatched) => dispatched,
+            Err(err) => Err(format!("The stream dispatcher failed, {}", err).into()),
+        };
+        res.and(dispatched)
+    }
+
+    // The ranges of keys, [start, end), an empty end is the end of the keys.
+    fn ranges(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut splits = self
+            .kv
+            .key_splits(&self.prefix, self.num_go * RANGES_PER_WORKER);
+        if self.read_ts.is_some() {
+            splits = splits.iter().map(|key| parse_key(key).to_vec()).collect();
+            splits.dedup();
+        }
+        let mut ranges = vec![];
+        let mut start = vec![];
+        for split in splits {
+            if split > start {
+                ranges.push((start, split.clone()));
+                start = split;
+            }
+        }
+        ranges.push((start, vec![]));
+        ranges
+    }
+
+    // Iterates the range and sends its batches to `tx`.
+    async fn produce(
+        &self,
+        (start, end): (Vec<u8>, Vec<u8>),
+        tx: &Sender<Result<KVList>>,
+    ) -> Result<()> {
+        let txn = match self.read_ts {
+            Some(read_ts) => self.kv.new_transaction_at(read_ts, false)?,
+            None => self.kv.new_transaction(false)?,
+        };
+        let mut itr = txn
+            .iter(IteratorOptions::default().with_prefix(&self.prefix))
+            .await?;
+        let mut item = itr.seek(&start).await;
+        let (mut batch, mut size) = (KVList::new(), 0);
+        let mut res = Ok(());
+        while let Some(el) = item {
+            let key = el.key().await;
+            if !end.is_empty() && key >= end {
+                break;
+            }
+            let list = match &self.key_to_list {
+                Some(key_to_list) => key_to_list(el).await,
+                None => Self::change_of(key, el).await.map(|change| vec![change]),
+            };
+            let list = match list {
+                Ok(list) => list,
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            };
+            size += list
+                .iter()
+                .map(|change| change.key.len() + change.value.len())
+                .sum::<usize>();
+            batch.extend(list);
+            if size >= STREAM_BATCH_SIZE {
+                if tx.send(Ok(std::mem::take(&mut batch))).await.is_err() {
+                    break;
+                }
+                size = 0;
+            }
+            item = itr.next().await;
+        }
+        itr.close().await?;
+        res?;
+        if !batch.is_empty() {
+            // The stream stopped if it fails.
+            let _ = tx.send(Ok(batch)).await;
+        }
+        Ok(())
+    }
+
+    async fn change_of(key: Vec<u8>, item: KVItem) -> Result<KVChange> {
+        Ok(KVChange {
+            key,
+            value: item.value().await?,
+            user_meta: item.user_meta().await,
+            deleted: false,
+            expires_at: item.expires_at().await,
+        })
+    }
+}
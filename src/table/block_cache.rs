@@ -0,0 +1,141 @@
+use crate::table::table::Block;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Keeps the decoded data blocks of the tables in memory within a byte budget, shared by all
+/// the tables of a KV. The least recently used blocks are evicted first.
+#[derive(Clone)]
+pub(crate) struct BlockCache {
+    inner: Arc<Mutex<BlockCacheInner>>,
+}
+
+struct BlockCacheInner {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    // (table id, block index) => (block, last access)
+    entries: BTreeMap<(u64, usize), (Block, u64)>,
+    // last access => (table id, block index)
+    lru: BTreeMap<u64, (u64, usize)>,
+}
+
+impl BlockCache {
+    pub(crate) fn new(budget: usize) -> BlockCache {
+        BlockCache {
+            inner: Arc::new(Mutex::new(BlockCacheInner {
+                budget,
+                used: 0,
+                tick: 0,
+                hits: 0,
+                misses: 0,
+                entries: BTreeMap::new(),
+                lru: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub(crate) fn get(&self, id: u64, index: usize) -> Option<Block> {
+        let mut inner = self.inner.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let block = match inner.entries.get_mut(&(id, index)) {
+            Some((block, last)) => {
+                let old = std::mem::replace(last, tick);
+                let block = block.clone();
+                inner.lru.remove(&old);
+                inner.lru.insert(tick, (id, index));
+                block
+            }
+            None => {
+                inner.misses += 1;
+                crate::event::get_metrics().num_block_cache_misses.inc();
+                return None;
+            }
+        };
+        inner.hits += 1;
+        crate::event::get_metrics().num_block_cache_hits.inc();
+        Some(block)
+    }
+
+    pub(crate) fn insert(&self, id: u64, index: usize, block: Block) {
+        let size = block.data.len();
+        let mut inner = self.inner.lock();
+        // A block bigger than the whole budget would evict everything for nothing.
+        if size > inner.budget {
+            return;
+        }
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.used += size;
+        if let Some((old, last)) = inner.entries.insert((id, index), (block, tick)) {
+            inner.used -= old.data.len();
+            inner.lru.remove(&last);
+        }
+        inner.lru.insert(tick, (id, index));
+        while inner.used > inner.budget {
+            let (_, victim) = inner.lru.pop_first().unwrap();
+            let (old, _) = inner.entries.remove(&victim).unwrap();
+            inner.used -= old.data.len();
+        }
+    }
+
+    /// Drops the blocks of the table `id`.
+    pub(crate) fn remove_table(&self, id: u64) {
+        let mut inner = self.inner.lock();
+        let keys = inner
+            .entries
+            .range((id, 0)..=(id, usize::MAX))
+            .map(|(key, (_, last))| (*key, *last))
+            .collect::<Vec<_>>();
+        for (key, last) in keys {
+            let (old, _) = inner.entries.remove(&key).unwrap();
+            inner.used -= old.data.len();
+            inner.lru.remove(&last);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock();
+        CacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            used: inner.used as u64,
+            budget: inner.budget as u64,
+        }
+    }
+}
+
+/// The usage of a cache since the KV was opened.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Bytes held.
+    pub used: u64,
+    /// Bytes the cache may hold.
+    pub budget: u64,
+}
+
+impl CacheStats {
+    /// Returns the share of the lookups that hit, 0 before any lookup.
+    pub fn hit_ratio(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            return 0.0;
+        }
+        self.hits as f64 / lookups as f64
+    }
+}
+
+/// The caches shared by the tables of a KV, see `KV::cache_metrics`. A cache is none when
+/// its size option is 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheMetrics {
+    /// See `Options::block_cache_size`.
+    pub block_cache: Option<CacheStats>,
+    /// See `Options::index_cache_size`.
+    pub index_cache: Option<CacheStats>,
+}
@@ -1,4 +1,11 @@
-use crate::y::{hash, is_eof, Decode, Encode, ValueStruct};
+use crate::options::{CompressionType, FilterType};
+use crate::table::compression::BlockCompressor;
+#[cfg(feature = "encryption")]
+use crate::table::encryption::xor_block;
+use crate::table::encryption::DataKey;
+use crate::table::xor_filter::XorFilter;
+use crate::value_log::MetaBit;
+use crate::y::{hash, hex_str, is_eof, Decode, Encode, ValueStruct};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 use drop_cell::defer;
 use growable_bloom_filter::GrowableBloom;
@@ -70,16 +77,102 @@ pub struct Builder {
     base_key: Vec<u8>,    // Base key for the current block.
     base_offset: u32,     // Offset for the current block.
     restarts: Vec<u32>,   // Base offsets of every block.
+    // Index key of every block, see `shortest_separator`, and their encoded size.
+    index_keys: Vec<Vec<u8>>,
+    index_keys_size: usize,
+    last_key: Vec<u8>, // Last key added, the next block's index key must sort after it.
     prev_offset: u32, // Tracks offset for the previous key-value-pair. Offset is relative to block base offset.
     key_buf: Cursor<Vec<u8>>,
     key_count: u32,
+    table_size: u64,      // Target size of the table, see `should_finish`.
+    block_size: usize,    // Target size of a block, zero means only `RESTART_INTERVAL` is used.
+    stale_data_size: u64, // Bytes of tombstones and superseded entries, stored in the footer.
+    compression: CompressionType,
+    filter_type: FilterType,
+    // Compresses finished blocks in background, `buf` only holds the current block then.
+    compressor: Option<BlockCompressor>,
+    #[cfg(feature = "encryption")]
+    data_key: Option<DataKey>,
 }
 
 impl Builder {
     // the max keys number of every block.
     pub(crate) const RESTART_INTERVAL: usize = 100;
+    // Last 4 bytes of every table, tells it apart from Go badger tables.
+    pub(crate) const MAGIC: u32 = 0x42524131;
+    // Version of the layout in front of the magic, bumped on every incompatible change.
+    pub(crate) const FORMAT_VERSION: u16 = 1;
+
+    /// Creates a builder that aims at tables of `table_size` bytes made of blocks
+    /// of roughly `block_size` bytes.
+    pub fn new(table_size: u64, block_size: usize) -> Self {
+        Self {
+            counter: 0,
+            buf: Cursor::new(Vec::with_capacity(table_size as usize)),
+            base_key: vec![],
+            base_offset: 0,
+            restarts: vec![],
+            index_keys: vec![],
+            index_keys_size: 0,
+            last_key: vec![],
+            prev_offset: u32::MAX,
+            key_buf: Cursor::new(Vec::with_capacity(32 << 20)),
+            key_count: 0,
+            table_size,
+            block_size,
+            stale_data_size: 0,
+            compression: CompressionType::None,
+            filter_type: FilterType::Bloom,
+            compressor: None,
+            #[cfg(feature = "encryption")]
+            data_key: None,
+        }
+    }
+
+    /// Builds a filter of `filter_type` instead of the bloom filter.
+    pub fn with_filter(mut self, filter_type: FilterType) -> Self {
+        self.filter_type = filter_type;
+        self
+    }
+
+    /// Encrypts the blocks with `data_key` if there's one, see `with_encryption`. A key is
+    /// only handed out with the encryption feature, see `KeyRegistry::open`.
+    pub(crate) fn with_data_key(self, data_key: Option<DataKey>) -> Self {
+        match data_key {
+            #[cfg(feature = "encryption")]
+            Some(data_key) => self.with_encryption(data_key),
+            _ => self,
+        }
+    }
+
+    /// Encrypts the blocks of the table with `data_key` in AES-CTR mode, after compression.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption(mut self, data_key: DataKey) -> Self {
+        assert_ne!(
+            data_key.id, 0,
+            "data key id 0 is reserved for plaintext tables"
+        );
+        self.data_key = Some(data_key);
+        self
+    }
+
+    /// Compresses the blocks of the table with `compression`, it's done on background
+    /// threads while the next blocks are encoded.
+    pub fn with_compression(mut self, compression: CompressionType, zstd_level: i32) -> Self {
+        self.compression = compression;
+        self.compressor = match compression {
+            CompressionType::None => None,
+            _ => Some(BlockCompressor::new(
+                compression,
+                zstd_level,
+                num_cpus::get().min(4),
+            )),
+        };
+        self
+    }
+
     pub(crate) fn is_zero_bytes(&self) -> bool {
-        self.buf.position() == 0
+        self.buf.position() == 0 && self.compressor.as_ref().map_or(true, |c| c.sent() == 0)
     }
 
     /// Returns a suffix of new_key that is different from b.base_key.
@@ -94,6 +187,19 @@ impl Builder {
         &new_key[i..]
     }
 
+    /// Returns the shortest key `s` with `prev < s <= key`, used as index key of the block
+    /// starting at `key` in place of the key itself. Tables are ordered bytewise, a seek for
+    /// any key between `prev` and `key` still lands on the block of `key` or the one before.
+    pub(crate) fn shortest_separator(prev: &[u8], key: &[u8]) -> Vec<u8> {
+        let common = prev
+            .iter()
+            .zip(key.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        // Either prev is a prefix of key, or they differ at `common` where key is greater.
+        key[..(common + 1).min(key.len())].to_vec()
+    }
+
     fn add_helper(&mut self, key: &[u8], v: &ValueStruct) {
         // Add key to bloom filter.
         self.key_buf
@@ -116,7 +222,7 @@ impl Builder {
         let h = Header {
             p_len: (key.len() - diff_key.len()) as u16,
             k_len: diff_key.len() as u16,
-            v_len: (v.value.len() + ValueStruct::header_size()) as u16,
+            v_len: v.size() as u16,
             prev: self.prev_offset, // prevOffset is the location of the last key-value added.
         };
         // Remember current offset for the next Add call.
@@ -142,41 +248,171 @@ impl Builder {
         self.add_helper(b"", &ValueStruct::default());
     }
 
+    // Ends the current block, with compression it's moved out of `buf` to the compressor.
+    fn cut_block(&mut self) {
+        self.finish_block();
+        if let Some(compressor) = self.compressor.as_mut() {
+            let block = std::mem::take(self.buf.get_mut());
+            self.buf.set_position(0);
+            compressor.send(block);
+        } else {
+            self.restarts.push(self.buf.get_ref().len() as u32);
+        }
+    }
+
     /// Add adds a key-value pair to the block.
     /// If doNotRestart is true, we will not restart even if b.counter >= restartInterval.
     pub fn add(&mut self, key: &[u8], value: &ValueStruct) -> crate::y::Result<()> {
-        if self.counter >= Self::RESTART_INTERVAL {
-            self.finish_block();
+        if self.should_finish_block() {
+            self.cut_block();
             // Start a new block. Initialize the block.
-            self.restarts.push(self.buf.get_ref().len() as u32);
             self.counter = 0;
             self.base_key.clear();
             self.base_offset = self.buf.get_ref().len() as u32;
             // First key-value pair of block has header.prev=MaxInt.
             self.prev_offset = u32::MAX;
         }
+        if self.counter == 0 {
+            // The first block covers everything before its first key.
+            let index_key = if self.index_keys.is_empty() {
+                vec![]
+            } else {
+                Self::shortest_separator(&self.last_key, key)
+            };
+            self.index_keys_size += 2 + index_key.len();
+            self.index_keys.push(index_key);
+        }
+        if value.meta & MetaBit::BIT_DELETE.bits() > 0 {
+            self.stale_data_size += Self::entry_size(key, value);
+        }
         self.add_helper(key, value);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
         Ok(())
     }
 
-    // TODO: vvv this was the comment on ReachedCapacity.
-    // FinalSize returns the *rough* final size of the array, counting the header which is not yet written.
-    // TODO: Look into why there is a discrepancy. I suspect it is because of Write(empty, empty)
-    // at the end. The diff can vary.
-    // ReachedCapacity returns true if we... roughly (?) reached capacity?
-    pub(crate) fn reached_capacity(&self, cap: u64) -> bool {
-        let estimate_sz =
-            self.buf.get_ref().len() + 8 /* empty header */ + 4*self.restarts.len() + 8;
-        // 8 = end of buf offset + len(restarts).
-        estimate_sz as u64 > cap
+    /// Adds a key-value pair that is known to be superseded by a newer one, it is
+    /// written as usual but its size is accounted as stale data.
+    pub(crate) fn add_stale_key(
+        &mut self,
+        key: &[u8],
+        value: &ValueStruct,
+    ) -> crate::y::Result<()> {
+        if value.meta & MetaBit::BIT_DELETE.bits() == 0 {
+            self.stale_data_size += Self::entry_size(key, value);
+        }
+        self.add(key, value)
+    }
+
+    /// Returns the bytes of stale data added so far.
+    pub(crate) fn stale_data_size(&self) -> u64 {
+        self.stale_data_size
+    }
+
+    fn entry_size(key: &[u8], value: &ValueStruct) -> u64 {
+        (Header::size() + key.len() + value.size()) as u64
+    }
+
+    // A block is finished after `RESTART_INTERVAL` keys or once it grows past `block_size`.
+    fn should_finish_block(&self) -> bool {
+        if self.counter >= Self::RESTART_INTERVAL {
+            return true;
+        }
+        let block_sz = self.buf.get_ref().len() - self.base_offset as usize;
+        self.block_size > 0 && self.counter > 0 && block_sz >= self.block_size
+    }
+
+    /// Returns true if the table reached its target size, the caller should
+    /// `finish` it and continue with a new builder.
+    pub(crate) fn should_finish(&self) -> bool {
+        self.reached_capacity(self.table_size)
+    }
+
+    /// Returns the estimated size of the table if it was finished now, including the index,
+    /// filter and footer that are only written by `finish`.
+    pub fn estimated_size(&self) -> u64 {
+        let (blocks_sz, blocks) = match self.compressor.as_ref() {
+            Some(c) => (c.estimated_size() as usize, c.sent()),
+            None => (0, self.restarts.len()),
+        };
+        let estimate_sz = blocks_sz
+            + self.buf.get_ref().len()
+            + 8 /* empty header */
+            + 8 * blocks
+            + self.index_keys_size
+            + 4
+            + 8
+            + self.estimated_filter_size()
+            + 4
+            + 24
+            + 8
+            + 2
+            + 4;
+        // 4 = len(index keys), 8 = end of buf offset + len(restarts), 4 = len(filter),
+        // 24 = iv + data key id, 8 = stale data size, 2 = filter type and compression,
+        // 4 = magic, every block has a restart, a checksum and an index key.
+        estimate_sz as u64
+    }
+
+    /// Returns true if the table would be bigger than `cap` bytes if it was finished now.
+    pub fn reached_capacity(&self, cap: u64) -> bool {
+        self.estimated_size() > cap
+    }
+
+    fn estimated_filter_size(&self) -> usize {
+        let keys = self.key_count as usize;
+        match self.filter_type {
+            // About 10 bits per key at 1% false positives, serialized as a JSON array of
+            // bytes, 3.6 chars per byte.
+            FilterType::Bloom => keys * 9 / 2 + 128,
+            // 1.23 fingerprints of a byte per key, see `XorFilter::build`.
+            FilterType::Xor => keys * 123 / 100 + 48,
+        }
+    }
+
+    // Encrypts the blocks and index keys, the first `end` bytes of the table, in place with a
+    // new random iv, returns the data key id and the iv to store in the footer.
+    #[cfg(feature = "encryption")]
+    fn encrypt_blocks(&mut self, end: usize) -> (u64, [u8; 16]) {
+        let Some(data_key) = self.data_key.as_ref() else {
+            return (0, [0u8; 16]);
+        };
+        let iv = rand::random::<[u8; 16]>();
+        xor_block(&data_key.key, &iv, 0, &mut self.buf.get_mut()[..end])
+            .expect("failed to encrypt table blocks");
+        (data_key.id, iv)
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn encrypt_blocks(&mut self, _end: usize) -> (u64, [u8; 16]) {
+        (0, [0u8; 16])
+    }
+
+    // blockChecksums generates a crc32 for every block, in the same order as restarts.
+    fn block_checksums(&self) -> Vec<u8> {
+        let data = self.buf.get_ref();
+        let mut wt = Cursor::new(vec![0u8; 4 * self.restarts.len()]);
+        let mut start = 0usize;
+        for restart in self.restarts.iter() {
+            let end = *restart as usize;
+            wt.write_u32::<BigEndian>(crc32fast::hash(&data[start..end]))
+                .unwrap();
+            start = end;
+        }
+        wt.into_inner()
+    }
+
+    // Writes the index key of every block, each prefixed by its u16 length.
+    fn write_index_keys(&mut self) {
+        for key in self.index_keys.iter() {
+            self.buf.write_u16::<BigEndian>(key.len() as u16).unwrap();
+            self.buf.write_all(key).unwrap();
+        }
     }
 
     // blockIndex generates the block index for the table.
     // It is mainly a list of all the block base offsets.
     fn block_index(&mut self) -> Vec<u8> {
-        // Store the end offset, so we know the length of the final block.
-        self.restarts.push(self.buf.get_ref().len() as u32);
-
         // Add 4 because we want to write out number of restarts at the end.
         let sz = 4 * self.restarts.len() + 4;
         let mut wt = Cursor::new(vec![0u8; sz]);
@@ -198,7 +434,8 @@ impl Builder {
             let cost = SystemTime::now().duration_since(start).unwrap().as_millis() as u64;
             crate::event::get_metrics().block_hash_calc_cost.inc_by(cost);
         }
-        let mut bf = GrowableBloom::new(0.01, self.counter);
+        let mut hashes = Vec::with_capacity(self.key_count as usize);
+        self.key_buf.set_position(0);
         loop {
             let kl = self.key_buf.read_u16::<BigEndian>();
             if is_eof(&kl) {
@@ -212,32 +449,97 @@ impl Builder {
             let kl = kl.unwrap();
             let mut hash_buffer = vec![0u8; kl as usize];
             self.key_buf.read(&mut hash_buffer).unwrap();
-            bf.insert(&hash(&hash_buffer));
+            hashes.push(hash(&hash_buffer));
         }
         // This will never start a new block.
-        self.finish_block();
+        // Store the end offset, so we know the length of the final block.
+        self.cut_block();
+        if let Some(compressor) = self.compressor.take() {
+            let blocks = compressor.finish().expect("failed to compress table block");
+            for block in blocks {
+                self.buf.write_all(&block).unwrap();
+                self.restarts.push(self.buf.get_ref().len() as u32);
+            }
+        }
+        // A table without keys still has the block of the dummy entry.
+        if self.index_keys.is_empty() {
+            self.index_keys.push(vec![]);
+            self.index_keys_size += 2;
+        }
+        assert_eq!(self.index_keys.len(), self.restarts.len());
+        self.write_index_keys();
+        let (data_key_id, iv) = self.encrypt_blocks(self.buf.get_ref().len());
+        self.buf
+            .write_u32::<BigEndian>(self.index_keys_size as u32)
+            .unwrap();
+        let checksums = self.block_checksums();
+        self.buf.write_all(&checksums).unwrap();
         let index = self.block_index();
         self.buf.write_all(&index).unwrap();
 
-        // Write bloom filter
-        let bdata = serde_json::to_vec(&bf).unwrap();
+        // Write filter
+        let bdata = match self.filter_type {
+            FilterType::Bloom => {
+                let mut bf = GrowableBloom::new(0.01, hashes.len().max(1));
+                hashes.iter().for_each(|h| {
+                    bf.insert(h);
+                });
+                serde_json::to_vec(&bf).unwrap()
+            }
+            FilterType::Xor => XorFilter::build(&hashes).encode(),
+        };
         self.buf.write_all(&bdata).unwrap();
         self.buf.write_u32::<BigEndian>(bdata.len() as u32).unwrap();
+        // Write encryption iv and data key id
+        self.buf.write_all(&iv).unwrap();
+        self.buf.write_u64::<BigEndian>(data_key_id).unwrap();
+        // Write stale data size
+        self.buf
+            .write_u64::<BigEndian>(self.stale_data_size)
+            .unwrap();
+        self.buf.write_u8(self.filter_type.to_u8()).unwrap();
+        self.buf.write_u8(self.compression.to_u8()).unwrap();
+        self.buf
+            .write_u16::<BigEndian>(Self::FORMAT_VERSION)
+            .unwrap();
+        self.buf.write_u32::<BigEndian>(Self::MAGIC).unwrap();
         self.buf.get_ref().clone()
     }
+
+    /// Encodes key-value pairs into one block of the table format, used to hand blocks of
+    /// foreign tables to the block iterator. Only the block is built, like `add_helper` and
+    /// `finish_block` would.
+    pub(crate) fn encode_block(items: &[(Vec<u8>, ValueStruct)]) -> crate::y::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        let mut prev_offset = u32::MAX;
+        let base_key = items.first().map_or(&[][..], |(key, _)| key.as_slice());
+        let dummy = (vec![], ValueStruct::default());
+        for (i, (key, value)) in items.iter().chain(std::iter::once(&dummy)).enumerate() {
+            if key.len() > u16::MAX as usize || value.size() > u16::MAX as usize {
+                return Err(format!("Entry too big for a block, key: {}", hex_str(key)).into());
+            }
+            let diff_key = if i == 0 {
+                key.as_slice()
+            } else {
+                Self::key_diff(key, base_key)
+            };
+            let h = Header {
+                p_len: (key.len() - diff_key.len()) as u16,
+                k_len: diff_key.len() as u16,
+                v_len: value.size() as u16,
+                prev: prev_offset,
+            };
+            prev_offset = buf.len() as u32;
+            buf.extend_from_slice(<Header as Into<Vec<u8>>>::into(h).as_slice());
+            buf.extend_from_slice(diff_key);
+            buf.extend_from_slice(<&ValueStruct as Into<Vec<u8>>>::into(value).as_slice());
+        }
+        Ok(buf)
+    }
 }
 
 impl Default for Builder {
     fn default() -> Self {
-        Self {
-            counter: 0,
-            buf: Cursor::new(Vec::with_capacity(64 << 20)),
-            base_key: vec![],
-            base_offset: 0,
-            restarts: vec![],
-            prev_offset: u32::MAX,
-            key_buf: Cursor::new(Vec::with_capacity(32 << 20)),
-            key_count: 0,
-        }
+        Self::new(64 << 20, 4 << 10)
     }
 }
@@ -0,0 +1,142 @@
+use crate::options::CompressionType;
+use crate::y::Result;
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+pub(crate) fn compress(compression: CompressionType, level: i32, data: &[u8]) -> Result<Vec<u8>> {
+    match compression {
+        CompressionType::None => Ok(data.to_vec()),
+        CompressionType::ZSTD => Ok(zstd::encode_all(data, level)?),
+    }
+}
+
+pub(crate) fn decompress(compression: CompressionType, data: Vec<u8>) -> Result<Vec<u8>> {
+    match compression {
+        CompressionType::None => Ok(data),
+        CompressionType::ZSTD => {
+            zstd::decode_all(data.as_slice()).map_err(|err| format!("zstd: {}", err).into())
+        }
+    }
+}
+
+/// Compresses the finished blocks of a builder on worker threads, so the builder keeps
+/// encoding the next block meanwhile. Blocks are handed back in the order they were sent.
+pub(crate) struct BlockCompressor {
+    jobs: Option<Sender<(usize, Vec<u8>)>>,
+    done: Receiver<(usize, Result<Vec<u8>>)>,
+    workers: Vec<JoinHandle<()>>,
+    // Number of blocks sent and their uncompressed bytes.
+    sent: usize,
+    sent_bytes: u64,
+    // Uncompressed and compressed bytes of the blocks done so far.
+    raw_done: Arc<AtomicU64>,
+    compressed_done: Arc<AtomicU64>,
+}
+
+impl BlockCompressor {
+    pub(crate) fn new(compression: CompressionType, level: i32, workers: usize) -> Self {
+        let (jobs, rx) = channel::<(usize, Vec<u8>)>();
+        let (tx, done) = channel();
+        let rx = Arc::new(Mutex::new(rx));
+        let raw_done = Arc::new(AtomicU64::new(0));
+        let compressed_done = Arc::new(AtomicU64::new(0));
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let rx = rx.clone();
+                let tx = tx.clone();
+                let raw_done = raw_done.clone();
+                let compressed_done = compressed_done.clone();
+                std::thread::spawn(move || loop {
+                    let job = rx.lock().recv();
+                    let Ok((idx, block)) = job else {
+                        return;
+                    };
+                    let out = compress(compression, level, &block);
+                    if let Ok(out) = out.as_ref() {
+                        compressed_done.fetch_add(out.len() as u64, Ordering::Relaxed);
+                    }
+                    raw_done.fetch_add(block.len() as u64, Ordering::Relaxed);
+                    // The builder may be gone.
+                    let _ = tx.send((idx, out));
+                })
+            })
+            .collect();
+        BlockCompressor {
+            jobs: Some(jobs),
+            done,
+            workers,
+            sent: 0,
+            sent_bytes: 0,
+            raw_done,
+            compressed_done,
+        }
+    }
+
+    pub(crate) fn send(&mut self, block: Vec<u8>) {
+        self.sent_bytes += block.len() as u64;
+        self.jobs
+            .as_ref()
+            .unwrap()
+            .send((self.sent, block))
+            .unwrap();
+        self.sent += 1;
+    }
+
+    /// Number of blocks sent so far.
+    pub(crate) fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Estimates the size of the blocks sent so far, the blocks still in flight are
+    /// counted uncompressed.
+    pub(crate) fn estimated_size(&self) -> u64 {
+        let raw_done = self.raw_done.load(Ordering::Relaxed);
+        let compressed_done = self.compressed_done.load(Ordering::Relaxed);
+        self.sent_bytes.saturating_sub(raw_done) + compressed_done
+    }
+
+    /// Waits for all the blocks sent and returns them compressed, in order.
+    pub(crate) fn finish(mut self) -> Result<Vec<Vec<u8>>> {
+        drop(self.jobs.take());
+        let mut blocks = vec![vec![]; self.sent];
+        for _ in 0..self.sent {
+            let (idx, block) = self.done.recv().unwrap();
+            blocks[idx] = block?;
+        }
+        for worker in self.workers.drain(..) {
+            worker.join().unwrap();
+        }
+        Ok(blocks)
+    }
+}
+
+impl Drop for BlockCompressor {
+    fn drop(&mut self) {
+        // Stop the workers of a builder that is thrown away.
+        drop(self.jobs.take());
+    }
+}
+
+#[test]
+fn block_compressor_keeps_order() {
+    let mut compressor = BlockCompressor::new(CompressionType::ZSTD, 1, 4);
+    let blocks = (0..64)
+        .map(|i| format!("block-{}", i).repeat(100 + i).into_bytes())
+        .collect::<Vec<_>>();
+    for block in blocks.iter() {
+        compressor.send(block.clone());
+    }
+    assert_eq!(compressor.sent(), 64);
+    let out = compressor.finish().unwrap();
+    assert_eq!(out.len(), blocks.len());
+    for (block, compressed) in blocks.into_iter().zip(out) {
+        assert!(compressed.len() < block.len());
+        assert_eq!(
+            decompress(CompressionType::ZSTD, compressed).unwrap(),
+            block
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use crate::y::{Result, SyncedFileWriter};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) const KEY_REGISTRY_FILE_NAME: &str = "KEYREGISTRY";
+// Encrypted in the registry file, tells if it's opened with the right master key.
+const SANITY_TEXT: &[u8; 16] = b"Hello Badger-rs!";
+
+/// An AES key (16, 24 or 32 bytes) used to encrypt table blocks. Only the id is
+/// stored in the table footer, so the key must be known again to read the table.
+#[derive(Clone)]
+pub struct DataKey {
+    /// Identifies the key, zero is reserved for plaintext tables.
+    pub id: u64,
+    pub key: Vec<u8>,
+}
+
+impl std::fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DataKey").field("id", &self.id).finish()
+    }
+}
+
+/// Data keys by id, used to find the key of an encrypted table when it's opened. Opened
+/// with a master key, see `KeyRegistry::open`, it makes the data keys of the new tables and
+/// keeps them in the `KEYREGISTRY` file of the dir, encrypted with the master key.
+#[derive(Clone, Default)]
+pub struct KeyRegistry {
+    keys: Arc<RwLock<HashMap<u64, DataKey>>>,
+    store: Option<Arc<KeyStore>>,
+}
+
+impl std::fmt::Debug for KeyRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut ids = self.keys.read().keys().copied().collect::<Vec<_>>();
+        ids.sort();
+        f.debug_struct("KeyRegistry").field("ids", &ids).finish()
+    }
+}
+
+struct KeyStore {
+    file_name: String,
+    master_key: Vec<u8>,
+    rotation: Duration,
+    // Encrypts the sanity text.
+    iv: [u8; 16],
+    // The creation time of the data keys, in unix seconds, by id.
+    created: Mutex<BTreeMap<u64, u64>>,
+}
+
+impl KeyRegistry {
+    pub fn add(&self, key: DataKey) {
+        self.keys.write().insert(key.id, key);
+    }
+
+    pub fn get(&self, id: u64) -> Option<DataKey> {
+        self.keys.read().get(&id).cloned()
+    }
+
+    /// Opens the data keys of `dir`, a new data key is made once the newest one is older
+    /// than `rotation`. Without a master key, the registry is empty and the tables are
+    /// plain, the file mustn't exist then.
+    pub(crate) fn open(dir: &str, master_key: &[u8], rotation: Duration) -> Result<KeyRegistry> {
+        let file_name = Path::new(dir)
+            .join(KEY_REGISTRY_FILE_NAME)
+            .to_string_lossy()
+            .to_string();
+        let exists = Path::new(&file_name).exists();
+        if master_key.is_empty() {
+            if exists {
+                return Err("The data keys are encrypted, the master key is needed".into());
+            }
+            return Ok(KeyRegistry::default());
+        }
+        if !cfg!(feature = "encryption") {
+            return Err("A master key needs the encryption feature".into());
+        }
+        let registry = if exists {
+            Self::read(&file_name, master_key, rotation)?
+        } else {
+            let registry = KeyRegistry {
+                keys: Default::default(),
+                store: Some(Arc::new(KeyStore {
+                    file_name,
+                    master_key: master_key.to_vec(),
+                    rotation,
+                    iv: rand::random(),
+                    created: Mutex::new(BTreeMap::new()),
+                })),
+            };
+            registry.persist(&BTreeMap::new())?;
+            registry
+        };
+        Ok(registry)
+    }
+
+    /// Returns the data key of the new tables, None without a master key. `now` is in unix
+    /// seconds.
+    pub(crate) fn latest_data_key(&self, now: u64) -> Result<Option<DataKey>> {
+        let Some(store) = &self.store else {
+            return Ok(None);
+        };
+        let mut created = store.created.lock();
+        if let Some((id, at)) = created.iter().next_back() {
+            if now < at + store.rotation.as_secs() {
+                return Ok(self.get(*id));
+            }
+        }
+        let id = created.keys().next_back().map_or(1, |id| id + 1);
+        let key = (0..store.master_key.len())
+            .map(|_| rand::random::<u8>())
+            .collect::<Vec<_>>();
+        let mut next = created.clone();
+        next.insert(id, now);
+        self.add(DataKey { id, key });
+        if let Err(err) = self.persist(&next) {
+            self.keys.write().remove(&id);
+            return Err(err);
+        }
+        *created = next;
+        Ok(self.get(id))
+    }
+
+    // Layout: iv(16) | encrypted sanity text(16) | (id(u64) | created at(u64) | iv(16) |
+    // key len(u32) | encrypted key)* | crc32 of the rest(u32)
+    fn persist(&self, created: &BTreeMap<u64, u64>) -> Result<()> {
+        let store = self.store.as_ref().unwrap();
+        let mut buf = store.iv.to_vec();
+        let mut sanity = SANITY_TEXT.to_vec();
+        crypt(&store.master_key, &store.iv, &mut sanity)?;
+        buf.extend_from_slice(&sanity);
+        for (id, at) in created {
+            let iv = rand::random::<[u8; 16]>();
+            let mut key = self.get(*id).unwrap().key;
+            crypt(&store.master_key, &iv, &mut key)?;
+            buf.write_u64::<BigEndian>(*id)?;
+            buf.write_u64::<BigEndian>(*at)?;
+            buf.extend_from_slice(&iv);
+            buf.write_u32::<BigEndian>(key.len() as u32)?;
+            buf.extend_from_slice(&key);
+        }
+        let crc = crc32fast::hash(&buf);
+        buf.write_u32::<BigEndian>(crc)?;
+        let mut wt = SyncedFileWriter::create(&store.file_name)?;
+        wt.write_all(&buf)?;
+        wt.finish()?;
+        Ok(())
+    }
+
+    fn read(file_name: &str, master_key: &[u8], rotation: Duration) -> Result<KeyRegistry> {
+        let buf = std::fs::read(file_name)?;
+        if buf.len() < 36 {
+            return Err(format!("Invalid {}, too short", file_name).into());
+        }
+        let (body, crc) = buf.split_at(buf.len() - 4);
+        if crc32fast::hash(body) != u32::from_be_bytes(crc.try_into().unwrap()) {
+            return Err(format!("Invalid {}, checksum mismatch", file_name).into());
+        }
+        let mut rd = Cursor::new(body);
+        let mut iv = [0u8; 16];
+        rd.read_exact(&mut iv)?;
+        let mut sanity = [0u8; 16];
+        rd.read_exact(&mut sanity)?;
+        crypt(master_key, &iv, &mut sanity)?;
+        if &sanity != SANITY_TEXT {
+            return Err("Invalid master key, it doesn't decrypt the data keys".into());
+        }
+        let registry = KeyRegistry::default();
+        let mut created = BTreeMap::new();
+        while (rd.position() as usize) < body.len() {
+            let id = rd.read_u64::<BigEndian>()?;
+            let at = rd.read_u64::<BigEndian>()?;
+            let mut iv = [0u8; 16];
+            rd.read_exact(&mut iv)?;
+            let mut key = vec![0u8; rd.read_u32::<BigEndian>()? as usize];
+            rd.read_exact(&mut key)?;
+            crypt(master_key, &iv, &mut key)?;
+            registry.add(DataKey { id, key });
+            created.insert(id, at);
+        }
+        Ok(KeyRegistry {
+            keys: registry.keys,
+            store: Some(Arc::new(KeyStore {
+                file_name: file_name.to_string(),
+                master_key: master_key.to_vec(),
+                rotation,
+                iv,
+                created: Mutex::new(created),
+            })),
+        })
+    }
+}
+
+// Starts an encrypted value log or WAL file, see `FileCipher`. Read as the key length of a
+// plain entry it's too big, so a plain file can't start with it.
+const FILE_MAGIC: &[u8; 8] = b"BGRSENC1";
+/// Bytes of the header of an encrypted value log or WAL file: the magic, the data key id and
+/// the iv. The entries come after it.
+pub(crate) const FILE_HEADER_SIZE: usize = FILE_MAGIC.len() + 8 + 16;
+
+/// The data key and iv the entries of a value log or WAL file are encrypted with, AES-CTR
+/// positioned at their file offset like the table blocks. Both are recorded in the header
+/// at the start of the file, the key by id.
+#[derive(Clone, Debug)]
+pub(crate) struct FileCipher {
+    data_key: DataKey,
+    iv: [u8; 16],
+}
+
+impl FileCipher {
+    /// A cipher of a new file, with a random iv.
+    pub(crate) fn new(data_key: DataKey) -> FileCipher {
+        FileCipher {
+            data_key,
+            iv: rand::random(),
+        }
+    }
+
+    // Layout: magic(8) | data key id(u64) | iv(16)
+    pub(crate) fn header(&self) -> Vec<u8> {
+        let mut buf = FILE_MAGIC.to_vec();
+        buf.extend_from_slice(&self.data_key.id.to_be_bytes());
+        buf.extend_from_slice(&self.iv);
+        buf
+    }
+
+    /// Reads the header at the start of a file, None if the file isn't encrypted. Fails if
+    /// its data key isn't in `keys`.
+    pub(crate) fn read_header(buf: &[u8], keys: &KeyRegistry) -> Result<Option<FileCipher>> {
+        if buf.len() < FILE_HEADER_SIZE || !buf.starts_with(FILE_MAGIC) {
+            return Ok(None);
+        }
+        let mut rd = Cursor::new(&buf[FILE_MAGIC.len()..FILE_HEADER_SIZE]);
+        let id = rd.read_u64::<BigEndian>()?;
+        let mut iv = [0u8; 16];
+        rd.read_exact(&mut iv)?;
+        let data_key = keys
+            .get(id)
+            .ok_or_else(|| format!("Unknown data key {}, the master key is needed", id))?;
+        Ok(Some(FileCipher { data_key, iv }))
+    }
+
+    /// Encrypts or decrypts `data`, stored at `offset` of the file, in place.
+    #[cfg(feature = "encryption")]
+    pub(crate) fn xor(&self, offset: u64, data: &mut [u8]) -> Result<()> {
+        xor_block(&self.data_key.key, &self.iv, offset, data)
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    pub(crate) fn xor(&self, _offset: u64, _data: &mut [u8]) -> Result<()> {
+        Err(format!(
+            "Encrypted with data key {}, built without the encryption feature",
+            self.data_key.id
+        )
+        .into())
+    }
+}
+
+// Encrypts or decrypts `data` alone with `key`.
+#[cfg(feature = "encryption")]
+fn crypt(key: &[u8], iv: &[u8; 16], data: &mut [u8]) -> Result<()> {
+    xor_block(key, iv, 0, data)
+}
+
+#[cfg(not(feature = "encryption"))]
+fn crypt(_key: &[u8], _iv: &[u8; 16], _data: &mut [u8]) -> Result<()> {
+    Err("Built without the encryption feature".into())
+}
+
+/// XORs `data`, stored at `offset` of a table, with the AES-CTR key stream of `key` and the
+/// table `iv`. The key stream is positioned at the table offset, so every block has its own
+/// part of the stream and can be decrypted on its own.
+#[cfg(feature = "encryption")]
+pub(crate) fn xor_block(key: &[u8], iv: &[u8; 16], offset: u64, data: &mut [u8]) -> Result<()> {
+    match key.len() {
+        16 => apply_key_stream::<ctr::Ctr128BE<aes::Aes128>>(key, iv, offset, data),
+        24 => apply_key_stream::<ctr::Ctr128BE<aes::Aes192>>(key, iv, offset, data),
+        32 => apply_key_stream::<ctr::Ctr128BE<aes::Aes256>>(key, iv, offset, data),
+        n => Err(format!("Invalid AES key size: {}, expected 16, 24 or 32", n).into()),
+    }
+}
+
+#[cfg(feature = "encryption")]
+fn apply_key_stream<C>(key: &[u8], iv: &[u8; 16], offset: u64, data: &mut [u8]) -> Result<()>
+where
+    C: ctr::cipher::KeyIvInit + ctr::cipher::StreamCipher + ctr::cipher::StreamCipherSeek,
+{
+    let mut cipher =
+        C::new_from_slices(key, iv).map_err(|err| format!("Invalid AES key: {}", err))?;
+    cipher.seek(offset);
+    cipher.apply_keystream(data);
+    Ok(())
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn xor_block_at_offset() {
+    let key = [7u8; 32];
+    let iv = [3u8; 16];
+    let plain = (0..200u8).collect::<Vec<_>>();
+    let mut all = plain.clone();
+    xor_block(&key, &iv, 0, &mut all).unwrap();
+    assert_ne!(all, plain);
+    // A part decrypted alone matches the same part of the whole stream.
+    let mut part = all[37..150].to_vec();
+    xor_block(&key, &iv, 37, &mut part).unwrap();
+    assert_eq!(part, plain[37..150]);
+    assert!(xor_block(&key[..10], &iv, 0, &mut part).is_err());
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn file_cipher_header() {
+    let keys = KeyRegistry::default();
+    let data_key = DataKey {
+        id: 9,
+        key: vec![1; 16],
+    };
+    keys.add(data_key.clone());
+    let cipher = FileCipher::new(data_key);
+    let header = cipher.header();
+    assert_eq!(header.len(), FILE_HEADER_SIZE);
+    let read = FileCipher::read_header(&header, &keys).unwrap().unwrap();
+    let mut data = b"plain entry".to_vec();
+    cipher.xor(100, &mut data).unwrap();
+    assert_ne!(data, b"plain entry".to_vec());
+    read.xor(100, &mut data).unwrap();
+    assert_eq!(data, b"plain entry".to_vec());
+    // A plain file, or a data key not in the registry.
+    assert!(FileCipher::read_header(&[0; 40], &keys).unwrap().is_none());
+    assert!(FileCipher::read_header(&header, &KeyRegistry::default()).is_err());
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn key_registry_rotation() {
+    let dir = crate::test_util::create_random_tmp_dir();
+    let master = [5u8; 16];
+    let day = Duration::from_secs(24 * 3600);
+    let keys = KeyRegistry::open(&dir, &master, day).unwrap();
+    let first = keys.latest_data_key(1000).unwrap().unwrap();
+    assert_eq!((first.id, first.key.len()), (1, 16));
+    assert_eq!(keys.latest_data_key(2000).unwrap().unwrap().id, 1);
+    let second = keys.latest_data_key(1000 + day.as_secs()).unwrap().unwrap();
+    assert_eq!(second.id, 2);
+
+    let keys = KeyRegistry::open(&dir, &master, day).unwrap();
+    assert_eq!(keys.get(1).unwrap().key, first.key);
+    assert_eq!(keys.get(2).unwrap().key, second.key);
+    assert!(KeyRegistry::open(&dir, &[6u8; 16], day).is_err());
+    assert!(KeyRegistry::open(&dir, &[], day).is_err());
+}
@@ -0,0 +1,62 @@
+use crate::table::table::new_file_name;
+#[cfg(test)]
+use crate::table::table::new_temp_file_name;
+use crate::y::{Result, SyncedFileWriter};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Hands out table file ids. It's seeded from the biggest id referenced by the manifest,
+/// memtable flush and compaction share one allocator, so they never pick the same id.
+#[derive(Clone, Debug)]
+pub(crate) struct FileIdAllocator {
+    next_id: Arc<AtomicU64>,
+}
+
+impl FileIdAllocator {
+    /// `max_id` is the biggest table id in use, the first reserved id is `max_id + 1`.
+    pub(crate) fn new(max_id: u64) -> Self {
+        FileIdAllocator {
+            next_id: Arc::new(AtomicU64::new(max_id + 1)),
+        }
+    }
+
+    /// Returns a new unique file id.
+    pub(crate) fn reserve(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Makes sure `id` will never be handed out, e.g. a table found on disk.
+    pub(crate) fn observe(&self, id: u64) {
+        self.next_id.fetch_max(id + 1, Ordering::Relaxed);
+    }
+}
+
+/// Creates the writer of table `id`, it's written to a temporary file and only gets the
+/// `{id:06}.sst` name once synced.
+pub(crate) fn create_table_file(id: u64, dir: &str) -> Result<SyncedFileWriter> {
+    SyncedFileWriter::create(&new_file_name(id, dir))
+}
+
+#[test]
+fn file_id_allocator() {
+    let alloc = FileIdAllocator::new(10);
+    assert_eq!(alloc.reserve(), 11);
+    assert_eq!(alloc.clone().reserve(), 12);
+    alloc.observe(5);
+    assert_eq!(alloc.reserve(), 13);
+    alloc.observe(20);
+    assert_eq!(alloc.reserve(), 21);
+}
+
+#[test]
+fn table_file_rename_on_finish() {
+    let dir = crate::test_util::create_random_tmp_dir();
+    let mut tf = create_table_file(3, &dir).unwrap();
+    tf.write_all(b"abc").unwrap();
+    assert!(!std::path::Path::new(tf.file_name()).exists());
+    assert!(std::path::Path::new(&new_temp_file_name(3, &dir)).exists());
+    let (_, file_name) = tf.finish().unwrap();
+    assert!(file_name.ends_with("000003.sst"));
+    assert_eq!(std::fs::read(&file_name).unwrap(), b"abc");
+    assert!(!std::path::Path::new(&new_temp_file_name(3, &dir)).exists());
+}
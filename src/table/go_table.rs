@@ -0,0 +1,783 @@
+//! Decoding of the table layout written by Go badger v2/v3.
+//!
+//! Layout: `block... | index | index_len(u32) | checksum | checksum_len(u32)`.
+//! A block is `entries | entry offsets([u32]) | entry count(u32) | checksum | checksum_len(u32)`,
+//! the index is a protobuf (v2) or a flatbuffer (v3) `TableIndex`, checksums are protobuf
+//! `Checksum` messages. Blocks may be compressed whole, with Snappy or ZSTD. Keys carry an
+//! 8 bytes version suffix, which is stripped when the entries are handed to badger-rs, only
+//! the newest version of a key is kept.
+//!
+//! Only the tables are read: the manifest and the value log of a Go badger directory have
+//! other layouts, and encrypted Go tables aren't supported either.
+use crate::value_log::MetaBit;
+use crate::y::{Result, ValueStruct};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::borrow::Cow;
+
+// Size of the version suffix of Go badger keys.
+const TS_SIZE: usize = 8;
+// overlap(u16) + diff(u16), little endian.
+const HEADER_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GoBlockOffset {
+    pub(crate) key: Vec<u8>,
+    pub(crate) offset: u32,
+    pub(crate) len: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GoTableIndex {
+    pub(crate) offsets: Vec<GoBlockOffset>,
+    pub(crate) bloom_filter: Vec<u8>,
+}
+
+impl GoTableIndex {
+    /// Decodes a v3 flatbuffer index, falling back to a v2 protobuf index. `table_size` is used
+    /// to reject an index that points outside the table.
+    pub(crate) fn decode(buf: &[u8], table_size: usize) -> Result<GoTableIndex> {
+        let valid = |index: &GoTableIndex| {
+            !index.offsets.is_empty()
+                && index
+                    .offsets
+                    .iter()
+                    .all(|ko| ko.offset as usize + ko.len as usize <= table_size)
+        };
+        if let Some(index) = Self::decode_fb(buf).filter(valid) {
+            return Ok(index);
+        }
+        match Self::decode_pb(buf) {
+            Some(index) if valid(&index) => Ok(index),
+            _ => Err("Unable to decode Go badger table index".into()),
+        }
+    }
+
+    fn decode_pb(buf: &[u8]) -> Option<GoTableIndex> {
+        let mut index = GoTableIndex::default();
+        let mut rd = PbReader::new(buf);
+        while !rd.is_empty() {
+            match rd.read_key()? {
+                (1, 2) => {
+                    let mut ko = GoBlockOffset::default();
+                    let mut sub = PbReader::new(rd.read_bytes()?);
+                    while !sub.is_empty() {
+                        match sub.read_key()? {
+                            (1, 2) => ko.key = sub.read_bytes()?.to_vec(),
+                            (2, 0) => ko.offset = sub.read_varint()? as u32,
+                            (3, 0) => ko.len = sub.read_varint()? as u32,
+                            (_, wire) => sub.skip(wire)?,
+                        }
+                    }
+                    index.offsets.push(ko);
+                }
+                (2, 2) => index.bloom_filter = rd.read_bytes()?.to_vec(),
+                (_, wire) => rd.skip(wire)?,
+            }
+        }
+        Some(index)
+    }
+
+    fn decode_fb(buf: &[u8]) -> Option<GoTableIndex> {
+        let root = FbTable::root(buf)?;
+        let mut index = GoTableIndex::default();
+        for ko in root.tables(0)? {
+            index.offsets.push(GoBlockOffset {
+                key: ko.bytes(0)?.to_vec(),
+                offset: ko.u32(1).unwrap_or(0),
+                len: ko.u32(2).unwrap_or(0),
+            });
+        }
+        index.bloom_filter = root.bytes(1).unwrap_or_default().to_vec();
+        Some(index)
+    }
+}
+
+/// The `Checksum` message of Go badger.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GoChecksum {
+    // 0: CRC32C, 1: XXHash64
+    algo: u64,
+    sum: u64,
+}
+
+impl GoChecksum {
+    pub(crate) fn decode(buf: &[u8]) -> Result<GoChecksum> {
+        let mut checksum = GoChecksum::default();
+        let mut rd = PbReader::new(buf);
+        while !rd.is_empty() {
+            let ok = match rd.read_key() {
+                Some((1, 0)) => rd.read_varint().map(|algo| checksum.algo = algo),
+                Some((2, 0)) => rd.read_varint().map(|sum| checksum.sum = sum),
+                Some((_, wire)) => rd.skip(wire),
+                None => None,
+            };
+            if ok.is_none() {
+                return Err("Invalid Go badger checksum".into());
+            }
+        }
+        Ok(checksum)
+    }
+
+    pub(crate) fn verify(&self, data: &[u8]) -> Result<()> {
+        let got = match self.algo {
+            0 => crc32c(data) as u64,
+            1 => xxh64(data, 0),
+            algo => return Err(format!("Unknown checksum algorithm: {}", algo).into()),
+        };
+        if got != self.sum {
+            return Err(crate::Error::ChecksumMismatch(format!(
+                "expected: {:#x}, got: {:#x}",
+                self.sum, got
+            )));
+        }
+        Ok(())
+    }
+}
+
+// Splits `data` into the payload and its trailing checksum, `checksum | checksum_len(u32)`.
+fn split_checksum(data: &[u8]) -> Result<(&[u8], GoChecksum)> {
+    if data.len() < 4 {
+        return Err(crate::Error::TooFewBytes);
+    }
+    let mut pos = data.len() - 4;
+    let checksum_len = BigEndian::read_u32(&data[pos..]) as usize;
+    if checksum_len > pos {
+        return Err("Invalid checksum length of Go badger table".into());
+    }
+    pos -= checksum_len;
+    let checksum = GoChecksum::decode(&data[pos..pos + checksum_len])?;
+    Ok((&data[..pos], checksum))
+}
+
+/// Returns the block without its compression, once its checksum is verified. Go badger keeps
+/// the compression of a table in its manifest, it's told here by the checksum of the block
+/// decoded as stored, with Snappy then with ZSTD.
+pub(crate) fn decompress_block(data: &[u8]) -> Result<Cow<'_, [u8]>> {
+    let verify = |data: &[u8]| split_checksum(data).and_then(|(data, sum)| sum.verify(data));
+    let err = match verify(data) {
+        Ok(()) => return Ok(Cow::Borrowed(data)),
+        Err(err) => err,
+    };
+    if let Ok(block) = snappy_decode(data) {
+        if verify(&block).is_ok() {
+            return Ok(Cow::Owned(block));
+        }
+    }
+    if let Ok(block) = zstd::decode_all(data) {
+        if verify(&block).is_ok() {
+            return Ok(Cow::Owned(block));
+        }
+    }
+    Err(err)
+}
+
+/// Decodes a block, verifying its checksum. Keys are returned without their version, which is
+/// moved into `cas_counter`. Only the newest version of a key is kept: the entries of
+/// `prev_key`, the last key of the block before, are older versions of it and dropped too.
+pub(crate) fn decode_block(
+    data: &[u8],
+    prev_key: Option<&[u8]>,
+) -> Result<Vec<(Vec<u8>, ValueStruct)>> {
+    let data = decompress_block(data)?;
+    let block = GoBlock::parse(&data)?;
+    let mut items: Vec<(Vec<u8>, ValueStruct)> = Vec::with_capacity(block.len());
+    for i in 0..block.len() {
+        let (mut key, value) = block.entry(i)?;
+        let version = strip_version(&mut key)?;
+        // Versions are sorted from the newest one, keep it only.
+        let last = items.last().map(|(last, _)| last.as_slice()).or(prev_key);
+        if last == Some(key.as_slice()) {
+            continue;
+        }
+        let value = decode_value(value, version)?;
+        items.push((key, value));
+    }
+    Ok(items)
+}
+
+/// Returns the last key of a block, without its version.
+pub(crate) fn last_key(data: &[u8]) -> Result<Vec<u8>> {
+    let data = decompress_block(data)?;
+    let block = GoBlock::parse(&data)?;
+    if block.len() == 0 {
+        return Err("Go badger block without entries".into());
+    }
+    let (mut key, _) = block.entry(block.len() - 1)?;
+    strip_version(&mut key)?;
+    Ok(key)
+}
+
+// Removes the version suffix of `key` and returns it.
+fn strip_version(key: &mut Vec<u8>) -> Result<u64> {
+    if key.len() < TS_SIZE {
+        return Err("Go badger key without version".into());
+    }
+    let version = u64::MAX - BigEndian::read_u64(&key[key.len() - TS_SIZE..]);
+    key.truncate(key.len() - TS_SIZE);
+    Ok(version)
+}
+
+// The entries of a decompressed block, every key is stored as its overlap with the first key
+// of the block and the rest.
+struct GoBlock<'a> {
+    data: &'a [u8],
+    entry_offsets: Vec<usize>,
+    entries_end: usize,
+}
+
+impl<'a> GoBlock<'a> {
+    fn parse(data: &'a [u8]) -> Result<GoBlock<'a>> {
+        let (data, _) = split_checksum(data)?;
+        if data.len() < 4 {
+            return Err(crate::Error::TooFewBytes);
+        }
+        let mut pos = data.len() - 4;
+        let num_entries = BigEndian::read_u32(&data[pos..]) as usize;
+        if num_entries * 4 > pos {
+            return Err("Invalid entry count of Go badger block".into());
+        }
+        pos -= num_entries * 4;
+        let entry_offsets = (0..num_entries)
+            .map(|i| LittleEndian::read_u32(&data[pos + i * 4..]) as usize)
+            .collect();
+        Ok(GoBlock {
+            data,
+            entry_offsets,
+            entries_end: pos,
+        })
+    }
+
+    fn len(&self) -> usize {
+        self.entry_offsets.len()
+    }
+
+    // Returns the overlap, the rest of the key and the value of the entry `i`.
+    fn raw_entry(&self, i: usize) -> Result<(usize, &'a [u8], &'a [u8])> {
+        let start = self.entry_offsets[i];
+        let end = self
+            .entry_offsets
+            .get(i + 1)
+            .copied()
+            .unwrap_or(self.entries_end);
+        if start + HEADER_SIZE > end || end > self.entries_end {
+            return Err("Invalid entry offset of Go badger block".into());
+        }
+        let entry = &self.data[start..end];
+        let overlap = LittleEndian::read_u16(entry) as usize;
+        let diff = LittleEndian::read_u16(&entry[2..]) as usize;
+        if HEADER_SIZE + diff > entry.len() {
+            return Err("Invalid entry of Go badger block".into());
+        }
+        Ok((
+            overlap,
+            &entry[HEADER_SIZE..HEADER_SIZE + diff],
+            &entry[HEADER_SIZE + diff..],
+        ))
+    }
+
+    // Returns the key, with its version, and the value of the entry `i`.
+    fn entry(&self, i: usize) -> Result<(Vec<u8>, &'a [u8])> {
+        let (overlap, diff_key, value) = self.raw_entry(i)?;
+        let mut key = if i == 0 {
+            vec![]
+        } else {
+            let (_, base_key, _) = self.raw_entry(0)?;
+            base_key
+                .get(..overlap)
+                .ok_or_else(|| crate::Error::from("Invalid key overlap of Go badger block"))?
+                .to_vec()
+        };
+        key.extend_from_slice(diff_key);
+        Ok((key, value))
+    }
+}
+
+// Decodes a Snappy block, the decoded length (uvarint) then literals and back references.
+fn snappy_decode(data: &[u8]) -> Result<Vec<u8>> {
+    let corrupt = || crate::Error::from("Corrupt Snappy block");
+    let mut rd = PbReader::new(data);
+    let len = rd.read_varint().ok_or_else(corrupt)? as usize;
+    // A 3 bytes back reference copies 64 bytes at most.
+    if len > data.len() * 22 {
+        return Err(corrupt());
+    }
+    let mut src = rd.remaining();
+    let mut out = Vec::with_capacity(len);
+    while let Some((&tag, rest)) = src.split_first() {
+        src = rest;
+        let (n, offset) = match tag & 0x3 {
+            0 => {
+                let mut n = (tag >> 2) as usize;
+                if n >= 60 {
+                    let size = n - 59;
+                    if src.len() < size {
+                        return Err(corrupt());
+                    }
+                    n = LittleEndian::read_uint(src, size) as usize;
+                    src = &src[size..];
+                }
+                let n = n + 1;
+                if src.len() < n || out.len() + n > len {
+                    return Err(corrupt());
+                }
+                out.extend_from_slice(&src[..n]);
+                src = &src[n..];
+                continue;
+            }
+            1 => {
+                let (&b, rest) = src.split_first().ok_or_else(corrupt)?;
+                src = rest;
+                let offset = ((tag as usize >> 5) << 8) | b as usize;
+                (4 + ((tag >> 2) & 0x7) as usize, offset)
+            }
+            tag_type => {
+                let size = if tag_type == 2 { 2 } else { 4 };
+                if src.len() < size {
+                    return Err(corrupt());
+                }
+                let offset = LittleEndian::read_uint(src, size) as usize;
+                src = &src[size..];
+                (1 + (tag >> 2) as usize, offset)
+            }
+        };
+        if offset == 0 || offset > out.len() || out.len() + n > len {
+            return Err(corrupt());
+        }
+        // The copy may overlap the bytes it appends.
+        for _ in 0..n {
+            out.push(out[out.len() - offset]);
+        }
+    }
+    if out.len() != len {
+        return Err(corrupt());
+    }
+    Ok(out)
+}
+
+// meta(u8) | user_meta(u8) | expires_at(uvarint) | value
+fn decode_value(buf: &[u8], version: u64) -> Result<ValueStruct> {
+    if buf.len() < 2 {
+        return Err(crate::Error::TooFewBytes);
+    }
+    let mut rd = PbReader::new(&buf[2..]);
+    let expires_at = rd
+        .read_varint()
+        .ok_or_else(|| crate::Error::from("Invalid expires_at of Go badger value"))?;
+    // Only the delete and value pointer bits mean the same for badger-rs.
+    let meta = buf[0] & (MetaBit::BIT_DELETE.bits() | MetaBit::BIT_VALUE_POINTER.bits());
+    Ok(
+        ValueStruct::new(rd.remaining().to_vec(), meta, buf[1], version)
+            .with_expires_at(expires_at),
+    )
+}
+
+/// Hash used by the Go badger bloom filter, applied to keys without their version.
+pub(crate) fn hash(key: &[u8]) -> u32 {
+    const SEED: u32 = 0xbc9f1d34;
+    const M: u32 = 0xc6a4a793;
+    let mut h = SEED ^ (key.len() as u32).wrapping_mul(M);
+    let mut chunks = key.chunks_exact(4);
+    for b in &mut chunks {
+        h = h.wrapping_add(LittleEndian::read_u32(b));
+        h = h.wrapping_mul(M);
+        h ^= h >> 16;
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (i, b) in rest.iter().enumerate().rev() {
+            h = h.wrapping_add((*b as u32) << (8 * i));
+        }
+        h = h.wrapping_mul(M);
+        h ^= h >> 24;
+    }
+    h
+}
+
+/// The leveldb style bloom filter of Go badger, returns false if the key is surely absent.
+pub(crate) fn may_contain(filter: &[u8], mut h: u32) -> bool {
+    if filter.len() < 2 {
+        return false;
+    }
+    let k = filter[filter.len() - 1];
+    if k > 30 {
+        // Reserved for potentially new encodings for short bloom filters.
+        return true;
+    }
+    let n_bits = (8 * (filter.len() - 1)) as u32;
+    let delta = h.rotate_right(17);
+    for _ in 0..k {
+        let bit_pos = h % n_bits;
+        if filter[(bit_pos / 8) as usize] & (1 << (bit_pos % 8)) == 0 {
+            return false;
+        }
+        h = h.wrapping_add(delta);
+    }
+    true
+}
+
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f63b78
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32 with the Castagnoli polynomial.
+pub(crate) fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in data {
+        crc = CRC32C_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const PRIME64_1: u64 = 0x9E3779B185EBCA87;
+const PRIME64_2: u64 = 0xC2B2AE3D27D4EB4F;
+const PRIME64_3: u64 = 0x165667B19E3779F9;
+const PRIME64_4: u64 = 0x85EBCA77C2B2AE63;
+const PRIME64_5: u64 = 0x27D4EB2F165667C5;
+
+fn xxh64_round(acc: u64, input: u64) -> u64 {
+    acc.wrapping_add(input.wrapping_mul(PRIME64_2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME64_1)
+}
+
+fn xxh64_merge_round(acc: u64, val: u64) -> u64 {
+    (acc ^ xxh64_round(0, val))
+        .wrapping_mul(PRIME64_1)
+        .wrapping_add(PRIME64_4)
+}
+
+/// XXH64, the other checksum algorithm supported by Go badger.
+pub(crate) fn xxh64(data: &[u8], seed: u64) -> u64 {
+    let mut rest = data;
+    let mut h = if data.len() >= 32 {
+        let mut v1 = seed.wrapping_add(PRIME64_1).wrapping_add(PRIME64_2);
+        let mut v2 = seed.wrapping_add(PRIME64_2);
+        let mut v3 = seed;
+        let mut v4 = seed.wrapping_sub(PRIME64_1);
+        while rest.len() >= 32 {
+            v1 = xxh64_round(v1, LittleEndian::read_u64(rest));
+            v2 = xxh64_round(v2, LittleEndian::read_u64(&rest[8..]));
+            v3 = xxh64_round(v3, LittleEndian::read_u64(&rest[16..]));
+            v4 = xxh64_round(v4, LittleEndian::read_u64(&rest[24..]));
+            rest = &rest[32..];
+        }
+        let mut h = v1
+            .rotate_left(1)
+            .wrapping_add(v2.rotate_left(7))
+            .wrapping_add(v3.rotate_left(12))
+            .wrapping_add(v4.rotate_left(18));
+        h = xxh64_merge_round(h, v1);
+        h = xxh64_merge_round(h, v2);
+        h = xxh64_merge_round(h, v3);
+        xxh64_merge_round(h, v4)
+    } else {
+        seed.wrapping_add(PRIME64_5)
+    };
+    h = h.wrapping_add(data.len() as u64);
+    while rest.len() >= 8 {
+        h ^= xxh64_round(0, LittleEndian::read_u64(rest));
+        h = h
+            .rotate_left(27)
+            .wrapping_mul(PRIME64_1)
+            .wrapping_add(PRIME64_4);
+        rest = &rest[8..];
+    }
+    if rest.len() >= 4 {
+        h ^= (LittleEndian::read_u32(rest) as u64).wrapping_mul(PRIME64_1);
+        h = h
+            .rotate_left(23)
+            .wrapping_mul(PRIME64_2)
+            .wrapping_add(PRIME64_3);
+        rest = &rest[4..];
+    }
+    for b in rest {
+        h ^= (*b as u64).wrapping_mul(PRIME64_5);
+        h = h.rotate_left(11).wrapping_mul(PRIME64_1);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(PRIME64_2);
+    h ^= h >> 29;
+    h = h.wrapping_mul(PRIME64_3);
+    h ^ (h >> 32)
+}
+
+// Minimal protobuf wire format reader, enough for the few Go badger messages.
+struct PbReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PbReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        PbReader { buf, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn remaining(&self) -> &'a [u8] {
+        &self.buf[self.pos.min(self.buf.len())..]
+    }
+
+    fn read_varint(&mut self) -> Option<u64> {
+        let mut v = 0u64;
+        for shift in (0..64).step_by(7) {
+            let b = *self.buf.get(self.pos)?;
+            self.pos += 1;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Some(v);
+            }
+        }
+        None
+    }
+
+    // Returns (field number, wire type).
+    fn read_key(&mut self) -> Option<(u64, u64)> {
+        let key = self.read_varint()?;
+        Some((key >> 3, key & 0x7))
+    }
+
+    fn read_bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.read_varint()? as usize;
+        let end = self.pos.checked_add(len)?;
+        let out = self.buf.get(self.pos..end)?;
+        self.pos = end;
+        Some(out)
+    }
+
+    fn skip(&mut self, wire: u64) -> Option<()> {
+        match wire {
+            0 => self.read_varint().map(|_| ()),
+            1 => self.advance(8),
+            2 => self.read_bytes().map(|_| ()),
+            5 => self.advance(4),
+            _ => None,
+        }
+    }
+
+    fn advance(&mut self, n: usize) -> Option<()> {
+        if self.pos + n > self.buf.len() {
+            return None;
+        }
+        self.pos += n;
+        Some(())
+    }
+}
+
+// Minimal flatbuffers table reader with bounds checks, used for the v3 index.
+struct FbTable<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    vtable: usize,
+    vtable_len: usize,
+}
+
+impl<'a> FbTable<'a> {
+    fn root(buf: &'a [u8]) -> Option<FbTable<'a>> {
+        let pos = Self::read_u32(buf, 0)? as usize;
+        Self::at(buf, pos)
+    }
+
+    fn at(buf: &'a [u8], pos: usize) -> Option<FbTable<'a>> {
+        let soffset = Self::read_u32(buf, pos)? as i32 as i64;
+        let vtable = pos as i64 - soffset;
+        if vtable < 0 || vtable as usize + 4 > buf.len() {
+            return None;
+        }
+        let vtable = vtable as usize;
+        let vtable_len = LittleEndian::read_u16(&buf[vtable..]) as usize;
+        if vtable_len < 4 || vtable_len % 2 != 0 || vtable + vtable_len > buf.len() {
+            return None;
+        }
+        Some(FbTable {
+            buf,
+            pos,
+            vtable,
+            vtable_len,
+        })
+    }
+
+    fn read_u32(buf: &[u8], pos: usize) -> Option<u32> {
+        buf.get(pos..pos + 4).map(LittleEndian::read_u32)
+    }
+
+    // Position of the field, None if it's absent.
+    fn field(&self, id: usize) -> Option<usize> {
+        let entry = 4 + 2 * id;
+        if entry + 2 > self.vtable_len {
+            return None;
+        }
+        let off = LittleEndian::read_u16(&self.buf[self.vtable + entry..]) as usize;
+        if off == 0 {
+            return None;
+        }
+        Some(self.pos + off)
+    }
+
+    fn u32(&self, id: usize) -> Option<u32> {
+        Self::read_u32(self.buf, self.field(id)?)
+    }
+
+    // Returns (start of elements, number of elements) of a vector field.
+    fn vector(&self, id: usize) -> Option<(usize, usize)> {
+        let pos = self.field(id)?;
+        let vec = pos + Self::read_u32(self.buf, pos)? as usize;
+        let len = Self::read_u32(self.buf, vec)? as usize;
+        Some((vec + 4, len))
+    }
+
+    fn bytes(&self, id: usize) -> Option<&'a [u8]> {
+        let (start, len) = self.vector(id)?;
+        self.buf.get(start..start.checked_add(len)?)
+    }
+
+    fn tables(&self, id: usize) -> Option<Vec<FbTable<'a>>> {
+        let (start, len) = self.vector(id)?;
+        let mut out = vec![];
+        for i in 0..len {
+            let pos = start + 4 * i;
+            out.push(Self::at(
+                self.buf,
+                pos + Self::read_u32(self.buf, pos)? as usize,
+            )?);
+        }
+        Some(out)
+    }
+}
+
+#[test]
+fn checksums() {
+    assert_eq!(crc32c(b"123456789"), 0xe3069283);
+    assert_eq!(xxh64(b"", 0), 0xef46db3751d8e999);
+    assert_eq!(xxh64(b"abc", 0), 0x44bc2cf5ad770999);
+}
+
+/// Encodes `(key, version, value)` entries, sorted, into a Go badger block with its CRC32C
+/// checksum.
+#[cfg(test)]
+pub(crate) fn encode_go_block(entries: &[(&[u8], u64, &[u8])]) -> Vec<u8> {
+    use byteorder::WriteBytesExt;
+    use std::io::Write;
+    let mut data = vec![];
+    let mut offsets = vec![];
+    let mut base = vec![];
+    for (i, (key, version, value)) in entries.iter().enumerate() {
+        offsets.push(data.len() as u32);
+        let key = [*key, &(u64::MAX - version).to_be_bytes()].concat();
+        if i == 0 {
+            base = key.clone();
+        }
+        let overlap = key
+            .iter()
+            .zip(base.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let overlap = if i == 0 { 0 } else { overlap };
+        data.write_u16::<LittleEndian>(overlap as u16).unwrap();
+        data.write_u16::<LittleEndian>((key.len() - overlap) as u16)
+            .unwrap();
+        data.write_all(&key[overlap..]).unwrap();
+        data.write_all(&[0, 7, 0]).unwrap(); // meta, user meta, expires_at
+        data.write_all(value).unwrap();
+    }
+    for offset in offsets.iter() {
+        data.write_u32::<LittleEndian>(*offset).unwrap();
+    }
+    data.write_u32::<BigEndian>(offsets.len() as u32).unwrap();
+    let checksum = encode_go_checksum(&data);
+    data.write_all(&checksum).unwrap();
+    data.write_u32::<BigEndian>(checksum.len() as u32).unwrap();
+    data
+}
+
+/// Encodes the CRC32C `Checksum` message of `data`.
+#[cfg(test)]
+pub(crate) fn encode_go_checksum(data: &[u8]) -> Vec<u8> {
+    // algo = CRC32C(field 1), sum (field 2)
+    let mut checksum = vec![0x08, 0x00, 0x10];
+    let mut v = crc32c(data) as u64;
+    while v >= 0x80 {
+        checksum.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    checksum.push(v as u8);
+    checksum
+}
+
+/// Encodes `data` as a Snappy block of literals only.
+#[cfg(test)]
+pub(crate) fn encode_snappy_literals(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut v = data.len() as u64;
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+    for chunk in data.chunks(1 << 16) {
+        // The length minus one is in the 2 bytes after the tag.
+        out.push(61 << 2);
+        out.extend_from_slice(&((chunk.len() - 1) as u16).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+#[test]
+fn decode_go_block() {
+    // Two versions of "key" and one of "kez", newest version first.
+    let mut data = encode_go_block(&[(b"key", 5, b"v5"), (b"key", 3, b"v3"), (b"kez", 1, b"z")]);
+    let items = decode_block(&data, None).unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0].0, b"key");
+    assert_eq!(items[0].1.value, b"v5");
+    assert_eq!(items[0].1.cas_counter, 5);
+    assert_eq!(items[0].1.user_meta, 7);
+    assert_eq!(items[1].0, b"kez");
+    assert_eq!(last_key(&data).unwrap(), b"kez");
+    // The versions of "key" continue those of the block before.
+    let items = decode_block(&data, Some(b"key")).unwrap();
+    assert_eq!(items.len(), 1);
+    assert_eq!(items[0].0, b"kez");
+
+    data[5] ^= 0xff;
+    assert!(decode_block(&data, None).is_err());
+}
+
+#[test]
+fn decode_compressed_go_block() {
+    let data = encode_go_block(&[(b"key", 5, b"v5"), (b"kez", 1, b"z")]);
+    let snappy = encode_snappy_literals(&data);
+    let zstd = zstd::encode_all(data.as_slice(), 3).unwrap();
+    for block in [snappy, zstd] {
+        assert!(matches!(decompress_block(&block).unwrap(), Cow::Owned(_)));
+        let items = decode_block(&block, None).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].1.value, b"z");
+    }
+    assert!(matches!(decompress_block(&data).unwrap(), Cow::Borrowed(_)));
+
+    // A literal then back references with 1 and 2 bytes offsets.
+    let block = [12, 0x08, b'a', b'b', b'c', 0x09, 0x03, 0x0a, 0x03, 0x00];
+    assert_eq!(snappy_decode(&block).unwrap(), b"abcabcabcabc");
+    assert!(snappy_decode(&block[..6]).is_err());
+    assert!(snappy_decode(&[3, 0x09, 0x01]).is_err());
+}
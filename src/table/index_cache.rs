@@ -0,0 +1,103 @@
+use crate::table::block_cache::CacheStats;
+use crate::table::table::TableIndex;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Keeps the index and bloom filter of tables in memory within a byte budget, apart from
+/// data blocks so scans can't push them out. The least recently used indexes are evicted
+/// first, an evicted index is read again from its table on the next access.
+#[derive(Clone)]
+pub(crate) struct IndexCache {
+    inner: Arc<Mutex<IndexCacheInner>>,
+}
+
+struct IndexCacheInner {
+    budget: usize,
+    used: usize,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    // table id => (index, last access)
+    entries: HashMap<u64, (Arc<TableIndex>, u64)>,
+}
+
+impl IndexCache {
+    pub(crate) fn new(budget: usize) -> IndexCache {
+        IndexCache {
+            inner: Arc::new(Mutex::new(IndexCacheInner {
+                budget,
+                used: 0,
+                tick: 0,
+                hits: 0,
+                misses: 0,
+                entries: HashMap::new(),
+            })),
+        }
+    }
+
+    pub(crate) fn get(&self, id: u64) -> Option<Arc<TableIndex>> {
+        let mut inner = self.inner.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let index = match inner.entries.get_mut(&id) {
+            Some((index, last)) => {
+                *last = tick;
+                index.clone()
+            }
+            None => {
+                inner.misses += 1;
+                crate::event::get_metrics().num_index_cache_misses.inc();
+                return None;
+            }
+        };
+        inner.hits += 1;
+        crate::event::get_metrics().num_index_cache_hits.inc();
+        Some(index)
+    }
+
+    pub(crate) fn insert(&self, id: u64, index: Arc<TableIndex>) {
+        let mut inner = self.inner.lock();
+        inner.tick += 1;
+        let tick = inner.tick;
+        inner.used += index.size();
+        if let Some((old, _)) = inner.entries.insert(id, (index, tick)) {
+            inner.used -= old.size();
+        }
+        // Keep at least the index just inserted, even if it alone exceeds the budget.
+        while inner.used > inner.budget && inner.entries.len() > 1 {
+            let victim = inner
+                .entries
+                .iter()
+                .filter(|(k, _)| **k != id)
+                .min_by_key(|(_, (_, last))| *last)
+                .map(|(k, _)| *k)
+                .unwrap();
+            let (old, _) = inner.entries.remove(&victim).unwrap();
+            inner.used -= old.size();
+            crate::event::get_metrics().num_index_cache_evictions.inc();
+        }
+    }
+
+    pub(crate) fn remove(&self, id: u64) {
+        let mut inner = self.inner.lock();
+        if let Some((old, _)) = inner.entries.remove(&id) {
+            inner.used -= old.size();
+        }
+    }
+
+    /// Bytes used by the cached indexes.
+    pub(crate) fn used(&self) -> usize {
+        self.inner.lock().used
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock();
+        CacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            used: inner.used as u64,
+            budget: inner.budget as u64,
+        }
+    }
+}
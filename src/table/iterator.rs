@@ -1,11 +1,14 @@
 use crate::table::builder::Header;
-use crate::table::table::Table;
+use crate::table::table::{Block, Table};
 use crate::y::iterator::{KeyValue, Xiterator};
-use crate::y::ValueStruct;
+use crate::y::{key_with_ts, parse_key, Result, ValueStruct};
 
 use log::debug;
 use std::borrow::{Borrow, BorrowMut};
 use std::cell::{RefCell, RefMut};
+use std::collections::HashMap;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, OnceLock};
 
 use std::fmt::Formatter;
 
@@ -268,6 +271,19 @@ impl IteratorItem {
     pub fn value(&self) -> &ValueStruct {
         &self.value
     }
+
+    pub fn meta(&self) -> u8 {
+        self.value.meta
+    }
+
+    pub fn user_meta(&self) -> u8 {
+        self.value.user_meta
+    }
+
+    /// Unix time in seconds after which the entry is expired, zero means never.
+    pub fn expires_at(&self) -> u64 {
+        self.value.expires_at
+    }
 }
 
 /// TODO add start or end
@@ -282,6 +298,112 @@ pub struct IteratorImpl {
     // unidirectional functionality for now.
     reversed: bool,
     id: u64,
+    // Reads the next blocks in background, None if prefetch is disabled.
+    prefetcher: Option<BlockPrefetcher>,
+}
+
+// The threads reading the blocks prefetched by all the iterators.
+const PREFETCH_THREADS: usize = 4;
+// The refills waiting for a prefetch thread, the ones past it aren't prefetched.
+const PREFETCH_QUEUE: usize = 64;
+
+type PrefetchJob = Box<dyn FnOnce() + Send>;
+
+// Returns the queue of the prefetch threads, started on first use.
+fn prefetch_queue() -> &'static SyncSender<PrefetchJob> {
+    static QUEUE: OnceLock<SyncSender<PrefetchJob>> = OnceLock::new();
+    QUEUE.get_or_init(|| {
+        let (tx, rx) = sync_channel::<PrefetchJob>(PREFETCH_QUEUE);
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..PREFETCH_THREADS {
+            let rx = rx.clone();
+            std::thread::Builder::new()
+                .name(format!("block-prefetch-{}", i))
+                .spawn(move || loop {
+                    let job = rx.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })
+                .unwrap();
+        }
+        tx
+    })
+}
+
+/// Reads ahead the blocks an iterator is going to visit next on the shared prefetch
+/// threads, so sequential scans do not wait on every block read.
+struct BlockPrefetcher {
+    table: Table,
+    // Number of blocks to read ahead.
+    n: usize,
+    // Blocks being read, by block index.
+    pending: RefCell<HashMap<usize, Receiver<Result<Block>>>>,
+}
+
+impl BlockPrefetcher {
+    fn new(table: Table, n: usize) -> BlockPrefetcher {
+        BlockPrefetcher {
+            table,
+            n,
+            pending: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // Returns the block at `idx`, from the prefetched ones if possible, and
+    // schedules the blocks after it.
+    fn get(&self, idx: usize, reversed: bool) -> Result<Block> {
+        let block = self
+            .pending
+            .borrow_mut()
+            .remove(&idx)
+            .and_then(|rx| rx.recv().ok())
+            .unwrap_or_else(|| self.table.block(idx));
+        self.schedule(idx, reversed);
+        block
+    }
+
+    fn schedule(&self, idx: usize, reversed: bool) {
+        let window = if reversed {
+            (idx.saturating_sub(self.n)..idx).rev().collect::<Vec<_>>()
+        } else {
+            (idx + 1..(idx + 1 + self.n).min(self.table.block_count())).collect::<Vec<_>>()
+        };
+        let mut pending = self.pending.borrow_mut();
+        // Drop the blocks that are not ahead of us anymore, e.g. after a seek.
+        pending.retain(|i, _| window.contains(i));
+        // Refill only when half of the window has been consumed, avoids spawning for every block.
+        if pending.len() > self.n / 2 {
+            return;
+        }
+        let mut jobs = vec![];
+        for i in window {
+            if pending.contains_key(&i) {
+                continue;
+            }
+            let (tx, rx) = sync_channel(1);
+            pending.insert(i, rx);
+            jobs.push((i, tx));
+        }
+        if jobs.is_empty() {
+            return;
+        }
+        let ids = jobs.iter().map(|(i, _)| *i).collect::<Vec<_>>();
+        let table = self.table.clone();
+        let job = Box::new(move || {
+            for (i, tx) in jobs {
+                // The iterator may be gone, ignore send errors.
+                let _ = tx.send(table.block(i));
+            }
+        });
+        // The prefetch threads are busy, the blocks are read when they're reached.
+        if prefetch_queue().try_send(job).is_err() {
+            for i in ids {
+                pending.remove(&i);
+            }
+        }
+    }
 }
 
 impl fmt::Display for IteratorImpl {
@@ -355,16 +477,34 @@ impl IteratorImpl {
             bpos: RefCell::new(0),
             bi: RefCell::new(None),
             reversed,
+            prefetcher: None,
         };
         itr.reset();
         itr
     }
 
+    /// Reads `n` blocks ahead in background while iterating, 0 disables it.
+    pub fn prefetch(mut self, n: usize) -> IteratorImpl {
+        self.prefetcher = if n > 0 {
+            Some(BlockPrefetcher::new(self.table.clone(), n))
+        } else {
+            None
+        };
+        self
+    }
+
+    fn load_block(&self, bpos: usize) -> Result<Block> {
+        match self.prefetcher.as_ref() {
+            Some(prefetcher) => prefetcher.get(bpos, self.reversed),
+            None => self.table.block(bpos),
+        }
+    }
+
     pub fn seek_to_first(&self) -> Option<IteratorItem> {
         #[cfg(test)]
-        assert!(!self.table.block_index.is_empty());
+        assert!(self.table.block_count() > 0);
 
-        if self.table.block_index.is_empty() {
+        if self.table.block_count() == 0 {
             return None;
         }
         *self.bpos.borrow_mut() = 0;
@@ -376,15 +516,20 @@ impl IteratorImpl {
     }
 
     pub fn seek_to_last(&self) -> Option<IteratorItem> {
-        if self.table.block_index.is_empty() {
+        if self.table.block_count() == 0 {
             return None;
         }
-        *self.bpos.borrow_mut() = (self.table.block_index.len() - 1) as isize;
-        let bi = self.get_bi_by_bpos(*self.bpos.borrow());
-        bi.as_ref()
-            .unwrap()
-            .seek_to_last()
-            .map(|b_item| b_item.into())
+        let mut bpos = self.table.block_count() - 1;
+        loop {
+            *self.bpos.borrow_mut() = bpos as isize;
+            let bi = self.get_bi_by_bpos(*self.bpos.borrow());
+            let item = bi.as_ref().unwrap().seek_to_last();
+            // Blocks of a Go badger table may have no entry left, see `go_table::decode_block`.
+            if item.is_some() || bpos == 0 {
+                return item.map(|b_item| b_item.into());
+            }
+            bpos -= 1;
+        }
     }
 
     fn _seek(&self, key: &[u8]) -> Option<IteratorItem> {
@@ -399,21 +544,25 @@ impl IteratorImpl {
             IteratorSeek::Current => {}
         }
 
-        let idx = self
-            .table
+        let index = self.table.index();
+        let idx = index
             .block_index
-            .binary_search_by(|ko| ko.key.as_slice().cmp(key));
-        if idx.is_ok() {
-            return self.seek_helper(idx.unwrap() as isize, key);
+            .partition_point(|ko| ko.key.as_slice() < key);
+        // A block of a Go badger table doesn't hold its first key if it's only older versions
+        // of the last key of the block before, the key is then in that block.
+        if idx < index.block_index.len()
+            && index.block_index[idx].key == key
+            && !self.table.is_go_table()
+        {
+            return self.seek_helper(idx as isize, key);
         }
 
         // not found
-        let idx = idx.err().unwrap();
         if idx == 0 {
             return self.seek_helper(idx as isize, key);
         }
 
-        if idx >= self.table.block_index.len() {
+        if idx >= self.table.block_count() {
             return self.seek_helper((idx - 1) as isize, key);
         }
 
@@ -434,16 +583,19 @@ impl IteratorImpl {
 
         let idx = self
             .table
+            .index()
             .block_index
             .binary_search_by(|ko| ko.key.as_slice().cmp(key));
-        if idx.is_ok() {
-            return self.seek_helper_rewind(idx.unwrap() as isize, key);
-        }
-
-        // not found
-        let idx = idx.err().unwrap();
-        if idx == 0 {
-            return None;
+        let idx = match idx {
+            Ok(idx) => idx,
+            Err(0) => return None,
+            Err(idx) => idx - 1,
+        };
+        // An index key may sort before the first key of its block, then the item is in the
+        // block before.
+        let item = self.seek_helper_rewind(idx as isize, key);
+        if item.is_some() || idx == 0 {
+            return item;
         }
         self.seek_helper_rewind((idx - 1) as isize, key)
     }
@@ -453,6 +605,50 @@ impl IteratorImpl {
         self.seek_from(key, IteratorSeek::Origin)
     }
 
+    /// Seeks to the newest version of `key` not newer than `version`, in a table whose keys
+    /// carry their version (see `key_with_ts`). Returns None if there's no such version.
+    pub fn seek_to_version(&self, key: &[u8], version: u64) -> Option<IteratorItem> {
+        let item = self.seek_from(&key_with_ts(key, version), IteratorSeek::Origin)?;
+        if parse_key(item.key()) != key {
+            return None;
+        }
+        Some(item)
+    }
+
+    /// Looks up the exact `keys`, sorted, reading each block once for all its keys instead of
+    /// once per seek. Returns the items in the order of the keys, None for the keys missing.
+    pub(crate) fn get_sorted(&self, keys: &[&[u8]]) -> Vec<Option<IteratorItem>> {
+        if self.table.is_go_table() {
+            // Its index keys may not be in their blocks, see `seek_from`.
+            return keys
+                .iter()
+                .map(|&key| self.seek(key).filter(|item| item.key() == key))
+                .collect();
+        }
+        let index = self.table.index();
+        let mut loaded: Option<(usize, BlockIterator)> = None;
+        keys.iter()
+            .map(|&key| {
+                // The index keys are the first keys of their blocks.
+                let bpos = match index
+                    .block_index
+                    .binary_search_by(|ko| ko.key.as_slice().cmp(key))
+                {
+                    Ok(idx) => idx,
+                    Err(0) => return None,
+                    Err(idx) => idx - 1,
+                };
+                if loaded.as_ref().map_or(true, |(pos, _)| *pos != bpos) {
+                    let block = self.load_block(bpos).unwrap();
+                    loaded = Some((bpos, BlockIterator::new(block.data)));
+                }
+                let (_, bi) = loaded.as_ref().unwrap();
+                let item = bi.seek(key, IteratorSeek::Origin)?;
+                (item.key == key).then(|| item.into())
+            })
+            .collect()
+    }
+
     // will reset iterator and seek to <= key.
     pub(crate) fn seek_for_prev(&self, key: &[u8]) -> Option<IteratorItem> {
         // TODO: Optimize this. We shouldn't have to take a Prev step.
@@ -475,7 +671,14 @@ impl IteratorImpl {
             // uninit
             let bi = self.get_or_set_bi(*self.bpos.borrow());
             let item = bi.as_ref().unwrap().seek_to_last();
-            return item.map(|b_item| b_item.into());
+            // Blocks of a Go badger table may have no entry left, see `go_table::decode_block`.
+            if item.is_some() || *self.bpos.borrow() == 0 {
+                return item.map(|b_item| b_item.into());
+            }
+            drop(bi);
+            *self.bpos.borrow_mut() -= 1;
+            self.bi.borrow_mut().take();
+            return self.prev();
         }
         let item = self
             .bi
@@ -498,7 +701,7 @@ impl IteratorImpl {
 
     fn _next(&self) -> Option<IteratorItem> {
         let mut bpos = self.bpos.borrow_mut();
-        if *bpos >= self.table.block_index.len() as isize {
+        if *bpos >= self.table.block_count() as isize {
             return None;
         }
         let mut bi = self.get_or_set_bi(*bpos);
@@ -518,7 +721,7 @@ impl IteratorImpl {
             *self.bpos.borrow_mut() = 0;
             self.bi.borrow_mut().take();
         } else {
-            *self.bpos.borrow_mut() = (self.table.block_index.len() - 1) as isize;
+            *self.bpos.borrow_mut() = (self.table.block_count() - 1) as isize;
             self.bi.borrow_mut().take();
         }
     }
@@ -557,7 +760,7 @@ impl IteratorImpl {
         if bi.is_some() {
             return bi;
         }
-        let block = self.table.block(bpos as usize).unwrap();
+        let block = self.load_block(bpos as usize).unwrap();
         let it = BlockIterator::new(block.data);
         *bi = Some(it);
         bi
@@ -565,7 +768,7 @@ impl IteratorImpl {
 
     fn get_bi_by_bpos(&self, bpos: isize) -> RefMut<'_, Option<BlockIterator>> {
         assert!(bpos >= 0);
-        let block = self.table.block(bpos as usize).unwrap();
+        let block = self.load_block(bpos as usize).unwrap();
         let mut bi = self.bi.borrow_mut();
         let it = BlockIterator::new(block.data);
         *bi = Some(it);
@@ -598,10 +801,19 @@ pub struct ConcatIterator {
 impl ConcatIterator {
     /// Note: new ConcatIterator is invalid(Not pointer first element)
     pub fn new(tables: Vec<Table>, reversed: bool) -> ConcatIterator {
+        Self::new_with_prefetch(tables, reversed, 0)
+    }
+
+    /// Same as `new`, every table iterator reads `prefetch_blocks` blocks ahead.
+    pub fn new_with_prefetch(
+        tables: Vec<Table>,
+        reversed: bool,
+        prefetch_blocks: usize,
+    ) -> ConcatIterator {
         let iters = tables
             .clone()
             .into_iter()
-            .map(|tb| IteratorImpl::new(tb, reversed))
+            .map(|tb| IteratorImpl::new(tb, reversed).prefetch(prefetch_blocks))
             .collect::<Vec<_>>();
         Self {
             index: RefCell::new(-1),
@@ -642,6 +854,7 @@ impl Xiterator for ConcatIterator {
             } else {
                 self.set_idx((self.iters.len() - 1) as isize);
             }
+            *self.init.borrow_mut() = true;
         }
 
         while (!self.reversed && *self.index.borrow() < self.iters.len() as isize)
@@ -677,6 +890,7 @@ impl Xiterator for ConcatIterator {
         for itr in self.iters.iter() {
             itr.reset();
         }
+        *self.init.borrow_mut() = true;
         // 2: reset iterator of current table
         self.get_cur().unwrap().rewind()
     }
@@ -686,6 +900,11 @@ impl Xiterator for ConcatIterator {
         if self.iters.is_empty() {
             return None;
         }
+        // The tables after the one sought are read from their start by `next`.
+        for itr in self.iters.iter() {
+            itr.reset();
+        }
+        *self.init.borrow_mut() = true;
         if !self.reversed {
             // >= key
             let idx = self.tables.binary_search_by(|tb| tb.biggest().cmp(key));
@@ -699,7 +918,7 @@ impl Xiterator for ConcatIterator {
                 return self.get_cur().unwrap()._seek(key);
             }
             // not found
-            self.set_idx(-1);
+            self.set_idx(self.iters.len() as isize);
             None
         } else {
             let idx = self.tables.binary_search_by(|tb| tb.smallest().cmp(key));
@@ -737,6 +956,10 @@ impl Xiterator for ConcatIterator {
         }
         id
     }
+
+    fn close(&self) {
+        self.iters.iter().for_each(|itr| itr.close());
+    }
 }
 
 impl fmt::Display for ConcatIterator {
@@ -1,4 +1,11 @@
+pub(crate) mod block_cache;
 pub(crate) mod builder;
+mod compression;
+pub(crate) mod encryption;
+pub(crate) mod file;
+pub(crate) mod go_table;
+pub(crate) mod index_cache;
 pub(crate) mod iterator;
 pub(crate) mod table;
 mod tests;
+mod xor_filter;
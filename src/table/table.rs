@@ -1,7 +1,14 @@
-use crate::options::FileLoadingMode;
+use crate::kv::_BADGER_PREFIX;
 use crate::options::FileLoadingMode::MemoryMap;
-use crate::table::builder::Header;
-use crate::y::{hash, mmap, parallel_load_block_key, read_at, Result};
+use crate::options::{AccessHint, CompressionType, FileLoadingMode, FilterType};
+use crate::table::block_cache::BlockCache;
+use crate::table::builder::{Builder, Header};
+use crate::table::compression::decompress;
+use crate::table::encryption::{DataKey, KeyRegistry};
+use crate::table::go_table;
+use crate::table::index_cache::IndexCache;
+use crate::table::xor_filter::XorFilter;
+use crate::y::{hash, madvise, mmap, open_existing_synced_file, read_at, Result, TEMP_FILE_SUFFIX};
 use crate::{event, hex_str, Error};
 use byteorder::{BigEndian, ReadBytesExt};
 
@@ -14,7 +21,8 @@ use std::fmt::{Debug, Display, Formatter};
 use std::fs::{read_dir, remove_file, File};
 use std::io::{Cursor, Seek, SeekFrom};
 use std::path::Path;
-use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::{fmt, io};
 
 #[cfg(target_os = "macos")]
@@ -38,6 +46,8 @@ pub(crate) struct KeyOffset {
     pub(crate) key: Vec<u8>,
     offset: usize,
     len: usize,
+    // crc32 of the block.
+    checksum: u32,
 }
 
 impl Display for KeyOffset {
@@ -46,10 +56,111 @@ impl Display for KeyOffset {
             .field("key", &String::from_utf8_lossy(&self.key))
             .field("offset", &self.offset)
             .field("len", &self.len)
+            .field("checksum", &self.checksum)
             .finish()
     }
 }
 
+// The filter probed before reading the blocks of a table.
+enum TableFilter {
+    Bloom(GrowableBloom),
+    Xor(XorFilter),
+    // Bloom filter of a table written by Go badger, its blocks are decoded on read.
+    Go(Vec<u8>),
+}
+
+/// Block index and filter of a table.
+pub(crate) struct TableIndex {
+    pub(crate) block_index: Vec<KeyOffset>,
+    filter: TableFilter,
+    // Bytes of the filter in the table.
+    filter_size: usize,
+    // Estimated bytes held in memory.
+    size: usize,
+}
+
+impl TableIndex {
+    fn new(block_index: Vec<KeyOffset>, filter: TableFilter, filter_size: usize) -> Self {
+        let size = block_index
+            .iter()
+            .map(|ko| ko.key.len() + std::mem::size_of::<KeyOffset>())
+            .sum::<usize>()
+            + filter_size;
+        TableIndex {
+            block_index,
+            filter,
+            filter_size,
+            size,
+        }
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// Summary of a table, see `TableCore::stats`.
+#[derive(Debug, Clone, Default)]
+pub struct TableStats {
+    pub id: u64,
+    pub entries: u64,
+    pub blocks: usize,
+    /// Bytes of the blocks once decompressed and decrypted.
+    pub raw_size: u64,
+    /// Bytes of the table file.
+    pub on_disk_size: u64,
+    pub bloom_size: usize,
+    pub stale_data_size: u64,
+    pub smallest: Vec<u8>,
+    pub biggest: Vec<u8>,
+}
+
+impl Display for TableStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TableStats")
+            .field("id", &self.id)
+            .field("entries", &self.entries)
+            .field("blocks", &self.blocks)
+            .field("raw_size", &self.raw_size)
+            .field("on_disk_size", &self.on_disk_size)
+            .field("bloom_size", &self.bloom_size)
+            .field("stale", &self.stale_data_size)
+            .field("smallest", &hex_str(&self.smallest))
+            .field("biggest", &hex_str(&self.biggest))
+            .finish()
+    }
+}
+
+/// A table of the LSM tree, see `KV::tables`.
+#[derive(Debug, Clone, Default)]
+pub struct TableInfo {
+    pub id: u64,
+    pub level: usize,
+    pub smallest: Vec<u8>,
+    pub biggest: Vec<u8>,
+    /// Bytes of the table file.
+    pub size: u64,
+    /// Number of entries, only counted when asked for since every block is read.
+    pub key_count: u64,
+}
+
+/// A level of the LSM tree, see `KV::levels`.
+#[derive(Debug, Clone, Default)]
+pub struct LevelInfo {
+    pub level: usize,
+    pub num_tables: usize,
+    /// Bytes of the table files.
+    pub size: u64,
+    /// Bytes of tombstones and superseded entries, as counted when the tables were built.
+    pub stale_size: u64,
+    /// Size limit of the level, level 0 is limited by its number of tables instead.
+    pub max_size: u64,
+    /// Compaction score, the level is compacted once it's at least 1.
+    pub score: f64,
+    /// True for the level the level 0 tables are compacted into.
+    pub is_base: bool,
+}
+
 pub type Table = XArc<TableCore>;
 pub type WeakTable = XWeak<TableCore>;
 
@@ -87,7 +198,10 @@ pub struct TableCore {
     file_name: String,
     // Initialized in OpenTable, using fd.Stat()
     table_size: usize,
-    pub(crate) block_index: Vec<KeyOffset>,
+    // The pinned index, none if the table uses an index cache.
+    index: Option<Arc<TableIndex>>,
+    index_cache: Option<IndexCache>,
+    block_cache: Option<BlockCache>,
     loading_mode: FileLoadingMode,
     _mmap: Option<MmapMut>,
     // Memory mapped.
@@ -97,7 +211,37 @@ pub struct TableCore {
     biggest: Vec<u8>,
     // biggest keys.
     id: u64,
-    bf: GrowableBloom,
+    // Bytes of tombstones and superseded entries, read from the footer.
+    stale_data_size: u64,
+    // Reads that passed the filter but didn't find their key, see `record_useless_probe`.
+    useless_probes: AtomicU64,
+    // Removed from the LSM tree, the file is deleted with the last reference, see `mark_deleted`.
+    deleted: AtomicBool,
+    compression: CompressionType,
+    filter_type: FilterType,
+    // Key and iv of an encrypted table.
+    data_key: Option<DataKey>,
+    #[cfg_attr(not(feature = "encryption"), allow(dead_code))]
+    iv: [u8; 16],
+    is_go_table: bool,
+    // Written before the table footer existed, its blocks have no checksums.
+    is_baseline_table: bool,
+    // Verify the checksum of every block read.
+    verify_blocks: bool,
+}
+
+// The fixed size part at the end of the tables written by us.
+struct TableFooter {
+    iv: [u8; 16],
+    data_key_id: u64,
+    stale_data_size: u64,
+    filter_type: FilterType,
+    compression: CompressionType,
+}
+
+impl TableFooter {
+    // iv + data key id + stale data size + filter type + compression + version + magic
+    const SIZE: usize = 16 + 8 + 8 + 1 + 1 + 2 + 4;
 }
 
 impl TableCore {
@@ -106,9 +250,19 @@ impl TableCore {
     // -- consider t.Close() instead).  The fd has to writeable because we call Truncate on it before
     // deleting.
     pub(crate) fn open_table(
+        fd: File,
+        filename: &str,
+        loading_mode: FileLoadingMode,
+    ) -> Result<Self> {
+        Self::open_table_with_keys(fd, filename, loading_mode, None)
+    }
+
+    /// Same as `open_table`, the key of an encrypted table is looked up in `keys`.
+    pub(crate) fn open_table_with_keys(
         mut fd: File,
         filename: &str,
         loading_mode: FileLoadingMode,
+        keys: Option<&KeyRegistry>,
     ) -> Result<Self> {
         let file_sz = fd.seek(SeekFrom::End(0)).or_else(Err)?;
         fd.seek(SeekFrom::Start(0)).or_else(Err)?;
@@ -118,13 +272,24 @@ impl TableCore {
             fd,
             file_name: filename.to_string(),
             table_size: file_sz as usize,
-            block_index: vec![],
+            index: None,
+            index_cache: None,
+            block_cache: None,
             loading_mode,
             _mmap: None,
             smallest: vec![],
             biggest: vec![],
             id,
-            bf: GrowableBloom::new(0.01, 1),
+            stale_data_size: 0,
+            useless_probes: AtomicU64::new(0),
+            deleted: AtomicBool::new(false),
+            compression: CompressionType::None,
+            filter_type: FilterType::Bloom,
+            data_key: None,
+            iv: [0u8; 16],
+            is_go_table: false,
+            is_baseline_table: false,
+            verify_blocks: false,
         };
 
         #[cfg(any(target_os = "macos", target_os = "linux"))]
@@ -140,7 +305,11 @@ impl TableCore {
             table.load_to_ram()?;
         }
 
-        table.read_index()?;
+        if let Err(err) = table.load_index(keys) {
+            // Keep the file of a table that can't be opened.
+            table.decr_ref();
+            return Err(err);
+        }
         let table_ref = Table::new(table);
         let biggest = {
             let iter1 = super::iterator::IteratorImpl::new(table_ref.clone(), true);
@@ -168,6 +337,76 @@ impl TableCore {
         Ok(tc)
     }
 
+    fn load_index(&mut self, keys: Option<&KeyRegistry>) -> Result<()> {
+        let footer = self.read_footer()?;
+        if footer.is_none() {
+            self.is_baseline_table = self.is_baseline_layout()?;
+        }
+        if let Some(footer) = footer {
+            self.stale_data_size = footer.stale_data_size;
+            self.compression = footer.compression;
+            self.filter_type = footer.filter_type;
+            self.iv = footer.iv;
+            if footer.data_key_id != 0 {
+                let data_key = keys.and_then(|keys| keys.get(footer.data_key_id));
+                if data_key.is_none() {
+                    return Err(format!(
+                        "Invalid table: {}, data key {} not found",
+                        self.file_name, footer.data_key_id
+                    )
+                    .into());
+                }
+                self.data_key = data_key;
+            }
+        }
+        let index = self.read_index()?;
+        self.is_go_table = matches!(index.filter, TableFilter::Go(_));
+        self.index = Some(Arc::new(index));
+        Ok(())
+    }
+
+    /// Verifies the checksum of every block read if `verify`, a corrupted block fails its read.
+    pub(crate) fn with_block_checksums(mut self, verify: bool) -> TableCore {
+        self.verify_blocks = verify;
+        self
+    }
+
+    /// Moves the index of the table into `cache`, so it's counted in the cache budget and may be
+    /// evicted. Without a cache the index stays pinned in memory.
+    pub(crate) fn with_index_cache(mut self, cache: Option<IndexCache>) -> TableCore {
+        if let Some(cache) = cache {
+            if let Some(index) = self.index.take() {
+                cache.insert(self.id, index);
+            }
+            self.index_cache = Some(cache);
+        }
+        self
+    }
+
+    /// Keeps the blocks read in `cache`, shared with the other tables.
+    pub(crate) fn with_block_cache(mut self, cache: Option<BlockCache>) -> TableCore {
+        self.block_cache = cache;
+        self
+    }
+
+    /// Returns the index of the table, reading it again from the file if it was evicted from
+    /// the index cache.
+    pub(crate) fn index(&self) -> Arc<TableIndex> {
+        if let Some(index) = self.index.as_ref() {
+            return index.clone();
+        }
+        let cache = self.index_cache.as_ref().unwrap();
+        if let Some(index) = cache.get(self.id) {
+            return index;
+        }
+        let index =
+            Arc::new(self.read_index().unwrap_or_else(|err| {
+                panic!("failed to reload index of {}: {}", self.file_name, err)
+            }));
+        cache.insert(self.id, index.clone());
+        index
+    }
+
     // increments the refcount (having to do with whether the file should be deleted)
     pub(crate) fn incr_ref(&self) {
         use std::backtrace::Backtrace;
@@ -225,16 +464,117 @@ impl TableCore {
         self.read(off, sz).unwrap()
     }
 
-    // TODO maybe use &self
-    fn read_index(&mut self) -> Result<()> {
+    // Reads the fixed size footer, none if the table was written by Go badger or
+    // before the footer existed.
+    fn read_footer(&self) -> Result<Option<TableFooter>> {
+        if self.table_size < TableFooter::SIZE {
+            return Ok(None);
+        }
+        let mut buf =
+            Cursor::new(self.read(self.table_size - TableFooter::SIZE, TableFooter::SIZE)?);
+        let mut iv = [0u8; 16];
+        std::io::Read::read_exact(&mut buf, &mut iv)?;
+        let data_key_id = buf.read_u64::<BigEndian>()?;
+        let stale_data_size = buf.read_u64::<BigEndian>()?;
+        let filter_type = buf.read_u8()?;
+        let compression = buf.read_u8()?;
+        let version = buf.read_u16::<BigEndian>()?;
+        if buf.read_u32::<BigEndian>()? != Builder::MAGIC {
+            return Ok(None);
+        }
+        if version != Builder::FORMAT_VERSION {
+            return Err(format!(
+                "Unsupported table format: {}, version: {}",
+                self.file_name, version
+            )
+            .into());
+        }
+        let filter_type = FilterType::from_u8(filter_type).ok_or_else(|| {
+            Error::from(format!(
+                "Invalid table: {}, unknown filter type: {}",
+                self.file_name, filter_type
+            ))
+        })?;
+        let compression = CompressionType::from_u8(compression).ok_or_else(|| {
+            Error::from(format!(
+                "Invalid table: {}, unknown compression: {}",
+                self.file_name, compression
+            ))
+        })?;
+        Ok(Some(TableFooter {
+            iv,
+            data_key_id,
+            stale_data_size,
+            filter_type,
+            compression,
+        }))
+    }
+
+    // Decrypts and decompresses a block read from `offset`.
+    fn decode_block_data(&self, offset: usize, data: Vec<u8>) -> Result<Vec<u8>> {
+        let data = match self.data_key.as_ref() {
+            Some(data_key) => self.decrypt(data_key, offset, data)?,
+            None => data,
+        };
+        decompress(self.compression, data)
+    }
+
+    #[cfg(feature = "encryption")]
+    fn decrypt(&self, data_key: &DataKey, offset: usize, mut data: Vec<u8>) -> Result<Vec<u8>> {
+        crate::table::encryption::xor_block(&data_key.key, &self.iv, offset as u64, &mut data)?;
+        Ok(data)
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    fn decrypt(&self, data_key: &DataKey, _offset: usize, _data: Vec<u8>) -> Result<Vec<u8>> {
+        Err(format!(
+            "Table: {} is encrypted with data key {}, built without the encryption feature",
+            self.file_name, data_key.id
+        )
+        .into())
+    }
+
+    // Tables written before the footer existed end with the bloom filter json and its length,
+    // neither Go badger tables nor ours end that way.
+    fn is_baseline_layout(&self) -> Result<bool> {
+        if self.table_size < 8 {
+            return Ok(false);
+        }
+        let bloom_len =
+            Cursor::new(self.read(self.table_size - 4, 4)?).read_u32::<BigEndian>()? as usize;
+        if bloom_len < 2 || bloom_len + 8 > self.table_size {
+            return Ok(false);
+        }
+        let start = self.read(self.table_size - 4 - bloom_len, 1)?;
+        let end = self.read(self.table_size - 5, 1)?;
+        Ok(start[0] == b'{' && end[0] == b'}')
+    }
+
+    fn read_index(&self) -> Result<TableIndex> {
+        if self.is_baseline_table {
+            return self.read_baseline_index();
+        }
         let mut read_pos = self.table_size;
-        // Read bloom filter.
+        // Tables without our magic are expected to be written by Go badger.
+        read_pos -= 4;
+        let magic = Cursor::new(self.read(read_pos, 4)?).read_u32::<BigEndian>()?;
+        if magic != Builder::MAGIC {
+            return self.read_go_index();
+        }
+
+        // Skip the rest of the footer, it's read at open.
+        read_pos -= TableFooter::SIZE - 4;
+
+        // Read filter.
         read_pos -= 4;
         let buf = self.read_no_fail(read_pos, 4);
         let bloom_len = Cursor::new(buf).read_u32::<BigEndian>().unwrap();
         read_pos -= bloom_len as usize;
         let data = self.read_no_fail(read_pos, bloom_len as usize);
-        self.bf = serde_json::from_slice(&data).unwrap();
+        let filter = match self.filter_type {
+            FilterType::Bloom => TableFilter::Bloom(serde_json::from_slice(&data).unwrap()),
+            FilterType::Xor => TableFilter::Xor(XorFilter::decode(&data)?),
+        };
 
         read_pos -= 4;
         let restarts_len = Cursor::new(self.read_no_fail(read_pos, 4))
@@ -248,7 +588,28 @@ impl TableCore {
         for i in 0..restarts_len as usize {
             offsets[i] = buf.read_u32::<BigEndian>().unwrap();
         }
+
+        // Read block checksums, one per block.
+        read_pos -= 4 * restarts_len as usize;
+        let mut buf = Cursor::new(self.read_no_fail(read_pos, 4 * restarts_len as usize));
+        let mut checksums = vec![0u32; restarts_len as usize];
+        for i in 0..restarts_len as usize {
+            checksums[i] = buf.read_u32::<BigEndian>().unwrap();
+        }
+        // Read the index keys, they are encrypted along with the blocks.
+        read_pos -= 4;
+        let keys_len = Cursor::new(self.read_no_fail(read_pos, 4))
+            .read_u32::<BigEndian>()
+            .unwrap() as usize;
+        read_pos -= keys_len;
+        let mut data = self.read(read_pos, keys_len)?;
+        if let Some(data_key) = self.data_key.as_ref() {
+            data = self.decrypt(data_key, read_pos, data)?;
+        }
+        let mut keys = Cursor::new(data);
+
         // The last offset stores the end of the last block.
+        let mut block_index = Vec::with_capacity(offsets.len());
         for i in 0..offsets.len() {
             let offset = {
                 if i == 0 {
@@ -257,58 +618,211 @@ impl TableCore {
                     offsets[i - 1]
                 }
             };
+            let mut key = vec![0u8; keys.read_u16::<BigEndian>()? as usize];
+            std::io::Read::read_exact(&mut keys, &mut key)?;
             let index = KeyOffset {
                 offset: offset as usize,
                 len: (offsets[i] - offset) as usize,
-                key: vec![],
+                key,
+                checksum: checksums[i],
             };
-            self.block_index.push(index);
+            block_index.push(index);
         }
-        // todo Why reload key
-        if self.block_index.len() == 1 {
-            return Ok(());
+        Ok(TableIndex::new(block_index, filter, bloom_len as usize))
+    }
+
+    // Reads the index of a table written by Go badger v2/v3.
+    fn read_go_index(&self) -> Result<TableIndex> {
+        let mut read_pos = self.table_size;
+        read_pos -= 4;
+        let checksum_len = Cursor::new(self.read(read_pos, 4)?).read_u32::<BigEndian>()? as usize;
+        if checksum_len + 8 > read_pos {
+            return Err(format!("Unsupported table format: {}", self.file_name).into());
         }
+        read_pos -= checksum_len;
+        let checksum = go_table::GoChecksum::decode(&self.read(read_pos, checksum_len)?)?;
+        read_pos -= 4;
+        let index_len = Cursor::new(self.read(read_pos, 4)?).read_u32::<BigEndian>()? as usize;
+        if index_len > read_pos {
+            return Err(format!("Unsupported table format: {}", self.file_name).into());
+        }
+        read_pos -= index_len;
+        let data = self.read(read_pos, index_len)?;
+        checksum.verify(&data).map_err(|err| {
+            format!(
+                "Invalid Go badger table index: {}, err: {}",
+                self.file_name, err
+            )
+        })?;
+        let index = go_table::GoTableIndex::decode(&data, read_pos)?;
+        let mut block_index = Vec::with_capacity(index.offsets.len());
+        for ko in index.offsets {
+            // Drop the version of the base keys.
+            let key_len = ko.key.len().saturating_sub(8);
+            block_index.push(KeyOffset {
+                key: ko.key[..key_len].to_vec(),
+                offset: ko.offset as usize,
+                len: ko.len as usize,
+                checksum: 0,
+            });
+        }
+        info!("Open Go badger table: {}", self.file_name);
+        let filter_size = index.bloom_filter.len();
+        Ok(TableIndex::new(
+            block_index,
+            TableFilter::Go(index.bloom_filter),
+            filter_size,
+        ))
+    }
 
-        if self._mmap.is_some() {
-            for (i, block) in self.block_index.clone().iter().enumerate() {
-                let buffer = self.read(block.offset, Header::size())?;
-                let head = Header::from(buffer.as_slice());
-                assert_eq!(
-                    head.p_len, 0,
-                    "key offset: {}, h.p_len = {}",
-                    block.offset, head.p_len
-                );
-                let out = self.read(Header::size() + block.offset, head.k_len as usize)?;
-                self.block_index[i].key = out.clone().to_vec();
-            }
-        } else {
-            let fp = self.fd.try_clone().unwrap();
-            let offsets = self
-                .block_index
-                .iter()
-                .map(|key_offset| key_offset.offset as u64)
-                .collect::<Vec<_>>();
-            let keys = parallel_load_block_key(fp, offsets);
-            for i in 0..keys.len() {
-                self.block_index[i].key = keys[i].to_vec();
-            }
+    // Reads the index of a table written before the footer existed, it only stores the block
+    // offsets and the bloom filter. Index keys are read from the first entry of every block.
+    fn read_baseline_index(&self) -> Result<TableIndex> {
+        let mut read_pos = self.table_size - 4;
+        let bloom_len = Cursor::new(self.read(read_pos, 4)?).read_u32::<BigEndian>()? as usize;
+        read_pos -= bloom_len;
+        let bloom = serde_json::from_slice(&self.read(read_pos, bloom_len)?)
+            .map_err(|err| format!("Invalid table: {}, bloom filter: {}", self.file_name, err))?;
+        read_pos -= 4;
+        let restarts_len = Cursor::new(self.read(read_pos, 4)?).read_u32::<BigEndian>()? as usize;
+        if 4 * restarts_len > read_pos {
+            return Err(format!("Unsupported table format: {}", self.file_name).into());
         }
-        self.block_index.sort_by(|a, b| a.key.cmp(&b.key));
-        Ok(())
+        read_pos -= 4 * restarts_len;
+        let mut offsets = Cursor::new(self.read(read_pos, 4 * restarts_len)?);
+        let mut block_index = Vec::with_capacity(restarts_len);
+        let mut offset = 0;
+        for i in 0..restarts_len {
+            let end = offsets.read_u32::<BigEndian>()? as usize;
+            // Like ours, the first block has an empty index key.
+            let key = if i == 0 {
+                vec![]
+            } else {
+                let head = Header::from(self.read(offset, Header::size())?.as_slice());
+                self.read(offset + Header::size(), head.k_len as usize)?
+            };
+            block_index.push(KeyOffset {
+                key,
+                offset,
+                len: end - offset,
+                checksum: 0,
+            });
+            offset = end;
+        }
+        info!("Open table without footer: {}", self.file_name);
+        Ok(TableIndex::new(
+            block_index,
+            TableFilter::Bloom(bloom),
+            bloom_len,
+        ))
+    }
+
+    pub(crate) fn is_go_table(&self) -> bool {
+        self.is_go_table
+    }
+
+    /// Returns the number of blocks in the table.
+    pub(crate) fn block_count(&self) -> usize {
+        self.index().block_index.len()
     }
 
     pub(crate) fn block(&self, index: usize) -> Result<Block> {
-        if index >= self.block_index.len() {
+        let Some(cache) = self.block_cache.as_ref() else {
+            return self.read_block(index);
+        };
+        if let Some(block) = cache.get(self.id, index) {
+            return Ok(block);
+        }
+        let block = self.read_block(index)?;
+        cache.insert(self.id, index, block.clone());
+        Ok(block)
+    }
+
+    fn read_block(&self, index: usize) -> Result<Block> {
+        let table_index = self.index();
+        if index >= table_index.block_index.len() {
             return Err("block out of index".into());
         }
-        let ko = &self.block_index[index];
+        let ko = &table_index.block_index[index];
         let data = self.read(ko.offset, ko.len)?;
+        if self.verify_blocks {
+            if let Err(err) = self.check_block(ko, &data) {
+                return Err(Error::ChecksumMismatch(format!(
+                    "table: {}, block: {}, {}",
+                    self.file_name, index, err
+                )));
+            }
+        }
+        if self.is_go_table() {
+            // The versions of its first key may start in the block before.
+            let prev = match index {
+                0 => None,
+                _ => {
+                    let ko = &table_index.block_index[index - 1];
+                    Some(self.read(ko.offset, ko.len)?)
+                }
+            };
+            let items = prev
+                .as_deref()
+                .map(go_table::last_key)
+                .transpose()
+                .and_then(|prev_key| go_table::decode_block(&data, prev_key.as_deref()))
+                .map_err(|err| {
+                    format!("table: {}, block: {}, err: {}", self.file_name, index, err)
+                })?;
+            return Ok(Block {
+                offset: ko.offset,
+                data: Builder::encode_block(&items)?,
+            });
+        }
         Ok(Block {
             offset: ko.offset,
-            data,
+            data: self.decode_block_data(ko.offset, data)?,
         })
     }
 
+    // Checks the stored `data` of the block `ko` against its checksum.
+    fn check_block(&self, ko: &KeyOffset, data: &[u8]) -> std::result::Result<(), String> {
+        if self.is_baseline_table {
+            // Nothing to check against.
+            return Ok(());
+        }
+        if self.is_go_table() {
+            return go_table::decompress_block(data)
+                .map(|_| ())
+                .map_err(|err| format!("offset: {}, len: {}, {}", ko.offset, ko.len, err));
+        }
+        let got = crc32fast::hash(data);
+        if got != ko.checksum {
+            return Err(format!(
+                "offset: {}, len: {}, expected: {:#010x}, got: {:#010x}",
+                ko.offset, ko.len, ko.checksum, got
+            ));
+        }
+        Ok(())
+    }
+
+    /// Recomputes the crc32 of every block and compares it with the one stored in the table.
+    /// All mismatched blocks are reported in the returned error.
+    pub(crate) fn verify_checksum(&self) -> Result<()> {
+        let mut corrupted = vec![];
+        for (i, ko) in self.index().block_index.iter().enumerate() {
+            let data = self.read(ko.offset, ko.len)?;
+            if let Err(err) = self.check_block(ko, &data) {
+                corrupted.push(format!("block: {}, {}", i, err));
+            }
+        }
+        if corrupted.is_empty() {
+            return Ok(());
+        }
+        Err(Error::ChecksumMismatch(format!(
+            "table: {}, {} corrupted blocks [{}]",
+            self.file_name,
+            corrupted.len(),
+            corrupted.join("; ")
+        )))
+    }
+
     pub fn size(&self) -> usize {
         self.table_size
     }
@@ -321,6 +835,59 @@ impl TableCore {
         &self.biggest
     }
 
+    /// Returns false if no key of the table starts with `prefix`, from its key range. The
+    /// bloom filter holds whole keys, it can't tell about a prefix.
+    pub(crate) fn may_contain_prefix(&self, prefix: &[u8]) -> bool {
+        (self.smallest.as_slice() <= prefix || self.smallest.starts_with(prefix))
+            && self.biggest.as_slice() >= prefix
+    }
+
+    /// Returns the bytes of the blocks that may hold keys starting with `prefix`, from the
+    /// range of every block in the index. Blocks of internal keys aren't counted.
+    pub(crate) fn prefix_size(&self, prefix: &[u8]) -> u64 {
+        let cut = |key: &[u8]| key[..key.len().min(prefix.len())].to_vec();
+        let index = self.index();
+        let blocks = &index.block_index;
+        let mut size = 0;
+        for (i, ko) in blocks.iter().enumerate() {
+            // The index key of the next block sorts after every key of this one.
+            let last = blocks.get(i + 1).map_or(self.biggest(), |ko| &ko.key);
+            let first = if i > 0 {
+                ko.key.clone()
+            } else if self.smallest.starts_with(_BADGER_PREFIX) {
+                // Internal keys sort first, the range starts at the first key after them.
+                match self.first_user_key() {
+                    Some(key) => key,
+                    None => continue,
+                }
+            } else {
+                self.smallest.clone()
+            };
+            if first.starts_with(_BADGER_PREFIX) && last.starts_with(_BADGER_PREFIX) {
+                continue;
+            }
+            if cut(&first).as_slice() <= prefix && cut(last).as_slice() >= prefix {
+                size += ko.len as u64;
+            }
+        }
+        size
+    }
+
+    // Returns the first key of the first block that isn't internal, none if there isn't any. On
+    // a read error the smallest key is returned, the block is then counted.
+    fn first_user_key(&self) -> Option<Vec<u8>> {
+        let Ok(block) = self.block(0) else {
+            return Some(self.smallest.clone());
+        };
+        let itr = super::iterator::BlockIterator::new(block.data);
+        while let Some(item) = itr.next() {
+            if !item.key().starts_with(_BADGER_PREFIX) {
+                return Some(item.key().to_vec());
+            }
+        }
+        None
+    }
+
     pub fn filename(&self) -> &String {
         &self.file_name
     }
@@ -329,10 +896,99 @@ impl TableCore {
         self.id
     }
 
-    /// Returns true if (but not "only if") the table does not have the key. It does a bloom filter lookup.
+    /// Checks the key range of the table against its block index: the first block starts
+    /// with the smallest key and the last one with a key no bigger than the biggest.
+    pub(crate) fn validate_key_range(&self) -> Result<()> {
+        let index = self.index();
+        let (first, last) = match (index.block_index.first(), index.block_index.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Err(format!("Table {} has no blocks", self.id).into()),
+        };
+        if first.key != self.smallest
+            || last.key.as_slice() > self.biggest.as_slice()
+            || self.smallest > self.biggest
+        {
+            return Err(format!(
+                "Table {} key range [{}, {}] doesn't match its blocks [{}, {}..]",
+                self.id,
+                hex_str(&self.smallest),
+                hex_str(&self.biggest),
+                hex_str(&first.key),
+                hex_str(&last.key)
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Returns the bytes of tombstones and superseded entries recorded at build time.
+    pub fn stale_data_size(&self) -> u64 {
+        self.stale_data_size
+    }
+
+    /// Counts a read that went past the filter of the table and didn't find the key, it
+    /// paid for a block read for nothing. Returns the count so far.
+    pub(crate) fn record_useless_probe(&self) -> u64 {
+        self.useless_probes.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub(crate) fn useless_probes(&self) -> u64 {
+        self.useless_probes.load(Ordering::Relaxed)
+    }
+
+    /// Marks the table as deleted from the manifest, its file is removed once the last handle
+    /// on it is dropped, whatever references are left.
+    pub(crate) fn mark_deleted(&self) {
+        self.deleted.store(true, Ordering::Release);
+    }
+
+    /// Reads every block of the table to summarize it.
+    pub fn stats(&self) -> Result<TableStats> {
+        let index = self.index();
+        let mut stats = TableStats {
+            id: self.id,
+            blocks: index.block_index.len(),
+            on_disk_size: self.table_size as u64,
+            bloom_size: index.filter_size,
+            stale_data_size: self.stale_data_size,
+            smallest: self.smallest.clone(),
+            biggest: self.biggest.clone(),
+            ..Default::default()
+        };
+        for i in 0..index.block_index.len() {
+            let block = self.block(i)?;
+            stats.raw_size += block.data.len() as u64;
+            let itr = super::iterator::BlockIterator::new(block.data);
+            while itr.next().is_some() {
+                stats.entries += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Returns true if (but not "only if") the table does not have the key. It does a filter lookup.
     pub fn does_not_have(&self, key: &[u8]) -> bool {
-        let id = hash(key);
-        self.bf.contains(&id)
+        let index = self.index();
+        match &index.filter {
+            TableFilter::Bloom(bf) => !bf.contains(&hash(key)),
+            TableFilter::Xor(filter) => !filter.contains(hash(key)),
+            TableFilter::Go(filter) => !go_table::may_contain(filter, go_table::hash(key)),
+        }
+    }
+
+    /// Passes the access pattern of the table to the OS, only memory mapped tables use it.
+    pub(crate) fn advise(&self, hint: AccessHint) {
+        if self.loading_mode != MemoryMap {
+            return;
+        }
+        if let Some(m) = self._mmap.as_ref() {
+            if let Err(err) = madvise(m, hint) {
+                warn!(
+                    "Failed to advise table: {}, {:?}, err: {}",
+                    self.id, hint, err
+                );
+            }
+        }
     }
 
     /// load to ram that stored with mmap
@@ -356,6 +1012,12 @@ impl TableCore {
 
 impl Drop for TableCore {
     fn drop(&mut self) {
+        if let Some(cache) = self.index_cache.as_ref() {
+            cache.remove(self.id);
+        }
+        if let Some(cache) = self.block_cache.as_ref() {
+            cache.remove_table(self.id);
+        }
         let _ref = self.get_ref();
         // We can safely delete this file, because for all the current files, we always have
         // at least one reference pointing to them.
@@ -367,7 +1029,7 @@ impl Drop for TableCore {
                 .flush()
                 .expect("failed to mmap")
         }
-        if _ref == 1 {
+        if _ref == 1 || self.deleted.load(Ordering::Acquire) {
             let sz = self.fd.metadata().unwrap().len();
             // It's necessary to delete windows files
             // This is very important to let the FS know that the file is deleted.
@@ -385,24 +1047,20 @@ impl Drop for TableCore {
 
 impl Display for TableCore {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let index_str = self
-            .block_index
-            .iter()
-            .map(|x| format!("{}", x))
-            .collect::<Vec<_>>();
         let smallest = hex_str(self.smallest());
         let biggest = hex_str(self.biggest());
         f.debug_struct("Table")
-            //.field("block_index", &index_str)
             .field("_ref", &self._ref.load(Ordering::Relaxed))
             .field("fname", &self.file_name)
             .field("size", &self.table_size)
+            .field("stale", &self.stale_data_size)
             .field("smallest", &smallest)
             .field("biggest", &biggest)
             .finish()
     }
 }
 
+#[derive(Clone)]
 pub(crate) struct Block {
     offset: usize,
     pub(crate) data: Vec<u8>,
@@ -419,6 +1077,28 @@ impl Debug for Block {
 
 type ByKey = Vec<KeyOffset>;
 
+/// Returns the stats of every table in `dir`, ordered by table id. Encrypted tables
+/// can't be read without their data keys and fail.
+pub fn summarize_tables(dir: &str) -> Result<Vec<TableStats>> {
+    let mut ids = get_id_map(dir).into_iter().collect::<Vec<_>>();
+    ids.sort();
+    let mut summary = Vec::with_capacity(ids.len());
+    for id in ids {
+        let file_name = new_file_name(id, dir);
+        let fd = open_existing_synced_file(&file_name, false)?;
+        let table = Table::new(TableCore::open_table(
+            fd,
+            &file_name,
+            FileLoadingMode::FileIO,
+        )?);
+        let stats = table.stats();
+        // Only inspecting, the file must stay.
+        table.decr_ref();
+        summary.push(stats?);
+    }
+    Ok(summary)
+}
+
 pub fn get_id_map(dir: &str) -> HashSet<u64> {
     let dir = read_dir(dir).unwrap();
     let mut ids = HashSet::new();
@@ -458,7 +1138,7 @@ pub fn parse_file_id(name: &str) -> Result<u64> {
 }
 
 pub fn id_to_filename(id: u64) -> String {
-    format!("{}{}", id, FILE_SUFFIX)
+    format!("{:06}{}", id, FILE_SUFFIX)
 }
 
 pub fn new_file_name(id: u64, dir: &str) -> String {
@@ -468,3 +1148,8 @@ pub fn new_file_name(id: u64, dir: &str) -> String {
         .unwrap()
         .to_string()
 }
+
+/// Name of the file a table is written to before it's renamed to `new_file_name`.
+pub fn new_temp_file_name(id: u64, dir: &str) -> String {
+    format!("{}{}", new_file_name(id, dir), TEMP_FILE_SUFFIX)
+}
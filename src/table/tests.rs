@@ -1,15 +1,21 @@
 #[cfg(test)]
 mod utils {
-    use crate::options::FileLoadingMode;
+    use crate::options::{AccessHint, CompressionType, FileLoadingMode, FilterType};
     use crate::table::builder::Builder;
+    use crate::table::index_cache::IndexCache;
     use crate::table::iterator::{
         BlockIterator, ConcatIterator, IteratorImpl, IteratorItem, IteratorSeek,
     };
     use crate::table::table;
     use crate::table::table::{Table, TableCore, FILE_SUFFIX};
-    use crate::y::{hex_str, open_synced_file, read_at, ValueStruct};
+    use crate::value_log::MetaBit;
+    use crate::y::{
+        hash, hex_str, key_with_ts, open_synced_file, parse_key, parse_ts, read_at, ValueStruct,
+    };
     use crate::{MergeIterOverBuilder, Xiterator};
+    use byteorder::{BigEndian, WriteBytesExt};
     use core::panic;
+    use growable_bloom_filter::GrowableBloom;
     use log::debug;
     use memmap::MmapOptions;
     use rand::random;
@@ -388,6 +394,19 @@ mod utils {
 
             let value = itr.seek(b"keyd");
             assert!(value.is_none());
+            assert!(itr.next().is_none());
+
+            // Goes on from the key sought, into the next tables.
+            let itr = ConcatIterator::new(vec![f1.clone(), f2.clone(), f3.clone()], false);
+            itr.seek(b"keyb9990");
+            let mut count = 0;
+            while let Some(item) = itr.next() {
+                if count == 0 {
+                    assert_eq!(item.key(), b"keyb9991");
+                }
+                count += 1;
+            }
+            assert_eq!(count, 9 + n);
         }
 
         {
@@ -574,6 +593,631 @@ mod utils {
         assert_eq!(count, 2);
     }
 
+    #[test]
+    fn builder_should_finish() {
+        let table_size = 16 << 10;
+        let mut tables = vec![];
+        let mut builder = Builder::new(table_size, 1 << 10);
+        for i in 0..10000 {
+            let k = key("key", i);
+            let v = ValueStruct::new(format!("{}", i).into_bytes(), 'A' as u8, 0, i as u64);
+            builder.add(k.as_bytes(), &v).unwrap();
+            if builder.should_finish() {
+                tables.push(builder.finish());
+                builder = Builder::new(table_size, 1 << 10);
+            }
+        }
+        if !builder.is_zero_bytes() {
+            tables.push(builder.finish());
+        }
+        assert!(tables.len() > 1);
+        let mut count = 0;
+        for data in tables.iter() {
+            assert!(data.len() < 2 * table_size as usize);
+            let file_name = temp_dir().join(random::<u64>().to_string() + FILE_SUFFIX);
+            let file_name = file_name.to_str().unwrap();
+            let mut fp = open_synced_file(file_name, true).unwrap();
+            fp.write_all(data).unwrap();
+            fp.flush().unwrap();
+            let table = TableCore::open_table(fp, file_name, FileLoadingMode::FileIO).unwrap();
+            assert!(table.block_count() > 1);
+            let itr = IteratorImpl::new(Table::new(table), false);
+            while let Some(_) = itr.next() {
+                count += 1;
+            }
+        }
+        assert_eq!(count, 10000);
+    }
+
+    #[test]
+    fn table_stale_data_size() {
+        let mut builder = Builder::default();
+        for i in 0..100 {
+            let k = key("key", i);
+            let v = ValueStruct::new(b"value".to_vec(), 0, 0, i as u64);
+            if i % 10 == 0 {
+                let mut v = v;
+                v.meta = MetaBit::BIT_DELETE.bits();
+                builder.add(k.as_bytes(), &v).unwrap();
+            } else if i % 10 == 1 {
+                builder.add_stale_key(k.as_bytes(), &v).unwrap();
+            } else {
+                builder.add(k.as_bytes(), &v).unwrap();
+            }
+        }
+        let stale = builder.stale_data_size();
+        assert!(stale > 0);
+        let file_name = temp_dir().join(random::<u64>().to_string() + FILE_SUFFIX);
+        let file_name = file_name.to_str().unwrap();
+        let mut fp = open_synced_file(file_name, true).unwrap();
+        fp.write_all(&builder.finish()).unwrap();
+        fp.flush().unwrap();
+        let table = TableCore::open_table(fp, file_name, FileLoadingMode::MemoryMap).unwrap();
+        assert_eq!(table.stale_data_size(), stale);
+    }
+
+    #[test]
+    fn table_verify_checksum() {
+        let (fp, path) = build_test_table("key", 1000);
+        let table = TableCore::open_table(fp, &path, FileLoadingMode::FileIO).unwrap();
+        assert!(table.verify_checksum().is_ok());
+        drop(table);
+
+        // Flip one byte of the first block.
+        // The table file is opened in append mode, so write through a separate handle.
+        let (fp, path) = build_test_table("key", 1000);
+        let mut wfp = File::options().write(true).open(&path).unwrap();
+        wfp.seek(SeekFrom::Start(20)).unwrap();
+        wfp.write_all(&[0xff]).unwrap();
+        wfp.sync_all().unwrap();
+        drop(wfp);
+        let table = TableCore::open_table(fp, &path, FileLoadingMode::FileIO).unwrap();
+        let err = table.verify_checksum().unwrap_err();
+        assert!(matches!(err, crate::Error::ChecksumMismatch(_)));
+        assert!(err.to_string().contains("block: 0"));
+
+        // Verified on read, only the corrupted block fails.
+        let table = table.with_block_checksums(true);
+        assert!(matches!(
+            table.block(0),
+            Err(crate::Error::ChecksumMismatch(_))
+        ));
+        assert!(table.block(1).is_ok());
+    }
+
+    #[test]
+    fn table_baseline_format() {
+        // Blocks, their end offsets and count, then the bloom filter json and its length,
+        // the layout written before the footer existed.
+        let n = 1000;
+        let mut data = vec![];
+        let mut restarts = vec![];
+        let mut bf = GrowableBloom::new(0.01, n as usize);
+        for start in (0..n).step_by(Builder::RESTART_INTERVAL) {
+            let end = (start + Builder::RESTART_INTERVAL as isize).min(n);
+            let items = (start..end)
+                .map(|i| {
+                    bf.insert(&hash(key("key", i).as_bytes()));
+                    let v = ValueStruct::new(format!("{}", i).into_bytes(), 'A' as u8, 0, i as u64);
+                    (key("key", i).into_bytes(), v)
+                })
+                .collect::<Vec<_>>();
+            data.extend_from_slice(&Builder::encode_block(&items).unwrap());
+            restarts.push(data.len() as u32);
+        }
+        for restart in restarts.iter() {
+            data.write_u32::<BigEndian>(*restart).unwrap();
+        }
+        data.write_u32::<BigEndian>(restarts.len() as u32).unwrap();
+        let bdata = serde_json::to_vec(&bf).unwrap();
+        data.extend_from_slice(&bdata);
+        data.write_u32::<BigEndian>(bdata.len() as u32).unwrap();
+        let file_name = temp_dir().join(random::<u64>().to_string() + FILE_SUFFIX);
+        let file_name = file_name.to_str().unwrap();
+        let mut fp = open_synced_file(file_name, true).unwrap();
+        fp.write_all(&data).unwrap();
+        fp.flush().unwrap();
+
+        for mode in [FileLoadingMode::FileIO, FileLoadingMode::MemoryMap] {
+            let fp = open_synced_file(file_name, true).unwrap();
+            let table = TableCore::open_table(fp, file_name, mode).unwrap();
+            assert!(!table.is_go_table());
+            assert_eq!(table.block_count(), restarts.len());
+            assert_eq!(table.stale_data_size(), 0);
+            assert!(table.verify_checksum().is_ok());
+            assert!(!table.does_not_have(key("key", 500).as_bytes()));
+            let itr = IteratorImpl::new(Table::new(table), false);
+            let item = itr.seek(key("key", 550).as_bytes()).unwrap();
+            assert_eq!(item.key(), key("key", 550).as_bytes());
+            assert_eq!(item.value().value, b"550".to_vec());
+            let mut count = 1;
+            while let Some(_) = itr.next() {
+                count += 1;
+            }
+            assert_eq!(count, n - 550);
+        }
+    }
+
+    #[test]
+    fn table_go_format() {
+        use crate::table::go_table::{encode_go_block, encode_go_checksum, encode_snappy_literals};
+        let varint = |buf: &mut Vec<u8>, mut v: u64| {
+            while v >= 0x80 {
+                buf.push((v as u8) | 0x80);
+                v >>= 7;
+            }
+            buf.push(v as u8);
+        };
+        // The versions of "key" go on in the second block, and in the third one, compressed.
+        let blocks: Vec<Vec<(&[u8], u64, &[u8])>> = vec![
+            vec![(b"a", 9, b"a9"), (b"b", 5, b"b5"), (b"key", 8, b"k8")],
+            vec![(b"key", 6, b"k6"), (b"key", 4, b"k4")],
+            vec![(b"key", 2, b"k2"), (b"kez", 3, b"z3"), (b"l", 1, b"l1")],
+        ];
+        let mut data = vec![];
+        let mut index = vec![];
+        for (i, entries) in blocks.iter().enumerate() {
+            let mut block = encode_go_block(entries);
+            if i == 2 {
+                block = encode_snappy_literals(&block);
+            }
+            let (key, version, _) = entries[0];
+            let key = [key, &(u64::MAX - version).to_be_bytes()].concat();
+            // BlockOffset: key (field 1), offset (field 2), len (field 3).
+            let mut ko = vec![0x0a];
+            varint(&mut ko, key.len() as u64);
+            ko.extend_from_slice(&key);
+            ko.push(0x10);
+            varint(&mut ko, data.len() as u64);
+            ko.push(0x18);
+            varint(&mut ko, block.len() as u64);
+            // TableIndex: offsets (field 1), bloom_filter (field 2).
+            index.push(0x0a);
+            varint(&mut index, ko.len() as u64);
+            index.extend_from_slice(&ko);
+            data.extend_from_slice(&block);
+        }
+        // A bloom filter with more than 30 probes is reserved, it may contain any key.
+        index.extend_from_slice(&[0x12, 0x02, 0x00, 31]);
+        data.extend_from_slice(&index);
+        data.write_u32::<BigEndian>(index.len() as u32).unwrap();
+        let checksum = encode_go_checksum(&index);
+        data.extend_from_slice(&checksum);
+        data.write_u32::<BigEndian>(checksum.len() as u32).unwrap();
+        let file_name = temp_dir().join(random::<u64>().to_string() + FILE_SUFFIX);
+        let file_name = file_name.to_str().unwrap();
+        let mut fp = open_synced_file(file_name, true).unwrap();
+        fp.write_all(&data).unwrap();
+        fp.flush().unwrap();
+
+        let want: Vec<(&[u8], &[u8])> = vec![
+            (b"a", b"a9"),
+            (b"b", b"b5"),
+            (b"key", b"k8"),
+            (b"kez", b"z3"),
+            (b"l", b"l1"),
+        ];
+        for mode in [FileLoadingMode::FileIO, FileLoadingMode::MemoryMap] {
+            let fp = open_synced_file(file_name, true).unwrap();
+            let table = TableCore::open_table(fp, file_name, mode).unwrap();
+            assert!(table.is_go_table());
+            assert_eq!(table.block_count(), 3);
+            assert!(table.verify_checksum().is_ok());
+            assert_eq!(table.smallest(), b"a");
+            assert_eq!(table.biggest(), b"l");
+            let table = Table::new(table);
+            let itr = IteratorImpl::new(table.clone(), false);
+            let mut got = vec![];
+            let mut item = itr.rewind();
+            while let Some(it) = item {
+                got.push((it.key().to_vec(), it.value().value.clone()));
+                item = itr.next();
+            }
+            let got_ref = got
+                .iter()
+                .map(|(k, v)| (k.as_slice(), v.as_slice()))
+                .collect::<Vec<_>>();
+            assert_eq!(got_ref, want);
+            // The newest version of "key" is in the first block, not in the one it starts.
+            let item = itr.seek(b"key").unwrap();
+            assert_eq!(item.value().value, b"k8".to_vec());
+            assert_eq!(item.value().cas_counter, 8);
+            assert_eq!(itr.seek(b"kez").unwrap().key(), b"kez");
+            assert!(itr.seek(b"m").is_none());
+            let items = itr.get_sorted(&[b"b", b"key", b"kez", b"x"]);
+            assert_eq!(items[1].as_ref().unwrap().value().value, b"k8".to_vec());
+            assert!(items[2].is_some() && items[3].is_none());
+
+            let itr = IteratorImpl::new(table, true);
+            let mut got = vec![];
+            let mut item = itr.rewind();
+            while let Some(it) = item {
+                got.push(it.key().to_vec());
+                item = itr.next();
+            }
+            let want_keys = want
+                .iter()
+                .rev()
+                .map(|(k, _)| k.to_vec())
+                .collect::<Vec<_>>();
+            assert_eq!(got, want_keys);
+        }
+    }
+
+    #[test]
+    fn table_unsupported_format_version() {
+        let (fp, path) = build_test_table("key", 100);
+        let size = fp.metadata().unwrap().len();
+        let mut wfp = File::options().write(true).open(&path).unwrap();
+        wfp.seek(SeekFrom::Start(size - 6)).unwrap();
+        wfp.write_u16::<BigEndian>(Builder::FORMAT_VERSION + 1)
+            .unwrap();
+        drop(wfp);
+        let err = TableCore::open_table(fp, &path, FileLoadingMode::FileIO)
+            .err()
+            .unwrap();
+        assert!(
+            err.to_string().contains("Unsupported table format"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn iterator_prefetch_blocks() {
+        let n = 10000;
+        let (fp, path) = build_test_table("key", n);
+        let table = TableCore::open_table(fp, &path, FileLoadingMode::FileIO).unwrap();
+        let tb = Table::new(table);
+        for reverse in [false, true] {
+            let itr = IteratorImpl::new(tb.clone(), reverse).prefetch(3);
+            let mut count = 0;
+            let mut last: Option<Vec<u8>> = None;
+            while let Some(item) = itr.next() {
+                if let Some(last) = last.as_ref() {
+                    assert_eq!(
+                        last.as_slice().cmp(item.key()),
+                        if reverse {
+                            Ordering::Greater
+                        } else {
+                            Ordering::Less
+                        }
+                    );
+                }
+                last = Some(item.key);
+                count += 1;
+            }
+            assert_eq!(count, n);
+        }
+        // Seek jumps out of the prefetched window.
+        let itr = IteratorImpl::new(tb.clone(), false).prefetch(3);
+        assert_eq!(
+            itr.seek(key("key", 9000).as_bytes()).unwrap().key(),
+            key("key", 9000).as_bytes()
+        );
+        assert_eq!(
+            itr.seek(key("key", 10).as_bytes()).unwrap().key(),
+            key("key", 10).as_bytes()
+        );
+        assert_eq!(itr.next().unwrap().key(), key("key", 11).as_bytes());
+    }
+
+    #[test]
+    fn table_may_contain_prefix() {
+        let (fp, path) = build_test_table("key", 100);
+        let table = TableCore::open_table(fp, &path, FileLoadingMode::FileIO).unwrap();
+        for prefix in ["", "k", "key", "key00", "key0099"] {
+            assert!(table.may_contain_prefix(prefix.as_bytes()), "{}", prefix);
+        }
+        for prefix in ["a", "kex", "kez", "key01", "z"] {
+            assert!(!table.may_contain_prefix(prefix.as_bytes()), "{}", prefix);
+        }
+    }
+
+    #[test]
+    fn table_index_cache() {
+        let (fp, path) = build_test_table("key", 10000);
+        let t1 = TableCore::open_table(fp, &path, FileLoadingMode::FileIO).unwrap();
+        let (fp, path) = build_test_table("key", 10000);
+        let t2 = TableCore::open_table(fp, &path, FileLoadingMode::FileIO).unwrap();
+        // Room for one index only.
+        let cache = IndexCache::new(t1.index().size() + t2.index().size() - 1);
+        let t1 = Table::new(t1.with_index_cache(Some(cache.clone())));
+        let t2 = Table::new(t2.with_index_cache(Some(cache.clone())));
+        assert!(cache.used() <= t1.index().size().max(t2.index().size()));
+        // Every lookup reloads the index evicted by the previous one.
+        for tb in [&t1, &t2, &t1] {
+            let itr = IteratorImpl::new(tb.clone(), false);
+            assert_eq!(
+                itr.seek(key("key", 5000).as_bytes()).unwrap().key(),
+                key("key", 5000).as_bytes()
+            );
+            itr.close();
+            assert!(!tb.does_not_have(key("key", 5000).as_bytes()));
+        }
+        drop(t1);
+        drop(t2);
+        assert_eq!(cache.used(), 0);
+    }
+
+    #[test]
+    fn table_zstd_compression() {
+        let n = 10000;
+        let mut plain = Builder::default();
+        let mut builder = Builder::default().with_compression(CompressionType::ZSTD, 1);
+        for i in 0..n {
+            let v = ValueStruct::new(format!("value{}", i).repeat(8).into_bytes(), 0, 0, i as u64);
+            plain.add(key("key", i).as_bytes(), &v).unwrap();
+            builder.add(key("key", i).as_bytes(), &v).unwrap();
+        }
+        let data = builder.finish();
+        assert!(data.len() < plain.finish().len());
+        let file_name = temp_dir().join(random::<u64>().to_string() + FILE_SUFFIX);
+        let file_name = file_name.to_str().unwrap();
+        let mut fp = open_synced_file(file_name, true).unwrap();
+        fp.write_all(&data).unwrap();
+        fp.flush().unwrap();
+        let table = TableCore::open_table(fp, file_name, FileLoadingMode::FileIO).unwrap();
+        assert!(table.verify_checksum().is_ok());
+        let itr = IteratorImpl::new(Table::new(table), false);
+        let item = itr.seek(key("key", 5000).as_bytes()).unwrap();
+        assert_eq!(item.key(), key("key", 5000).as_bytes());
+        assert_eq!(item.value().value, "value5000".repeat(8).into_bytes());
+        let mut count = 1;
+        while let Some(_) = itr.next() {
+            count += 1;
+        }
+        assert_eq!(count, n - 5000);
+    }
+
+    #[test]
+    fn table_entry_meta_and_expires_at() {
+        let mut builder = Builder::default();
+        for i in 0..1000 {
+            let v = ValueStruct::new(format!("{}", i).into_bytes(), 0, (i % 256) as u8, i as u64)
+                .with_expires_at(if i % 2 == 0 {
+                    0
+                } else {
+                    1_700_000_000 + i as u64
+                });
+            builder.add(key("key", i).as_bytes(), &v).unwrap();
+        }
+        let file_name = temp_dir().join(random::<u64>().to_string() + FILE_SUFFIX);
+        let file_name = file_name.to_str().unwrap();
+        let mut fp = open_synced_file(file_name, true).unwrap();
+        fp.write_all(&builder.finish()).unwrap();
+        fp.flush().unwrap();
+        let table = TableCore::open_table(fp, file_name, FileLoadingMode::MemoryMap).unwrap();
+        let itr = IteratorImpl::new(Table::new(table), false);
+        let mut i = 0;
+        while let Some(item) = itr.next() {
+            assert_eq!(item.key(), key("key", i).as_bytes());
+            assert_eq!(item.user_meta(), (i % 256) as u8);
+            let expires_at = if i % 2 == 0 {
+                0
+            } else {
+                1_700_000_000 + i as u64
+            };
+            assert_eq!(item.expires_at(), expires_at);
+            assert_eq!(item.value().value, format!("{}", i).into_bytes());
+            i += 1;
+        }
+        assert_eq!(i, 1000);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn table_encryption() {
+        use crate::table::encryption::{DataKey, KeyRegistry};
+        let data_key = DataKey {
+            id: 3,
+            key: vec![9u8; 32],
+        };
+        let mut builder = Builder::default()
+            .with_compression(CompressionType::ZSTD, 1)
+            .with_encryption(data_key.clone());
+        for i in 0..5000 {
+            let v = ValueStruct::new(format!("value{}", i).into_bytes(), 0, 0, i as u64);
+            builder.add(key("key", i).as_bytes(), &v).unwrap();
+        }
+        let data = builder.finish();
+        assert!(!data.windows(b"value4999".len()).any(|w| w == b"value4999"));
+        let file_name = temp_dir().join(random::<u64>().to_string() + FILE_SUFFIX);
+        let file_name = file_name.to_str().unwrap();
+        let mut fp = open_synced_file(file_name, true).unwrap();
+        fp.write_all(&data).unwrap();
+        fp.flush().unwrap();
+
+        let keys = KeyRegistry::default();
+        let err = TableCore::open_table_with_keys(
+            fp.try_clone().unwrap(),
+            file_name,
+            FileLoadingMode::FileIO,
+            Some(&keys),
+        )
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("data key 3 not found"));
+
+        keys.add(data_key);
+        let table =
+            TableCore::open_table_with_keys(fp, file_name, FileLoadingMode::FileIO, Some(&keys))
+                .unwrap();
+        assert!(table.verify_checksum().is_ok());
+        let itr = IteratorImpl::new(Table::new(table), false);
+        let item = itr.seek(key("key", 4321).as_bytes()).unwrap();
+        assert_eq!(item.key(), key("key", 4321).as_bytes());
+        assert_eq!(item.value().value, b"value4321".to_vec());
+        let mut count = 1;
+        while let Some(_) = itr.next() {
+            count += 1;
+        }
+        assert_eq!(count, 5000 - 4321);
+    }
+
+    #[test]
+    fn table_stats() {
+        let dir = crate::test_util::create_random_tmp_dir();
+        for (id, n) in [(1, 1000), (2, 10000)] {
+            let mut builder = Builder::default();
+            for i in 0..n {
+                let v = ValueStruct::new(format!("{}", i).into_bytes(), 0, 0, i as u64);
+                builder.add(key("key", i).as_bytes(), &v).unwrap();
+            }
+            std::fs::write(table::new_file_name(id, &dir), builder.finish()).unwrap();
+        }
+        let summary = crate::summarize_tables(&dir).unwrap();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].id, 1);
+        assert_eq!(summary[0].entries, 1000);
+        assert_eq!(summary[1].entries, 10000);
+        for stats in summary.iter() {
+            assert_eq!(stats.blocks as u64, (stats.entries + 99) / 100);
+            assert!(stats.raw_size > 0 && stats.raw_size < stats.on_disk_size);
+            assert!(stats.bloom_size > 0);
+            assert_eq!(stats.smallest, key("key", 0).into_bytes());
+        }
+        assert_eq!(summary[1].biggest, key("key", 9999).into_bytes());
+        // The tables are still there.
+        assert_eq!(crate::summarize_tables(&dir).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn table_filters() {
+        for filter_type in [FilterType::Bloom, FilterType::Xor] {
+            let mut builder = Builder::default().with_filter(filter_type);
+            for i in 0..5000 {
+                let v = ValueStruct::new(format!("{}", i).into_bytes(), 0, 0, i as u64);
+                builder.add(key("key", i * 2).as_bytes(), &v).unwrap();
+            }
+            let file_name = temp_dir().join(random::<u64>().to_string() + FILE_SUFFIX);
+            let file_name = file_name.to_str().unwrap();
+            let mut fp = open_synced_file(file_name, true).unwrap();
+            fp.write_all(&builder.finish()).unwrap();
+            fp.flush().unwrap();
+            let table = TableCore::open_table(fp, file_name, FileLoadingMode::FileIO).unwrap();
+            for i in 0..5000 {
+                assert!(!table.does_not_have(key("key", i * 2).as_bytes()));
+            }
+            let false_positives = (0..5000)
+                .filter(|i| !table.does_not_have(key("key", i * 2 + 1).as_bytes()))
+                .count();
+            assert!(
+                false_positives < 100,
+                "{:?} false positives: {}",
+                filter_type,
+                false_positives
+            );
+        }
+    }
+
+    #[test]
+    fn table_index_separator_keys() {
+        assert_eq!(Builder::shortest_separator(b"abc", b"abd"), b"abd");
+        assert_eq!(Builder::shortest_separator(b"abcxyz", b"abzaaa"), b"abz");
+        assert_eq!(Builder::shortest_separator(b"ab", b"abcd"), b"abc");
+
+        let prefix = "a_rather_long_prefix_shared_by_all_keys_";
+        let (fp, path) = build_test_table(prefix, 10000);
+        let table = TableCore::open_table(fp, &path, FileLoadingMode::FileIO).unwrap();
+        let index = table.index();
+        assert!(index.block_index.len() > 1);
+        assert!(index.block_index[0].key.is_empty());
+        for pair in index.block_index.windows(2) {
+            assert!(pair[0].key < pair[1].key);
+        }
+        for ko in index.block_index.iter().skip(1) {
+            assert!(ko.key.len() < key(prefix, 0).len(), "{}", ko);
+        }
+
+        let iter = IteratorImpl::new(Table::new(table), false);
+        for i in 0..10000 {
+            let k = key(prefix, i);
+            assert_eq!(iter.seek(k.as_bytes()).unwrap().key(), k.as_bytes());
+            // Sorts between the previous key and k.
+            let mut before = key(prefix, i - 1).into_bytes();
+            before.push(0xff);
+            assert_eq!(iter.seek(&before).unwrap().key(), k.as_bytes());
+        }
+        let mut after = key(prefix, 9999).into_bytes();
+        after.push(0);
+        assert!(iter.seek(&after).is_none());
+    }
+
+    #[test]
+    fn iterator_seek_to_version() {
+        let mut key_values = vec![];
+        for i in 0..1000 {
+            for version in [2, 4, 6, 8, 10] {
+                let k = key_with_ts(key("key", i).as_bytes(), version);
+                key_values.push((k, format!("{}@{}", i, version).into_bytes()));
+            }
+        }
+        let (fp, path) = build_table(key_values);
+        let table = TableCore::open_table(fp, &path, FileLoadingMode::FileIO).unwrap();
+        let iter = IteratorImpl::new(Table::new(table), false);
+        for i in 0..1000 {
+            let k = key("key", i);
+            for (read_ts, want) in [(100, 10), (10, 10), (7, 6), (2, 2)] {
+                let item = iter.seek_to_version(k.as_bytes(), read_ts).unwrap();
+                assert_eq!(parse_key(item.key()), k.as_bytes());
+                assert_eq!(parse_ts(item.key()), want);
+                assert_eq!(item.value().value, format!("{}@{}", i, want).into_bytes());
+            }
+            assert!(iter.seek_to_version(k.as_bytes(), 1).is_none());
+        }
+        assert!(iter
+            .seek_to_version(key("key", 1000).as_bytes(), 10)
+            .is_none());
+    }
+
+    #[test]
+    fn table_access_hints() {
+        for mode in [FileLoadingMode::MemoryMap, FileLoadingMode::FileIO] {
+            let (fp, path) = build_test_table("key", 1000);
+            let table = Table::new(TableCore::open_table(fp, &path, mode).unwrap());
+            for hint in [
+                AccessHint::Random,
+                AccessHint::Sequential,
+                AccessHint::Normal,
+            ] {
+                table.advise(hint);
+                let iter = IteratorImpl::new(table.clone(), false);
+                let mut count = 0;
+                let mut item = iter.rewind();
+                while let Some(it) = item {
+                    assert_eq!(it.key(), key("key", count).as_bytes());
+                    count += 1;
+                    item = iter.next();
+                }
+                assert_eq!(count, 1000);
+                iter.close();
+            }
+        }
+    }
+
+    #[test]
+    fn builder_estimated_size() {
+        for filter_type in [FilterType::Bloom, FilterType::Xor] {
+            let mut builder = Builder::default().with_filter(filter_type);
+            for i in 0..10000 {
+                let v = ValueStruct::new(format!("{}", i).into_bytes(), 0, 0, i as u64);
+                builder.add(key("key", i).as_bytes(), &v).unwrap();
+            }
+            let estimated = builder.estimated_size();
+            assert!(builder.reached_capacity(estimated - 1));
+            assert!(!builder.reached_capacity(estimated));
+            let size = builder.finish().len() as u64;
+            assert!(
+                size.abs_diff(estimated) * 20 < size,
+                "{:?} size: {}, estimated: {}",
+                filter_type,
+                size,
+                estimated
+            );
+        }
+    }
+
     // #[test]
     // fn chaos_merge_iterator_ext() {
     //     crate::test_util::tracing_log();
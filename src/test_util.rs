@@ -1,3 +1,6 @@
+use crate::options::Options;
+use crate::value_log::Entry;
+use crate::KV;
 use atomic::Atomic;
 use chrono::Local;
 use log::{info, kv::source::as_map, kv::Source, warn, Level};
@@ -6,6 +9,7 @@ use std::collections::HashMap;
 use std::env::temp_dir;
 use std::fs::create_dir_all;
 use std::io;
+use std::ops::Range;
 use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
@@ -128,6 +132,37 @@ pub fn create_random_tmp_dir() -> String {
     fpath
 }
 
+pub(crate) fn get_test_option(dir: &str) -> Options {
+    let mut opt = Options::default();
+    opt.max_table_size = 1 << 15; // Force more compaction.
+    opt.base_level_size = 4 << 15; // Force more compaction.
+    opt.dir = Box::new(dir.to_string());
+    opt.value_dir = Box::new(dir.to_string());
+    opt
+}
+
+// The key of the i-th entry written by a test, they sort like their index.
+pub(crate) fn test_key(i: usize) -> Vec<u8> {
+    format!("key{:06}", i).into_bytes()
+}
+
+// The value of the i-th entry written by a test, big enough for the value log.
+pub(crate) fn test_value(i: usize) -> Vec<u8> {
+    format!("{:0100}", i).into_bytes()
+}
+
+// Writes the entries of `range`, see `test_key` and `test_value`, `batch` of them a time.
+pub(crate) async fn write_test_entries(kv: &KV, range: Range<usize>, batch: usize) {
+    for i in range.clone().step_by(batch) {
+        let entries = (i..(i + batch).min(range.end))
+            .map(|i| Entry::default().key(test_key(i)).value(test_value(i)))
+            .collect::<Vec<_>>();
+        for res in kv.batch_set(entries).await {
+            assert!(res.is_ok());
+        }
+    }
+}
+
 #[test]
 fn it_work() {
     #[tracing::instrument(skip_all)]
@@ -0,0 +1,827 @@
+use crate::iterator::{IteratorOptions, KVItem, KVItemInner};
+use crate::kv::{_BADGER_PREFIX, KV};
+use crate::log_file::LogFile;
+use crate::types::TArcRW;
+use crate::value_log::{Entry, MetaBit};
+use crate::y::{hash, key_with_ts, parse_key, parse_ts, ValueStruct};
+use crate::{Error, MergeIterator, Result, Xiterator};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+/// Hands out the read timestamps of the transactions and checks their conflicts at commit.
+/// A commit conflicts with the transactions committed after it started that wrote a key it
+/// read, keys are compared by fingerprint.
+#[derive(Default)]
+pub(crate) struct Oracle {
+    // Serializes the commits, from their conflict check until their writes are sent to the
+    // writer, which writes them in commit ts order. Their writes are waited for outside.
+    commit_lock: tokio::sync::Mutex<()>,
+    inner: Mutex<OracleInner>,
+    // The read ts of the oldest snapshot, 0 without any. The compactions keep the versions
+    // it reads, it's shared with the `LevelsController`.
+    read_mark: Arc<AtomicU64>,
+}
+
+#[derive(Default)]
+struct OracleInner {
+    // The last commit done, the transactions starting read what it wrote.
+    read_ts: u64,
+    next_commit_ts: u64,
+    // The write fingerprints of the commits, by commit ts, as long as an update
+    // transaction started before them may be running.
+    committed: Vec<(u64, HashSet<u64>)>,
+    // The commits whose writes aren't done yet, by commit ts.
+    pending: BTreeMap<u64, usize>,
+    // The newest commit done, `read_ts` catches up with it once the ones before are done.
+    last_done: u64,
+    // The running update transactions, by read ts.
+    running: BTreeMap<u64, usize>,
+    // The snapshots alive, by read ts.
+    snapshots: BTreeMap<u64, usize>,
+}
+
+impl Oracle {
+    // Returns the read ts of a transaction, `read_ts` if it's given by the application. The
+    // ones checking their conflicts are running until `done_read`.
+    fn new_read_ts(&self, read_ts: Option<u64>, detect: bool) -> u64 {
+        let mut inner = self.inner.lock();
+        let read_ts = read_ts.unwrap_or(inner.read_ts);
+        if detect {
+            *inner.running.entry(read_ts).or_default() += 1;
+        }
+        read_ts
+    }
+
+    fn done_read(&self, read_ts: u64) {
+        let mut inner = self.inner.lock();
+        if let Some(n) = inner.running.get_mut(&read_ts) {
+            *n -= 1;
+            if *n == 0 {
+                inner.running.remove(&read_ts);
+            }
+        }
+        Self::forget_committed(&mut inner);
+    }
+
+    // Drops the commits no running transaction can conflict with, nor the ones starting.
+    fn forget_committed(inner: &mut OracleInner) {
+        let oldest = inner.running.keys().next().copied().unwrap_or(u64::MAX);
+        let oldest = oldest.min(inner.read_ts);
+        inner.committed.retain(|(ts, _)| *ts > oldest);
+    }
+
+    // Returns the commit ts of a transaction, `commit_ts` if it's given by the application,
+    // Conflict if a key it read was written since it started. Has to be called under
+    // `commit_lock`.
+    fn new_commit_ts(
+        &self,
+        read_ts: u64,
+        commit_ts: Option<u64>,
+        reads: &[u64],
+        writes: HashSet<u64>,
+    ) -> Result<u64> {
+        let mut inner = self.inner.lock();
+        for (ts, written) in inner.committed.iter() {
+            if *ts > read_ts && reads.iter().any(|fp| written.contains(fp)) {
+                return Err(Error::TxnConflict);
+            }
+        }
+        let commit_ts = match commit_ts {
+            Some(commit_ts) => commit_ts,
+            None => {
+                inner.next_commit_ts = inner.next_commit_ts.max(inner.read_ts) + 1;
+                inner.next_commit_ts
+            }
+        };
+        // Kept until `done_commit` at least, a transaction may start meanwhile.
+        inner.committed.push((commit_ts, writes));
+        *inner.pending.entry(commit_ts).or_default() += 1;
+        Ok(commit_ts)
+    }
+
+    // The last commit done.
+    fn read_ts(&self) -> u64 {
+        self.inner.lock().read_ts
+    }
+
+    pub(crate) fn read_mark(&self) -> Arc<AtomicU64> {
+        self.read_mark.clone()
+    }
+
+    // Returns the read ts of a snapshot, the last commit done, it's read until
+    // `done_snapshot`.
+    fn new_snapshot_ts(&self) -> u64 {
+        let mut inner = self.inner.lock();
+        let read_ts = inner.read_ts;
+        *inner.snapshots.entry(read_ts).or_default() += 1;
+        self.update_read_mark(&inner);
+        read_ts
+    }
+
+    fn done_snapshot(&self, read_ts: u64) {
+        let mut inner = self.inner.lock();
+        if let Some(n) = inner.snapshots.get_mut(&read_ts) {
+            *n -= 1;
+            if *n == 0 {
+                inner.snapshots.remove(&read_ts);
+            }
+        }
+        self.update_read_mark(&inner);
+    }
+
+    // Stored under the lock, the mark follows the snapshots in order.
+    fn update_read_mark(&self, inner: &OracleInner) {
+        let oldest = inner.snapshots.keys().next().copied().unwrap_or(0);
+        self.read_mark.store(oldest, Ordering::Release);
+    }
+
+    // The writes of `commit_ts` are done, they're read by the transactions starting after
+    // the commits before it are done too.
+    fn done_commit(&self, commit_ts: u64) {
+        let mut inner = self.inner.lock();
+        if let Some(n) = inner.pending.get_mut(&commit_ts) {
+            *n -= 1;
+            if *n == 0 {
+                inner.pending.remove(&commit_ts);
+            }
+        }
+        inner.last_done = inner.last_done.max(commit_ts);
+        let done = match inner.pending.keys().next() {
+            Some(first) => inner.last_done.min(first.saturating_sub(1)),
+            None => inner.last_done,
+        };
+        inner.read_ts = inner.read_ts.max(done);
+        Self::forget_committed(&mut inner);
+    }
+}
+
+/// A transaction, see `KV::new_transaction`. Its reads see its own writes, which are written
+/// only on `commit`. A commit fails with `TxnConflict` if a key the transaction read was
+/// written by another transaction committed since it started, the transaction can be retried
+/// then, unless `Options::detect_conflicts` is off. The writes made outside transactions
+/// aren't checked.
+///
+/// With `Options::managed_txns`, the application gives the read and commit timestamps, see
+/// `KV::new_transaction_at` and `commit_at`, and the reads see the versions committed at or
+/// before the read timestamp. Otherwise the keys aren't versioned, the reads see the latest
+/// value of a key, committed after the transaction started or not.
+///
+/// The writes of a transaction are written as one batch, `set_entry` fails with `TxnTooBig`
+/// past the batch limits, see `KV::max_batch_count` and `KV::max_batch_size`. The writes
+/// have to be split in several transactions then.
+pub struct Txn {
+    kv: KV,
+    update: bool,
+    managed: bool,
+    // Whether the conflicts of the transaction are checked at commit, see
+    // `Options::detect_conflicts`.
+    detect: bool,
+    read_ts: u64,
+    // Fingerprints of the keys read, by `get` and the iterators.
+    reads: Arc<Mutex<Vec<u64>>>,
+    pending: BTreeMap<Vec<u8>, Entry>,
+    // The estimated bytes of the pending writes, as written in a batch.
+    pending_size: u64,
+    done: bool,
+}
+
+impl Txn {
+    // Starts a transaction reading at `read_ts`, given only with managed transactions.
+    pub(crate) fn new(kv: KV, read_ts: Option<u64>, update: bool) -> Result<Txn> {
+        let managed = kv.opt.managed_txns;
+        let detect = update && kv.opt.detect_conflicts;
+        match (managed, read_ts) {
+            (true, None) => return Err(Error::ManagedTxns),
+            (false, Some(_)) => return Err(Error::NotManagedTxns),
+            _ => {}
+        }
+        let read_ts = kv.orc.new_read_ts(read_ts, detect);
+        Ok(Txn {
+            kv,
+            update,
+            managed,
+            detect,
+            read_ts,
+            reads: Arc::new(Mutex::new(vec![])),
+            pending: BTreeMap::new(),
+            pending_size: 0,
+            done: false,
+        })
+    }
+
+    /// Returns the value of `key`, the one set by this transaction if it did. NotFound if the
+    /// key doesn't exist or is deleted.
+    pub async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        if self.done {
+            return Err(Error::DiscardedTxn);
+        }
+        if self.kv.is_banned(key) {
+            return Err(Error::BannedKey);
+        }
+        if let Some(entry) = self.pending.get(key) {
+            if entry.meta & MetaBit::BIT_DELETE.bits() != 0
+                || entry.is_expired(self.kv.opt.clock.now())
+            {
+                return Err(Error::NotFound);
+            }
+            return Ok(entry.value.clone());
+        }
+        if self.detect {
+            self.reads.lock().push(hash(key));
+        }
+        self.get_committed(key).await
+    }
+
+    /// Returns the values of `keys` in their order, like `get` for each of them. Faster than
+    /// getting them one by one: the keys are looked up sorted, the memtables are read once and
+    /// every table once for all the keys it may hold.
+    pub async fn multi_get(&self, keys: &[Vec<u8>]) -> Vec<Result<Vec<u8>>> {
+        if self.done {
+            return keys.iter().map(|_| Err(Error::DiscardedTxn)).collect();
+        }
+        let mut res: Vec<Result<Vec<u8>>> = keys.iter().map(|_| Err(Error::NotFound)).collect();
+        let mut lookups = vec![];
+        for (i, key) in keys.iter().enumerate() {
+            if self.kv.is_banned(key) {
+                res[i] = Err(Error::BannedKey);
+                continue;
+            }
+            if let Some(entry) = self.pending.get(key) {
+                if entry.meta & MetaBit::BIT_DELETE.bits() == 0
+                    && !entry.is_expired(self.kv.opt.clock.now())
+                {
+                    res[i] = Ok(entry.value.clone());
+                }
+                continue;
+            }
+            if self.detect {
+                self.reads.lock().push(hash(key));
+            }
+            lookups.push(i);
+        }
+        let lookup_keys = lookups
+            .iter()
+            .map(|&i| keys[i].as_slice())
+            .collect::<Vec<_>>();
+        if self.managed {
+            let got = self.kv._multi_get_version(&lookup_keys, self.read_ts);
+            for (i, got) in lookups.into_iter().zip(got) {
+                res[i] = match got {
+                    Ok((version, vs)) => {
+                        KVItemInner::new_version(keys[i].clone(), version, vs, self.kv.clone())
+                            .get_value()
+                            .await
+                    }
+                    Err(err) => Err(err),
+                };
+            }
+            return res;
+        }
+        let got = self.kv._multi_get(&lookup_keys);
+        for (i, vs) in lookups.into_iter().zip(got) {
+            res[i] = match vs {
+                Ok(vs) => {
+                    KVItemInner::new(keys[i].clone(), vs, self.kv.clone())
+                        .get_value()
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+        }
+        res
+    }
+
+    // Returns the value of `key` read from the KV, the newest version not newer than the read
+    // ts if the keys are versioned.
+    async fn get_committed(&self, key: &[u8]) -> Result<Vec<u8>> {
+        if !self.managed {
+            return self.kv.get(key).await;
+        }
+        let (key, vs) = self.kv._get_version(key, self.read_ts)?;
+        let (version, key) = (parse_ts(&key), parse_key(&key).to_vec());
+        KVItemInner::new_version(key, version, vs, self.kv.clone())
+            .get_value()
+            .await
+    }
+
+    /// Returns an iterator over the keys seen by the transaction: its own writes merged with
+    /// the memtables and the tables of every level, see `IteratorOptions` for the prefix and
+    /// the versions iterated. With managed transactions, the versions committed after the read
+    /// ts aren't seen; otherwise the writes made after it's created may be. It has to be
+    /// closed.
+    pub async fn iter(&self, opt: IteratorOptions) -> Result<TxnIterator> {
+        if self.done {
+            return Err(Error::DiscardedTxn);
+        }
+        // The keys under a banned prefix are skipped, all of them are with a prefix under it.
+        if self.kv.is_banned(&opt.prefix) {
+            return Err(Error::BannedKey);
+        }
+        let vlog = self.kv.must_vlog();
+        vlog.incr_iterator_count();
+        let vlogs = vlog.pin_files().await;
+        let mut pending = self
+            .pending
+            .values()
+            .filter(|entry| entry.key.starts_with(&opt.prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        if opt.reverse {
+            pending.reverse();
+        }
+        Ok(TxnIterator {
+            kv: self.kv.clone(),
+            itr: self.kv.merge_iterator(opt.reverse, &opt.prefix),
+            workers: Arc::new(Semaphore::new(opt.pre_fetch_workers.max(1))),
+            opt,
+            managed: self.managed,
+            read_ts: self.read_ts,
+            reads: self.detect.then(|| self.reads.clone()),
+            pending,
+            pending_pos: 0,
+            lsm_next: None,
+            items: VecDeque::new(),
+            vlogs,
+        })
+    }
+
+    /// Returns the read timestamp of the transaction.
+    pub fn read_ts(&self) -> u64 {
+        self.read_ts
+    }
+
+    /// Sets `value` of `key` once committed.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>, user_meta: u8) -> Result<()> {
+        let entry = Entry::default().key(key).value(value).user_meta(user_meta);
+        self.set_entry(entry)
+    }
+
+    /// Deletes `key` once committed.
+    pub fn delete(&mut self, key: Vec<u8>) -> Result<()> {
+        let entry = Entry::default().key(key).meta(MetaBit::BIT_DELETE.bits());
+        self.set_entry(entry)
+    }
+
+    /// Adds `entry` to the writes of the transaction, replacing the previous one of its key.
+    /// Fails with `TxnTooBig` if the writes wouldn't fit in a batch, the entry isn't added.
+    pub fn set_entry(&mut self, entry: Entry) -> Result<()> {
+        if self.done {
+            return Err(Error::DiscardedTxn);
+        }
+        if !self.update {
+            return Err(Error::ReadOnlyTxn);
+        }
+        if entry.key.is_empty() {
+            return Err(Error::ValueInvalidRequest);
+        }
+        if self.kv.is_banned(&entry.key) {
+            return Err(Error::BannedKey);
+        }
+        let (count, size) = match self.pending.get(&entry.key) {
+            Some(old) => (
+                self.pending.len() as u64,
+                self.pending_size - self.entry_size(old),
+            ),
+            None => (self.pending.len() as u64 + 1, self.pending_size),
+        };
+        let size = size + self.entry_size(&entry);
+        if count > self.kv.opt.max_batch_count || size > self.kv.opt.max_batch_size {
+            return Err(Error::TxnTooBig);
+        }
+        self.pending_size = size;
+        self.pending.insert(entry.key.clone(), entry);
+        Ok(())
+    }
+
+    // The bytes `entry` counts for in a batch, its key gets a version with managed
+    // transactions.
+    fn entry_size(&self, entry: &Entry) -> u64 {
+        let ts = if self.managed { 8 } else { 0 };
+        (self.kv.opt.estimate_size(entry) + ts) as u64
+    }
+
+    /// Checks the conflicts and writes the transaction. The transaction is discarded, whether
+    /// it's committed or not.
+    pub async fn commit(self) -> Result<()> {
+        if self.managed {
+            return Err("Managed transactions are committed with commit_at".into());
+        }
+        self.commit_inner(None).await
+    }
+
+    /// Commits the transaction at `commit_ts`, with managed transactions, see `commit`. The
+    /// versions of the keys written are `commit_ts`.
+    pub async fn commit_at(self, commit_ts: u64) -> Result<()> {
+        if !self.managed {
+            return Err("Only managed transactions are committed at a ts".into());
+        }
+        self.commit_inner(Some(commit_ts)).await
+    }
+
+    #[cfg_attr(feature = "tracing-spans", tracing::instrument(
+        name = "txn_commit",
+        skip_all,
+        fields(writes = self.pending.len(), managed = self.managed)
+    ))]
+    async fn commit_inner(mut self, commit_ts: Option<u64>) -> Result<()> {
+        if self.done {
+            return Err(Error::DiscardedTxn);
+        }
+        if self.pending.is_empty() {
+            self.discard();
+            return Ok(());
+        }
+        self.kv.throttle_writes().await;
+        let orc = self.kv.orc.clone();
+        let sent = {
+            let _commit = orc.commit_lock.lock().await;
+            let writes = self.pending.keys().map(|key| hash(key)).collect();
+            let reads = std::mem::take(&mut *self.reads.lock());
+            match orc.new_commit_ts(self.read_ts, commit_ts, &reads, writes) {
+                Ok(commit_ts) => {
+                    let mut entries = std::mem::take(&mut self.pending)
+                        .into_values()
+                        .collect::<Vec<_>>();
+                    if self.managed {
+                        for entry in entries.iter_mut() {
+                            entry.key = key_with_ts(&entry.key, commit_ts);
+                        }
+                    }
+                    Ok((commit_ts, self.kv.send_batch(entries).await))
+                }
+                Err(err) => Err(err),
+            }
+        };
+        let res = match sent {
+            Ok((commit_ts, batch)) => {
+                let res = batch.wait().await.into_iter().collect::<Result<()>>();
+                orc.done_commit(commit_ts);
+                res
+            }
+            Err(err) => Err(err),
+        };
+        self.discard();
+        res
+    }
+
+    /// Drops the writes of the transaction, it can't be used after. Done on drop too.
+    pub fn discard(&mut self) {
+        if self.done {
+            return;
+        }
+        self.done = true;
+        self.pending.clear();
+        self.pending_size = 0;
+        if self.detect {
+            self.kv.orc.done_read(self.read_ts);
+        }
+    }
+}
+
+impl Drop for Txn {
+    fn drop(&mut self) {
+        self.discard();
+    }
+}
+
+/// A read-only view of the versions committed at its read timestamp, see `KV::snapshot`.
+/// Its reads and iterators don't see the commits done after it's taken. The compactions keep
+/// the versions it reads until it's dropped, whatever the discard ts. Only with
+/// `Options::managed_txns`: otherwise the keys aren't versioned, a write replaces the value
+/// a snapshot would read.
+pub struct Snapshot {
+    txn: Txn,
+}
+
+impl Snapshot {
+    pub(crate) fn new(kv: KV) -> Result<Snapshot> {
+        if !kv.opt.managed_txns {
+            return Err(Error::NotManagedTxns);
+        }
+        let read_ts = kv.orc.new_snapshot_ts();
+        let mut txn = Txn::new(kv, Some(read_ts), false)?;
+        // The one taken by the oracle, a commit may be done since.
+        txn.read_ts = read_ts;
+        Ok(Snapshot { txn })
+    }
+
+    /// Returns the value of `key` at the snapshot, NotFound if it didn't exist or was deleted.
+    pub async fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.txn.get(key).await
+    }
+
+    /// Returns an iterator over the keys at the snapshot, see `Txn::iter`.
+    pub async fn iter(&self, opt: IteratorOptions) -> Result<TxnIterator> {
+        self.txn.iter(opt).await
+    }
+
+    /// Returns the read timestamp of the snapshot, the last commit it sees.
+    pub fn read_ts(&self) -> u64 {
+        self.txn.read_ts()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.txn.kv.orc.done_snapshot(self.txn.read_ts);
+    }
+}
+
+/// Iterates the keys seen by a transaction, in order, see `Txn::iter`. A key written by the
+/// transaction shadows its versions in the LSM tree, its deletes are skipped.
+pub struct TxnIterator {
+    kv: KV,
+    itr: MergeIterator,
+    opt: IteratorOptions,
+    managed: bool,
+    read_ts: u64,
+    // The reads of the transaction, the keys iterated are added to them if it checks its
+    // conflicts.
+    reads: Option<Arc<Mutex<Vec<u64>>>>,
+    // The writes of the transaction under the prefix, in iteration order, and the next one.
+    pending: Vec<Entry>,
+    pending_pos: usize,
+    // The next key of the LSM tree and its versions, newest first.
+    lsm_next: Option<(Vec<u8>, Vec<(u64, ValueStruct)>)>,
+    // The current item and the ones prefetched.
+    items: VecDeque<KVItem>,
+    // Bounds the value log reads of the prefetching tasks.
+    workers: Arc<Semaphore>,
+    // The value log files there were when it was created, held until it's closed.
+    vlogs: Vec<TArcRW<LogFile>>,
+}
+
+impl TxnIterator {
+    /// Moves to the first key, the smallest one, or the largest one if iterating backward.
+    pub async fn rewind(&mut self) -> Option<KVItem> {
+        if !self.opt.prefix.is_empty() {
+            let mut key = self.opt.prefix.clone();
+            if self.opt.reverse {
+                key.extend_from_slice(&[0xff; 16]);
+            }
+            return self.seek(&key).await;
+        }
+        self.reset();
+        self.itr.rewind();
+        self.fill().await;
+        self.peek()
+    }
+
+    /// Moves to `key`, or the first key after it, before it if iterating backward.
+    pub async fn seek(&mut self, key: &[u8]) -> Option<KVItem> {
+        self.reset();
+        let key = self.seek_start(key);
+        let reverse = self.opt.reverse;
+        if self.managed {
+            // The newest version of the key comes first, the oldest one backward.
+            self.itr
+                .seek(&key_with_ts(&key, if reverse { 0 } else { u64::MAX }));
+        } else {
+            self.itr.seek(&key);
+        }
+        self.pending_pos = self
+            .pending
+            .iter()
+            .position(|entry| {
+                if reverse {
+                    entry.key <= key
+                } else {
+                    entry.key >= key
+                }
+            })
+            .unwrap_or(self.pending.len());
+        self.fill().await;
+        self.peek()
+    }
+
+    /// Moves to the next item and returns it, None at the end.
+    pub async fn next(&mut self) -> Option<KVItem> {
+        self.items.pop_front();
+        self.fill().await;
+        self.peek()
+    }
+
+    /// Returns the current item.
+    pub fn peek(&self) -> Option<KVItem> {
+        self.items.front().cloned()
+    }
+
+    /// Closes the iterator, the value log files it held can be deleted.
+    pub async fn close(mut self) -> Result<()> {
+        self.items.clear();
+        self.vlogs.clear();
+        self.kv.must_vlog().decr_iterator_count().await
+    }
+
+    fn reset(&mut self) {
+        self.items.clear();
+        self.lsm_next = None;
+        self.pending_pos = 0;
+    }
+
+    // Where a seek to `key` starts, the keys out of the prefix aren't iterated.
+    fn seek_start(&self, key: &[u8]) -> Vec<u8> {
+        let prefix = &self.opt.prefix;
+        if key.starts_with(prefix) {
+            return key.to_vec();
+        }
+        match (self.opt.reverse, key < prefix.as_slice()) {
+            (false, true) => prefix.clone(),
+            (true, false) => {
+                let mut key = prefix.clone();
+                key.extend_from_slice(&[0xff; 16]);
+                key
+            }
+            // Past the prefix, nothing is iterated.
+            _ => key.to_vec(),
+        }
+    }
+
+    // Adds items until the prefetch size is reached, or the end.
+    async fn fill(&mut self) {
+        let mut want = 1;
+        if self.opt.pre_fetch_values && self.opt.pre_fetch_size > 1 {
+            want = self.opt.pre_fetch_size as usize;
+        }
+        while self.items.len() < want {
+            if !self.fill_key().await {
+                break;
+            }
+        }
+    }
+
+    // Adds the items of the next key, from the writes of the transaction or the LSM tree.
+    // Returns false at the end.
+    async fn fill_key(&mut self) -> bool {
+        loop {
+            if self.lsm_next.is_none() {
+                self.lsm_next = self.next_lsm_key();
+            }
+            let lsm_key = self.lsm_next.as_ref().map(|(key, _)| key.as_slice());
+            let take_pending = match (self.pending.get(self.pending_pos), lsm_key) {
+                (None, None) => return false,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (Some(entry), Some(key)) if self.opt.reverse => entry.key.as_slice() >= key,
+                (Some(entry), Some(key)) => entry.key.as_slice() <= key,
+            };
+            if take_pending {
+                let entry = self.pending[self.pending_pos].clone();
+                self.pending_pos += 1;
+                if lsm_key == Some(entry.key.as_slice()) {
+                    self.lsm_next = None;
+                }
+                if entry.meta & MetaBit::BIT_DELETE.bits() != 0
+                    || entry.is_expired(self.kv.opt.clock.now())
+                {
+                    continue;
+                }
+                let vs = ValueStruct::new(entry.value, entry.meta, entry.user_meta, 0)
+                    .with_expires_at(entry.expires_at);
+                let item = KVItemInner::new(entry.key, vs, self.kv.clone());
+                self.items.push_back(KVItem::from(item));
+                return true;
+            }
+            let (key, versions) = self.lsm_next.take().unwrap();
+            if let Some(reads) = &self.reads {
+                reads.lock().push(hash(&key));
+            }
+            let versions = self.visible(versions);
+            if versions.is_empty() {
+                continue;
+            }
+            for (version, vs) in versions {
+                let pointer = vs.meta & MetaBit::BIT_VALUE_POINTER.bits() != 0;
+                let item = KVItem::from(KVItemInner::new_version(
+                    key.clone(),
+                    version,
+                    vs,
+                    self.kv.clone(),
+                ));
+                if self.opt.pre_fetch_values && pointer {
+                    item.pre_fetch_in_background(self.workers.clone()).await;
+                }
+                self.items.push_back(item);
+            }
+            return true;
+        }
+    }
+
+    // Returns the next key of the LSM tree under the prefix and its versions, newest first.
+    // The merge iterator is left at the key after.
+    fn next_lsm_key(&self) -> Option<(Vec<u8>, Vec<(u64, ValueStruct)>)> {
+        loop {
+            let item = self.itr.peek()?;
+            if item.key().starts_with(_BADGER_PREFIX) {
+                self.itr.next();
+                continue;
+            }
+            let key = self.user_key(item.key()).to_vec();
+            if !key.starts_with(&self.opt.prefix) {
+                return None;
+            }
+            let mut versions = vec![];
+            while let Some(item) = self.itr.peek() {
+                if self.user_key(item.key()) != key.as_slice() {
+                    break;
+                }
+                let version = if self.managed {
+                    parse_ts(item.key())
+                } else {
+                    0
+                };
+                versions.push((version, item.value().clone()));
+                self.itr.next();
+            }
+            if self.kv.is_banned(&key) {
+                continue;
+            }
+            versions.sort_by(|a, b| b.0.cmp(&a.0));
+            return Some((key, versions));
+        }
+    }
+
+    fn user_key<'a>(&self, key: &'a [u8]) -> &'a [u8] {
+        if self.managed {
+            parse_key(key)
+        } else {
+            key
+        }
+    }
+
+    // The versions of a key, newest first, that compactions keep: all the ones above the
+    // discard ts, then `Options::num_versions_to_keep` of them up to a tombstone or a version
+    // discarding the earlier ones.
+    fn retained(
+        &self,
+        versions: impl Iterator<Item = (u64, ValueStruct)>,
+    ) -> Vec<(u64, ValueStruct)> {
+        let discard_ts = self.kv.must_lc().discard_ts();
+        let keep = self.kv.opt.num_versions_to_keep.max(1);
+        let hides = MetaBit::BIT_DELETE.bits() | MetaBit::BIT_DISCARD_EARLIER_VERSIONS.bits();
+        let mut retained = vec![];
+        let mut below = 0;
+        for (version, vs) in versions {
+            let last = version <= discard_ts && {
+                below += 1;
+                vs.meta & hides != 0 || below >= keep
+            };
+            retained.push((version, vs));
+            if last {
+                break;
+            }
+        }
+        retained
+    }
+
+    // The versions of a key to iterate, none if it's hidden to the transaction.
+    fn visible(&self, versions: Vec<(u64, ValueStruct)>) -> Vec<(u64, ValueStruct)> {
+        let mut versions = versions
+            .into_iter()
+            .filter(|(version, _)| !self.managed || *version <= self.read_ts);
+        if self.managed && self.opt.all_versions {
+            return self
+                .retained(versions)
+                .into_iter()
+                .filter(|(version, _)| *version >= self.opt.since_ts)
+                .collect();
+        }
+        match versions.next() {
+            Some((version, vs))
+                if vs.meta & MetaBit::BIT_DELETE.bits() == 0
+                    && !vs.is_expired(self.kv.opt.clock.now())
+                    && (!self.managed || version >= self.opt.since_ts) =>
+            {
+                vec![(version, vs)]
+            }
+            _ => vec![],
+        }
+    }
+}
+
+#[test]
+fn oracle_read_ts_waits_for_pending_commits() {
+    let orc = Oracle::default();
+    let t1 = orc.new_commit_ts(0, None, &[], HashSet::new()).unwrap();
+    let t2 = orc.new_commit_ts(0, None, &[], HashSet::new()).unwrap();
+    assert!(t1 < t2);
+    // The writes of t1 aren't done, a transaction starting wouldn't read them.
+    orc.done_commit(t2);
+    assert_eq!(orc.read_ts(), 0);
+    orc.done_commit(t1);
+    assert_eq!(orc.read_ts(), t2);
+
+    // A transaction started before a pending commit conflicts with it.
+    let read_ts = orc.new_read_ts(None, true);
+    let t3 = orc
+        .new_commit_ts(read_ts, None, &[], HashSet::from([7]))
+        .unwrap();
+    let err = orc.new_commit_ts(read_ts, None, &[7], HashSet::new());
+    assert!(matches!(err, Err(Error::TxnConflict)));
+    orc.done_commit(t3);
+    orc.done_read(read_ts);
+    assert!(orc.inner.lock().committed.is_empty());
+}
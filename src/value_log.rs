@@ -9,14 +9,13 @@ use drop_cell::defer;
 use getset::{Getters, Setters};
 
 use log::kv::Source;
-use log::{debug, info};
-use memmap::Mmap;
+use log::{debug, info, warn};
 
 use rand::random;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Debug, Display, Formatter};
-use std::fs::{read_dir, remove_file};
+use std::fs::{read_dir, remove_file, File};
 use std::future::Future;
 use std::io::{BufRead, Cursor, Read, Seek, SeekFrom, Write};
 
@@ -25,7 +24,7 @@ use std::ops::{Deref, Index};
 use std::path::Path;
 use std::pin::Pin;
 
-use std::sync::atomic::{AtomicI32, AtomicU32, AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering};
 use std::sync::Arc;
 
 use std::time::{Duration, SystemTime};
@@ -33,12 +32,19 @@ use std::{fmt, fs, io, ptr};
 
 use tokio::macros::support::thread_rng_n;
 
+use crate::discard_stats::DiscardStats;
 use crate::kv::{BoxKV, KVCore};
 use crate::log_file::LogFile;
-use crate::options::Options;
+use crate::options::{Options, ValueLogFormat};
+use crate::table::encryption::{FileCipher, KeyRegistry, KEY_REGISTRY_FILE_NAME};
+use crate::table::go_table::crc32c;
+use crate::vlog_registry::ValueLog;
 
 use crate::types::{Channel, Closer, TArcRW};
-use crate::y::{create_synced_file, open_existing_synced_file, sync_directory, Decode, Encode};
+use crate::y::{
+    create_synced_file, open_existing_synced_file, punch_hole, read_at, sync_directory, Clock,
+    Decode, Encode,
+};
 use crate::Error::Unexpected;
 use crate::{event, hex_str, Error, Result, EMPTY_SLICE};
 
@@ -53,11 +59,20 @@ bitflags! {
         const BIT_UNUSED = 4;
         /// Set if the key is set using SetIfAbsent.
         const BIT_SET_IF_ABSENT = 8;
+        /// Set if the value was moved to a later file by the value log GC, it keeps the CAS
+        /// counter of the value it moved.
+        const BIT_MOVE = 16;
+        /// Set if the compactions can drop the versions of the key older than this one, once
+        /// they're below the discard ts, see `Entry::with_discard`.
+        const BIT_DISCARD_EARLIER_VERSIONS = 32;
     }
 }
 
 const M: u64 = 1 << 20;
 
+// Holes are punched in whole file system blocks.
+const HOLE_ALIGN: u64 = 4 << 10;
+
 pub(crate) const MAX_KEY_SIZE: usize = 1 << 20;
 
 #[derive(Debug, Default)]
@@ -77,6 +92,32 @@ impl Header {
     }
 }
 
+// The native header has the expiry of the entry in its padding, 6 bytes after the fields.
+const NATIVE_EXPIRES_AT_OFFSET: usize = 26;
+const MAX_NATIVE_EXPIRES_AT: u64 = (1 << 48) - 1;
+
+impl ValueLogFormat {
+    // The bytes of an entry header, the legacy Go header isn't padded.
+    pub(crate) fn header_size(self) -> usize {
+        match self {
+            ValueLogFormat::Native => Header::encoded_size(),
+            ValueLogFormat::GoLegacy => 26,
+        }
+    }
+
+    // The bytes of an entry of a `k_len` bytes key and a `v_len` bytes value.
+    pub(crate) fn entry_size(self, k_len: u32, v_len: u32) -> u64 {
+        self.header_size() as u64 + k_len as u64 + v_len as u64 + 4
+    }
+
+    pub(crate) fn checksum(self, buf: &[u8]) -> u32 {
+        match self {
+            ValueLogFormat::Native => crc32fast::hash(buf),
+            ValueLogFormat::GoLegacy => crc32c(buf),
+        }
+    }
+}
+
 impl Encode for Header {
     fn enc(&self, wt: &mut dyn Write) -> Result<usize> {
         wt.write_u32::<BigEndian>(self.k_len)?;
@@ -112,6 +153,11 @@ pub struct Entry {
     pub(crate) value: Vec<u8>,
     // If nonzero, we will check if existing casCounter matches.
     pub(crate) cas_counter_check: u64,
+    // Unix time in seconds the entry expires at, 0 if it doesn't. It's kept in the value log
+    // in the native format only.
+    pub(crate) expires_at: u64,
+    // Set by `with_ttl`, turned into `expires_at` when the entry is written.
+    pub(crate) ttl: Option<Duration>,
     // Fields maintained internally.
     pub(crate) offset: u32,
     pub(crate) cas_counter: AtomicU64,
@@ -125,6 +171,8 @@ impl Clone for Entry {
             user_meta: self.user_meta.clone(),
             value: self.value.clone(),
             cas_counter_check: self.cas_counter_check.clone(),
+            expires_at: self.expires_at,
+            ttl: self.ttl,
             offset: self.offset.clone(),
             cas_counter: AtomicU64::new(self.get_cas_counter()),
         }
@@ -157,6 +205,60 @@ impl Entry {
         self
     }
 
+    /// Sets the unix time in seconds the entry expires at, it's read as missing from then
+    /// on and dropped by the compactions.
+    pub fn expires_at(mut self, expires_at: u64) -> Self {
+        self.expires_at = expires_at;
+        self.ttl = None;
+        self
+    }
+
+    /// Lets the compactions drop the older versions of the key once this one is below the
+    /// discard ts, see `KV::set_discard_ts`, whatever `Options::num_versions_to_keep` is.
+    pub fn with_discard(mut self) -> Self {
+        self.meta |= MetaBit::BIT_DISCARD_EARLIER_VERSIONS.bits();
+        self
+    }
+
+    /// Makes the entry expire `ttl` after it's written, by `Options::clock`, see `expires_at`.
+    /// It's rounded up to the second, the entry doesn't expire sooner.
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    // Turns the ttl set by `with_ttl` into the time the entry expires at, `now` is the time
+    // it's written at.
+    pub(crate) fn resolve_ttl(&mut self, now: u64) {
+        if let Some(ttl) = self.ttl.take() {
+            self.expires_at = now + ttl.as_nanos().div_ceil(1_000_000_000) as u64;
+        }
+    }
+
+    pub fn get_expires_at(&self) -> u64 {
+        self.expires_at
+    }
+
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+
+    pub fn get_key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn get_value(&self) -> &[u8] {
+        &self.value
+    }
+
+    pub fn get_meta(&self) -> u8 {
+        self.meta
+    }
+
+    pub fn get_user_meta(&self) -> u8 {
+        self.user_meta
+    }
+
     pub fn get_cas_counter(&self) -> u64 {
         self.cas_counter.load(Ordering::Acquire)
     }
@@ -172,39 +274,58 @@ impl Entry {
 }
 
 impl Entry {
-    pub(crate) fn from_slice(cursor_offset: u32, m: &[u8]) -> Result<Entry> {
-        let mut entry = Entry::default();
+    /// Decodes the entry at `cursor_offset` of `m`, written in `format`. Fails if the entry
+    /// runs past the end of `m` or its checksum doesn't match, i.e. it's torn or corrupt.
+    pub(crate) fn from_slice(
+        format: ValueLogFormat,
+        cursor_offset: u32,
+        m: &[u8],
+    ) -> Result<Entry> {
+        let start = cursor_offset as usize;
+        if m.len() < start + format.header_size() + 4 {
+            return Err(format!("Truncated value log entry header at offset {}", start).into());
+        }
         let mut h = Header::default();
-        h.dec(&mut Cursor::new(
-            &m[cursor_offset as usize..cursor_offset as usize + Header::encoded_size()],
-        ))?;
-        entry.key = vec![0u8; h.k_len as usize];
-        entry.value = vec![0u8; h.v_len as usize];
-        entry.meta = h.meta;
-        entry.offset = cursor_offset as u32;
-        entry.cas_counter = AtomicU64::new(h.cas_counter);
-        entry.user_meta = h.user_mata;
-        entry.cas_counter_check = h.cas_counter_check;
-        let mut start = cursor_offset as usize + Header::encoded_size();
-        entry
-            .key
-            .extend_from_slice(&m[start..start + h.k_len as usize]);
-        start += h.k_len as usize;
-        entry
-            .value
-            .extend_from_slice(&m[start..start + h.v_len as usize]);
-        start += h.v_len as usize;
-        let _crc32 = Cursor::new(&m[start..start + 4]).read_u32::<BigEndian>()?;
+        h.dec(&mut Cursor::new(&m[start..start + format.header_size()]))?;
+        let key_start = start + format.header_size();
+        let value_start = key_start + h.k_len as usize;
+        let crc_start = value_start + h.v_len as usize;
+        if h.k_len as usize > MAX_KEY_SIZE || m.len() < crc_start + 4 {
+            return Err(format!(
+                "Truncated value log entry at offset {}, key: {} bytes, value: {} bytes",
+                start, h.k_len, h.v_len
+            )
+            .into());
+        }
+        let crc32 = Cursor::new(&m[crc_start..crc_start + 4]).read_u32::<BigEndian>()?;
+        if format.checksum(&m[start..crc_start]) != crc32 {
+            return Err(format!("Checksum mismatch of value log entry at offset {}", start).into());
+        }
+        let mut expires_at = 0;
+        if format == ValueLogFormat::Native {
+            let offset = start + NATIVE_EXPIRES_AT_OFFSET;
+            expires_at = Cursor::new(&m[offset..key_start]).read_u48::<BigEndian>()?;
+        }
+        let entry = Entry {
+            key: m[key_start..value_start].to_vec(),
+            meta: h.meta,
+            user_meta: h.user_mata,
+            value: m[value_start..crc_start].to_vec(),
+            cas_counter_check: h.cas_counter_check,
+            expires_at,
+            ttl: None,
+            offset: cursor_offset,
+            cas_counter: AtomicU64::new(h.cas_counter),
+        };
         Ok(entry)
     }
 
     fn to_string(&self, prefix: &str) -> String {
         format!("{} {}", prefix, self)
     }
-}
 
-impl Encode for Entry {
-    fn enc(&self, wt: &mut dyn Write) -> Result<usize> {
+    // Encodes the entry in `format`, returns the bytes written.
+    pub(crate) fn enc_with(&self, format: ValueLogFormat, wt: &mut dyn Write) -> Result<usize> {
         let mut h = Header::default();
         h.k_len = self.key.len() as u32;
         h.v_len = self.value.len() as u32;
@@ -212,20 +333,22 @@ impl Encode for Entry {
         h.user_mata = self.user_meta;
         h.cas_counter = self.cas_counter.load(Ordering::Relaxed);
         h.cas_counter_check = self.cas_counter_check;
-        let mut buffer = vec![0u8; Header::encoded_size() + (h.k_len + h.v_len + 4) as usize];
+        let mut buffer = vec![0u8; format.entry_size(h.k_len, h.v_len) as usize];
         // write header
         let mut start = 0;
         h.enc(&mut Cursor::new(&mut buffer[start..]))?;
+        if format == ValueLogFormat::Native {
+            let expires_at = self.expires_at.min(MAX_NATIVE_EXPIRES_AT);
+            (&mut buffer[NATIVE_EXPIRES_AT_OFFSET..]).write_u48::<BigEndian>(expires_at)?;
+        }
         // write key
-        start += Header::encoded_size();
+        start += format.header_size();
         (&mut buffer[start..]).write(&self.key)?;
         // write value
         start += h.k_len as usize;
         (&mut buffer[start..]).write(&self.value)?;
         start += h.v_len as usize;
-        let mut hasher = Hasher::new();
-        hasher.update(&buffer[..start]);
-        let check_sum = hasher.finalize();
+        let check_sum = format.checksum(&buffer[..start]);
         // write crc32
         (&mut buffer[start..]).write_u32::<BigEndian>(check_sum)?;
         wt.write_all(&buffer)?;
@@ -233,6 +356,12 @@ impl Encode for Entry {
     }
 }
 
+impl Encode for Entry {
+    fn enc(&self, wt: &mut dyn Write) -> Result<usize> {
+        self.enc_with(ValueLogFormat::Native, wt)
+    }
+}
+
 impl Decode for Entry {
     fn dec(&mut self, rd: &mut dyn Read) -> Result<()> {
         let mut h = Header::default();
@@ -240,6 +369,8 @@ impl Decode for Entry {
         let sz = rd.read(&mut buffer)?;
         assert_eq!(sz, buffer.len());
         h.dec(&mut Cursor::new(&buffer))?;
+        self.expires_at =
+            Cursor::new(&buffer[NATIVE_EXPIRES_AT_OFFSET..]).read_u48::<BigEndian>()?;
         self.key = vec![0u8; h.k_len as usize];
         self.value = vec![0u8; h.v_len as usize];
         self.meta = h.meta;
@@ -251,8 +382,14 @@ impl Decode for Entry {
         assert_eq!(sz, h.k_len as usize);
         let sz = rd.read(&mut self.value)?;
         assert_eq!(sz, h.v_len as usize);
-        // TODO check crc32
-        let _crc32 = rd.read_u32::<BigEndian>()?;
+        let crc32 = rd.read_u32::<BigEndian>()?;
+        let mut hasher = Hasher::new();
+        hasher.update(&buffer);
+        hasher.update(&self.key);
+        hasher.update(&self.value);
+        if hasher.finalize() != crc32 {
+            return Err("Checksum mismatch of value log entry".into());
+        }
         Ok(())
     }
 }
@@ -267,6 +404,7 @@ impl fmt::Display for Entry {
             .field("value", &self.value)
             .field("case=", &self.get_cas_counter())
             .field("check", &self.cas_counter_check)
+            .field("expires_at", &self.expires_at)
             .finish()
     }
 }
@@ -280,6 +418,22 @@ pub struct ValuePointer {
 }
 
 impl ValuePointer {
+    pub fn new(fid: u32, offset: u32, len: u32) -> ValuePointer {
+        ValuePointer { fid, len, offset }
+    }
+
+    pub fn get_fid(&self) -> u32 {
+        self.fid
+    }
+
+    pub fn get_offset(&self) -> u32 {
+        self.offset
+    }
+
+    pub fn get_len(&self) -> u32 {
+        self.len
+    }
+
     fn less(&self, o: &ValuePointer) -> bool {
         if self.fid != o.fid {
             return self.fid < o.fid;
@@ -433,12 +587,12 @@ pub struct ValueLogCore {
     // TODO
     // guards our view of which files exist, which to be deleted, how many active iterators
     pub(crate) files_log: TArcRW<()>,
-    vlogs: TArcRW<HashMap<u32, TArcRW<LogFile>>>,
-    // TODO It is not good idea that use raw lock for Arc<RwLock<LogFile>>, it maybe lock AsyncRuntime thread.
-    dirty_vlogs: TArcRW<HashSet<u32>>,
-    // A refcount of iterators -- when this hits zero, we can delete the files_to_be_deleted
+    vlogs: ValueLog,
+    // A refcount of iterators, holes aren't punched while they may read stale values.
     num_active_iterators: AtomicI32,
     writable_log_offset: AtomicU32,
+    // Number of entries in the file being written to.
+    writable_log_entries: AtomicU32,
     buf: TArcRW<Cursor<Vec<u8>>>,
     opt: Options,
     kv: BoxKV,
@@ -453,10 +607,10 @@ impl Default for ValueLogCore {
             dir_path: Box::new("".to_string()),
             max_fid: Default::default(),
             files_log: Arc::new(Default::default()),
-            vlogs: Arc::new(Default::default()),
-            dirty_vlogs: Arc::new(Default::default()),
+            vlogs: Default::default(),
             num_active_iterators: Default::default(),
             writable_log_offset: Default::default(),
+            writable_log_entries: Default::default(),
             buf: Arc::new(tokio::sync::RwLock::new(Cursor::new(Vec::with_capacity(
                 1 << 12,
             )))),
@@ -490,22 +644,48 @@ impl ValueLogCore {
             fid,
             _mmap: None,
             sz: 0,
+            loading_mode: self.opt.value_log_loading_mode,
+            write_mode: self.opt.active_value_log_loading_mode,
+            format: self.opt.value_log_format,
+            holes: BTreeMap::new(),
+            cipher: None,
+            delete_on_drop: AtomicBool::new(false),
         };
-        self.writable_log_offset.store(0, Ordering::Release);
         let fd = create_synced_file(&_path, self.opt.sync_writes)?;
         log_file.fd.replace(fd);
+        if let Some(data_key) = self.get_kv().must_lc().data_key()? {
+            log_file.write_cipher(FileCipher::new(data_key))?;
+        }
         sync_directory(&self.dir_path)?;
         Ok(log_file)
     }
 
-    fn create_mmap_vlog_file(&self, fid: u32, offset: u64) -> Result<LogFile> {
+    // Creates the file `fid` to write to, of `size` bytes and memory mapped writable.
+    fn create_mmap_vlog_file(&self, fid: u32, size: u64) -> Result<LogFile> {
         let mut vlog_file = self.create_vlog_file(fid)?;
-        vlog_file.fd.as_mut().unwrap().set_len(offset)?;
-        let mut _mmap = unsafe { Mmap::map(vlog_file.fd.as_ref().unwrap())? };
-        vlog_file._mmap.replace(_mmap.into());
+        vlog_file.set_write(size)?;
         Ok(vlog_file)
     }
 
+    // Makes the next file the one written to, the current one must be done writing. The new
+    // file is registered before `max_fid` points to it, so readers always find the file of
+    // a value pointer.
+    async fn rotate(&self) -> Result<()> {
+        let new_id = self.max_fid.load(Ordering::Acquire) + 1;
+        assert!(new_id < 1 << 16, "new_id will overflow u16: {}", new_id);
+        let log_file = self.create_mmap_vlog_file(new_id, 2 * self.opt.value_log_file_size)?;
+        let start = log_file.data_start();
+        self.vlogs
+            .write()
+            .await
+            .insert(new_id, TArcRW::new(tokio::sync::RwLock::new(log_file)));
+        self.max_fid.store(new_id, Ordering::Release);
+        self.writable_log_offset.store(start, Ordering::Release);
+        self.writable_log_entries.store(0, Ordering::Release);
+        info!("Rotated the value log to file {}", new_id);
+        Ok(())
+    }
+
     // TODO Use Arc<KV> to replace it
     pub(crate) async fn open(&mut self, kv: *const KVCore, opt: Options) -> Result<()> {
         self.dir_path = opt.value_dir.clone();
@@ -518,7 +698,12 @@ impl ValueLogCore {
     }
 
     fn get_kv(&self) -> &KVCore {
-        unsafe { &*self.kv.kv }
+        unsafe { &*self.kv.get() }
+    }
+
+    // The KVCore moved after `open`.
+    pub(crate) fn set_kv(&self, kv: *const KVCore) {
+        self.kv.set(kv);
     }
 
     pub async fn close(&self) -> Result<()> {
@@ -528,8 +713,9 @@ impl ValueLogCore {
             let mut lf = vlog.1.write().await;
             if *vlog.0 == self.max_fid.load(Ordering::Acquire) {
                 info!("close vlog: {}", vlog.0);
-                let _mmap = lf._mmap.take().unwrap();
-                _mmap.get_mut_mmap().flush()?;
+                if let Some(_mmap) = lf._mmap.take() {
+                    _mmap.get_mut_mmap().flush()?;
+                }
                 lf.fd
                     .as_mut()
                     .unwrap()
@@ -558,6 +744,12 @@ impl ValueLogCore {
                 fid: fid as u32,
                 _mmap: None,
                 sz: 0,
+                loading_mode: self.opt.value_log_loading_mode,
+                write_mode: self.opt.active_value_log_loading_mode,
+                format: self.opt.value_log_format,
+                holes: BTreeMap::new(),
+                cipher: None,
+                delete_on_drop: AtomicBool::new(false),
             };
             self.vlogs
                 .write()
@@ -571,16 +763,51 @@ impl ValueLogCore {
 
         // Open all previous log files are read only. Open the last log file
         // as read write.
+        let keys = self.get_kv().must_lc().keys();
         let mut vlogs = self.vlogs.write().await;
+        // Set if the last file is plain but the writes are encrypted, they go to a new file.
+        let mut seal_last = false;
         for (fid, fp) in vlogs.iter() {
             if *fid == max_fid as u32 {
                 let fpath = self.fpath(*fid as u32);
                 let _fp = open_existing_synced_file(&fpath, self.opt.sync_writes)?;
-                fp.write().await.fd.replace(_fp);
+                let mut lf = fp.write().await;
+                lf.fd.replace(_fp);
+                lf.read_cipher(keys)?;
+                lf.format = lf.detect_format()?.unwrap_or(self.opt.value_log_format);
+                let entries = self.truncate_corrupt_tail(&mut lf).await?;
+                self.writable_log_entries.store(entries, Ordering::Release);
+                if lf.cipher.is_none() {
+                    if let Some(data_key) = self.get_kv().must_lc().data_key()? {
+                        if lf.fd.as_ref().unwrap().metadata()?.len() == 0 {
+                            lf.write_cipher(FileCipher::new(data_key))?;
+                        } else {
+                            seal_last = true;
+                        }
+                    }
+                }
             } else {
-                fp.write().await.open_read_only()?;
+                let mut lf = fp.write().await;
+                lf.open_read_only()?;
+                lf.read_cipher(keys)?;
+                lf.holes = self.get_kv().must_lc().discard_stats().holes(*fid);
+                lf.format = lf.detect_format()?.unwrap_or(self.opt.value_log_format);
             }
         }
+        if seal_last {
+            let new_id = max_fid as u32 + 1;
+            vlogs
+                .get(&(max_fid as u32))
+                .unwrap()
+                .write()
+                .await
+                .open_read_only()?;
+            let log_file = self.create_vlog_file(new_id)?;
+            info!("Encrypting the writes in the new value log {}", new_id);
+            vlogs.insert(new_id, TArcRW::new(tokio::sync::RwLock::new(log_file)));
+            self.max_fid.store(new_id, Ordering::Release);
+            self.writable_log_entries.store(0, Ordering::Release);
+        }
         // If no files are found, creating a new file.
         if vlogs.is_empty() {
             let log_file = self.create_vlog_file(0)?;
@@ -591,12 +818,54 @@ impl ValueLogCore {
         Ok(())
     }
 
+    // Cuts the last file `lf` at its end of data, a crash may leave a torn entry or the space
+    // preallocated for the writes after it. A corrupt entry is cut only if `truncate` is set.
+    // Returns the number of entries kept.
+    async fn truncate_corrupt_tail(&self, lf: &mut LogFile) -> Result<u32> {
+        let mut entries = 0;
+        let end = lf
+            .iterate(0, &mut |_, _| {
+                entries += 1;
+                Box::pin(async { Ok(true) })
+            })
+            .await?;
+        let Some(end) = end else {
+            return Ok(entries);
+        };
+        // The preallocated space is no data.
+        if !lf.is_zero_from(end)? {
+            let lost = lf.fd.as_ref().unwrap().metadata()?.len() - end as u64;
+            if !self.opt.truncate {
+                return Err(format!(
+                    "Value log {} is corrupt at offset {}, truncating it would lose the last {} \
+                    bytes of entries. Set Options::truncate to truncate it",
+                    lf._path, end, lost
+                )
+                .into());
+            }
+            warn!(
+                "Truncating value log {} at offset {}, {} bytes are lost",
+                lf._path, end, lost
+            );
+        }
+        let fd = lf.fd.as_mut().unwrap();
+        fd.set_len(end as u64)?;
+        fd.sync_all()?;
+        Ok(entries)
+    }
+
     pub async fn async_read(
         &self,
         vp: &ValuePointer,
         consumer: impl FnMut(&[u8]) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>,
     ) -> Result<()> {
-        // Check for valid offset if we are reading to writable log.
+        self.check_readable(vp)?;
+        self.async_read_bytes(vp, consumer).await?;
+        Ok(())
+    }
+
+    // Check for valid offset if we are reading to writable log.
+    fn check_readable(&self, vp: &ValuePointer) -> Result<()> {
         if vp.fid == self.max_fid.load(Ordering::Acquire)
             && vp.offset >= self.writable_log_offset.load(Ordering::Acquire)
         {
@@ -607,10 +876,42 @@ impl ValueLogCore {
             )
             .into());
         }
-        self.async_read_bytes(vp, consumer).await?;
         Ok(())
     }
 
+    // Returns a reader of the value of `vp` straight from its file, only the entry header is
+    // read here.
+    pub(crate) async fn value_reader(&self, vp: &ValuePointer) -> Result<ValueReader> {
+        self.check_readable(vp)?;
+        let vlog = self.vlogs.get(vp.fid).await.ok_or(Error::ValueRetry)?;
+        let (fd, start, len, cipher) = {
+            let lf = vlog.read().await;
+            let header_size = lf.format.header_size();
+            let mut h = Header::default();
+            h.dec(&mut Cursor::new(
+                lf.read_at(vp.offset, header_size as u32)?.as_ref(),
+            ))?;
+            if (h.meta & MetaBit::BIT_DELETE.bits()) != 0 {
+                // Tombstone key
+                return Ok(ValueReader::from_value(vec![]));
+            }
+            let start = vp.offset as u64 + header_size as u64 + h.k_len as u64;
+            (
+                lf.file_ref().try_clone()?,
+                start,
+                h.v_len as u64,
+                lf.cipher.clone(),
+            )
+        };
+        Ok(ValueReader {
+            buf: Cursor::new(vec![]),
+            file: Some((vlog, fd)),
+            offset: start,
+            end: start + len,
+            cipher,
+        })
+    }
+
     /// Replays the value log. The kv provide is only valid for the lifetime of function call.
     pub async fn replay(
         &self,
@@ -634,7 +935,13 @@ impl ValueLogCore {
                 of = 0;
             }
             let mut log_file = vlogs.vlogs.get(&id).unwrap().write().await;
-            log_file.iterate(of, &mut f).await?;
+            if let Some(end) = log_file.iterate(of, &mut f).await? {
+                return Err(format!(
+                    "Value log {} is corrupt at offset {}, it and the later files can't be replayed",
+                    log_file._path, end
+                )
+                .into());
+            }
         }
         // Seek to the end to start writing.
         let last_file = vlogs
@@ -658,6 +965,50 @@ impl ValueLogCore {
         Ok(())
     }
 
+    // Waits for a running GC to finish and keeps new ones from starting.
+    pub(crate) async fn pause_gc(&self) {
+        self.garbage_ch.send(()).await.unwrap();
+    }
+
+    pub(crate) async fn resume_gc(&self) {
+        self.garbage_ch.recv().await.unwrap();
+    }
+
+    pub(crate) fn has_active_iterators(&self) -> bool {
+        self.num_active_iterators.load(Ordering::Relaxed) > 0
+    }
+
+    // Deletes all the log files and starts over with an empty one, writes and GC must be held
+    // back. Returns the number of files deleted.
+    pub(crate) async fn drop_all(&self) -> Result<usize> {
+        let mut vlogs = self.vlogs.write().await;
+        let count = vlogs.len();
+        // The retired files still held would be deleted when dropped, after the new ones of
+        // the same ids are created.
+        let retired = self.vlogs.take_retired().await;
+        // The stats of the old files would apply to the new ones, cleared before the files
+        // are deleted for a crash in between to only lose them.
+        self.get_kv().must_lc().discard_stats().clear()?;
+        for lf in vlogs.drain().map(|(_, lf)| lf).chain(retired) {
+            let mut lf = lf.write().await;
+            lf._mmap.take();
+            lf.fd.take();
+            if Path::new(lf._path.as_str()).exists() {
+                remove_file(lf._path.as_str())?;
+            }
+        }
+        sync_directory(&self.dir_path)?;
+        self.buf.write().await.get_mut().clear();
+        self.max_fid.store(0, Ordering::Release);
+        let mut log_file = self.create_vlog_file(0)?;
+        log_file.set_write(2 * self.opt.value_log_file_size)?;
+        self.writable_log_offset
+            .store(log_file.data_start(), Ordering::Release);
+        vlogs.insert(0, TArcRW::new(tokio::sync::RwLock::new(log_file)));
+        info!("Dropped {} value log files", count);
+        Ok(count)
+    }
+
     // incr iterator count avoid gc
     pub(crate) fn incr_iterator_count(&self) {
         let lock = self._flock.read().unwrap();
@@ -665,63 +1016,43 @@ impl ValueLogCore {
     }
 
     pub(crate) async fn decr_iterator_count(&self) -> Result<()> {
-        // TODO add share lock.
-        let old = self.num_active_iterators.fetch_sub(1, Ordering::Relaxed);
-        if old != 1 {
-            // the lasted reference
-            return Ok(());
-        }
-        let mut lfs = vec![];
-        for dirty_vlog in self.dirty_vlogs.read().await.iter() {
-            // TODO
-            let vlog = self.vlogs.write().await.remove(dirty_vlog).unwrap();
-            lfs.push(vlog);
-        }
-        self.dirty_vlogs.write().await.clear();
-        for lf in lfs {
-            self.delete_log_file_by_fid(lf).await?;
-        }
+        self.num_active_iterators.fetch_sub(1, Ordering::Relaxed);
         Ok(())
     }
 
-    // Delete log file after no refernece of LogFile
-    async fn delete_log_file_by_fid(&self, log_file: TArcRW<LogFile>) -> Result<()> {
-        let mut vlog_file_wl = log_file.write().await;
-        if let Some(mp) = vlog_file_wl._mmap.take() {
-            mp.get_mut_mmap().flush()?;
-        }
-        if let Some(fp) = vlog_file_wl.fd.take() {
-            fp.sync_all()?;
-        }
-        remove_file(self.fpath(vlog_file_wl.fid))?;
-        Ok(())
+    // The files for an iterator to hold, so the values it has yet to read aren't deleted
+    // by a GC.
+    pub(crate) async fn pin_files(&self) -> Vec<TArcRW<LogFile>> {
+        self.vlogs.pin().await
     }
 
     // sync is thread-unsafe and should not be called concurrently with write.
     pub(crate) async fn sync(&self) -> Result<()> {
         if self.opt.sync_writes {
+            // Every write is synced.
             return Ok(());
         }
-        let _cur_wt_vlog = self
+        let cur_wt_vlog = self
             .pick_log_guard()
             .await
             .vlogs
             .get(&self.max_fid.load(Ordering::Acquire))
-            .unwrap();
-        // TODO add sync directory meta
-        // sync_dir_async()
-        Ok(())
+            .unwrap()
+            .clone();
+        let lf = cur_wt_vlog.read().await;
+        lf.sync_range(0, self.writable_log_offset.load(Ordering::Acquire) as usize)
     }
 
+    // Passes the entry bytes of `vp`, and the format of its file, to `consumer`.
     async fn read_value_bytes(
         &self,
         vp: &ValuePointer,
-        mut consumer: impl FnMut(&[u8]) -> Result<()>,
+        mut consumer: impl FnMut(ValueLogFormat, &[u8]) -> Result<()>,
     ) -> Result<()> {
         let log_file = self.pick_log_by_vlog_id(&vp.fid).await;
         let lf = log_file.read().await;
         let buffer = lf.read(vp)?;
-        consumer(buffer)
+        consumer(lf.format, &buffer)
     }
 
     async fn async_read_bytes(
@@ -729,16 +1060,17 @@ impl ValueLogCore {
         vp: &ValuePointer,
         mut consumer: impl FnMut(&[u8]) -> Pin<Box<dyn Future<Output = Result<()>> + Send>>,
     ) -> Result<()> {
-        let vlog = self.pick_log_by_vlog_id(&vp.fid).await;
-        let buffer = vlog.read().await;
-        let buffer = buffer.read(&vp)?;
+        // The GC may have moved the value and removed the file since the pointer was read.
+        let vlog = self.vlogs.get(vp.fid).await.ok_or(Error::ValueRetry)?;
+        let lf = vlog.read().await;
+        let buffer = lf.read(&vp)?;
         let mut h = Header::default();
-        h.dec(&mut Cursor::new(&buffer[0..Header::encoded_size()]))?;
+        h.dec(&mut Cursor::new(&buffer[0..lf.format.header_size()]))?;
         if (h.meta & MetaBit::BIT_DELETE.bits()) != 0 {
             // Tombstone key
             consumer(&EMPTY_SLICE).await
         } else {
-            let n = Header::encoded_size() + h.k_len as usize;
+            let n = lf.format.header_size() + h.k_len as usize;
             consumer(&buffer[n..n + h.v_len as usize]).await
         }
     }
@@ -754,11 +1086,15 @@ impl ValueLogCore {
         let cur_fid = cur_vlog_wl.fid;
         let reqs_count = reqs.len();
         let mut wt_count = 0;
+        let mut entries = 0;
         for mut req in reqs.into_iter() {
             for (idx, mut entry) in req.entries.into_iter().enumerate() {
-                if !self.opt.sync_writes && entry.entry().value.len() < self.opt.value_threshold {
+                if (!self.opt.sync_writes || self.opt.mem_table_wal)
+                    && self.opt.value_in_lsm(entry.entry().value.len())
+                {
                     // No need to write to value log.
-                    // WARN: if mt not flush into disk but process abort, that will discard data(the data not write into vlog that WAL file)
+                    // WARN: without the memtable WAL, a crash before the memtable is flushed
+                    // loses the value.
                     req.ptrs[idx] = Arc::new(Atomic::new(None));
                     continue;
                 }
@@ -779,7 +1115,9 @@ impl ValueLogCore {
                     + self.buf.read().await.get_ref().len() as u32;
                 let mut buf = self.buf.write().await;
                 let entry = entry.mut_entry();
-                let sz = entry.enc(&mut buf.get_mut()).unwrap();
+                let sz = entry
+                    .enc_with(cur_vlog_wl.format, &mut buf.get_mut())
+                    .unwrap();
                 wt_count += sz;
                 ptr.len = sz as u32;
                 assert_eq!(
@@ -787,6 +1125,7 @@ impl ValueLogCore {
                     ptr.offset as usize + sz
                 );
                 req.ptrs[idx].store(Some(ptr), Ordering::Release);
+                entries += 1;
             }
         }
         {
@@ -799,6 +1138,9 @@ impl ValueLogCore {
             let offset = self.writable_log_offset.load(Ordering::Acquire);
             let n = cur_vlog_wl.write_buffer(buffer.get_ref(), offset as usize)?;
             assert_eq!(n, buffer.get_ref().len());
+            if self.opt.sync_writes {
+                cur_vlog_wl.sync_range(offset as usize, n)?;
+            }
             // todo add metrics
             // update log
             self.writable_log_offset
@@ -813,21 +1155,119 @@ impl ValueLogCore {
             );
             // clear buffer
             buffer.get_mut().clear();
-            if self.writable_log_offset.load(Ordering::Acquire)
-                > self.opt.value_log_file_size as u32
+            let offset = self.writable_log_offset.load(Ordering::Acquire);
+            let entries = self
+                .writable_log_entries
+                .fetch_add(entries, Ordering::AcqRel)
+                + entries;
+            if offset > self.opt.value_log_file_size as u32
+                || entries >= self.opt.value_log_max_entries
             {
-                cur_vlog_wl.done_writing(self.writable_log_offset.load(Ordering::Acquire))?;
-                let new_id = self.max_fid.fetch_add(1, Ordering::Release);
-                assert!(new_id < 1 << 16, "new_id will overflow u16: {}", new_id);
-                *cur_vlog_wl =
-                    self.create_mmap_vlog_file(new_id, 2 * self.opt.value_log_file_size)?;
+                cur_vlog_wl.done_writing(offset)?;
+                // Readers lock the files after the map, not the other way around.
+                drop(cur_vlog_wl);
+                self.rotate().await?;
             }
             Ok(())
         }
     }
 
+    // Frees the disk blocks of the stale ranges of the file `lf`, the file keeps its size and
+    // the ranges are skipped when it's read through. Returns the bytes freed, nothing is freed
+    // if the file system can't punch holes or iterators may still read old values.
+    async fn punch_holes(&self, lf: &TArcRW<LogFile>) -> Result<u64> {
+        if self.has_active_iterators() {
+            return Ok(0);
+        }
+        let discard_stats = self.get_kv().must_lc().discard_stats();
+        let mut lf = lf.write().await;
+        // The files other than the one written are opened read only.
+        let fd = std::fs::OpenOptions::new()
+            .write(true)
+            .open(lf._path.as_ref())?;
+        let mut punched = vec![];
+        let mut freed = 0;
+        for (start, end) in discard_stats.stale_ranges(lf.fid) {
+            // Only the whole blocks in the range are freed.
+            let from = (start as u64 + HOLE_ALIGN - 1) / HOLE_ALIGN * HOLE_ALIGN;
+            let to = end as u64 / HOLE_ALIGN * HOLE_ALIGN;
+            if to <= from {
+                continue;
+            }
+            if !punch_hole(&fd, from, to - from)? {
+                info!("Can't punch holes in value log {}", lf._path);
+                break;
+            }
+            freed += to - from;
+            punched.push((start, end));
+        }
+        discard_stats.set_punched(lf.fid, &punched)?;
+        lf.holes.extend(punched);
+        if freed > 0 {
+            info!("Punched {} bytes of holes in value log {}", freed, lf._path);
+        }
+        Ok(freed)
+    }
+
+    /// Returns the space usage of every file, by id.
+    pub(crate) async fn info(&self) -> Vec<ValueLogInfo> {
+        let discard_stats = self.get_kv().must_lc().discard_stats();
+        let max_fid = self.max_fid.load(Ordering::Acquire);
+        let vlogs = self.vlogs.read().await;
+        let mut info = Vec::with_capacity(vlogs.len());
+        for (fid, lf) in vlogs.iter() {
+            let writable = *fid == max_fid;
+            let size = if writable {
+                self.writable_log_offset.load(Ordering::Acquire)
+            } else {
+                lf.read().await.sz
+            };
+            info.push(ValueLogInfo {
+                fid: *fid,
+                size: size as u64,
+                stale: discard_stats.get(*fid),
+                punched: discard_stats.punched(*fid),
+                writable,
+            });
+        }
+        info.sort_by_key(|file| file.fid);
+        info
+    }
+
+    // Verifies the entries of every file, up to where the writable one is written, on
+    // blocking threads. Returns the files checked and the corrupt ones with the offset of
+    // their first corrupt entry.
+    pub(crate) async fn verify_checksum(&self) -> Result<(usize, Vec<(u32, u32)>)> {
+        let max_fid = self.max_fid.load(Ordering::Acquire);
+        let written = self.writable_log_offset.load(Ordering::Acquire);
+        let files = self.pin_files().await;
+        let checks = files
+            .iter()
+            .map(|lf| {
+                let lf = lf.clone();
+                tokio::task::spawn_blocking(move || {
+                    let lf = lf.blocking_read();
+                    let end = if lf.fid == max_fid { written } else { lf.sz };
+                    lf.verify(end).map(|corrupt| (lf.fid, corrupt))
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut corrupt = vec![];
+        for check in checks {
+            let (fid, offset) = check
+                .await
+                .map_err(|err| format!("Value log verification panicked, {}", err))??;
+            if let Some(offset) = offset {
+                corrupt.push((fid, offset));
+            }
+        }
+        corrupt.sort();
+        Ok((files.len(), corrupt))
+    }
+
     // rewrite the log_file
-    async fn rewrite(&self, lf: TArcRW<LogFile>, _x: &KVCore) -> Result<()> {
+    #[cfg_attr(feature = "tracing-spans", tracing::instrument(skip_all))]
+    pub(crate) async fn rewrite(&self, lf: TArcRW<LogFile>, _x: &KVCore) -> Result<()> {
         let max_fid = self.max_fid.load(Ordering::Relaxed);
         assert!(
             lf.read().await.fid < max_fid,
@@ -842,18 +1282,18 @@ impl ValueLogCore {
         let kv = self.get_kv();
         let mut write_batch = Vec::with_capacity(1000);
         loop {
-            let (mut entries, next) = lf.read().await.read_entries(offset, 1).await?;
+            let (entries, next) = lf.read().await.read_entries(offset, 1).await?;
             if entries.is_empty() {
                 info!("not anything need to rewrite");
                 break;
             }
-            offset += next;
+            offset = next;
             count += 1;
             if count % 1000 == 0 {
                 info!("Processing entry {}", count);
             }
             // TODO don't need decode vptr
-            let entry = &mut entries[0].0;
+            let entry = &entries[0].0;
             let vs = kv._get(&entry.key);
             if let Err(ref err) = vs {
                 if err.is_not_found() {
@@ -874,7 +1314,7 @@ impl ValueLogCore {
                 );
                 continue;
             }
-            if (vs.meta & MetaBit::BIT_VALUE_POINTER.bits()) < 0 {
+            if (vs.meta & MetaBit::BIT_VALUE_POINTER.bits()) == 0 {
                 info!(
                     "REWRITE=> {} has been skipped, meta: {}",
                     String::from_utf8_lossy(&entry.key),
@@ -901,59 +1341,56 @@ impl ValueLogCore {
             assert_eq!(vptr.fid, lf.read().await.fid);
             assert_eq!(vptr.offset, entry.offset);
             {
-                // This new entry only contains the key, and a pointer to the value.
-                let mut ne = Entry::default();
-                // TODO why?
-                if entry.meta == MetaBit::BIT_SET_IF_ABSENT.bits() {
-                    // If we rewrite this entry without removing BitSetIfAbsent, LSM would see that
-                    // the key is already present, which would be this same entry and won't update
-                    // the vptr to point to the new file.
-                    entry.meta = 0;
-                }
-                assert_eq!(entry.meta, 0, "Got meta: 0");
-                ne.meta = entry.meta;
-                ne.user_meta = entry.user_meta;
-                ne.key = entry.key.clone(); // TODO avoid copy
-                ne.value = entry.value.clone();
-                // CAS counter check. Do not rewrite if key has a newer value.
-                ne.cas_counter_check = vs.cas_counter;
+                // The value is written again through the write path, marked moved. It keeps
+                // its CAS counter, so readers holding the old pointer find it again, see
+                // `KV::yield_item_value`, and CAS checks against it still pass.
+                //
+                // If we rewrite this entry with BitSetIfAbsent, LSM would see that the key is
+                // already present, which would be this same entry and won't update the vptr to
+                // point to the new file.
+                let ne = Entry::default()
+                    .key(entry.key.clone()) // TODO avoid copy
+                    .value(entry.value.clone())
+                    .meta(
+                        MetaBit::BIT_MOVE.bits()
+                            | (vs.meta & MetaBit::BIT_DISCARD_EARLIER_VERSIONS.bits()),
+                    )
+                    .user_meta(entry.user_meta)
+                    .expires_at(vs.expires_at)
+                    .cas_counter(vs.cas_counter)
+                    // CAS counter check. Do not rewrite if key has a newer value.
+                    .cas_counter_check(vs.cas_counter);
                 write_batch.push(ne);
             }
         }
         if write_batch.is_empty() {
             info!("REWRITE: nothing to rewrite.");
-            return Ok(());
+        } else {
+            info!(
+                "REWRITE: request has {} entries, size {}",
+                write_batch.len(),
+                count
+            );
+            for res in kv.batch_set(write_batch).await {
+                match res {
+                    // The key has a newer value, the moved one is stale.
+                    Ok(()) | Err(Error::ValueCasMisMatch) => {}
+                    // The file is kept, some of its values may not be moved.
+                    Err(err) => return Err(err),
+                }
+            }
         }
-        info!(
-            "REWRITE: request has {} entries, size {}",
-            write_batch.len(),
-            count
-        );
-        info!("REWRITE: Removing fid: {}", lf.read().await.fid);
-        kv.batch_set(write_batch).await;
+        // The live values went to a later file.
+        self.get_kv()
+            .must_lc()
+            .discard_stats()
+            .remove(lf.read().await.fid)?;
         info!("REWRITE: Processed {} entries in total", count);
         info!("REWRITE: Removing fid: {}", lf.read().await.fid);
-        let mut deleted_file_now = false;
-        // Entries written to LSM. Remove the older file now.
-        {
-            // Just a sanity-check.
-            let mut vlogs = self.vlogs.write().await;
-            if !vlogs.contains_key(&lf.read().await.fid) {
-                return Err(format!("Unable to find fid: {}", lf.read().await.fid).into());
-            }
-            // TODO Why?
-            if self.num_active_iterators.load(Ordering::Relaxed) == 0 {
-                vlogs.remove(&lf.read().await.fid);
-                deleted_file_now = true;
-            } else {
-                self.dirty_vlogs
-                    .write()
-                    .await
-                    .insert(lf.read().await.fid.clone());
-            }
-        }
-        if deleted_file_now {}
-        Ok(())
+        // Entries written to LSM. The older file is deleted once the readers holding it,
+        // `lf` included, are done.
+        let fid = lf.read().await.fid;
+        self.vlogs.retire(fid).await
     }
 
     async fn pick_log(&self) -> Option<TArcRW<LogFile>> {
@@ -972,22 +1409,28 @@ impl ValueLogCore {
         Some(vlog.clone())
     }
 
+    // Picks the file with the most stale bytes in the discard stats, and its stale bytes.
+    async fn pick_log_by_discard(&self) -> Option<(TArcRW<LogFile>, u64)> {
+        let vlogs_guard = self.pick_log_guard().await;
+        let max_fid = self.max_fid.load(Ordering::Acquire);
+        let fids = vlogs_guard
+            .fids
+            .iter()
+            .filter(|fid| **fid < max_fid)
+            .copied()
+            .collect::<Vec<_>>();
+        let (fid, discarded) = self.get_kv().must_lc().discard_stats().max_discard(&fids)?;
+        Some((vlogs_guard.vlogs.get(&fid).unwrap().clone(), discarded))
+    }
+
     pub(crate) async fn pick_log_by_vlog_id(&self, id: &u32) -> TArcRW<LogFile> {
-        let pick_vlogs = self.pick_log_guard().await;
-        let vlogs = pick_vlogs.vlogs.get(id);
-        let vlog = vlogs.unwrap();
-        vlog.clone()
+        self.vlogs.get(*id).await.unwrap()
     }
 
-    // Note: it not including dirty file
+    // Note: it not including retired files
     async fn pick_log_guard(&self) -> PickVlogsGuardsReadLock {
         let vlogs = self.vlogs.read().await;
-        let vlogs_fids = vlogs.keys().map(|fid| *fid).collect::<HashSet<_>>();
-        let dirty_vlogs = self.dirty_vlogs.read().await;
-        let mut fids = vlogs_fids
-            .difference(&*dirty_vlogs)
-            .map(|fid| *fid)
-            .collect::<Vec<_>>();
+        let mut fids = vlogs.keys().map(|fid| *fid).collect::<Vec<_>>();
         fids.sort();
         PickVlogsGuardsReadLock { vlogs, fids }
     }
@@ -1046,7 +1489,31 @@ impl ValueLogCore {
     }
 
     /// Running Gc
+    #[cfg_attr(feature = "tracing-spans", tracing::instrument(
+        name = "vlog_gc",
+        skip_all,
+        fields(discard_ratio = gc_threshold)
+    ))]
     pub async fn do_run_gc(&self, gc_threshold: f64) -> Result<()> {
+        // The discard stats tell how much of a file is stale, no need to sample it.
+        if let Some((lf, discarded)) = self.pick_log_by_discard().await {
+            let (fid, size) = {
+                let lf = lf.read().await;
+                (lf.fid, lf.sz as u64)
+            };
+            info!("Fid: {} Discarded: {} of {} bytes", fid, discarded, size);
+            if (discarded as f64) < gc_threshold * size as f64 {
+                // Not worth a rewrite, the stale ranges can still be freed.
+                if self.punch_holes(&lf).await? > 0 {
+                    return Ok(());
+                }
+                info!("Skipping GC on fid: {}", fid);
+                return Err(Error::ValueNoRewrite);
+            }
+            info!("REWRITING VLOG {}", fid);
+            return self.rewrite(lf, self.get_kv()).await;
+        }
+        // No stats yet, sample a random file.
         #[derive(Debug, Default)]
         struct Reason {
             total: f64,
@@ -1141,9 +1608,8 @@ impl ValueLogCore {
                     // TODO Maybe abort gc process, it should be happen
                     info!("Reason={:?}", reason);
                     let err = self
-                        .read_value_bytes(&vptr, |buf| {
-                            let mut unexpect_entry = Entry::default();
-                            unexpect_entry.dec(&mut io::Cursor::new(buf))?;
+                        .read_value_bytes(&vptr, |format, buf| {
+                            let mut unexpect_entry = Entry::from_slice(format, 0, buf)?;
                             unexpect_entry.offset = vptr.offset;
                             if unexpect_entry.get_cas_counter() == entry.get_cas_counter() {
                                 info!("Latest Entry Header in LSM: {}", unexpect_entry);
@@ -1180,6 +1646,187 @@ struct PickVlogsGuardsReadLock<'a> {
     fids: Vec<u32>,
 }
 
+/// Space usage of a value log file, see `KV::value_log_info`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValueLogInfo {
+    pub fid: u32,
+    /// Bytes written to the file.
+    pub size: u64,
+    /// Bytes of the values compactions found deleted or superseded, punched ones included.
+    pub stale: u64,
+    /// Bytes of the stale values whose space was given back by punching holes.
+    pub punched: u64,
+    /// True for the file being written to, it isn't garbage collected.
+    pub writable: bool,
+}
+
+impl ValueLogInfo {
+    /// Bytes of the values still in use.
+    pub fn live(&self) -> u64 {
+        self.size.saturating_sub(self.stale)
+    }
+
+    /// Bytes a GC of the file would give back.
+    pub fn reclaimable(&self) -> u64 {
+        if self.writable {
+            return 0;
+        }
+        self.stale.saturating_sub(self.punched)
+    }
+}
+
+/// Streams a value, see `KVItem::value_reader`. A value in the value log is read from its
+/// file as it's consumed, and the file is held, a GC deletes it only once the reader is
+/// dropped. The entry checksum isn't verified, it covers the whole entry.
+pub struct ValueReader {
+    // The value, if it's in the LSM tree or was prefetched.
+    buf: Cursor<Vec<u8>>,
+    file: Option<(TArcRW<LogFile>, File)>,
+    // The range of the value in the file left to read.
+    offset: u64,
+    end: u64,
+    // Decrypts the bytes read from an encrypted file.
+    cipher: Option<FileCipher>,
+}
+
+impl ValueReader {
+    pub(crate) fn from_value(value: Vec<u8>) -> ValueReader {
+        ValueReader {
+            buf: Cursor::new(value),
+            file: None,
+            offset: 0,
+            end: 0,
+            cipher: None,
+        }
+    }
+
+    /// The bytes left to read.
+    pub fn remaining(&self) -> u64 {
+        match self.file {
+            Some(_) => self.end - self.offset,
+            None => self.buf.get_ref().len() as u64 - self.buf.position(),
+        }
+    }
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some((_, fd)) = self.file.as_ref() else {
+            return self.buf.read(buf);
+        };
+        let n = buf.len().min((self.end - self.offset) as usize);
+        if n == 0 {
+            return Ok(0);
+        }
+        let n = read_at(fd, &mut buf[..n], self.offset)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if let Some(cipher) = &self.cipher {
+            cipher
+                .xor(self.offset, &mut buf[..n])
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        }
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Reads the value log files of a value dir on their own, without opening the KV, e.g. to
+/// audit, recover or migrate them. The files are only read, punched holes are skipped.
+pub struct ValueLogReader {
+    vlogs: BTreeMap<u32, LogFile>,
+}
+
+impl ValueLogReader {
+    /// Opens the files of `value_dir`, they must be plain.
+    pub fn open(value_dir: &str) -> Result<ValueLogReader> {
+        Self::open_with_keys(value_dir, &KeyRegistry::default())
+    }
+
+    /// Opens the files of `value_dir`, the encrypted ones are decrypted with the data keys of
+    /// the database dir `dir` and its master key, see `Options::encryption_key`.
+    pub fn open_encrypted(value_dir: &str, dir: &str, master_key: &[u8]) -> Result<ValueLogReader> {
+        if master_key.is_empty() {
+            return Err("The master key is needed to read encrypted value logs".into());
+        }
+        if !Path::new(dir).join(KEY_REGISTRY_FILE_NAME).exists() {
+            return Err(format!("No data keys in {}, it isn't encrypted", dir).into());
+        }
+        let keys = KeyRegistry::open(dir, master_key, Duration::MAX)?;
+        Self::open_with_keys(value_dir, &keys)
+    }
+
+    fn open_with_keys(value_dir: &str, keys: &KeyRegistry) -> Result<ValueLogReader> {
+        let mut vlog_files = ValueLogCore::get_data_files(value_dir)?;
+        let fids = ValueLogCore::parse_file_ids(&mut vlog_files)?;
+        let discard_stats = DiscardStats::open(value_dir)?;
+        let mut vlogs = BTreeMap::new();
+        for fid in fids {
+            let fid = fid as u32;
+            let mut lf = LogFile::new(&ValueLogCore::vlog_file_path(value_dir, fid))?;
+            lf.fid = fid;
+            lf.read_cipher(keys)?;
+            lf.holes = discard_stats.holes(fid);
+            lf.format = lf.detect_format()?.unwrap_or(ValueLogFormat::Native);
+            vlogs.insert(fid, lf);
+        }
+        Ok(ValueLogReader { vlogs })
+    }
+
+    /// The ids of the value log files, in order.
+    pub fn fids(&self) -> Vec<u32> {
+        self.vlogs.keys().copied().collect()
+    }
+
+    /// Passes every entry after `from`, and its value pointer, to `f` until it returns false.
+    /// `ValuePointer::default()` replays all the files. A file ends at its first all zero tail,
+    /// e.g. the space preallocated by a running KV. Errors on a torn or corrupt entry, the
+    /// entries before it are passed to `f`.
+    pub async fn replay(
+        &mut self,
+        from: &ValuePointer,
+        mut f: impl for<'a> FnMut(
+            &'a Entry,
+            &'a ValuePointer,
+        ) -> Pin<Box<dyn Future<Output = Result<bool>> + 'a>>,
+    ) -> Result<()> {
+        // `iterate` doesn't tell whether `f` stopped it.
+        let stopped = Arc::new(AtomicBool::new(false));
+        for (fid, lf) in self.vlogs.range_mut(from.fid..) {
+            let offset = if *fid == from.fid {
+                from.offset + from.len
+            } else {
+                0
+            };
+            let end = lf
+                .iterate(offset, &mut |entry, vp| {
+                    let fut = f(entry, vp);
+                    let stopped = stopped.clone();
+                    Box::pin(async move {
+                        let more = fut.await?;
+                        stopped.store(!more, Ordering::Release);
+                        Ok(more)
+                    })
+                })
+                .await?;
+            // The space preallocated for the writes is no data, as in `truncate_corrupt_tail`.
+            if let Some(end) = end {
+                if !lf.is_zero_from(end)? {
+                    return Err(
+                        format!("Value log {} is corrupt at offset {}", lf._path, end).into(),
+                    );
+                }
+            }
+            if stopped.load(Ordering::Acquire) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[test]
 fn t_value_vptr_size() {
     let mut vpr = ValuePointer::default();
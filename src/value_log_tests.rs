@@ -10,7 +10,7 @@ use std::sync::Arc;
 fn new_test_options(dir: String) -> Options {
     let mut opt = Options::default();
     opt.max_table_size = 1 << 15; // Force more compaction
-    opt.level_one_size = 4 << 15; // Force more compaction.
+    opt.base_level_size = 4 << 15; // Force more compaction.
     opt.dir = Box::new(dir.clone());
     opt.value_dir = Box::new(dir.clone());
     return opt;
@@ -0,0 +1,80 @@
+use crate::log_file::LogFile;
+use crate::types::TArcRW;
+use crate::Result;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Weak};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// The value log files by id. A reader holds the `TArcRW<LogFile>` it got for as long as it
+/// reads the file, iterators hold all the files there were when they were created, see `pin`.
+/// A retired file is no longer handed out to new readers, and is deleted when the last
+/// reference to it drops.
+#[derive(Default)]
+pub(crate) struct ValueLog {
+    files: RwLock<HashMap<u32, TArcRW<LogFile>>>,
+    // Retired files, still readable by id while someone holds them, e.g. the values an
+    // iterator has yet to read.
+    retired: Mutex<HashMap<u32, Weak<RwLock<LogFile>>>>,
+}
+
+impl ValueLog {
+    pub(crate) async fn read(&self) -> RwLockReadGuard<'_, HashMap<u32, TArcRW<LogFile>>> {
+        self.files.read().await
+    }
+
+    pub(crate) async fn write(&self) -> RwLockWriteGuard<'_, HashMap<u32, TArcRW<LogFile>>> {
+        self.files.write().await
+    }
+
+    /// The file `fid`, a retired one too while it isn't deleted.
+    pub(crate) async fn get(&self, fid: u32) -> Option<TArcRW<LogFile>> {
+        if let Some(lf) = self.files.read().await.get(&fid) {
+            return Some(lf.clone());
+        }
+        self.retired.lock().get(&fid).and_then(Weak::upgrade)
+    }
+
+    /// All the files, for an iterator to hold while it's open.
+    pub(crate) async fn pin(&self) -> Vec<TArcRW<LogFile>> {
+        self.files.read().await.values().cloned().collect()
+    }
+
+    /// Takes the file `fid` out, it's deleted once the last reference to it drops.
+    pub(crate) async fn retire(&self, fid: u32) -> Result<()> {
+        let mut files = self.files.write().await;
+        let Some(lf) = files.get(&fid).cloned() else {
+            return Err(format!("Unable to find fid: {}", fid).into());
+        };
+        lf.read()
+            .await
+            .delete_on_drop
+            .store(true, Ordering::Release);
+        {
+            let mut retired = self.retired.lock();
+            retired.retain(|_, lf| lf.strong_count() > 0);
+            // Readers find it retired before it's gone from the files.
+            retired.insert(fid, Arc::downgrade(&lf));
+        }
+        files.remove(&fid);
+        Ok(())
+    }
+
+    /// Takes out the retired files still held, they're no longer deleted when dropped.
+    pub(crate) async fn take_retired(&self) -> Vec<TArcRW<LogFile>> {
+        let retired = self
+            .retired
+            .lock()
+            .drain()
+            .filter_map(|(_, lf)| lf.upgrade())
+            .collect::<Vec<_>>();
+        for lf in retired.iter() {
+            lf.read()
+                .await
+                .delete_on_drop
+                .store(false, Ordering::Release);
+        }
+        retired
+    }
+}
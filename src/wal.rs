@@ -0,0 +1,213 @@
+use crate::table::encryption::{DataKey, FileCipher, KeyRegistry, FILE_HEADER_SIZE};
+use crate::y::{create_synced_file, sync_directory, Result, ValueStruct};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use log::{info, warn};
+use std::fs::{read_dir, remove_file, File};
+use std::io::{BufWriter, Cursor, Write};
+use std::path::Path;
+
+const MEM_FILE_SUFFIX: &str = ".mem";
+
+/// The write-ahead log of a memtable, see `Options::mem_table_wal`. Every entry put into the
+/// memtable is appended, inline values and value pointers alike, so the memtable can be
+/// rebuilt on open. The file is deleted once the memtable is flushed to level 0. With a data
+/// key, the entries are encrypted and the file starts with the header of its cipher.
+pub(crate) struct MemTableWal {
+    id: u32,
+    file_name: String,
+    wt: BufWriter<File>,
+    cipher: Option<FileCipher>,
+    // The file offset of the next entry.
+    offset: u64,
+}
+
+impl MemTableWal {
+    pub(crate) fn create(dir: &str, id: u32, data_key: Option<DataKey>) -> Result<MemTableWal> {
+        let file_name = Self::file_name(dir, id);
+        let fd = create_synced_file(&file_name, false)?;
+        let mut wt = BufWriter::new(fd);
+        let cipher = data_key.map(FileCipher::new);
+        if let Some(cipher) = &cipher {
+            wt.write_all(&cipher.header())?;
+        }
+        sync_directory(dir)?;
+        Ok(MemTableWal {
+            id,
+            file_name,
+            wt,
+            offset: cipher.as_ref().map_or(0, |_| FILE_HEADER_SIZE as u64),
+            cipher,
+        })
+    }
+
+    pub(crate) fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn file_name(dir: &str, id: u32) -> String {
+        Path::new(dir)
+            .join(format!("{:06}{}", id, MEM_FILE_SUFFIX))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    // Layout: key_len(u32) | value_len(u32) | key | value struct | crc32 of all before
+    pub(crate) fn append(&mut self, key: &[u8], value: &ValueStruct) -> Result<()> {
+        let value: Vec<u8> = value.into();
+        let mut buf = Vec::with_capacity(8 + key.len() + value.len() + 4);
+        buf.write_u32::<BigEndian>(key.len() as u32)?;
+        buf.write_u32::<BigEndian>(value.len() as u32)?;
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&value);
+        let crc = crc32fast::hash(&buf);
+        buf.write_u32::<BigEndian>(crc)?;
+        if let Some(cipher) = &self.cipher {
+            cipher.xor(self.offset, &mut buf)?;
+        }
+        self.wt.write_all(&buf)?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+
+    /// Writes the buffered entries to the file, and syncs it if `fsync`.
+    pub(crate) fn sync(&mut self, fsync: bool) -> Result<()> {
+        self.wt.flush()?;
+        if fsync {
+            self.wt.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// The ids of the WAL files in `dir`, in order.
+    pub(crate) fn list(dir: &str) -> Result<Vec<u32>> {
+        let mut ids = vec![];
+        for entry in read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if let Some(id) = name.strip_suffix(MEM_FILE_SUFFIX) {
+                if let Ok(id) = id.parse() {
+                    ids.push(id);
+                }
+            }
+        }
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    /// Reads the entries of the WAL file `id`, decrypted with their data key of `keys` if it's
+    /// encrypted. A torn or corrupt tail, a crash while it was being written, ends the entries.
+    pub(crate) fn read(
+        dir: &str,
+        id: u32,
+        keys: &KeyRegistry,
+    ) -> Result<Vec<(Vec<u8>, ValueStruct)>> {
+        let file_name = Self::file_name(dir, id);
+        let mut buf = std::fs::read(&file_name)?;
+        let mut entries = vec![];
+        let mut offset = 0;
+        if let Some(cipher) = FileCipher::read_header(&buf, keys)? {
+            offset = FILE_HEADER_SIZE;
+            cipher.xor(offset as u64, &mut buf[offset..])?;
+        }
+        while offset + 8 <= buf.len() {
+            let mut rd = Cursor::new(&buf[offset..]);
+            let k_len = rd.read_u32::<BigEndian>()? as usize;
+            let v_len = rd.read_u32::<BigEndian>()? as usize;
+            let end = offset + 8 + k_len + v_len;
+            if v_len < ValueStruct::header_size() || end + 4 > buf.len() {
+                break;
+            }
+            let crc = (&buf[end..]).read_u32::<BigEndian>()?;
+            if crc32fast::hash(&buf[offset..end]) != crc {
+                break;
+            }
+            let key = buf[offset + 8..offset + 8 + k_len].to_vec();
+            entries.push((key, ValueStruct::from(&buf[offset + 8 + k_len..end])));
+            offset = end + 4;
+        }
+        if offset < buf.len() {
+            warn!(
+                "WAL {} has a torn or corrupt tail at offset {}, {} bytes are dropped",
+                file_name,
+                offset,
+                buf.len() - offset
+            );
+        }
+        Ok(entries)
+    }
+
+    /// Deletes the WAL files `ids`.
+    pub(crate) fn remove(dir: &str, ids: &[u32]) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        for id in ids {
+            let file_name = Self::file_name(dir, *id);
+            if Path::new(&file_name).exists() {
+                remove_file(&file_name)?;
+            }
+        }
+        sync_directory(dir)?;
+        info!("Removed the WALs {:?}", ids);
+        Ok(())
+    }
+}
+
+#[test]
+fn wal_torn_tail() {
+    let dir = crate::test_util::create_random_tmp_dir();
+    let mut wal = MemTableWal::create(&dir, 3, None).unwrap();
+    for i in 0..10u8 {
+        let value = ValueStruct::new(vec![i; i as usize], 0, i, i as u64).with_expires_at(7);
+        wal.append(&[b'k', i], &value).unwrap();
+    }
+    wal.sync(true).unwrap();
+    drop(wal);
+    assert_eq!(MemTableWal::list(&dir).unwrap(), vec![3]);
+    let entries = MemTableWal::read(&dir, 3, &KeyRegistry::default()).unwrap();
+    assert_eq!(entries.len(), 10);
+    assert_eq!(entries[4].0, vec![b'k', 4]);
+    assert_eq!(
+        entries[4].1,
+        ValueStruct::new(vec![4; 4], 0, 4, 4).with_expires_at(7)
+    );
+
+    // A crash in the middle of the last entry.
+    let file_name = MemTableWal::file_name(&dir, 3);
+    let len = std::fs::metadata(&file_name).unwrap().len();
+    let fd = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&file_name)
+        .unwrap();
+    fd.set_len(len - 3).unwrap();
+    let entries = MemTableWal::read(&dir, 3, &KeyRegistry::default()).unwrap();
+    assert_eq!(entries.len(), 9);
+
+    MemTableWal::remove(&dir, &[3]).unwrap();
+    assert!(MemTableWal::list(&dir).unwrap().is_empty());
+}
+
+#[cfg(feature = "encryption")]
+#[test]
+fn wal_encrypted() {
+    let dir = crate::test_util::create_random_tmp_dir();
+    let keys = KeyRegistry::default();
+    let data_key = DataKey {
+        id: 1,
+        key: vec![2; 32],
+    };
+    keys.add(data_key.clone());
+    let mut wal = MemTableWal::create(&dir, 0, Some(data_key)).unwrap();
+    for i in 0..10u8 {
+        let value = ValueStruct::new(b"secret".to_vec(), 0, 0, i as u64);
+        wal.append(&[b'k', i], &value).unwrap();
+    }
+    wal.sync(true).unwrap();
+    drop(wal);
+    let data = std::fs::read(MemTableWal::file_name(&dir, 0)).unwrap();
+    assert!(!data.windows(6).any(|w| w == b"secret"));
+    let entries = MemTableWal::read(&dir, 0, &keys).unwrap();
+    assert_eq!(entries.len(), 10);
+    assert_eq!(entries[7].0, vec![b'k', 7]);
+    assert_eq!(entries[7].1.value, b"secret".to_vec());
+    assert!(MemTableWal::read(&dir, 0, &KeyRegistry::default()).is_err());
+}
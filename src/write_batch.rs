@@ -0,0 +1,112 @@
+use crate::kv::KV;
+use crate::value_log::{Entry, MetaBit};
+use crate::{Error, Result};
+use tokio::task::JoinHandle;
+
+/// Batches writes for bulk loading, see `KV::new_write_batch`. The entries are written in
+/// chunks of at most `Options::max_batch_count` entries or `Options::max_batch_size` bytes,
+/// a chunk is written in background while the next one is filled. There's no CAS check:
+/// a later entry of a key overwrites the earlier ones.
+///
+/// Once a chunk fails, the batch keeps its error and no more entries are written.
+pub struct WriteBatch {
+    kv: KV,
+    entries: Vec<Entry>,
+    size: u64,
+    // The chunk being written.
+    pending: Option<JoinHandle<Result<()>>>,
+    err: Option<Error>,
+    cancelled: bool,
+}
+
+impl WriteBatch {
+    pub(crate) fn new(kv: KV) -> WriteBatch {
+        WriteBatch {
+            kv,
+            entries: vec![],
+            size: 0,
+            pending: None,
+            err: None,
+            cancelled: false,
+        }
+    }
+
+    /// Sets `value` of `key`.
+    pub async fn set(&mut self, key: Vec<u8>, value: Vec<u8>, user_meta: u8) -> Result<()> {
+        let entry = Entry::default().key(key).value(value).user_meta(user_meta);
+        self.set_entry(entry).await
+    }
+
+    /// Deletes `key`.
+    pub async fn delete(&mut self, key: Vec<u8>) -> Result<()> {
+        let entry = Entry::default().key(key).meta(MetaBit::BIT_DELETE.bits());
+        self.set_entry(entry).await
+    }
+
+    /// Adds `entry`, it's written when its chunk is full or on `flush`. Returns the error of a
+    /// chunk written before.
+    pub async fn set_entry(&mut self, entry: Entry) -> Result<()> {
+        self.check()?;
+        self.size += self.kv.opt.estimate_size(&entry) as u64;
+        self.entries.push(entry);
+        if self.entries.len() as u64 >= self.kv.opt.max_batch_count
+            || self.size >= self.kv.opt.max_batch_size
+        {
+            self.commit().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the entries left and waits for all of them to be written.
+    pub async fn flush(mut self) -> Result<()> {
+        self.check()?;
+        if !self.entries.is_empty() {
+            self.commit().await?;
+        }
+        self.wait().await?;
+        self.check()
+    }
+
+    /// Drops the entries not written yet, and waits for the chunk being written. The batch
+    /// can't be used after.
+    pub async fn cancel(&mut self) {
+        self.cancelled = true;
+        self.entries.clear();
+        self.size = 0;
+        let _ = self.wait().await;
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.cancelled {
+            return Err(Error::WriteBatchCancelled);
+        }
+        match &self.err {
+            Some(err) => Err(err.clone()),
+            None => Ok(()),
+        }
+    }
+
+    // Writes the entries as a chunk, once the previous one is written.
+    async fn commit(&mut self) -> Result<()> {
+        self.wait().await?;
+        let entries = std::mem::take(&mut self.entries);
+        self.size = 0;
+        let kv = self.kv.clone();
+        self.pending = Some(tokio::spawn(async move {
+            kv.batch_set(entries).await.into_iter().collect()
+        }));
+        Ok(())
+    }
+
+    async fn wait(&mut self) -> Result<()> {
+        if let Some(pending) = self.pending.take() {
+            let res = pending
+                .await
+                .unwrap_or_else(|err| Err(format!("Write batch panicked, {}", err).into()));
+            if let Err(err) = res {
+                self.err.get_or_insert(err);
+            }
+        }
+        self.check()
+    }
+}
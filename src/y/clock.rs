@@ -0,0 +1,27 @@
+use std::fmt::{Debug, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the time that `expires_at` of values is compared to, in unix seconds. It's
+/// set in `Options::clock`, tests use their own to expire values without waiting.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+impl Debug for dyn Clock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Clock({})", self.now())
+    }
+}
+
+/// The system time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+}
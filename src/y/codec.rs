@@ -21,3 +21,49 @@ where
     async fn enc(&self, wt: &mut W) -> Result<usize>;
     async fn dec(&mut self, rd: &R) -> Result<()>;
 }
+
+/// Appends `v` as an unsigned varint, same as Go's `binary.PutUvarint`.
+pub(crate) fn put_uvarint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Reads an unsigned varint, returns it with the number of bytes read. None if the
+/// buffer is truncated or the varint overflows u64.
+pub(crate) fn read_uvarint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut v = 0u64;
+    for (i, b) in buf.iter().enumerate().take(10) {
+        if i == 9 && *b > 1 {
+            return None;
+        }
+        v |= ((*b & 0x7f) as u64) << (7 * i);
+        if *b < 0x80 {
+            return Some((v, i + 1));
+        }
+    }
+    None
+}
+
+/// Returns the number of bytes of `v` encoded as varint.
+pub(crate) fn uvarint_len(mut v: u64) -> usize {
+    let mut n = 1;
+    while v >= 0x80 {
+        v >>= 7;
+        n += 1;
+    }
+    n
+}
+
+#[test]
+fn uvarint() {
+    for v in [0, 1, 127, 128, 300, 1 << 35, u64::MAX] {
+        let mut buf = vec![];
+        put_uvarint(&mut buf, v);
+        assert_eq!(buf.len(), uvarint_len(v));
+        assert_eq!(read_uvarint(&buf), Some((v, buf.len())));
+        assert_eq!(read_uvarint(&buf[..buf.len() - 1]), None);
+    }
+}
@@ -2,18 +2,22 @@ use byteorder::BigEndian;
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use log::info;
 
+use crate::y::{put_uvarint, read_uvarint, uvarint_len};
+
 use serde::{Deserialize, Serialize};
 use std::io::{Cursor, Write};
 
 /// ValueStruct represents the value info that can be associated with a key, but also the internal
 /// Meta field.
-/// |meta|user_meta|cas_counter|value_size|value|
+/// |meta|user_meta|cas_counter|expires_at(uvarint)|value|
 #[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 #[repr(C)]
 pub struct ValueStruct {
     pub(crate) meta: u8,
     pub(crate) user_meta: u8,
     pub(crate) cas_counter: u64,
+    // Unix time in seconds after which the value is expired, zero means never.
+    pub(crate) expires_at: u64,
     pub(crate) value: Vec<u8>,
 }
 
@@ -23,15 +27,28 @@ impl ValueStruct {
             meta,
             user_meta,
             cas_counter,
+            expires_at: 0,
             value,
         }
     }
+
+    pub(crate) fn with_expires_at(mut self, expires_at: u64) -> ValueStruct {
+        self.expires_at = expires_at;
+        self
+    }
+
+    /// Returns true if the value has an expiry time and it's not after `now`.
+    pub(crate) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+
+    /// Size of the fixed part of the header, `expires_at` takes 1 to 10 more bytes.
     pub(crate) const fn header_size() -> usize {
         10
     }
 
     pub(crate) fn size(&self) -> usize {
-        Self::header_size() + self.value.len()
+        Self::header_size() + uvarint_len(self.expires_at) + self.value.len()
     }
 
     pub(crate) fn write_data(&self, buffer: &mut [u8]) {
@@ -40,6 +57,9 @@ impl ValueStruct {
         cursor.write_u8(self.meta).unwrap();
         cursor.write_u8(self.user_meta).unwrap();
         cursor.write_u64::<BigEndian>(self.cas_counter).unwrap();
+        let mut expires_at = Vec::with_capacity(uvarint_len(self.expires_at));
+        put_uvarint(&mut expires_at, self.expires_at);
+        cursor.write_all(&expires_at).unwrap();
         cursor.write_all(&self.value).unwrap();
     }
 
@@ -48,17 +68,22 @@ impl ValueStruct {
         self.meta = cursor.read_u8().unwrap();
         self.user_meta = cursor.read_u8().unwrap();
         self.cas_counter = cursor.read_u64::<BigEndian>().unwrap();
-        self.value.extend_from_slice(&buffer[Self::header_size()..]);
+        let (expires_at, n) =
+            read_uvarint(&buffer[Self::header_size()..]).expect("invalid expires_at of value");
+        self.expires_at = expires_at;
+        self.value
+            .extend_from_slice(&buffer[Self::header_size() + n..]);
     }
 
     #[cfg(test)]
     pub(crate) fn pretty(&self) -> String {
         use crate::hex_str;
         format!(
-            "meta: {}, user_meta: {}, cas: {}, value: {}",
+            "meta: {}, user_meta: {}, cas: {}, expires_at: {}, value: {}",
             self.meta,
             self.user_meta,
             self.cas_counter,
+            self.expires_at,
             hex_str(&self.value)
         )
     }
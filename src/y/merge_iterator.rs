@@ -21,10 +21,8 @@ pub struct MergeCursor {
 }
 
 impl MergeCursor {
+    // A seek can land on internal keys, the iterators over the merge skip them.
     fn replace(&mut self, index: usize, cur_item: Option<IteratorItem>) {
-        if cur_item.is_some() {
-            assert!(!cur_item.as_ref().unwrap().key().starts_with(_BADGER_PREFIX));
-        }
         self.index = index;
         self.cur_item = cur_item;
     }
@@ -41,6 +39,8 @@ pub struct MergeIterator {
     pub cursor: RefCell<MergeCursor>,
     pub heap: RefCell<BinaryHeap<IterRef>>,
     pub heap_flag: RefCell<Vec<bool>>,
+    // Called with the older versions of a key left out, see `MergeIterOverBuilder::on_shadowed`.
+    on_shadowed: Option<Box<dyn Fn(&IteratorItem) + Send>>,
 }
 
 impl Xiterator for MergeIterator {
@@ -219,6 +219,9 @@ impl MergeIterator {
                 // Find the same, pop it
                 // #[cfg(test)]
                 // info!("Find a same value, {}", hex_str(pop_key));
+                if let Some(on_shadowed) = self.on_shadowed.as_ref() {
+                    on_shadowed(&pop.key);
+                }
                 drop(heap);
                 self.pop_item_from_heap();
 
@@ -284,6 +287,7 @@ impl MergeIterator {
 pub struct MergeIterOverBuilder {
     all: Vec<Box<dyn Xiterator<Output = IteratorItem>>>,
     reverse: bool,
+    on_shadowed: Option<Box<dyn Fn(&IteratorItem) + Send>>,
 }
 
 impl MergeIterOverBuilder {
@@ -292,6 +296,15 @@ impl MergeIterOverBuilder {
         self
     }
 
+    /// Calls `f` with every item left out because a newer iterator has the same key.
+    pub fn on_shadowed(
+        mut self,
+        f: impl Fn(&IteratorItem) + Send + 'static,
+    ) -> MergeIterOverBuilder {
+        self.on_shadowed = Some(Box::new(f));
+        self
+    }
+
     pub fn add(mut self, x: Box<dyn Xiterator<Output = IteratorItem>>) -> MergeIterOverBuilder {
         self.all.push(x);
         self
@@ -317,6 +330,7 @@ impl MergeIterOverBuilder {
             }),
             heap_flag: RefCell::new(flag),
             heap: RefCell::new(BinaryHeap::with_capacity(cap)),
+            on_shadowed: self.on_shadowed,
         }
     }
 }
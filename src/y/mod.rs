@@ -1,8 +1,14 @@
+mod clock;
 mod codec;
 pub(crate) mod iterator;
 pub mod merge_iterator;
 mod metrics;
+mod rate_limiter;
+mod synced_file;
 
+use crate::options::AccessHint;
+pub use clock::{Clock, SystemClock};
+pub(crate) use codec::{put_uvarint, read_uvarint, uvarint_len};
 pub use codec::{AsyncEncDec, Decode, Encode};
 pub use iterator::*;
 #[cfg(any(target_os = "macos", target_os = "linux"))]
@@ -10,8 +16,10 @@ use libc::O_DSYNC;
 use log::error;
 use memmap::MmapMut;
 pub use merge_iterator::*;
+pub(crate) use rate_limiter::RateLimiter;
 use std::cmp::Ordering;
 use std::collections::hash_map::DefaultHasher;
+pub(crate) use synced_file::{SyncedFileWriter, TEMP_FILE_SUFFIX};
 
 use std::fs::{File, OpenOptions};
 use std::hash::Hasher;
@@ -33,6 +41,9 @@ pub const USER_META_SIZE: usize = 1;
 pub const CAS_SIZE: usize = 8;
 pub const VALUE_SIZE: usize = 4;
 
+/// Largest `Options::value_threshold`, the values in memtables are at most 64KB.
+pub const MAX_VALUE_THRESHOLD: usize = u16::MAX as usize - 16;
+
 #[derive(Debug, Error, Clone)]
 pub enum Error {
     #[error(transparent)]
@@ -69,10 +80,43 @@ pub enum Error {
     /// Returned if the user request is invalid.
     #[error("Invalid request")]
     ValueInvalidRequest,
+    /// Returned when a transaction conflicts with another transaction, it can be retried.
+    #[error("Transaction Conflict. Please retry")]
+    TxnConflict,
+    /// Returned when a read-only transaction is written to.
+    #[error("No sets or deletes are allowed in a read-only transaction")]
+    ReadOnlyTxn,
+    /// Returned when a transaction is used after it was committed or discarded.
+    #[error("This transaction has been discarded. Create a new one")]
+    DiscardedTxn,
+    /// Returned when a transaction has more writes than fit in a batch, see
+    /// `KV::max_batch_count` and `KV::max_batch_size`.
+    #[error("Txn is too big to fit into one request")]
+    TxnTooBig,
+    /// Returned by the reads and writes of the keys under a banned prefix, see
+    /// `KV::ban_prefix`.
+    #[error("Key is using a banned prefix")]
+    BannedKey,
+    /// Returned by the calls reading without a read ts, e.g. `KV::new_transaction`, with
+    /// `Options::managed_txns`: the versions are given by the application.
+    #[error("A read ts is needed with managed transactions")]
+    ManagedTxns,
+    /// Returned by the calls reading at a read ts, e.g. `KV::new_transaction_at`, without
+    /// `Options::managed_txns`.
+    #[error("A read ts is only given with managed transactions")]
+    NotManagedTxns,
+    /// Returned by the writes made after the KV is closed.
+    #[error("DB Closed")]
+    DBClosed,
+    /// Returned by a `WriteBatch` used after it was cancelled.
+    #[error("Write batch has been cancelled")]
+    WriteBatchCancelled,
     #[error("Invalid Dir, directory does not exist")]
     InValidDir,
     #[error("Invalid ValueLogFileSize, must be between 1MB and 2GB")]
     ValueLogSize,
+    #[error("Invalid ValueThreshold, must be at most {}", MAX_VALUE_THRESHOLD)]
+    ValueThreshold,
 
     //////////////////////////////////
     // valueLog error
@@ -85,6 +129,19 @@ pub enum Error {
     EOF,
     #[error("Manifest has bad magic")]
     BadMagic,
+    /// Returned when a memtable can't be flushed because level 0 stayed full for longer
+    /// than `Options::level_zero_stall_timeout`.
+    #[error("Level 0 is full, waiting for compaction")]
+    LevelZeroStalled,
+    /// Returned by a compaction given up so a stalled level 0 can be compacted first.
+    #[error("Compaction preempted by a stalled level 0")]
+    CompactionPreempted,
+    /// Returned by a compaction given up because the DB is closing.
+    #[error("Compaction aborted, the DB is closing")]
+    CompactionAborted,
+    /// Returned when the data read from a table does not match its checksum.
+    #[error("Checksum mismatch: {0}")]
+    ChecksumMismatch(String),
     /////////////////////////////////
     #[error("Not found")]
     NotFound,
@@ -215,6 +272,57 @@ pub fn mmap(fd: &File, _writable: bool, size: usize) -> Result<MmapMut> {
     Ok(m)
 }
 
+/// Tells the OS how the mapped memory is going to be accessed.
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub(crate) fn madvise(m: &MmapMut, hint: AccessHint) -> Result<()> {
+    let advice = match hint {
+        AccessHint::Normal => libc::MADV_NORMAL,
+        AccessHint::Random => libc::MADV_RANDOM,
+        AccessHint::Sequential => libc::MADV_SEQUENTIAL,
+    };
+    if m.is_empty() {
+        return Ok(());
+    }
+    let ret = unsafe { libc::madvise(m.as_ptr() as *mut libc::c_void, m.len(), advice) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+pub(crate) fn madvise(_m: &MmapMut, _hint: AccessHint) -> Result<()> {
+    Ok(())
+}
+
+/// Frees the disk blocks of [offset, offset + len) of the file, reads of the range return
+/// zeros afterwards and the file size is kept. Returns false if the file system can't do it.
+#[cfg(target_os = "linux")]
+pub(crate) fn punch_hole(fd: &File, offset: u64, len: u64) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+    let ret = unsafe {
+        libc::fallocate(
+            fd.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EOPNOTSUPP) {
+            return Ok(false);
+        }
+        return Err(err.into());
+    }
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn punch_hole(_fd: &File, _offset: u64, _len: u64) -> Result<bool> {
+    Ok(false)
+}
+
 pub fn open_synced_file(file_name: &str, _sync: bool) -> Result<File> {
     let file = File::options()
         .write(true)
@@ -238,47 +346,21 @@ pub(crate) fn read_at(fp: &File, buffer: &mut [u8], offset: u64) -> Result<usize
     fp.seek_read(buffer, offset).map_err(|err| err.into())
 }
 
-pub(crate) fn num_cpu() -> usize {
-    let n = num_cpus::get();
-    n
-}
-
-// todo add error
-pub(crate) fn parallel_load_block_key(fp: File, offsets: Vec<u64>) -> Vec<Vec<u8>> {
-    use crate::table::builder::Header;
-    use std::sync::mpsc::sync_channel;
-    use threads_pool::*;
-    let (tx, rx) = sync_channel(offsets.len());
-    let num = num_cpu();
-    let mut pool = ThreadPool::new(num);
-    for (i, offset) in offsets.iter().enumerate() {
-        let offset = *offset;
-        let fp = fp.try_clone().unwrap();
-        let tx = tx.clone();
-        pool.execute(move || {
-            let mut buffer = vec![0u8; Header::size()];
-            read_at(&fp, &mut buffer, offset).unwrap();
-            let head = Header::from(buffer.as_slice());
-            assert_eq!(
-                head.p_len, 0,
-                "key offset: {}, h.p_len = {}",
-                offset, head.p_len
-            );
-            let out = vec![0u8; head.k_len as usize];
-            read_at(&fp, &mut buffer, offset + Header::size() as u64).unwrap();
-            tx.send((i, out)).unwrap();
-        })
-        .unwrap();
-    }
-    pool.close();
+#[cfg(any(target_os = "macos", target_os = "linux"))]
+pub(crate) fn write_at(fp: &File, buffer: &[u8], offset: u64) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    fp.write_all_at(buffer, offset).map_err(|err| err.into())
+}
 
-    let mut keys = vec![vec![0u8]; offsets.len()];
-    for _ in 0..offsets.len() {
-        let (i, key) = rx.recv().unwrap();
-        keys[i] = key;
+#[cfg(target_os = "windows")]
+pub(crate) fn write_at(fp: &File, mut buffer: &[u8], mut offset: u64) -> Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buffer.is_empty() {
+        let n = fp.seek_write(buffer, offset)?;
+        buffer = &buffer[n..];
+        offset += n as u64;
     }
-    drop(tx);
-    keys
+    Ok(())
 }
 
 pub(crate) fn slice_cmp_gte(a: &[u8], b: &[u8]) -> cmp::Ordering {
@@ -359,6 +441,28 @@ pub(crate) fn hex_str(buf: &[u8]) -> String {
     String::from_utf8(buf.to_vec()).unwrap_or_else(|_| "Sorry, Hex String Failed!!!".to_string())
 }
 
+/// Appends the version `ts` to `key` as `u64::MAX - ts` in big endian, so the versions of a
+/// key sort from the newest to the oldest.
+pub fn key_with_ts(key: &[u8], ts: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(key.len() + 8);
+    out.extend_from_slice(key);
+    out.extend_from_slice(&(u64::MAX - ts).to_be_bytes());
+    out
+}
+
+/// Returns the version of a key encoded by `key_with_ts`.
+pub fn parse_ts(key: &[u8]) -> u64 {
+    if key.len() < 8 {
+        return 0;
+    }
+    u64::MAX - u64::from_be_bytes(key[key.len() - 8..].try_into().unwrap())
+}
+
+/// Returns the key without the version appended by `key_with_ts`.
+pub fn parse_key(key: &[u8]) -> &[u8] {
+    &key[..key.len().saturating_sub(8)]
+}
+
 #[cfg(any(target_os = "macos", target_os = "linux"))]
 #[test]
 fn dsync() {
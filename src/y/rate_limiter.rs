@@ -0,0 +1,76 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// A token bucket of bytes refilled at `rate` bytes per second, it holds at most one second
+/// of tokens. A take larger than the tokens left goes into debt and waits it out, so big
+/// takes are throttled like many small ones.
+#[derive(Clone)]
+pub(crate) struct RateLimiter {
+    rate: u64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(rate: u64) -> RateLimiter {
+        assert!(rate > 0);
+        RateLimiter {
+            rate,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: rate as f64,
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    /// Takes `n` tokens and returns how long to wait before using them.
+    pub(crate) fn reserve(&self, n: u64) -> Duration {
+        let rate = self.rate as f64;
+        let mut bucket = self.bucket.lock();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.last = now;
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - n as f64;
+        if bucket.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+
+    /// Takes `n` tokens, blocking the thread until they're available.
+    pub(crate) fn wait_blocking(&self, n: u64) {
+        let delay = self.reserve(n);
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// Takes `n` tokens, waiting until they're available.
+    pub(crate) async fn wait(&self, n: u64) {
+        let delay = self.reserve(n);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[test]
+fn rate_limiter_debt() {
+    let limiter = RateLimiter::new(1000);
+    // The bucket starts full.
+    assert!(limiter.reserve(1000).is_zero());
+    let delay = limiter.reserve(500);
+    assert!(
+        delay > Duration::from_millis(400) && delay <= Duration::from_millis(500),
+        "{:?}",
+        delay
+    );
+    // The debt adds up.
+    assert!(limiter.reserve(1000) > Duration::from_millis(1400));
+}
@@ -0,0 +1,107 @@
+use crate::y::{create_synced_file, sync_directory, Result};
+use log::warn;
+use std::fs::{remove_file, rename, File};
+use std::io::Write;
+use std::path::Path;
+
+pub(crate) const TEMP_FILE_SUFFIX: &str = ".tmp";
+
+/// Writes a new file so that it never shows up half written under its final name, even on a
+/// crash. The content goes to `{file_name}.tmp`, `finish` fsyncs it, renames it to `file_name`
+/// and fsyncs the directory, so the file is durable before anything (e.g. a manifest change)
+/// refers to it. A writer dropped without `finish` removes the temporary file.
+pub(crate) struct SyncedFileWriter {
+    fd: Option<File>,
+    tmp_name: String,
+    file_name: String,
+}
+
+impl SyncedFileWriter {
+    pub(crate) fn create(file_name: &str) -> Result<SyncedFileWriter> {
+        let tmp_name = format!("{}{}", file_name, TEMP_FILE_SUFFIX);
+        // A leftover of a crashed write must not be appended to.
+        if Path::new(&tmp_name).exists() {
+            remove_file(&tmp_name)?;
+        }
+        let fd = create_synced_file(&tmp_name, true)?;
+        Ok(SyncedFileWriter {
+            fd: Some(fd),
+            tmp_name,
+            file_name: file_name.to_string(),
+        })
+    }
+
+    /// The final name of the file.
+    pub(crate) fn file_name(&self) -> &String {
+        &self.file_name
+    }
+
+    /// Returns a new handle of the underlying temporary file.
+    pub(crate) fn try_clone(&self) -> Result<File> {
+        Ok(self.fd.as_ref().unwrap().try_clone()?)
+    }
+
+    pub(crate) fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        self.fd.as_mut().unwrap().write_all(buf)?;
+        Ok(())
+    }
+
+    /// Syncs the temporary file, renames it to the final name and syncs the directory.
+    /// Returns the opened file and its name.
+    pub(crate) fn finish(mut self) -> Result<(File, String)> {
+        // On errors the temporary file is removed by drop.
+        self.fd
+            .as_ref()
+            .unwrap()
+            .sync_all()
+            .map_err(|err| format!("While syncing {}, err: {}", self.tmp_name, err))?;
+        rename(&self.tmp_name, &self.file_name).map_err(|err| {
+            format!(
+                "While renaming {} to {}, err: {}",
+                self.tmp_name, self.file_name, err
+            )
+        })?;
+        let fd = self.fd.take().unwrap();
+        let dir = Path::new(&self.file_name)
+            .parent()
+            .and_then(|dir| dir.to_str())
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or(".");
+        sync_directory(dir)?;
+        Ok((fd, std::mem::take(&mut self.file_name)))
+    }
+}
+
+impl Drop for SyncedFileWriter {
+    fn drop(&mut self) {
+        // Not finished, the file is incomplete.
+        if self.fd.take().is_some() {
+            if let Err(err) = remove_file(&self.tmp_name) {
+                warn!("Failed to remove {}, err: {}", self.tmp_name, err);
+            }
+        }
+    }
+}
+
+#[test]
+fn synced_file_writer() {
+    let dir = crate::test_util::create_random_tmp_dir();
+    let file_name = format!("{}/000003.sst", dir);
+    let tmp_name = format!("{}{}", file_name, TEMP_FILE_SUFFIX);
+    let mut wt = SyncedFileWriter::create(&file_name).unwrap();
+    wt.write_all(b"abc").unwrap();
+    assert!(!Path::new(&file_name).exists());
+    assert!(Path::new(&tmp_name).exists());
+    let (_, name) = wt.finish().unwrap();
+    assert_eq!(name, file_name);
+    assert_eq!(std::fs::read(&file_name).unwrap(), b"abc");
+    assert!(!Path::new(&tmp_name).exists());
+
+    // An unfinished write leaves nothing behind.
+    let file_name = format!("{}/000004.sst", dir);
+    let mut wt = SyncedFileWriter::create(&file_name).unwrap();
+    wt.write_all(b"abc").unwrap();
+    drop(wt);
+    assert!(!Path::new(&file_name).exists());
+    assert!(!Path::new(&format!("{}{}", file_name, TEMP_FILE_SUFFIX)).exists());
+}